@@ -0,0 +1,52 @@
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use tempfile::TempDir;
+
+use kvs::{KvStore, KvStoreOptions, KvsEngine};
+
+/// keys alive after compaction
+const LIVE_KEYS: [usize; 2] = [4_000, 16_000];
+/// writes of each key, all but the last one being redundant
+const WRITES_PER_KEY: [usize; 2] = [4, 8];
+
+/// a store with `live` keys each written `writes` times, not compacted yet
+fn overwritten_store(live: usize, writes: usize) -> (TempDir, KvStore) {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().auto_compaction(false);
+    let store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+    for round in 0..writes {
+        for i in 0..live {
+            store.set(format!("key{}", i), format!("value{}-{}", i, round)).unwrap();
+        }
+    }
+    store.flush().unwrap();
+    (temp_dir, store)
+}
+
+// Every case holds more redundant records than the automatic compaction threshold, so the
+// time of an iteration is the stall a write would see when it triggers the compaction.
+// Throughput counts every record of the log, live or redundant, as compaction reads them all.
+fn bench_compact(c: &mut Criterion) {
+    let mut group = c.benchmark_group("kvs compact");
+    group.sample_size(10);
+    for &live in LIVE_KEYS.iter() {
+        for &writes in WRITES_PER_KEY.iter() {
+            group.throughput(Throughput::Elements((live * writes) as u64));
+            let id = BenchmarkId::new(format!("{} live", live), format!("{} writes per key", writes));
+            group.bench_function(id, |b| {
+                b.iter_batched(
+                    || overwritten_store(live, writes),
+                    |(temp_dir, store)| {
+                        store.compact().unwrap();
+                        // dropped outside of the timing
+                        (temp_dir, store)
+                    },
+                    BatchSize::PerIteration,
+                )
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_compact);
+criterion_main!(benches);
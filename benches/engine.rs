@@ -1,11 +1,11 @@
 use std::collections::HashMap;
 
-use criterion::{criterion_group, criterion_main, Criterion};
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
 use tempfile::TempDir;
 
-use kvs::{KvStore, KvsEngine, SledKvsEngine};
+use kvs::{Durability, KvStore, KvStoreOptions, KvsEngine, SledKvsEngine, SledOptions};
 
 fn bench_write(c: &mut Criterion) {
     let mut rng = thread_rng();
@@ -18,6 +18,43 @@ fn bench_write(c: &mut Criterion) {
         map.insert(key, val);
     }
 
+    let map_clone = map.clone();
+    c.bench_function("sled write (periodic flush)", move |b| {
+        b.iter(|| {
+            let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+            let options = SledOptions::new().durability(Durability::Periodic);
+            let store = SledKvsEngine::open_with_options(temp_dir.path(), options).unwrap();
+            map_clone.iter().for_each(|(k, v)| {
+                store.set(k.clone(), v.clone()).unwrap();
+            });
+        })
+    });
+    let map_clone = map.clone();
+    c.bench_function("sled write (batch)", move |b| {
+        // the pairs are copied outside of the timing, `set_many` taking them by value
+        b.iter_batched(
+            || map_clone.clone().into_iter().collect(),
+            |pairs| {
+                let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+                let store = SledKvsEngine::open(temp_dir.path()).unwrap();
+                store.set_many(pairs).unwrap();
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    let map_clone = map.clone();
+    c.bench_function("kvs write (batch)", move |b| {
+        // the pairs are copied outside of the timing, `set_many` taking them by value
+        b.iter_batched(
+            || map_clone.clone().into_iter().collect(),
+            |pairs| {
+                let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+                let store = KvStore::open(temp_dir.path()).unwrap();
+                store.set_many(pairs).unwrap();
+            },
+            BatchSize::LargeInput,
+        )
+    });
     let map_clone = map.clone();
     c.bench_function("sled write", move |b| {
         b.iter(|| {
@@ -39,6 +76,24 @@ fn bench_write(c: &mut Criterion) {
     });
 }
 
+// Keys dominate the size of each record, so copies of the key show up in the timing.
+fn bench_write_large_keys(c: &mut Criterion) {
+    let mut rng = thread_rng();
+    let keys: Vec<String> = (0..1000)
+        .map(|_| rng.sample_iter(&Alphanumeric).take(100_000).collect())
+        .collect();
+
+    c.bench_function("kvs write (large keys)", move |b| {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path()).unwrap();
+        b.iter(|| {
+            keys.iter().for_each(|k| {
+                store.set(k.clone(), "value".to_owned()).unwrap();
+            });
+        })
+    });
+}
+
 fn bench_read(c: &mut Criterion) {
     let mut rng = thread_rng();
     let mut map = HashMap::new();
@@ -66,6 +121,23 @@ fn bench_read(c: &mut Criterion) {
         });
     });
 
+    let map_clone = map.clone();
+    c.bench_function("kvs read (value cache)", move |b| {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions::new().value_cache_capacity(map_clone.len());
+        let store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+        map_clone.iter().for_each(|(k, v)| {
+            store.set(k.clone(), v.clone()).unwrap();
+        });
+        b.iter(|| {
+            for _ in 0..10 {
+                map_clone.keys().for_each(|k| {
+                    store.get(k.clone()).unwrap();
+                });
+            }
+        });
+    });
+
     c.bench_function("kvs read", move |b| {
         let temp_dir = TempDir::new().expect("unable to create temporary working directory");
         let store = KvStore::open(temp_dir.path()).unwrap();
@@ -82,5 +154,20 @@ fn bench_read(c: &mut Criterion) {
     });
 }
 
-criterion_group!(benches, bench_read, bench_write);
+fn bench_open(c: &mut Criterion) {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    {
+        let options = KvStoreOptions::new().auto_compaction(false);
+        let store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+        for i in 0..200_000 {
+            store.set(format!("key{}", i % 20_000), format!("value{}", i)).unwrap();
+        }
+    }
+
+    c.bench_function("kvs open", move |b| {
+        b.iter(|| KvStore::open(temp_dir.path()).unwrap());
+    });
+}
+
+criterion_group!(benches, bench_read, bench_write, bench_write_large_keys, bench_open);
 criterion_main!(benches);
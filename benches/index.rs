@@ -0,0 +1,67 @@
+use std::ops::Bound;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use rand::{thread_rng, Rng};
+
+use kvs::{IndexType, KvStore, KvStoreOptions, KvsEngine, MemoryStorage};
+
+/// keys held by the store, all sharing one of `PREFIXES` prefixes
+const KEYS: usize = 20_000;
+const PREFIXES: usize = 100;
+const INDEX_TYPES: [IndexType; 3] = [IndexType::Hash, IndexType::BTree, IndexType::Interned];
+
+/// a store of `KEYS` keys indexed by `index_type`, values cached so that reads measure the index
+fn indexed_store(index_type: IndexType) -> KvStore {
+    let options = KvStoreOptions::new()
+        .index_type(index_type)
+        .value_cache_capacity(KEYS)
+        .flush_on_write(false);
+    let store = KvStore::open_with_storage(MemoryStorage::new(), options).unwrap();
+    for i in 0..KEYS {
+        store.set(format!("prefix{}:key{}", i % PREFIXES, i), "value").unwrap();
+    }
+    store
+}
+
+fn bench_get(c: &mut Criterion) {
+    let mut group = c.benchmark_group("index point get");
+    for &index_type in INDEX_TYPES.iter() {
+        let store = indexed_store(index_type);
+        let mut rng = thread_rng();
+        group.bench_function(BenchmarkId::from_parameter(format!("{:?}", index_type)), |b| {
+            b.iter(|| {
+                let i = rng.gen_range(0, KEYS);
+                store.get(format!("prefix{}:key{}", i % PREFIXES, i)).unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+// A range only covers a hundredth of the keys, which the B-tree visits alone.
+fn bench_range(c: &mut Criterion) {
+    let mut group = c.benchmark_group("index range scan");
+    for &index_type in INDEX_TYPES.iter() {
+        let store = indexed_store(index_type);
+        let name = format!("{:?}", index_type);
+        group.bench_function(BenchmarkId::new("count_prefix", &name), |b| {
+            b.iter(|| store.count_prefix("prefix42:".to_owned()).unwrap())
+        });
+        group.bench_function(BenchmarkId::new("keys", &name), |b| b.iter(|| store.keys().unwrap()));
+        // the range is empty once removed, so only the first iteration removes anything
+        group.bench_function(BenchmarkId::new("remove_range", &name), |b| {
+            b.iter(|| {
+                store
+                    .remove_range(
+                        Bound::Included("prefix7:".to_owned()),
+                        Bound::Excluded("prefix7;".to_owned()),
+                    )
+                    .unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_get, bench_range);
+criterion_main!(benches);
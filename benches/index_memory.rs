@@ -0,0 +1,63 @@
+//! Memory taken by each kind of index, measured with a global allocator counting live bytes.
+//!
+//! Not a criterion benchmark: it prints the bytes every key takes once loaded.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use tempfile::TempDir;
+
+use kvs::{IndexType, KvStore, KvStoreOptions};
+
+/// keys loaded into every store, each sharing one of `PREFIXES` prefixes
+const KEYS: usize = 200_000;
+const PREFIXES: usize = 100;
+
+struct Counting;
+
+static LIVE: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        LIVE.fetch_add(layout.size(), Ordering::SeqCst);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        LIVE.fetch_sub(layout.size(), Ordering::SeqCst);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+/// bytes taken by a store of `KEYS` keys indexed by `index_type`, its log staying on disk
+fn index_bytes(index_type: IndexType) -> usize {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .index_type(index_type)
+        .auto_compaction(false)
+        .flush_on_write(false);
+    let store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+    let before = LIVE.load(Ordering::SeqCst);
+    for i in 0..KEYS {
+        store
+            .set(format!("tenant-{:04}:sessions:{:08}", i % PREFIXES, i), "value")
+            .unwrap();
+    }
+    LIVE.load(Ordering::SeqCst) - before
+}
+
+fn main() {
+    for &index_type in [IndexType::Hash, IndexType::BTree, IndexType::Interned].iter() {
+        let bytes = index_bytes(index_type);
+        println!(
+            "{:?}: {} bytes for {} keys, {} per key",
+            index_type,
+            bytes,
+            KEYS,
+            bytes / KEYS
+        );
+    }
+}
@@ -0,0 +1,110 @@
+use std::io::{self, Read, Seek, SeekFrom};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use rand::{thread_rng, Rng};
+use tempfile::TempDir;
+
+use kvs::{FsStorage, KvStore, KvStoreOptions, Result, Storage, StorageReader, StorageWriter};
+
+/// keys of the store, each holding a value of `VALUE_LEN` bytes
+const KEYS: usize = 20_000;
+const VALUE_LEN: usize = 16;
+
+/// `FsStorage` counting the reads of its files, each one a system call
+#[derive(Clone, Debug)]
+struct CountingStorage {
+    inner: FsStorage,
+    reads: Arc<AtomicU64>,
+}
+
+struct CountedReader {
+    inner: Box<dyn StorageReader>,
+    reads: Arc<AtomicU64>,
+}
+
+impl Read for CountedReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        self.inner.read(buf)
+    }
+}
+
+impl Seek for CountedReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+impl Storage for CountingStorage {
+    fn open(&self, name: &str) -> Result<Box<dyn StorageReader>> {
+        Ok(Box::new(CountedReader {
+            inner: self.inner.open(name)?,
+            reads: self.reads.clone(),
+        }))
+    }
+
+    fn create(&self, name: &str) -> Result<Box<dyn StorageWriter>> {
+        self.inner.create(name)
+    }
+
+    fn append(&self, name: &str) -> Result<Box<dyn StorageWriter>> {
+        self.inner.append(name)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        self.inner.rename(from, to)
+    }
+
+    fn remove(&self, name: &str) -> Result<()> {
+        self.inner.remove(name)
+    }
+
+    fn list(&self) -> Result<Vec<String>> {
+        self.inner.list()
+    }
+}
+
+// Every get of a value read from the log seeks and reads the segment, while an inline
+// value is copied out of the index. The reads per get are printed along with the timings.
+fn bench_inline_get(c: &mut Criterion) {
+    let mut group = c.benchmark_group("kvs small value get");
+    for &inline in [false, true].iter() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let reads = Arc::new(AtomicU64::new(0));
+        let storage = CountingStorage {
+            inner: FsStorage::new(temp_dir.path()).unwrap(),
+            reads: reads.clone(),
+        };
+        let mut options = KvStoreOptions::new().flush_on_write(false);
+        if inline {
+            options = options.inline_values(VALUE_LEN, KEYS * VALUE_LEN);
+        }
+        let store = KvStore::open_with_storage(storage, options).unwrap();
+        for i in 0..KEYS {
+            store.set(format!("key{}", i), format!("{:01$}", i, VALUE_LEN)).unwrap();
+        }
+        store.get("key0").unwrap();
+
+        let before = reads.load(Ordering::Relaxed);
+        for i in 0..KEYS {
+            store.get(format!("key{}", i)).unwrap();
+        }
+        let name = if inline { "inline" } else { "log" };
+        println!(
+            "{}: {:.2} reads per get",
+            name,
+            (reads.load(Ordering::Relaxed) - before) as f64 / KEYS as f64
+        );
+
+        let mut rng = thread_rng();
+        group.bench_function(BenchmarkId::from_parameter(name), |b| {
+            b.iter(|| store.get(format!("key{}", rng.gen_range(0, KEYS))).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_inline_get);
+criterion_main!(benches);
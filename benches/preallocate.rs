@@ -0,0 +1,43 @@
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use tempfile::TempDir;
+
+use kvs::{KvStore, KvStoreOptions};
+
+/// small records written into a new store, each flushed on its own
+const RECORDS: usize = 20_000;
+/// size the active segment is preallocated to, holding every record written
+const SEGMENT_SIZE: u64 = 16 * 1024 * 1024;
+
+// Every flushed record grows a segment growing on demand, while a preallocated one only
+// has its bytes overwritten.
+fn bench_preallocate(c: &mut Criterion) {
+    let mut group = c.benchmark_group("kvs segment writes");
+    group.sample_size(20);
+    group.throughput(Throughput::Elements(RECORDS as u64));
+    for &preallocate in [false, true].iter() {
+        let name = if preallocate { "preallocated" } else { "grow on demand" };
+        group.bench_with_input(BenchmarkId::new(name, RECORDS), &preallocate, |b, &preallocate| {
+            b.iter_batched(
+                || {
+                    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+                    let mut options = KvStoreOptions::new().auto_compaction(false);
+                    if preallocate {
+                        options = options.preallocate_segments(SEGMENT_SIZE);
+                    }
+                    let store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+                    (temp_dir, store)
+                },
+                |(_temp_dir, store)| {
+                    for i in 0..RECORDS {
+                        store.set(format!("key{}", i), format!("value{}", i)).unwrap();
+                    }
+                },
+                BatchSize::PerIteration,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_preallocate);
+criterion_main!(benches);
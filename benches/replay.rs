@@ -0,0 +1,42 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use tempfile::TempDir;
+
+use kvs::{KvStore, KvStoreOptions};
+
+/// records of the log replayed on open
+const RECORDS: usize = 50_000;
+/// capacities of the segment readers and writers, the first one being the default
+const BUFFER_SIZES: [usize; 4] = [8 * 1024, 64 * 1024, 256 * 1024, 1024 * 1024];
+
+/// a store holding `RECORDS` records, half of them redundant, never compacted
+fn logged_store() -> TempDir {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().auto_compaction(false);
+    let store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+    for i in 0..RECORDS {
+        store
+            .set(format!("key{}", i % (RECORDS / 2)), format!("value{}", i))
+            .unwrap();
+    }
+    temp_dir
+}
+
+// Opening the store replays the whole log, which is where the buffers of the readers count most.
+fn bench_replay(c: &mut Criterion) {
+    let temp_dir = logged_store();
+    let mut group = c.benchmark_group("kvs replay");
+    group.sample_size(20);
+    group.throughput(Throughput::Elements(RECORDS as u64));
+    for &size in BUFFER_SIZES.iter() {
+        group.bench_with_input(BenchmarkId::new("buffer size", size), &size, |b, &size| {
+            b.iter(|| {
+                let options = KvStoreOptions::new().auto_compaction(false).buffer_size(size);
+                KvStore::open_with_options(temp_dir.path(), options).unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_replay);
+criterion_main!(benches);
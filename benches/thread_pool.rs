@@ -6,7 +6,7 @@ use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
 use tempfile::TempDir;
 
 use kvs::thread_pool::{RayonThreadPool, SharedQueueThreadPool, ThreadPool};
-use kvs::{KvStore, KvsClient, KvsEngine, KvsServer, SledKvsEngine};
+use kvs::{KvStore, KvsClient, KvsClientPool, KvsEngine, KvsServer, SledKvsEngine};
 
 fn read_queued_kvstore(c: &mut Criterion) {
     read_general_bench::<KvStore, SharedQueueThreadPool, Box<dyn Fn() -> KvStore>>(
@@ -125,6 +125,61 @@ fn write_general_bench<E: KvsEngine, T: ThreadPool, F: Fn() -> E>(
     }
 }
 
+// Many queries over a single connection, reusing the per-connection buffers.
+fn read_persistent_connection(c: &mut Criterion) {
+    let engine = generate_kvstore();
+    for i in 1000..2000 {
+        engine.set(i.to_string(), "value".to_string()).unwrap();
+    }
+    let addr: SocketAddr = "127.0.0.1:4001".parse().unwrap();
+    let server = KvsServer::init(engine, addr, SharedQueueThreadPool::new(1).unwrap()).unwrap();
+    server.start();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::init(&addr).unwrap();
+    c.bench_function("read_persistent_connection", |b| {
+        b.iter(|| {
+            for num in 1000..2000 {
+                assert_eq!(client.get(num.to_string()).unwrap(), Some("value".to_string()));
+            }
+        })
+    });
+    drop(client);
+    server.stop_server();
+}
+
+// The same reads fanned out over a pool of connections, against sequential gets.
+fn read_client_pool(c: &mut Criterion) {
+    let engine = generate_kvstore();
+    for i in 1000..2000 {
+        engine.set(i.to_string(), "value".to_string()).unwrap();
+    }
+    let addr: SocketAddr = "127.0.0.1:4002".parse().unwrap();
+    let server = KvsServer::init(engine, addr, SharedQueueThreadPool::new(8).unwrap()).unwrap();
+    server.start();
+    thread::sleep(Duration::from_secs(1));
+
+    let keys: Vec<String> = (1000..2000).map(|num| num.to_string()).collect();
+    let mut group = c.benchmark_group("read_client_pool");
+    let mut client = KvsClient::init(&addr).unwrap();
+    group.bench_function("sequential", |b| {
+        b.iter(|| {
+            for key in keys.iter() {
+                assert_eq!(client.get(key.clone()).unwrap(), Some("value".to_string()));
+            }
+        })
+    });
+    for concurrency in [2, 4, 8].iter() {
+        let pool = KvsClientPool::new(addr, *concurrency).unwrap();
+        group.bench_with_input(BenchmarkId::new("get_all", concurrency), &keys, |b, keys| {
+            b.iter(|| assert_eq!(pool.get_all(keys.clone()).unwrap().len(), keys.len()))
+        });
+    }
+    group.finish();
+    drop(client);
+    server.stop_server();
+}
+
 fn generate_kvstore() -> KvStore {
     let temp_dir = TempDir::new().unwrap();
     KvStore::open(temp_dir).unwrap()
@@ -142,6 +197,8 @@ criterion_group!(
     read_rayon_kvstore,
     write_rayon_kvstore,
     read_rayon_sledkvengine,
-    write_rayon_sledkvengine
+    write_rayon_sledkvengine,
+    read_persistent_connection,
+    read_client_pool
 );
 criterion_main!(benches);
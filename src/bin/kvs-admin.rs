@@ -0,0 +1,76 @@
+use std::env::current_dir;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::PathBuf;
+
+use structopt::StructOpt;
+
+use kvs::{KvStore, KvsEngine};
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Compact the log into a new segment
+    #[structopt(name = "compact")]
+    Compact,
+    /// Check that every key can be read back
+    #[structopt(name = "verify")]
+    Verify,
+    /// Print the segments and key count
+    #[structopt(name = "stats")]
+    Stats,
+    /// Write every key-value pair to a file
+    #[structopt(name = "export")]
+    Export { file: PathBuf },
+    /// Set every key-value pair of a file written by `export`
+    #[structopt(name = "import")]
+    Import { file: PathBuf },
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "kvs-admin", about = "Maintenance commands for a local kvs store")]
+struct Opt {
+    /// Directory of the store, defaults to the current directory
+    #[structopt(long, parse(from_os_str))]
+    data_dir: Option<PathBuf>,
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+fn main() -> kvs::Result<()> {
+    let opt: Opt = Opt::from_args();
+    let dir = match opt.data_dir {
+        Some(dir) => dir,
+        None => current_dir()?,
+    };
+    let store = KvStore::open(dir)?;
+
+    match opt.command {
+        Command::Compact => {
+            store.compact()?;
+            println!("compacted into epoch {}", store.epoch());
+        }
+        Command::Verify => println!("verified {} keys", store.verify()?),
+        Command::Stats => {
+            println!("epoch: {}", store.epoch());
+            println!("keys: {}", store.keys()?.len());
+            for segment in store.segments()? {
+                println!(
+                    "segment {}: {} bytes, {} live keys{}",
+                    segment.name,
+                    segment.size,
+                    segment.live_keys,
+                    if segment.compressed { ", compressed" } else { "" }
+                );
+            }
+        }
+        Command::Export { file } => {
+            let exported = kvs::export(&store, BufWriter::new(File::create(file)?))?;
+            println!("exported {} keys", exported);
+        }
+        Command::Import { file } => {
+            let imported = kvs::import(&store, BufReader::new(File::open(file)?))?;
+            println!("imported {} keys", imported);
+        }
+    }
+    Ok(())
+}
@@ -1,10 +1,11 @@
+use std::io::{self, Write};
 use std::net::SocketAddr;
 use std::process::exit;
 
 use log::error;
 use structopt::StructOpt;
 
-use kvs::KvsClient;
+use kvs::{ChangeOp, KvsClient};
 
 #[derive(Debug, StructOpt)]
 enum Command {
@@ -27,6 +28,15 @@ enum Command {
         #[structopt(long, parse(try_from_str), default_value = "127.0.0.1:4000")]
         addr: SocketAddr,
     },
+    /// Print every write of the server as `seq op key` until interrupted
+    ///
+    /// The server does not wait for a terminal too slow to keep up, it ends the stream
+    /// once this falls too far behind, and the command exits with an error.
+    #[structopt(name = "watch")]
+    Watch {
+        #[structopt(long, parse(try_from_str), default_value = "127.0.0.1:4000")]
+        addr: SocketAddr,
+    },
 }
 
 impl Command {
@@ -35,6 +45,7 @@ impl Command {
             Command::Set { addr, .. } => addr,
             Command::Get { addr, .. } => addr,
             Command::Remove { addr, .. } => addr,
+            Command::Watch { addr } => addr,
         }
     }
 }
@@ -69,5 +80,27 @@ fn main() {
                 exit(1);
             }
         },
+        Command::Watch { .. } => {
+            match watch(client) {
+                Ok(()) => eprintln!("the server ended the stream"),
+                Err(err) => eprintln!("{}", err),
+            }
+            exit(1);
+        }
     };
 }
+
+fn watch(client: KvsClient) -> kvs::Result<()> {
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    for event in client.subscribe()? {
+        let event = event?;
+        let op = match event.op {
+            ChangeOp::Set => "set",
+            ChangeOp::Rm => "rm",
+        };
+        writeln!(stdout, "{} {} {}", event.seq, op, event.key)?;
+        stdout.flush()?;
+    }
+    Ok(())
+}
@@ -1,39 +1,267 @@
 use std::env::current_dir;
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::io;
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 
-use log::info;
+use log::{info, LevelFilter};
+use serde::de::{self, Deserializer};
+use serde::Deserialize;
 use structopt::StructOpt;
 
-use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
-use kvs::{EngineType, KvStore, KvsEngine, KvsServer, SledKvsEngine};
+use kvs::thread_pool::{NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool};
+use kvs::{
+    CommandPolicy, Durability, EngineType, KvStore, KvStoreOptions, KvsEngine, KvsError, KvsServer, ServerConfig,
+    SledKvsEngine, SledOptions,
+};
 
+/// address listened on if neither the flags nor the config file give one
+const DEFAULT_ADDR: ([u8; 4], u16) = ([127, 0, 0, 1], 4000);
+
+/// Options of the server, each flag given overriding the value of the config file
 #[derive(Debug, StructOpt)]
 #[structopt(name = "kvs-server", about = env!("CARGO_PKG_DESCRIPTION"))]
 struct Opt {
-    #[structopt(long, parse(try_from_str), default_value = "127.0.0.1:4000")]
-    addr: SocketAddr,
-    #[structopt(long, parse(try_from_str), default_value = "kvs")]
-    engine: EngineType,
+    /// Read the options from a TOML file, or a JSON one if its name ends with `.json`
+    #[structopt(long, parse(from_os_str))]
+    config: Option<PathBuf>,
+    /// Address to listen on, may be given several times [default: 127.0.0.1:4000]
+    #[structopt(long, parse(try_from_str), number_of_values = 1)]
+    addr: Vec<SocketAddr>,
+    /// Engine storing the data [default: kvs]
+    #[structopt(long, parse(try_from_str))]
+    engine: Option<EngineType>,
+    /// Directory of the data, defaults to the current directory
+    #[structopt(long, parse(from_os_str))]
+    data_dir: Option<PathBuf>,
+    /// When writes are made durable: on every write, or in the background and possibly lost
+    /// on a crash [default: flush-on-write]
+    #[structopt(long, parse(try_from_str), possible_values = &["flush-on-write", "periodic"])]
+    durability: Option<Durability>,
+    /// Threads serving connections, defaults to the number of CPUs
+    #[structopt(long)]
+    threads: Option<u32>,
+    /// Thread pool serving connections [default: shared-queue]
+    #[structopt(long, parse(try_from_str), possible_values = &["naive", "shared-queue", "rayon"])]
+    pool: Option<PoolType>,
+    /// Read the data of the engine once before accepting connections
+    #[structopt(long)]
+    warm_up: bool,
+    /// Let clients run maintenance commands such as compaction
+    #[structopt(long)]
+    allow_admin: bool,
+    /// Refuse every query that would change the data, e.g. for a public read replica
+    #[structopt(long)]
+    read_only: bool,
+    /// Log a warning for queries taking longer than this many milliseconds, 0 to never log [default: 100]
+    #[structopt(long)]
+    slow_query_ms: Option<u64>,
+    /// Close connections sending no query for this many seconds, 0 to keep them open [default: 0]
+    #[structopt(long)]
+    idle_timeout_secs: Option<u64>,
+    /// Most verbose level of the messages logged [default: info]
+    #[structopt(
+        long,
+        parse(try_from_str),
+        possible_values = &["trace", "debug", "info", "warn", "error"],
+        case_insensitive = true
+    )]
+    log_level: Option<LevelFilter>,
+}
+
+/// Options read from the file given with `--config`, each one optional
+///
+/// The options of the `server` table are those of `ServerConfig`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    addr: Vec<SocketAddr>,
+    #[serde(deserialize_with = "parse")]
+    engine: Option<EngineType>,
+    data_dir: Option<PathBuf>,
+    #[serde(deserialize_with = "parse")]
+    durability: Option<Durability>,
+    threads: Option<u32>,
+    #[serde(deserialize_with = "parse")]
+    pool: Option<PoolType>,
+    #[serde(deserialize_with = "parse")]
+    log_level: Option<LevelFilter>,
+    server: ServerConfig,
+}
+
+/// read a value from its string form, as given on the command line
+fn parse<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    String::deserialize(deserializer)?
+        .parse()
+        .map(Some)
+        .map_err(de::Error::custom)
+}
+
+impl FileConfig {
+    /// read the file, failing with `KvsError::InvalidOption` naming the offending field
+    fn load(path: &Path) -> kvs::Result<Self> {
+        let content = fs::read_to_string(path)?;
+        let config = if path.extension() == Some("json".as_ref()) {
+            serde_json::from_str(&content).map_err(|e| e.to_string())
+        } else {
+            toml::from_str(&content).map_err(|e| e.to_string())
+        };
+        config.map_err(|e| KvsError::InvalidOption(format!("{}: {}", path.display(), e)))
+    }
+}
+
+/// Thread pool serving the connections of the server
+#[derive(Copy, Clone, Debug)]
+enum PoolType {
+    Naive,
+    SharedQueue,
+    Rayon,
+}
+
+impl FromStr for PoolType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "naive" => Ok(PoolType::Naive),
+            "shared-queue" => Ok(PoolType::SharedQueue),
+            "rayon" => Ok(PoolType::Rayon),
+            _ => Err(format!(
+                "unknown pool `{}`, expected `naive`, `shared-queue` or `rayon`",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for PoolType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            PoolType::Naive => write!(f, "naive"),
+            PoolType::SharedQueue => write!(f, "shared-queue"),
+            PoolType::Rayon => write!(f, "rayon"),
+        }
+    }
 }
 
 fn main() -> kvs::Result<()> {
-    env_logger::builder().filter_level(log::LevelFilter::Info).init();
+    let opt: Opt = Opt::from_args();
+    let file = match &opt.config {
+        Some(path) => FileConfig::load(path)?,
+        None => FileConfig::default(),
+    };
+    let log_level = opt.log_level.or(file.log_level).unwrap_or(LevelFilter::Info);
+    env_logger::builder().filter_level(log_level).init();
     info!("kvs-server {}", env!("CARGO_PKG_VERSION"));
 
-    let opt: Opt = Opt::from_args();
-    info!("server addr: {}, engine: {}", opt.addr, opt.engine);
+    let addrs = match (opt.addr.is_empty(), file.addr.is_empty()) {
+        (false, _) => opt.addr,
+        (true, false) => file.addr,
+        (true, true) => vec![DEFAULT_ADDR.into()],
+    };
+    let engine = opt.engine.or(file.engine).unwrap_or(EngineType::KvStore);
+    let dir = match opt.data_dir.or(file.data_dir) {
+        Some(dir) => dir,
+        None => current_dir()?,
+    };
+    let durability = opt.durability.or(file.durability).unwrap_or(Durability::FlushOnWrite);
+    let threads = opt.threads.or(file.threads).unwrap_or(num_cpus::get() as u32);
+    if threads == 0 {
+        return Err(KvsError::InvalidOption("`threads` must be at least 1".to_owned()));
+    }
+    let pool = opt.pool.or(file.pool).unwrap_or(PoolType::SharedQueue);
+    let mut config = file.server;
+    if opt.warm_up {
+        config = config.warm_up(true);
+    }
+    if opt.allow_admin {
+        config = config.allow_admin(true);
+    }
+    if opt.read_only {
+        config = config.command_policy(CommandPolicy::read_only());
+    }
+    if let Some(millis) = opt.slow_query_ms {
+        config =
+            config.slow_query_threshold(Some(Duration::from_millis(millis)).filter(|threshold| !threshold.is_zero()));
+    }
+    if let Some(secs) = opt.idle_timeout_secs {
+        config = config.idle_timeout(Some(Duration::from_secs(secs)));
+    }
+    info!(
+        "server addr: {:?}, engine: {}, data dir: {}, durability: {}, threads: {}, pool: {}, log level: {}, \
+         config: {:?}",
+        addrs,
+        engine,
+        dir.display(),
+        durability,
+        threads,
+        pool,
+        log_level,
+        config
+    );
 
-    let dir = current_dir()?;
+    match engine {
+        EngineType::KvStore => {
+            let options = KvStoreOptions::new().flush_on_write(durability == Durability::FlushOnWrite);
+            run(KvStore::open_with_options(dir, options)?, pool, threads, &addrs, config)
+        }
+        EngineType::Sled => {
+            let options = SledOptions::new().durability(durability);
+            run(
+                SledKvsEngine::open_with_options(dir, options)?,
+                pool,
+                threads,
+                &addrs,
+                config,
+            )
+        }
+    }
+}
 
-    let thread_pool = SharedQueueThreadPool::new(num_cpus::get() as u32)?;
-    match opt.engine {
-        EngineType::KvStore => start_server(KvStore::open(dir)?, opt.addr, thread_pool),
-        EngineType::Sled => start_server(SledKvsEngine::open(dir)?, opt.addr, thread_pool),
+fn run<E: KvsEngine>(
+    engine: E,
+    pool: PoolType,
+    threads: u32,
+    addrs: &[SocketAddr],
+    config: ServerConfig,
+) -> kvs::Result<()> {
+    match pool {
+        PoolType::Naive => start_server(engine, addrs, NaiveThreadPool::new(threads)?, config),
+        PoolType::SharedQueue => start_server(engine, addrs, SharedQueueThreadPool::new(threads)?, config),
+        PoolType::Rayon => start_server(engine, addrs, RayonThreadPool::new(threads)?, config),
     }
 }
 
-fn start_server<E: KvsEngine, P: ThreadPool>(engine: E, addr: SocketAddr, thread_pool: P) -> kvs::Result<()> {
-    let server = KvsServer::init(engine, addr, thread_pool)?;
+fn start_server<E: KvsEngine, P: ThreadPool>(
+    engine: E,
+    addrs: &[SocketAddr],
+    thread_pool: P,
+    config: ServerConfig,
+) -> kvs::Result<()> {
+    let mut server = KvsServer::init_with_config(engine, addrs[0], thread_pool, config)?;
+    for addr in addrs[1..].iter() {
+        server.add_listener(*addr)?;
+    }
+    let server = Arc::new(server);
     let handle = server.start();
-    handle.join().unwrap()
+
+    // stop accepting on SIGINT or SIGTERM, e.g. from `docker stop`, then drain below
+    let stopping = server.clone();
+    ctrlc::set_handler(move || {
+        info!("received a termination signal, no longer accepting connections");
+        stopping.stop_server();
+    })
+    .map_err(io::Error::other)?;
+
+    handle.join().unwrap()?;
+    server.shutdown()
 }
@@ -0,0 +1,160 @@
+use std::io;
+use std::ops::Bound;
+use std::panic;
+use std::path::PathBuf;
+
+use crate::{KvStore, KvsEngine, Result};
+
+/// An engine callable from async code, every call running on the blocking threads of tokio
+///
+/// The engines block on disk I/O and locks, which would stall the executor if called
+/// from an async task directly. Every method of `AsyncKvStore` moves the call to
+/// `tokio::task::spawn_blocking` instead, so it must be awaited within a tokio runtime.
+///
+/// Examples:
+/// ```rust
+/// use kvs::{AsyncKvStore, KvStore, KvStoreOptions, MemoryStorage};
+///
+/// let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+/// let store = KvStore::open_with_storage(MemoryStorage::new(), KvStoreOptions::new()).unwrap();
+/// let store = AsyncKvStore::new(store);
+/// runtime.block_on(async {
+///     store.set("abc".to_owned(), "def".to_owned()).await.unwrap();
+///     assert_eq!(store.get("abc".to_owned()).await.unwrap(), Some("def".to_owned()));
+/// });
+/// ```
+#[derive(Clone)]
+pub struct AsyncKvStore<E: KvsEngine = KvStore> {
+    inner: E,
+}
+
+impl AsyncKvStore<KvStore> {
+    /// open the `KvStore` at the given path, replaying its log on a blocking thread
+    pub async fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        spawn_blocking(move || KvStore::open(path)).await.map(Self::new)
+    }
+}
+
+impl<E: KvsEngine> AsyncKvStore<E> {
+    /// wrap `inner`, whose calls are made on the blocking threads
+    pub fn new(inner: E) -> Self {
+        Self { inner }
+    }
+
+    /// the wrapped engine
+    pub fn inner(&self) -> &E {
+        &self.inner
+    }
+
+    /// run `f` with a clone of the engine on a blocking thread
+    async fn run<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(E) -> Result<T> + Send + 'static,
+    {
+        let inner = self.inner.clone();
+        spawn_blocking(move || f(inner)).await
+    }
+
+    /// get the value of a key, see `KvsEngine::get`
+    pub async fn get(&self, key: String) -> Result<Option<String>> {
+        self.run(move |engine| engine.get(key)).await
+    }
+
+    /// set a key-value pair, see `KvsEngine::set`
+    pub async fn set(&self, key: String, value: String) -> Result<()> {
+        self.run(move |engine| engine.set(key, value)).await
+    }
+
+    /// set every key-value pair in order, see `KvsEngine::set_many`
+    pub async fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        self.run(move |engine| engine.set_many(pairs)).await
+    }
+
+    /// remove a key, see `KvsEngine::remove`
+    pub async fn remove(&self, key: String) -> Result<()> {
+        self.run(move |engine| engine.remove(key)).await
+    }
+
+    /// get the value of a key along with its version, see `KvsEngine::get_versioned`
+    pub async fn get_versioned(&self, key: String) -> Result<Option<(String, u64)>> {
+        self.run(move |engine| engine.get_versioned(key)).await
+    }
+
+    /// set a key-value pair only if the version of the key is still `expected`, see
+    /// `KvsEngine::set_if_version`
+    pub async fn set_if_version(&self, key: String, value: String, expected: u64) -> Result<bool> {
+        self.run(move |engine| engine.set_if_version(key, value, expected))
+            .await
+    }
+
+    /// set a key-value pair and return the value it replaced, see `KvsEngine::set_and_get_old`
+    pub async fn set_and_get_old(&self, key: String, value: String) -> Result<Option<String>> {
+        self.run(move |engine| engine.set_and_get_old(key, value)).await
+    }
+
+    /// fold `operand` into the value of a key, see `KvsEngine::merge`
+    pub async fn merge(&self, key: String, operand: String) -> Result<()> {
+        self.run(move |engine| engine.merge(key, operand)).await
+    }
+
+    /// move the value of `from` to `to` and remove `from`, see `KvsEngine::rename`
+    pub async fn rename(&self, from: String, to: String) -> Result<bool> {
+        self.run(move |engine| engine.rename(from, to)).await
+    }
+
+    /// remove every key within the given range, see `KvsEngine::remove_range`
+    pub async fn remove_range(&self, start: Bound<String>, end: Bound<String>) -> Result<usize> {
+        self.run(move |engine| engine.remove_range(start, end)).await
+    }
+
+    /// list every key, see `KvsEngine::keys`
+    pub async fn keys(&self) -> Result<Vec<String>> {
+        self.run(|engine| engine.keys()).await
+    }
+
+    /// count the keys starting with `prefix`, see `KvsEngine::count_prefix`
+    pub async fn count_prefix(&self, prefix: String) -> Result<usize> {
+        self.run(move |engine| engine.count_prefix(prefix)).await
+    }
+
+    /// prepare the store to serve its first requests quickly, see `KvsEngine::warm_up`
+    pub async fn warm_up(&self) -> Result<()> {
+        self.run(|engine| engine.warm_up()).await
+    }
+
+    /// check that the store can serve reads and writes, see `KvsEngine::health_check`
+    pub async fn health_check(&self) -> Result<()> {
+        self.run(|engine| engine.health_check()).await
+    }
+
+    /// make every write so far durable, see `KvsEngine::flush`
+    pub async fn flush(&self) -> Result<()> {
+        self.run(|engine| engine.flush()).await
+    }
+
+    /// reclaim the space of overwritten and removed values, see `KvsEngine::compact`
+    pub async fn compact(&self) -> Result<()> {
+        self.run(|engine| engine.compact()).await
+    }
+
+    /// reclaim the space of the keys within the given range, see `KvsEngine::compact_range`
+    pub async fn compact_range(&self, start: Bound<String>, end: Bound<String>) -> Result<()> {
+        self.run(move |engine| engine.compact_range(start, end)).await
+    }
+}
+
+/// run `f` on a blocking thread, carrying a panic of `f` over to the awaiting task
+async fn spawn_blocking<T, F>(f: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T> + Send + 'static,
+{
+    match tokio::task::spawn_blocking(f).await {
+        Ok(result) => result,
+        Err(e) if e.is_panic() => panic::resume_unwind(e.into_panic()),
+        // the runtime shut down before running it
+        Err(e) => Err(io::Error::other(e).into()),
+    }
+}
@@ -0,0 +1,166 @@
+use std::ops::Bound;
+use std::sync::{Arc, Mutex};
+
+use lru::LruCache;
+
+use crate::{KvsEngine, Result};
+
+/// An engine answering repeated `get`s from an in-memory cache in front of another engine
+///
+/// Recent `get` results, including absent keys, are kept in an LRU cache shared by all
+/// clones. Writes through the `CachingEngine` invalidate the keys they touch; writes made
+/// to the inner engine directly are not seen until the key is evicted.
+///
+/// Examples:
+/// ```rust
+/// use kvs::{CachingEngine, KvStore, KvStoreOptions, KvsEngine, MemoryStorage};
+///
+/// let store = KvStore::open_with_storage(MemoryStorage::new(), KvStoreOptions::new()).unwrap();
+/// let engine = CachingEngine::new(store, 1000);
+/// engine.set("abc".to_owned(), "def".to_owned()).unwrap();
+/// assert_eq!(engine.get("abc".to_owned()).unwrap(), Some("def".to_owned()));
+/// ```
+#[derive(Clone)]
+pub struct CachingEngine<E: KvsEngine> {
+    inner: E,
+    cache: Arc<Mutex<Cache>>,
+}
+
+struct Cache {
+    entries: LruCache<String, Option<String>>,
+    /// bumped by every write, so a `get` racing with a write does not cache what it read
+    generation: u64,
+}
+
+impl<E: KvsEngine> CachingEngine<E> {
+    /// wrap `inner`, caching the results of up to `capacity` keys
+    pub fn new(inner: E, capacity: usize) -> Self {
+        Self {
+            inner,
+            cache: Arc::new(Mutex::new(Cache {
+                entries: LruCache::new(capacity),
+                generation: 0,
+            })),
+        }
+    }
+
+    /// the wrapped engine
+    pub fn inner(&self) -> &E {
+        &self.inner
+    }
+
+    fn invalidate(&self, key: Option<&String>) {
+        let mut cache = self.cache.lock().unwrap();
+        cache.generation += 1;
+        match key {
+            Some(key) => {
+                cache.entries.pop(key);
+            }
+            None => cache.entries.clear(),
+        }
+    }
+}
+
+impl<E: KvsEngine> KvsEngine for CachingEngine<E> {
+    fn get(&self, key: String) -> Result<Option<String>> {
+        let generation = {
+            let mut cache = self.cache.lock().unwrap();
+            if let Some(value) = cache.entries.get(&key) {
+                return Ok(value.clone());
+            }
+            cache.generation
+        };
+
+        let value = self.inner.get(key.clone())?;
+        let mut cache = self.cache.lock().unwrap();
+        if cache.generation == generation {
+            cache.entries.put(key, value.clone());
+        }
+        Ok(value)
+    }
+
+    fn set(&self, key: String, value: String) -> Result<()> {
+        let result = self.inner.set(key.clone(), value);
+        self.invalidate(Some(&key));
+        result
+    }
+
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        let keys: Vec<String> = pairs.iter().map(|(key, _)| key.clone()).collect();
+        let result = self.inner.set_many(pairs);
+        for key in keys.iter() {
+            self.invalidate(Some(key));
+        }
+        result
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        let result = self.inner.remove(key.clone());
+        self.invalidate(Some(&key));
+        result
+    }
+
+    /// always read from the inner engine, which alone knows the version
+    fn get_versioned(&self, key: String) -> Result<Option<(String, u64)>> {
+        self.inner.get_versioned(key)
+    }
+
+    fn set_if_version(&self, key: String, value: String, expected: u64) -> Result<bool> {
+        let result = self.inner.set_if_version(key.clone(), value, expected);
+        self.invalidate(Some(&key));
+        result
+    }
+
+    fn set_and_get_old(&self, key: String, value: String) -> Result<Option<String>> {
+        let result = self.inner.set_and_get_old(key.clone(), value);
+        self.invalidate(Some(&key));
+        result
+    }
+
+    fn merge(&self, key: String, operand: String) -> Result<()> {
+        let result = self.inner.merge(key.clone(), operand);
+        self.invalidate(Some(&key));
+        result
+    }
+
+    fn rename(&self, from: String, to: String) -> Result<bool> {
+        let result = self.inner.rename(from.clone(), to.clone());
+        self.invalidate(Some(&from));
+        self.invalidate(Some(&to));
+        result
+    }
+
+    fn remove_range(&self, start: Bound<String>, end: Bound<String>) -> Result<usize> {
+        let result = self.inner.remove_range(start, end);
+        self.invalidate(None);
+        result
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        self.inner.keys()
+    }
+
+    fn count_prefix(&self, prefix: String) -> Result<usize> {
+        self.inner.count_prefix(prefix)
+    }
+
+    fn warm_up(&self) -> Result<()> {
+        self.inner.warm_up()
+    }
+
+    fn health_check(&self) -> Result<()> {
+        self.inner.health_check()
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.inner.flush()
+    }
+
+    fn compact(&self) -> Result<()> {
+        self.inner.compact()
+    }
+
+    fn compact_range(&self, start: Bound<String>, end: Bound<String>) -> Result<()> {
+        self.inner.compact_range(start, end)
+    }
+}
@@ -0,0 +1,385 @@
+use std::borrow::Borrow;
+use std::cell::Cell;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::ops::{Bound, Deref, RangeBounds};
+use std::sync::{Arc, RwLock};
+
+use chashmap::CHashMap;
+
+use crate::engine::IndexType;
+
+/// In-memory index of a `KvStore` from each live key to its record
+///
+/// Every kind holds the same entries and only differs in which operations are cheap,
+/// and in how much memory it takes, see `IndexType`.
+pub(crate) enum KeyIndex<V> {
+    Hash(CHashMap<String, V>),
+    BTree(RwLock<BTreeMap<String, V>>),
+    Interned(RwLock<InternedMap<V>>),
+}
+
+impl<V: Clone> KeyIndex<V> {
+    pub(crate) fn new(index_type: IndexType) -> Self {
+        match index_type {
+            IndexType::Hash => KeyIndex::Hash(CHashMap::new()),
+            IndexType::BTree => KeyIndex::BTree(RwLock::new(BTreeMap::new())),
+            IndexType::Interned => KeyIndex::Interned(RwLock::new(InternedMap::new())),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        match self {
+            KeyIndex::Hash(map) => map.len(),
+            KeyIndex::BTree(map) => map.read().unwrap().len(),
+            KeyIndex::Interned(map) => map.read().unwrap().map.len(),
+        }
+    }
+
+    pub(crate) fn get(&self, key: &str) -> Option<V> {
+        match self {
+            KeyIndex::Hash(map) => map.get(key).map(|value| (*value).clone()),
+            KeyIndex::BTree(map) => map.read().unwrap().get(key).cloned(),
+            KeyIndex::Interned(map) => map.read().unwrap().get(key).cloned(),
+        }
+    }
+
+    pub(crate) fn contains_key(&self, key: &str) -> bool {
+        match self {
+            KeyIndex::Hash(map) => map.contains_key(key),
+            KeyIndex::BTree(map) => map.read().unwrap().contains_key(key),
+            KeyIndex::Interned(map) => map.read().unwrap().get(key).is_some(),
+        }
+    }
+
+    /// return the entry replaced, if any
+    pub(crate) fn insert(&self, key: String, value: V) -> Option<V> {
+        match self {
+            KeyIndex::Hash(map) => map.insert(key, value),
+            KeyIndex::BTree(map) => map.write().unwrap().insert(key, value),
+            KeyIndex::Interned(map) => map.write().unwrap().insert(key, value),
+        }
+    }
+
+    pub(crate) fn remove(&self, key: &str) -> Option<V> {
+        match self {
+            KeyIndex::Hash(map) => map.remove(key),
+            KeyIndex::BTree(map) => map.write().unwrap().remove(key),
+            KeyIndex::Interned(map) => map.write().unwrap().remove(key),
+        }
+    }
+
+    /// replace the entry of `key` with what `f` makes of it, `None` removing it
+    ///
+    /// no other write of the key is applied while `f` runs. the hash index only locks the
+    /// bucket of the key meanwhile, the B-trees the whole index.
+    pub(crate) fn alter(&self, key: String, f: impl FnOnce(Option<V>) -> Option<V>) {
+        match self {
+            KeyIndex::Hash(map) => map.alter(key, f),
+            KeyIndex::BTree(map) => {
+                let mut map = map.write().unwrap();
+                match f(map.get(&key).cloned()) {
+                    Some(value) => map.insert(key, value),
+                    None => map.remove(&key),
+                };
+            }
+            KeyIndex::Interned(map) => {
+                let mut map = map.write().unwrap();
+                match f(map.get(&key).cloned()) {
+                    Some(value) => map.insert(key, value),
+                    None => map.remove(&key),
+                };
+            }
+        }
+    }
+
+    /// a copy of every entry, in key order for the B-trees
+    pub(crate) fn entries(&self) -> Vec<(String, V)> {
+        match self {
+            KeyIndex::Hash(map) => map.clone().into_iter().collect(),
+            KeyIndex::BTree(map) => map
+                .read()
+                .unwrap()
+                .iter()
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect(),
+            KeyIndex::Interned(map) => map
+                .read()
+                .unwrap()
+                .map
+                .iter()
+                .map(|(key, value)| (key.key(), value.clone()))
+                .collect(),
+        }
+    }
+
+    /// a copy of the entries within `range`, only visiting those for the B-trees
+    pub(crate) fn range(&self, range: &(Bound<String>, Bound<String>)) -> Vec<(String, V)> {
+        match self {
+            KeyIndex::Hash(map) => map.clone().into_iter().filter(|(key, _)| range.contains(key)).collect(),
+            KeyIndex::BTree(_) | KeyIndex::Interned(_) if is_empty(range) => Vec::new(),
+            KeyIndex::BTree(map) => map
+                .read()
+                .unwrap()
+                .range::<String, _>(range.clone())
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect(),
+            KeyIndex::Interned(map) => map
+                .read()
+                .unwrap()
+                .map
+                .range::<dyn SplitKey, _>((split_bound(&range.0), split_bound(&range.1)))
+                .map(|(key, value)| (key.key(), value.clone()))
+                .collect(),
+        }
+    }
+
+    /// every key in ascending byte-wise order, which the hash index has to sort
+    pub(crate) fn sorted_keys(&self) -> Vec<String> {
+        match self {
+            KeyIndex::Hash(map) => {
+                let mut keys: Vec<String> = map.clone().into_iter().map(|(key, _)| key).collect();
+                keys.sort_unstable();
+                keys
+            }
+            KeyIndex::BTree(map) => map.read().unwrap().keys().cloned().collect(),
+            KeyIndex::Interned(map) => map.read().unwrap().map.keys().map(InternedKey::key).collect(),
+        }
+    }
+
+    /// count the keys starting with `prefix` without copying any
+    pub(crate) fn count_prefix(&self, prefix: &str) -> usize {
+        match self {
+            // `retain` visits the entries in place
+            KeyIndex::Hash(map) => {
+                let count = Cell::new(0);
+                map.retain(|key, _| {
+                    if key.starts_with(prefix) {
+                        count.set(count.get() + 1);
+                    }
+                    true
+                });
+                count.get()
+            }
+            KeyIndex::BTree(map) => map
+                .read()
+                .unwrap()
+                .range::<str, _>((Bound::Included(prefix), Bound::Unbounded))
+                .take_while(|(key, _)| key.starts_with(prefix))
+                .count(),
+            KeyIndex::Interned(map) => map
+                .read()
+                .unwrap()
+                .map
+                .range::<dyn SplitKey, _>((Bound::Included(&prefix as &dyn SplitKey), Bound::Unbounded))
+                .take_while(|(key, _)| key.starts_with(prefix))
+                .count(),
+        }
+    }
+}
+
+/// whether `range` holds no key, which `BTreeMap::range` would panic on if its ends are reversed
+fn is_empty(range: &(Bound<String>, Bound<String>)) -> bool {
+    match range {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (Bound::Included(start), Bound::Excluded(end))
+        | (Bound::Excluded(start), Bound::Included(end))
+        | (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+        _ => false,
+    }
+}
+
+/// ends every prefix the interned index shares between keys, as `NamespacedStore` writes them
+const PREFIX_SEPARATOR: char = ':';
+
+/// B-tree whose keys share their prefix, see `IndexType::Interned`
+pub(crate) struct InternedMap<V> {
+    map: BTreeMap<InternedKey, V>,
+    /// the prefix of every key in `map`, once
+    prefixes: HashSet<Prefix>,
+}
+
+impl<V> InternedMap<V> {
+    fn new() -> Self {
+        Self {
+            map: BTreeMap::new(),
+            prefixes: HashSet::new(),
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<&V> {
+        self.map.get(&key as &dyn SplitKey)
+    }
+
+    /// return the entry replaced, if any
+    ///
+    /// replacing the entry of a key interns nothing, only new keys look their prefix up.
+    fn insert(&mut self, key: String, value: V) -> Option<V> {
+        if let Some(old) = self.map.get_mut(&key as &dyn SplitKey) {
+            return Some(std::mem::replace(old, value));
+        }
+        let (prefix, suffix) = split(&key);
+        let prefix = match self.prefixes.get(prefix) {
+            Some(prefix) => prefix.clone(),
+            None => {
+                let prefix = Prefix(Arc::new(prefix.to_owned()));
+                self.prefixes.insert(prefix.clone());
+                prefix
+            }
+        };
+        let key = InternedKey {
+            prefix,
+            suffix: suffix.into(),
+        };
+        self.map.insert(key, value)
+    }
+
+    fn remove(&mut self, key: &str) -> Option<V> {
+        let (key, value) = self.map.remove_entry(&key as &dyn SplitKey)?;
+        // the prefix is only left in `prefixes` and in the removed key
+        if Arc::strong_count(&key.prefix.0) == 2 {
+            self.prefixes.remove(&*key.prefix);
+        }
+        Some(value)
+    }
+}
+
+/// the prefix of `key` up to and including its first separator, empty if it has none, and the rest
+fn split(key: &str) -> (&str, &str) {
+    match key.find(PREFIX_SEPARATOR) {
+        Some(end) => key.split_at(end + PREFIX_SEPARATOR.len_utf8()),
+        None => ("", key),
+    }
+}
+
+/// Key of the interned index, its prefix shared with every other key starting with it
+struct InternedKey {
+    prefix: Prefix,
+    suffix: Box<str>,
+}
+
+/// Prefix shared between keys
+///
+/// An `Arc<String>` rather than an `Arc<str>`, its thin pointer keeping an `InternedKey`
+/// as small as a `String` within the tree.
+#[derive(Clone, PartialEq, Eq)]
+struct Prefix(Arc<String>);
+
+impl Deref for Prefix {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Borrow<str> for Prefix {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Hash for Prefix {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.as_str().hash(state)
+    }
+}
+
+impl InternedKey {
+    /// the whole key
+    fn key(&self) -> String {
+        let mut key = String::with_capacity(self.prefix.len() + self.suffix.len());
+        key.push_str(&self.prefix);
+        key.push_str(&self.suffix);
+        key
+    }
+
+    fn starts_with(&self, prefix: &str) -> bool {
+        match prefix.strip_prefix(&*self.prefix) {
+            Some(rest) => self.suffix.starts_with(rest),
+            None => self.prefix.starts_with(prefix),
+        }
+    }
+}
+
+/// A key of the interned index, whole or split in two
+///
+/// Keys compare by their bytes however they are split, so the index is searched with a
+/// `&str` without interning or copying it.
+trait SplitKey {
+    fn parts(&self) -> (&str, &str);
+}
+
+impl SplitKey for &str {
+    fn parts(&self) -> (&str, &str) {
+        (self, "")
+    }
+}
+
+impl SplitKey for String {
+    fn parts(&self) -> (&str, &str) {
+        (self, "")
+    }
+}
+
+impl SplitKey for InternedKey {
+    fn parts(&self) -> (&str, &str) {
+        (&self.prefix, &self.suffix)
+    }
+}
+
+impl<'a> Borrow<dyn SplitKey + 'a> for InternedKey {
+    fn borrow(&self) -> &(dyn SplitKey + 'a) {
+        self
+    }
+}
+
+impl Ord for dyn SplitKey + '_ {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let (prefix, suffix) = self.parts();
+        let (other_prefix, other_suffix) = other.parts();
+        prefix
+            .bytes()
+            .chain(suffix.bytes())
+            .cmp(other_prefix.bytes().chain(other_suffix.bytes()))
+    }
+}
+
+impl PartialOrd for dyn SplitKey + '_ {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for dyn SplitKey + '_ {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for dyn SplitKey + '_ {}
+
+impl Ord for InternedKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self as &dyn SplitKey).cmp(other)
+    }
+}
+
+impl PartialOrd for InternedKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for InternedKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for InternedKey {}
+
+fn split_bound(bound: &Bound<String>) -> Bound<&dyn SplitKey> {
+    bound.as_ref().map(|key| key as &dyn SplitKey)
+}
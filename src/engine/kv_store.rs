@@ -1,37 +1,351 @@
-use std::cell::RefCell;
-use std::collections::HashMap;
-use std::fs::{create_dir_all, File, OpenOptions};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
 use std::io::prelude::*;
-use std::io::{BufReader, BufWriter, SeekFrom};
-use std::path::{Path, PathBuf};
+use std::io::{self, BufReader, BufWriter, SeekFrom};
+use std::ops::{Bound, Deref};
+use std::path::Path;
 use std::str::FromStr;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, TryLockError, Weak};
+use std::thread;
+use std::time::Instant;
 
-use chashmap::CHashMap;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use crossbeam::{Receiver, Sender};
+#[cfg(not(feature = "tracing"))]
+use log::debug;
+use log::{error, info, warn};
+use lru::LruCache;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
-use crate::engine::{try_add_engine_type, EngineType};
+use crate::engine::index::KeyIndex;
+use crate::engine::latency::Op;
+#[cfg(feature = "hdrhistogram")]
+use crate::engine::latency::{Latency, LatencyReport};
+use crate::engine::options::{KeyValidator, MergeOperator, ProgressCallback};
+use crate::engine::{
+    check_engine_type, try_add_engine_type, CompactionProgress, EngineType, FsStorage, KvStoreOptions, NamespacedStore,
+    Storage, StorageReader, StorageWriter,
+};
 use crate::{KvsEngine, KvsError, Result};
 
 const COMPACTION_THRESHOLD: u32 = 10_000;
+const COMPACTION_PROGRESS_INTERVAL: u64 = 1 << 20;
+
+/// values cached along with the record they were read from
+type ValueCache = Mutex<LruCache<String, (LogIndex, Arc<str>)>>;
+
+/// segment readers of a handle keyed by epoch
+type ReaderCache = Mutex<LruCache<usize, CachedReader>>;
+
+/// file holding the `Manifest`, replaced only once the segments it lists are durable
+const CURRENT: &str = "CURRENT";
+
+/// zstd level sealed segments are compressed with, 0 picks zstd's default
+const COMPRESSION_LEVEL: i32 = 0;
+
+/// files a store needs open at least: the active segment's writer and one reader
+const MIN_OPEN_FILES: usize = 2;
+
+/// format version written at the start of every new segment
+///
+/// format 2 added `SetRef` records, format 3 `Seq` records, format 4 footers of sealed segments,
+/// format 5 `SetCompressed` records, format 6 `Rename` records.
+const LOG_FORMAT: u32 = 6;
+
+/// last bytes of a `SegmentFooter`
+const FOOTER_MAGIC: &[u8; 8] = b"kvsfoot1";
+
+/// length of a `SegmentFooter`: its record count, checksum and magic
+const FOOTER_LEN: usize = 8 + 32 + 8;
+
+/// first bytes of an index snapshot
+const SNAPSHOT_MAGIC: &[u8; 8] = b"kvssnap1";
+
+/// format version written in the header of every index snapshot
+const SNAPSHOT_FORMAT: u32 = 1;
+
+/// magic, format, epoch and offset
+#[cfg(feature = "test-util")]
+const SNAPSHOT_HEADER_LEN: usize = 8 + 4 + 8 + 8;
+
+/// epoch, offset, length and sequence number of an entry of an index snapshot, then whether
+/// its record is compressed
+#[cfg(feature = "test-util")]
+const SNAPSHOT_ENTRY_LEN: usize = 8 + 8 + 8 + 8 + 1;
+
+/// first bytes of a checkpoint archive
+const ARCHIVE_MAGIC: &[u8; 8] = b"kvsarch1";
+
+/// format version written in the header of every checkpoint archive
+const ARCHIVE_FORMAT: u32 = 1;
+
+/// first value of a segment, segments written before it existed start with a `Cmd`
+#[derive(Default, Serialize, Deserialize)]
+struct SegmentHeader {
+    format: u32,
+    /// whether each record is a zstd frame preceded by its length, omitted if not
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    compressed: bool,
+    /// whether the segment was sealed by a compaction and ends with a `SegmentFooter`, omitted if not
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    sealed: bool,
+}
+
+/// End of a sealed segment, written along with its records before it is renamed into place
+///
+/// The checksum is the SHA-256 of every byte before the footer, header included, so a
+/// sealed segment is checked in one sequential read, and a modified byte is caught even
+/// where the records still parse.
+struct SegmentFooter {
+    records: u64,
+    checksum: [u8; 32],
+}
+
+impl SegmentFooter {
+    fn encode(&self) -> Vec<u8> {
+        let mut footer = Vec::with_capacity(FOOTER_LEN);
+        footer.extend_from_slice(&self.records.to_be_bytes());
+        footer.extend_from_slice(&self.checksum);
+        footer.extend_from_slice(FOOTER_MAGIC);
+        footer
+    }
+
+    /// the footer `log` ends with, and where it starts
+    fn decode(log: &[u8]) -> Option<(Self, usize)> {
+        let start = log.len().checked_sub(FOOTER_LEN)?;
+        let footer = &log[start..];
+        if &footer[40..] != FOOTER_MAGIC {
+            return None;
+        }
+        let mut records = [0; 8];
+        records.copy_from_slice(&footer[..8]);
+        let mut checksum = [0; 32];
+        checksum.copy_from_slice(&footer[8..40]);
+        let footer = Self {
+            records: u64::from_be_bytes(records),
+            checksum,
+        };
+        Some((footer, start))
+    }
+}
 
 #[derive(Serialize, Deserialize)]
 pub(crate) enum Cmd {
     Set(String, String),
     Rm(String),
+    /// `Set` of a value stored in the blob file named after its hash, with its length
+    SetRef(String, String, u64),
+    /// `Set` of a value compressed with zstd, the frame encoded in base64
+    SetCompressed(String, String),
+    /// removal of the first key along with the `Set`, `SetRef` or `SetCompressed` giving its
+    /// value to another key, replayed whole or not at all
+    Rename(String, Box<Cmd>),
+    /// command with its sequence number, which every write has unless it was logged by an
+    /// older version
+    Seq(u64, Box<Cmd>),
+}
+
+impl Cmd {
+    /// `cmd` with the sequence number `seq`, unless it is 0
+    fn with_seq(self, seq: u64) -> Cmd {
+        match seq {
+            0 => self,
+            seq => Cmd::Seq(seq, Box::new(self)),
+        }
+    }
+
+    /// the sequence number of the command, 0 if it has none, and the command itself
+    fn unseq(self) -> (u64, Cmd) {
+        match self {
+            Cmd::Seq(seq, cmd) => (seq, cmd.unseq().1),
+            cmd => (0, cmd),
+        }
+    }
+
+    fn key(&self) -> &str {
+        match self {
+            Cmd::Set(key, _) | Cmd::Rm(key) | Cmd::SetRef(key, ..) | Cmd::SetCompressed(key, _) => key,
+            Cmd::Rename(_, cmd) | Cmd::Seq(_, cmd) => cmd.key(),
+        }
+    }
+
+    /// the command without the removal of a `Rename`, as copied by compactions
+    ///
+    /// the key renamed from may have been written again after the rename, which a copy
+    /// of the whole `Rename` replayed later would undo.
+    fn without_rename(self) -> Cmd {
+        match self {
+            Cmd::Rename(_, cmd) => *cmd,
+            cmd => cmd,
+        }
+    }
 }
 
-#[derive(Copy, Clone)]
+/// borrowed `Cmd` for appending, serialized the same way so the key is not copied
+#[derive(Serialize)]
+enum CmdRef<'a> {
+    Set(&'a str, &'a str),
+    Rm(&'a str),
+    SetRef(&'a str, &'a str, u64),
+    SetCompressed(&'a str, &'a str),
+    Rename(&'a str, &'a CmdRef<'a>),
+    Seq(u64, &'a CmdRef<'a>),
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
 struct LogIndex {
     epoch: usize,
     offset: u64,
     len: u64,
+    /// whether the record is compressed with zstd, omitted if not
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    compressed: bool,
+    /// sequence number of the record, 0 if it has none, omitted then
+    #[serde(default, skip_serializing_if = "is_zero")]
+    seq: u64,
 }
 
 impl LogIndex {
     fn new(epoch: usize, offset: u64, len: u64) -> Self {
-        Self { epoch, offset, len }
+        Self {
+            epoch,
+            offset,
+            len,
+            compressed: false,
+            seq: 0,
+        }
+    }
+
+    fn compressed(epoch: usize, offset: u64, len: u64) -> Self {
+        Self {
+            epoch,
+            offset,
+            len,
+            compressed: true,
+            seq: 0,
+        }
+    }
+
+    fn with_seq(self, seq: u64) -> Self {
+        Self { seq, ..self }
+    }
+
+    /// version of the value the record holds, see `KvsEngine::get_versioned`
+    ///
+    /// derived from the sequence number, which increases with every write and is kept by
+    /// compactions. a record logged without one has version 1, as no write numbered since
+    /// can have it.
+    fn version(&self) -> u64 {
+        self.seq + 1
+    }
+}
+
+/// Entry of a key in the index, dereferencing to the location of its record
+#[derive(Clone)]
+enum IndexEntry {
+    /// the value is read from its record
+    OnDisk(LogIndex),
+    /// the value is kept in memory too, see `KvStoreOptions::inline_values`
+    Inline(LogIndex, Arc<InlineValue>),
+}
+
+impl IndexEntry {
+    /// the entry of the same value, its record moved to `log_index`
+    /// the entry of the same record copied to `log_index`, keeping its sequence number
+    fn moved(&self, log_index: LogIndex) -> Self {
+        let log_index = log_index.with_seq(self.seq);
+        match self {
+            IndexEntry::OnDisk(_) => IndexEntry::OnDisk(log_index),
+            IndexEntry::Inline(_, value) => IndexEntry::Inline(log_index, value.clone()),
+        }
+    }
+}
+
+impl Deref for IndexEntry {
+    type Target = LogIndex;
+
+    fn deref(&self) -> &LogIndex {
+        match self {
+            IndexEntry::OnDisk(log_index) | IndexEntry::Inline(log_index, _) => log_index,
+        }
+    }
+}
+
+/// Value kept in the index, its bytes counted against the capacity until it is dropped
+struct InlineValue {
+    value: Arc<str>,
+    values: Arc<InlineValues>,
+}
+
+impl Drop for InlineValue {
+    fn drop(&mut self) {
+        self.values.used.fetch_sub(self.value.len(), Ordering::SeqCst);
+    }
+}
+
+/// Bounds of the values kept in the index, shared by all handles of a store
+struct InlineValues {
+    max_len: usize,
+    capacity: usize,
+    /// bytes of the values kept in the index
+    used: AtomicUsize,
+}
+
+/// the entry of a value recorded at `log_index`, keeping the value in memory if it is short
+/// enough and fits within the capacity
+fn index_entry(inline: Option<&Arc<InlineValues>>, log_index: LogIndex, value: &str) -> IndexEntry {
+    let inline = match inline {
+        Some(inline) if value.len() <= inline.max_len => inline,
+        _ => return IndexEntry::OnDisk(log_index),
+    };
+    let reserved = inline.used.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+        Some(used + value.len()).filter(|used| *used <= inline.capacity)
+    });
+    match reserved {
+        Ok(_) => IndexEntry::Inline(
+            log_index,
+            Arc::new(InlineValue {
+                value: value.into(),
+                values: inline.clone(),
+            }),
+        ),
+        Err(_) => IndexEntry::OnDisk(log_index),
+    }
+}
+
+/// Live segments of a store in replay order, the last one being the active segment
+///
+/// Stored as JSON in `CURRENT`.
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    segments: Vec<ManifestSegment>,
+    /// epochs of the segments of writer handles not merged yet, omitted if none
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    writers: Vec<usize>,
+    /// last sequence number given out when the manifest was committed, so numbers of the
+    /// records a compaction dropped are not given out again. omitted if 0
+    #[serde(default, skip_serializing_if = "is_zero")]
+    seq: u64,
+}
+
+/// A live segment listed in the `Manifest`
+#[derive(Copy, Clone, Serialize, Deserialize)]
+struct ManifestSegment {
+    epoch: usize,
+    /// whether each record is a zstd frame preceded by its length, as in sealed segments
+    compressed: bool,
+}
+
+impl ManifestSegment {
+    fn active(epoch: usize) -> Self {
+        Self {
+            epoch,
+            compressed: false,
+        }
     }
 }
 
@@ -46,11 +360,11 @@ impl LogIndex {
 /// std::fs::create_dir(&dir);
 ///
 /// let mut store = KvStore::open(&dir).unwrap();
-/// store.set("abc".to_string(), "def".to_string());
-/// assert_eq!(store.get("abc".to_string()).unwrap(), Some("def".to_string()));
-/// assert_eq!(store.get("ijk".to_string()).unwrap(), None);
-/// store.remove("abc".to_string());
-/// assert_eq!(store.get("abc".to_string()).unwrap(), None);
+/// store.set("abc", "def");
+/// assert_eq!(store.get("abc").unwrap(), Some("def".to_string()));
+/// assert_eq!(store.get("ijk").unwrap(), None);
+/// store.remove("abc");
+/// assert_eq!(store.get("abc").unwrap(), None);
 ///
 /// std::fs::remove_dir_all(&dir);
 /// ```
@@ -58,264 +372,3259 @@ impl LogIndex {
 pub struct KvStore {
     reader: KvStoreReader,
     writer: Arc<Mutex<KvStoreWriter>>,
+    /// notified when a background compaction finishes
+    compacted: Arc<Condvar>,
+    /// whether the writer holds records not yet flushed to the storage
+    unflushed: Arc<AtomicBool>,
+    key_validator: Option<KeyValidator>,
+    #[cfg(feature = "hdrhistogram")]
+    latency: Arc<Latency>,
 }
 
 impl KvStore {
     /// load the kv store from disk
     pub fn open<T: AsRef<Path>>(dir: T) -> Result<Self> {
-        let mut log_dir = PathBuf::new();
-        log_dir.push(dir);
-        create_dir_all(&log_dir)?;
+        Self::open_with_options(dir, KvStoreOptions::default())
+    }
 
-        try_add_engine_type(&log_dir, EngineType::KvStore)?;
+    /// load the kv store from disk with the given options
+    ///
+    /// a full compaction rewrites every live record into a new segment: uncompressed, it
+    /// becomes the active one; with `compress_sealed_segments`, it is sealed and followed by
+    /// a new active segment. `compact_range` writes a sealed segment holding only the live
+    /// records within its range, followed by a new active segment, and leaves the records
+    /// of other keys in place. segments are never rolled over by size. the segments
+    /// replaced by a compaction are kept for handles still reading them and removed by the
+    /// next one.
+    ///
+    /// the directory is locked as long as the store is open, another store opening it
+    /// meanwhile, in this process or another, fails with `KvsError::AlreadyLocked`.
+    pub fn open_with_options<T: AsRef<Path>>(dir: T, options: KvStoreOptions) -> Result<Self> {
+        let storage = FsStorage::new(dir)?;
+        let lock = storage.lock(true)?;
+        Self::open_storage(storage, options, Some(lock), false)
+    }
 
-        let log_file = log_dir
-            .read_dir()?
-            .into_iter()
-            .filter_map(|entry_result| entry_result.ok())
-            .filter(|entry| entry.metadata().unwrap().is_file())
-            .filter_map(|entry| {
-                let file_name = entry.file_name().to_str().unwrap().to_owned();
-                if file_name.ends_with(".log") {
-                    let prefix = file_name.trim_end_matches(".log");
-                    let epoch = usize::from_str(prefix).ok()?;
-                    Some((entry, epoch))
-                } else {
-                    None
-                }
-            })
-            .max_by_key(|(_, epoch)| *epoch);
+    /// unpack an archive written by `checkpoint` into a directory holding no store, and
+    /// open the store it holds.
+    ///
+    /// the manifest is only written once every other file is and the archive matched its
+    /// checksum, so an archive failing with `KvsError::Corrupted` never leaves a store behind.
+    pub fn restore<T: AsRef<Path>, R: Read>(dir: T, input: R) -> Result<Self> {
+        let storage = FsStorage::new(dir)?;
+        let lock = storage.lock(true)?;
+        unpack_archive(&storage, input)?;
+        Self::open_storage(storage, KvStoreOptions::default(), Some(lock), false)
+    }
+
+    /// unpack an archive written by `checkpoint` into a storage holding no store, and open
+    /// the store it holds with the given options, see `restore`.
+    pub fn restore_with_storage<S: Storage, R: Read>(storage: S, input: R, options: KvStoreOptions) -> Result<Self> {
+        unpack_archive(&storage, input)?;
+        Self::open_storage(storage, options, None, false)
+    }
+
+    /// load the kv store from disk without writing to it
+    ///
+    /// any number of read-only stores may have the directory open at once, but no store
+    /// opened for writing: either one fails with `KvsError::AlreadyLocked` while the
+    /// other is open. writes and compactions fail with `KvsError::ReadOnly`. a segment
+    /// left torn or unmerged by a crash is replayed as a writable open would, without
+    /// being repaired.
+    pub fn open_read_only<T: AsRef<Path>>(dir: T, options: KvStoreOptions) -> Result<Self> {
+        let dir = dir.as_ref();
+        if !dir.is_dir() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("no store in {}", dir.display())).into());
+        }
+        let storage = FsStorage::new(dir)?;
+        let lock = storage.lock(false)?;
+        Self::open_storage(storage, options, Some(lock), true)
+    }
+
+    /// rebuild the kv store in `dir` from its segments, then open it
+    ///
+    /// for a store that fails to open, e.g. because `CURRENT` is corrupted or a segment
+    /// ends with a torn record. see [`repair_with_storage`](#method.repair_with_storage).
+    pub fn repair<T: AsRef<Path>>(dir: T) -> Result<Self> {
+        let storage = FsStorage::new(dir)?;
+        let lock = storage.lock(true)?;
+        rebuild(&storage)?;
+        Self::open_storage(storage, KvStoreOptions::default(), Some(lock), false)
+    }
+
+    /// rebuild the kv store in the given storage from its segments, then open it
+    ///
+    /// every segment is replayed from the oldest to the newest, ignoring `CURRENT`. a
+    /// segment stops at its first record that cannot be read, the rest of it is discarded
+    /// with a warning. the live records are written into a new segment which becomes the
+    /// only one, so segments that were no longer live in a healthy store are removed too.
+    pub fn repair_with_storage<S: Storage>(storage: S, options: KvStoreOptions) -> Result<Self> {
+        rebuild(&storage)?;
+        Self::open_with_storage(storage, options)
+    }
+
+    /// load the kv store from the given storage, such as a `MemoryStorage` in tests
+    ///
+    /// the storage is not locked, the caller makes sure no other store writes to it.
+    pub fn open_with_storage<S: Storage>(storage: S, options: KvStoreOptions) -> Result<Self> {
+        Self::open_storage(storage, options, None, false)
+    }
+
+    /// open the store holding `lock` until it is dropped, without writing to the storage if `read_only`
+    fn open_storage<S: Storage>(
+        storage: S,
+        options: KvStoreOptions,
+        lock: Option<File>,
+        read_only: bool,
+    ) -> Result<Self> {
+        let start = Instant::now();
+        let storage: Arc<dyn Storage> = Arc::new(storage);
+        if let Some(limit) = options.max_open_files {
+            if limit < MIN_OPEN_FILES {
+                return Err(KvsError::InvalidOption(format!(
+                    "max_open_files is {}, a store needs at least {}",
+                    limit, MIN_OPEN_FILES
+                )));
+            }
+        }
 
-        let (mut reader, writer, epoch) = match log_file {
-            Some((entry, epoch)) => {
-                let path = entry.path();
-                let writer = OpenOptions::new().append(true).open(&path)?;
-                let reader = File::open(path)?;
-                (BufReader::new(reader), BufWriter::new(writer), epoch)
+        #[cfg(feature = "tracing")]
+        let span = tracing::info_span!(
+            "open",
+            storage = ?storage,
+            epoch = tracing::field::Empty,
+            keys = tracing::field::Empty,
+            duration_us = tracing::field::Empty,
+        )
+        .entered();
+
+        if read_only {
+            check_engine_type(&*storage, EngineType::KvStore)?;
+        } else {
+            try_add_engine_type(&*storage, EngineType::KvStore)?;
+        }
+
+        let Manifest {
+            segments,
+            writers,
+            seq: committed_seq,
+        } = match recover_manifest(&*storage, read_only)? {
+            Some(manifest) => manifest,
+            None if read_only => {
+                return Err(io::Error::new(io::ErrorKind::NotFound, format!("no store in {:?}", storage)).into())
             }
             None => {
-                let path = log_dir.join("0.log".to_string());
-                let writer = File::create(&path)?;
-                let reader = File::open(path)?;
-                (BufReader::new(reader), BufWriter::new(writer), 0)
+                let mut writer = storage.create(&log_name(0))?;
+                write_header(&mut writer, false, false)?;
+                let segments = vec![ManifestSegment::active(0)];
+                commit_manifest(&*storage, &segments, &[], 0)?;
+                Manifest {
+                    segments,
+                    writers: Vec::new(),
+                    seq: 0,
+                }
+            }
+        };
+        let epoch = segments.last().expect("the manifest lists the active segment").epoch;
+
+        let key_index = KeyIndex::new(options.index_type);
+        let inline = options.inline_values.map(|(max_len, capacity)| {
+            Arc::new(InlineValues {
+                max_len,
+                capacity,
+                used: AtomicUsize::new(0),
+            })
+        });
+        let mut blob_refs = HashMap::new();
+        let mut seqs = HashMap::new();
+        let mut redundant = 0;
+        let mut active_reader = None;
+        let mut active_end = 0;
+        let mut stats = HashMap::new();
+        for segment in segments.iter() {
+            let name = log_name(segment.epoch);
+            let mut reader = BufReader::with_capacity(options.buffer_size, storage.open(&name)?);
+            let active = segment.epoch == epoch;
+            let imported = Self::import_log(
+                &mut reader,
+                *segment,
+                &key_index,
+                inline.as_ref(),
+                &mut blob_refs,
+                &mut seqs,
+                None,
+                active,
+            )?;
+            redundant += imported.redundant;
+            stats.insert(segment.epoch, imported.stat);
+            let end = imported.end;
+            if imported.torn && !read_only {
+                warn!("discarding a torn record at the end of {}", name);
+                truncate(&*storage, &name, end)?;
+                reader = BufReader::with_capacity(options.buffer_size, storage.open(&name)?);
+            }
+            active_reader = Some(reader);
+            active_end = end;
+        }
+        // segments of writer handles not merged before the store was closed, merged below
+        let mut replay = WriterReplay::default();
+        for &writer_epoch in writers.iter() {
+            let name = log_name(writer_epoch);
+            let mut reader = BufReader::with_capacity(options.buffer_size, storage.open(&name)?);
+            let segment = ManifestSegment::active(writer_epoch);
+            let imported = Self::import_log(
+                &mut reader,
+                segment,
+                &key_index,
+                inline.as_ref(),
+                &mut blob_refs,
+                &mut seqs,
+                Some(&mut replay),
+                true,
+            )?;
+            redundant += imported.redundant;
+            if imported.torn {
+                warn!("discarding a torn record at the end of {}", name);
+            }
+        }
+        if let Some((writer_epoch, seq, last_seq)) = replay.disorder {
+            let message = format!(
+                "record numbered {} follows one numbered {} in {}",
+                seq,
+                last_seq,
+                log_name(writer_epoch)
+            );
+            if options.check_sequence {
+                return Err(KvsError::Consistency(message));
             }
+            warn!("{}", message);
+        }
+        let next_epoch = writers.iter().copied().fold(epoch, usize::max) + 1;
+        let seq = seqs.values().copied().fold(committed_seq, u64::max);
+        let reader = active_reader.expect("the manifest lists the active segment");
+        let (writer, end) = if read_only {
+            (Box::new(ReadOnlyWriter) as Box<dyn StorageWriter>, active_end)
+        } else {
+            let name = log_name(epoch);
+            let writer = open_active(&*storage, &name, active_end, options.preallocate_segments)?;
+            (writer, active_end)
         };
+        let writer = BufWriter::with_capacity(options.buffer_size, writer);
+        let key_index = Arc::new(key_index);
 
         let latest = Arc::new(AtomicUsize::from(epoch));
 
-        let (key_index, redundant) = Self::import_log(&mut reader, epoch)?;
-        let path = Arc::new(log_dir);
+        let min_epoch = Arc::new(AtomicUsize::new(0));
 
-        let mut buf_readers = [None, None];
-        buf_readers[epoch % 2] = Some(reader);
+        // the active segment's writer is open for the lifetime of the store, the rest is for readers
+        let files = Arc::new(OpenFiles::new(options.max_open_files.map(|limit| limit - 1)));
+        files.open.fetch_add(1, Ordering::SeqCst);
+        let mut buf_readers = LruCache::new(options.reader_cache_capacity);
+        buf_readers.put(epoch, CachedReader::new(reader, &files));
+        let readers = Arc::new(Mutex::new(buf_readers));
+        files.register(&readers);
 
         let reader = KvStoreReader {
-            path: path.clone(),
-            epoch: latest.clone(),
+            storage: storage.clone(),
+            min_epoch: min_epoch.clone(),
             key_index: key_index.clone(),
-            reader_epoch: AtomicUsize::from(epoch),
-            readers: RefCell::new(buf_readers),
+            readers,
+            files,
+            reader_min_epoch: AtomicUsize::new(0),
+            values: match options.value_cache_capacity {
+                0 => None,
+                capacity => Some(Arc::new(Mutex::new(LruCache::new(capacity)))),
+            },
+            inline,
+            buffer_size: options.buffer_size,
+        };
+
+        let (compaction_signal, compaction_requests) = if options.background_compaction {
+            let (tx, rx) = crossbeam::bounded(1);
+            (Some(tx), Some(rx))
+        } else {
+            (None, None)
         };
 
-        let writer = KvStoreWriter {
-            path: path.clone(),
+        #[cfg(feature = "hdrhistogram")]
+        let latency = Arc::new(Latency::new());
+
+        let keys = key_index.len();
+        let blobs = storage
+            .list()?
+            .iter()
+            .filter_map(|name| blob_hash(name))
+            .map(str::to_owned)
+            .collect();
+        let unflushed = Arc::new(AtomicBool::new(false));
+        let mut writer = KvStoreWriter {
+            storage: storage.clone(),
             epoch: latest.clone(),
+            min_epoch,
             key_index,
             redundant,
             reader: reader.clone(),
             writer,
+            end,
+            auto_compaction: options.auto_compaction,
+            flush_on_write: options.flush_on_write,
+            merge_operator: options.merge_operator,
+            unflushed: unflushed.clone(),
+            compaction_progress: options.compaction_progress,
+            compacting: false,
+            compaction_signal,
+            compress_sealed: options.compress_sealed_segments,
+            segments,
+            retired: Vec::new(),
+            large_value_threshold: options.large_value_threshold,
+            value_compression_threshold: options.value_compression_threshold,
+            blob_refs,
+            blobs,
+            seq: Arc::new(AtomicU64::new(seq)),
+            writers: writers.clone(),
+            next_epoch,
+            check_sequence: options.check_sequence,
+            read_only,
+            preallocate: options.preallocate_segments,
+            stats,
+            _lock: lock,
+            #[cfg(feature = "hdrhistogram")]
+            latency: latency.clone(),
         };
+        if !writers.is_empty() && !read_only {
+            info!("merging {} writer segments left open", writers.len());
+            writer.merge_writers(&writers, replay.written, 0)?;
+        }
+        writer.count_live();
+        if options.gc_orphans_on_open && !read_only {
+            let removed = writer.gc_orphans()?;
+            if !removed.is_empty() {
+                info!("removed {} orphaned files: {:?}", removed.len(), removed);
+            }
+        }
+        let writer = Arc::new(Mutex::new(writer));
+        let compacted = Arc::new(Condvar::new());
+        if let Some(requests) = compaction_requests {
+            spawn_compactor(Arc::downgrade(&writer), compacted.clone(), requests)?;
+        }
+
+        let elapsed = start.elapsed();
+        #[cfg(feature = "tracing")]
+        {
+            span.record("epoch", epoch);
+            span.record("keys", keys);
+            span.record("duration_us", elapsed.as_micros() as u64);
+        }
+        #[cfg(not(feature = "tracing"))]
+        debug!(
+            "opened {:?} at epoch {} with {} keys in {:?}",
+            storage, epoch, keys, elapsed
+        );
 
         Ok(Self {
             reader,
-            writer: Arc::new(Mutex::new(writer)),
+            writer,
+            compacted,
+            unflushed,
+            key_validator: options.key_validator,
+            #[cfg(feature = "hdrhistogram")]
+            latency,
         })
     }
 
-    fn import_log(reader: &mut BufReader<File>, epoch: usize) -> Result<(Arc<CHashMap<String, LogIndex>>, u32)> {
+    /// run an operation, recording how long it took if latency histograms are enabled
+    #[cfg(feature = "hdrhistogram")]
+    fn timed<T>(&self, op: Op, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        self.latency.record(op, start.elapsed());
+        result
+    }
+
+    #[cfg(not(feature = "hdrhistogram"))]
+    #[inline(always)]
+    fn timed<T>(&self, _op: Op, f: impl FnOnce() -> T) -> T {
+        f()
+    }
+
+    /// percentiles of the latency of each operation since the store was opened,
+    /// shared by all handles
+    #[cfg(feature = "hdrhistogram")]
+    pub fn latency_report(&self) -> LatencyReport {
+        self.latency.report()
+    }
+
+    fn validate(&self, key: &str) -> Result<()> {
+        match &self.key_validator {
+            Some(validator) => validator(key).map_err(KvsError::InvalidKey),
+            None => Ok(()),
+        }
+    }
+
+    /// replay a log segment into `key_index`
+    ///
+    /// the segment is read into memory in one sequential pass and parsed from there,
+    /// which is much faster than deserializing from the reader record by record.
+    ///
+    /// the active segment may end with zeros left by preallocation, and with a record
+    /// torn by a crash while appending it. if `active`, the zeros are skipped, and so is
+    /// such a record, returning that the segment should be cut.
+    ///
+    /// `seqs` tracks the sequence number of the last write of each key written with one,
+    /// see `in_sequence`. a writer segment is replayed into `writer`.
+    #[allow(clippy::too_many_arguments)]
+    fn import_log(
+        reader: &mut BufReader<Box<dyn StorageReader>>,
+        segment: ManifestSegment,
+        key_index: &KeyIndex<IndexEntry>,
+        inline: Option<&Arc<InlineValues>>,
+        blob_refs: &mut HashMap<String, String>,
+        seqs: &mut HashMap<String, u64>,
+        mut writer: Option<&mut WriterReplay>,
+        active: bool,
+    ) -> Result<ImportedLog> {
+        let len = reader.seek(SeekFrom::End(0))?;
         reader.seek(SeekFrom::Start(0))?;
-        let mut cur_pos = 0;
-        let mut stream = serde_json::Deserializer::from_reader(reader).into_iter::<Cmd>();
-        let key_index = CHashMap::new();
-        let mut redundant = 0;
+        let mut log = Vec::with_capacity(len as usize);
+        reader.read_to_end(&mut log)?;
 
-        while let Some(cmd) = stream.next() {
-            let cmd = cmd?;
-            let key = match &cmd {
-                Cmd::Set(key, _) => key.clone(),
-                Cmd::Rm(key) => key.clone(),
+        let mut redundant = 0;
+        let mut replayed = 0;
+        let mut last_seq = 0;
+        let mut stat = SegmentStat::default();
+        let apply = |cmd: Cmd, log_index: LogIndex| {
+            replayed += 1;
+            stat.add(log_index.len);
+            if let Some(writer) = writer.as_mut() {
+                let seq = match cmd {
+                    Cmd::Seq(seq, _) => seq,
+                    _ => 0,
+                };
+                if seq <= last_seq && writer.disorder.is_none() {
+                    writer.disorder = Some((segment.epoch, seq, last_seq));
+                }
+                last_seq = last_seq.max(seq);
+            }
+            if !in_sequence(seqs, &cmd) {
+                redundant += 1;
+                return;
+            }
+            let (seq, cmd) = cmd.unseq();
+            if let Some(writer) = writer.as_mut() {
+                writer.written.insert(cmd.key().to_owned(), seq);
+            }
+            let log_index = log_index.with_seq(seq);
+            let cmd = match cmd {
+                Cmd::Rename(from, cmd) => {
+                    blob_refs.remove(&from);
+                    if key_index.remove(&from).is_some() {
+                        redundant += 1;
+                    }
+                    *cmd
+                }
+                cmd => cmd,
             };
-            let new_pos = stream.byte_offset() as u64;
-            match cmd {
-                Cmd::Rm(_) => {
+            let (key, entry) = match cmd {
+                Cmd::Rm(key) => {
+                    blob_refs.remove(&key);
                     key_index.remove(&key);
                     redundant += 2;
+                    return;
                 }
-                Cmd::Set(_, _) => {
-                    if let Some(_) = key_index.insert(key, LogIndex::new(epoch, cur_pos, new_pos - cur_pos)) {
-                        redundant += 1;
-                    }
+                Cmd::Set(key, value) => {
+                    blob_refs.remove(&key);
+                    let entry = index_entry(inline, log_index, &value);
+                    (key, entry)
+                }
+                Cmd::SetRef(key, hash, _) => {
+                    blob_refs.insert(key.clone(), hash);
+                    (key, IndexEntry::OnDisk(log_index))
                 }
+                // inlined once read, rather than decompressed on replay
+                Cmd::SetCompressed(key, _) => {
+                    blob_refs.remove(&key);
+                    (key, IndexEntry::OnDisk(log_index))
+                }
+                Cmd::Rename(..) => unreachable!("a rename holds a single set"),
+                Cmd::Seq(..) => unreachable!("unseq removes every sequence number"),
             };
+            if key_index.insert(key, entry).is_some() {
+                redundant += 1;
+            }
+        };
 
-            cur_pos = new_pos;
+        let (header, start) = read_header(&log)?;
+        let (mut records, footer) = check_footer(&log, &header, segment.epoch)?;
+        if active && !segment.compressed {
+            records = strip_zeros(records, start);
+        }
+        match replay_records(records, start, segment, apply) {
+            (end, Some(KvsError::SerdeJson(e))) if active && e.is_eof() => Ok(ImportedLog {
+                redundant,
+                end,
+                torn: true,
+                stat,
+            }),
+            (_, Some(e)) => Err(e),
+            (end, None) => match footer {
+                Some(footer) if footer.records != replayed => Err(KvsError::Corrupted(format!(
+                    "{} holds {} records, its footer {}",
+                    log_name(segment.epoch),
+                    replayed,
+                    footer.records
+                ))),
+                _ => Ok(ImportedLog {
+                    redundant,
+                    end,
+                    torn: false,
+                    stat,
+                }),
+            },
         }
-        Ok((Arc::new(key_index), redundant))
     }
-}
 
-impl KvsEngine for KvStore {
-    fn get(&self, key: String) -> Result<Option<String>> {
-        self.reader.get(key)
+    /// get the value for a given key, accepting any key convertible into `String`.
+    ///
+    /// see [`KvsEngine::get`](trait.KvsEngine.html#tymethod.get).
+    pub fn get<K: Into<String>>(&self, key: K) -> Result<Option<String>> {
+        KvsEngine::get(self, key.into())
     }
 
-    fn set(&self, key: String, value: String) -> Result<()> {
-        self.writer.lock().unwrap().set(key, value)
+    /// get the value for a given key as a string shared with the store where possible,
+    /// cheap to clone across threads.
+    ///
+    /// a value kept in the index, see `KvStoreOptions::inline_values`, or in the value
+    /// cache, see `KvStoreOptions::value_cache_capacity`, is returned without copying it,
+    /// and later gets of the key return the same allocation until it is written again,
+    /// or for a cached value, evicted or moved by a compaction. any other value is read
+    /// from the log into a new allocation, which the value cache then shares with later
+    /// gets if enabled.
+    pub fn get_shared<K: Into<String>>(&self, key: K) -> Result<Option<Arc<str>>> {
+        let key = key.into();
+        self.validate(&key)?;
+        self.timed(Op::Get, || {
+            if self.unflushed.load(Ordering::SeqCst) {
+                self.writer.lock().unwrap().flush_buffer()?;
+            }
+            self.reader.get_shared(key)
+        })
     }
 
-    fn remove(&self, key: String) -> Result<()> {
-        self.writer.lock().unwrap().remove(key)
+    /// get the value of a key parsed as an `i64`.
+    ///
+    /// return `KvsError::ParseError` if the value is not an integer.
+    pub fn get_i64<K: Into<String>>(&self, key: K) -> Result<Option<i64>> {
+        match self.get(key)? {
+            Some(value) => Ok(Some(value.parse()?)),
+            None => Ok(None),
+        }
     }
-}
-
-struct KvStoreReader {
-    path: Arc<PathBuf>,
-    epoch: Arc<AtomicUsize>,
-    key_index: Arc<CHashMap<String, LogIndex>>,
-    readers: RefCell<[Option<BufReader<File>>; 2]>,
-    reader_epoch: AtomicUsize,
-}
 
-impl Clone for KvStoreReader {
-    fn clone(&self) -> Self {
-        let reader = Self {
-            path: self.path.clone(),
-            epoch: self.epoch.clone(),
-            key_index: self.key_index.clone(),
-            readers: RefCell::new([None, None]),
-            reader_epoch: AtomicUsize::new(0),
-        };
+    /// set a key-value pair, accepting any key and value convertible into `String`.
+    ///
+    /// see [`KvsEngine::set`](trait.KvsEngine.html#tymethod.set).
+    pub fn set<K: Into<String>, V: Into<String>>(&self, key: K, value: V) -> Result<()> {
+        KvsEngine::set(self, key.into(), value.into())
+    }
 
-        let _ = reader.update_reader(true);
+    /// remove a key, accepting any key convertible into `String`.
+    ///
+    /// see [`KvsEngine::remove`](trait.KvsEngine.html#tymethod.remove).
+    pub fn remove<K: Into<String>>(&self, key: K) -> Result<()> {
+        KvsEngine::remove(self, key.into())
+    }
 
-        reader
+    /// move the value of a key to another, accepting any keys convertible into `String`.
+    ///
+    /// see [`KvsEngine::rename`](trait.KvsEngine.html#method.rename).
+    pub fn rename<K: Into<String>, T: Into<String>>(&self, from: K, to: T) -> Result<bool> {
+        KvsEngine::rename(self, from.into(), to.into())
     }
-}
 
-impl KvStoreReader {
-    fn get(&self, key: String) -> Result<Option<String>> {
-        if let Some(log_index) = self.key_index.get(&key) {
-            self.update_reader(false)?;
-            let cmd = self.read_from_log(*log_index)?;
-            match cmd {
-                Cmd::Set(_, val) => Ok(Some(val)),
-                Cmd::Rm(_) => Ok(None),
-            }
-        } else {
-            Ok(None)
-        }
+    /// set a key-value pair unless the writer is held, by another write or a compaction.
+    ///
+    /// return `KvsError::Busy` right away instead of waiting for the writer, leaving the
+    /// caller to retry or give up. a compaction triggered by this write still runs.
+    pub fn try_set<K: Into<String>, V: Into<String>>(&self, key: K, value: V) -> Result<()> {
+        let key = key.into();
+        self.validate(&key)?;
+        self.timed(Op::Set, || self.try_lock_writer()?.set(key, value.into()))
     }
 
-    fn read_from_log(&self, log_index: LogIndex) -> Result<Cmd> {
-        let mut readers = self.readers.borrow_mut();
-        let reader = readers[log_index.epoch % 2].get_or_insert_with(|| {
-            BufReader::new(File::open(self.path.join(format!("{}.log", log_index.epoch))).unwrap())
-        });
-        reader.seek(SeekFrom::Start(log_index.offset))?;
-        let take = reader.take(log_index.len);
-        serde_json::from_reader(take).map_err(|e| e.into())
+    /// remove a key unless the writer is held, returning `KvsError::Busy` like `try_set`.
+    pub fn try_remove<K: Into<String>>(&self, key: K) -> Result<()> {
+        let key = key.into();
+        self.validate(&key)?;
+        self.timed(Op::Remove, || self.try_lock_writer()?.remove(key))
     }
 
-    fn update_reader(&self, initial: bool) -> Result<()> {
-        let latest = self.epoch.load(Ordering::SeqCst);
-        let self_epoch = self.reader_epoch.load(Ordering::SeqCst);
-        if self_epoch < latest || initial {
-            let cur_reader = BufReader::new(File::open(self.path.join(format!("{}.log", latest)))?);
-            self.readers.borrow_mut()[latest % 2] = Some(cur_reader);
-            if self_epoch + 1 < latest {
-                let prev_file = File::open(self.path.join(format!("{}.log", latest - 1))).ok();
-                self.readers.borrow_mut()[(latest - 1) % 2] = prev_file.map(|file| BufReader::new(file));
-            }
-            self.reader_epoch.store(latest, Ordering::SeqCst);
+    fn try_lock_writer(&self) -> Result<MutexGuard<'_, KvStoreWriter>> {
+        match self.writer.try_lock() {
+            Ok(writer) => Ok(writer),
+            Err(TryLockError::WouldBlock) => Err(KvsError::Busy),
+            Err(TryLockError::Poisoned(e)) => panic!("writer lock poisoned: {}", e),
         }
-        Ok(())
     }
-}
 
-struct KvStoreWriter {
-    path: Arc<PathBuf>,
-    epoch: Arc<AtomicUsize>,
-    key_index: Arc<CHashMap<String, LogIndex>>,
-    writer: BufWriter<File>,
-    redundant: u32,
-    reader: KvStoreReader,
-}
+    /// merge an operand into the value of a key, accepting any key and operand convertible into `String`.
+    ///
+    /// see [`KvsEngine::merge`](trait.KvsEngine.html#method.merge).
+    pub fn merge<K: Into<String>, V: Into<String>>(&self, key: K, operand: V) -> Result<()> {
+        KvsEngine::merge(self, key.into(), operand.into())
+    }
 
-impl KvStoreWriter {
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        self.append_log(Cmd::Set(key.clone(), value), key)
+    /// compact the log if it has accumulated too many redundant records.
+    ///
+    /// this is the same check writes run after each record unless automatic compaction is
+    /// disabled in `KvStoreOptions`, return whether compaction ran.
+    pub fn compact_if_needed(&self) -> Result<bool> {
+        self.lock_idle_writer().try_compact()
     }
 
-    fn remove(&mut self, key: String) -> Result<()> {
-        if self.key_index.contains_key(&key) {
-            self.append_log(Cmd::Rm(key.clone()), key)
-        } else {
-            Err(KvsError::KeyNotFound)
+    /// compact the log now, regardless of how many records are redundant
+    ///
+    /// waits for a background compaction in progress to finish first. return
+    /// `KvsError::Busy` while writer handles are open.
+    pub fn compact(&self) -> Result<()> {
+        self.lock_idle_writer().compact()
+    }
+
+    /// flush and sync the store, returning the errors dropping it would only log
+    ///
+    /// waits for a background compaction in progress to finish, then runs the compaction
+    /// a write would have triggered, unless automatic compaction is disabled, on this
+    /// thread rather than leaving it to the background, and syncs the active segment.
+    /// other handles of the store stay usable, its files are closed once the last one
+    /// is dropped.
+    pub fn close(self) -> Result<()> {
+        let mut writer = self.lock_idle_writer();
+        if writer.auto_compaction {
+            writer.try_compact()?;
         }
+        writer.sync()
     }
 
-    fn append_log(&mut self, cmd: Cmd, key: String) -> Result<()> {
-        let offset = self.writer.seek(SeekFrom::End(0))?;
-        serde_json::to_writer(&mut self.writer, &cmd)?;
-        self.writer.flush()?;
-        let new_offset = self.writer.seek(SeekFrom::End(0))?;
+    /// remove the files of the store left behind by a crash, return their names
+    ///
+    /// these are the `temp` files of compactions and other atomic writes, segments neither
+    /// listed in `CURRENT` nor kept for other handles still reading them, and blob files no
+    /// live key refers to. files the store does not name are left alone. waits for a
+    /// background compaction in progress to finish first. return `KvsError::ReadOnly` for
+    /// a store opened read-only.
+    pub fn gc_orphans(&self) -> Result<Vec<String>> {
+        self.lock_idle_writer().gc_orphans()
+    }
 
-        let epoch = self.epoch.load(Ordering::SeqCst);
-        let log_index = LogIndex::new(epoch, offset, new_offset - offset);
+    /// open a writer appending to a segment of its own, see [`WriterHandle`].
+    ///
+    /// waits for a background compaction in progress to finish first.
+    pub fn writer_handle(&self) -> Result<WriterHandle> {
+        let mut writer = self.lock_idle_writer();
+        writer.writable()?;
+        let epoch = writer.next_epoch;
+        let mut file = writer.storage.create(&log_name(epoch))?;
+        let end = write_header(&mut file, false, false)?;
+        file.sync()?;
+        writer.storage.sync_dir()?;
+        let mut writers = writer.writers.clone();
+        writers.push(epoch);
+        let seq = writer.seq.load(Ordering::SeqCst);
+        commit_manifest(&*writer.storage, &writer.segments, &writers, seq)?;
+        writer.writers = writers;
+        writer.next_epoch = epoch + 1;
 
-        //trigger compaction if necessary: too much redundant records or active_file too large
-        if let Some(_) = self.key_index.insert(key, log_index) {
-            self.redundant += 1;
-            if self.redundant > COMPACTION_THRESHOLD {
-                self.compact()?
+        Ok(WriterHandle {
+            store: self.clone(),
+            seq: writer.seq.clone(),
+            epoch,
+            file,
+            end,
+            written: HashMap::new(),
+            redundant: 0,
+            check_sequence: writer.check_sequence,
+            closed: false,
+        })
+    }
+
+    /// lock the writer once no background compaction is in progress
+    fn lock_idle_writer(&self) -> MutexGuard<'_, KvStoreWriter> {
+        let mut writer = self.writer.lock().unwrap();
+        while writer.compacting {
+            writer = self.compacted.wait(writer).unwrap();
+        }
+        writer
+    }
+
+    /// check that the record of every indexed key can be read back and belongs to that key.
+    ///
+    /// sealed segments are checked whole against the checksum in their footer, in one
+    /// sequential read each, so only their records referring to blobs are read one by one.
+    ///
+    /// return the number of keys checked.
+    pub fn verify(&self) -> Result<usize> {
+        let mut writer = self.writer.lock().unwrap();
+        writer.flush_buffer()?;
+        let mut checked = HashSet::new();
+        for segment in &writer.segments[..writer.segments.len() - 1] {
+            let mut log = Vec::new();
+            let mut reader = writer.storage.open(&log_name(segment.epoch))?;
+            reader.read_to_end(&mut log)?;
+            let (header, _) = read_header(&log)?;
+            if let (_, Some(_)) = check_footer(&log, &header, segment.epoch)? {
+                checked.insert(segment.epoch);
             }
         }
-        Ok(())
+        let key_index = writer.key_index.entries();
+        for (key, log_index) in key_index.iter().cloned() {
+            if checked.contains(&log_index.epoch) && !writer.blob_refs.contains_key(&key) {
+                continue;
+            }
+            match writer.reader.read_from_log(*log_index)? {
+                Cmd::Set(ref record_key, _) if *record_key == key => {}
+                Cmd::SetRef(ref record_key, ref hash, len) if *record_key == key => {
+                    writer.reader.read_blob(hash, len)?;
+                }
+                _ => {
+                    return Err(KvsError::Corrupted(format!(
+                        "record at epoch {} offset {} is not the value of `{}`",
+                        log_index.epoch, log_index.offset, key
+                    )))
+                }
+            }
+        }
+        Ok(key_index.len())
     }
 
-    fn compact(&mut self) -> Result<()> {
-        let temp_path = self.path.join("temp");
-        let mut new_writer = BufWriter::new(File::create(&temp_path)?);
-        let cur_key_index = (*self.key_index).clone();
-        let mut new_key_index = HashMap::new();
-        let new_epoch = self.epoch.load(Ordering::SeqCst) + 1;
+    /// write a snapshot of the index into the file `name` of the storage, replacing it if any.
+    ///
+    /// the snapshot records the end of the log it covers. writes of open writer handles
+    /// may be missing from it. return the number of keys in the snapshot.
+    pub fn snapshot_index(&self, name: &str) -> Result<usize> {
+        let mut writer = self.lock_idle_writer();
+        writer.writable()?;
+        writer.flush_buffer()?;
+        let epoch = writer.epoch.load(Ordering::SeqCst);
+        let entries: Vec<(String, LogIndex)> = writer
+            .key_index
+            .entries()
+            .into_iter()
+            .map(|(key, entry)| (key, *entry))
+            .collect();
+        // written aside and renamed, so a crash never leaves a partial snapshot behind
+        let tmp = format!("{}.tmp", name);
+        let mut file = BufWriter::new(writer.storage.create(&tmp)?);
+        write_snapshot(&mut file, epoch, writer.end, &entries)?;
+        let mut file = file.into_inner().map_err(|err| err.into_error())?;
+        file.sync()?;
+        drop(file);
+        writer.storage.rename(&tmp, name)?;
+        writer.storage.sync_dir()?;
+        Ok(entries.len())
+    }
+
+    /// read the snapshot written by `snapshot_index` into the file `name` of the storage,
+    /// and check whether it holds the same entries as the index.
+    ///
+    /// fails with `KvsError::Corrupted` if the snapshot does not match its checksum.
+    /// only built with the `test-util` feature, to check snapshots in tests.
+    #[cfg(feature = "test-util")]
+    pub fn check_index_snapshot(&self, name: &str) -> Result<bool> {
+        let writer = self.lock_idle_writer();
+        let mut data = Vec::new();
+        writer.storage.open(name)?.read_to_end(&mut data)?;
+        let IndexSnapshot {
+            epoch,
+            offset,
+            mut entries,
+        } = read_snapshot(&data)?;
+        #[cfg(feature = "tracing")]
+        tracing::debug!(snapshot = name, epoch, offset, "index snapshot read");
+        #[cfg(not(feature = "tracing"))]
+        debug!(
+            "index snapshot {} covers {} up to offset {}",
+            name,
+            log_name(epoch),
+            offset
+        );
+        let mut index: Vec<(String, LogIndex)> = writer
+            .key_index
+            .entries()
+            .into_iter()
+            .map(|(key, entry)| (key, *entry))
+            .collect();
+        entries.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        index.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        Ok(entries == index)
+    }
 
-        let mut offset = 0;
-        for (key, log_index) in cur_key_index.into_iter() {
-            let cmd = self.reader.read_from_log(log_index)?;
-            serde_json::to_writer(&mut new_writer, &cmd)?;
-            new_key_index.insert(key, LogIndex::new(new_epoch, offset, log_index.len));
-            offset += log_index.len;
+    /// write the files of the store into a single archive, to be unpacked elsewhere by `restore`.
+    ///
+    /// the archive holds the files as they are: the manifest, every live segment and every
+    /// blob file a live key refers to, so it is as large as the store and restores without
+    /// replaying anything but the segments. compact the store first to archive a single
+    /// segment without redundant records. writes wait until the archive is written, reads go
+    /// on. fails with `KvsError::Busy` while writer handles are open, as their segments are
+    /// still being written.
+    pub fn checkpoint<W: Write>(&self, out: W) -> Result<()> {
+        let mut writer = self.lock_idle_writer();
+        if !writer.writers.is_empty() {
+            return Err(KvsError::Busy);
+        }
+        writer.flush_buffer()?;
+        let active = writer.epoch.load(Ordering::SeqCst);
+        let mut files = Vec::new();
+        for segment in writer.segments.iter() {
+            let name = log_name(segment.epoch);
+            let len = if segment.epoch == active {
+                // without the zeros preallocated past its records
+                writer.end
+            } else {
+                writer.storage.open(&name)?.seek(SeekFrom::End(0))?
+            };
+            files.push((name, len));
+        }
+        let blobs: HashSet<&String> = writer.blob_refs.values().collect();
+        for hash in blobs {
+            let name = blob_name(hash);
+            let len = writer.storage.open(&name)?.seek(SeekFrom::End(0))?;
+            files.push((name, len));
         }
-        new_writer.flush()?;
-        drop(new_writer);
+        let len = writer.storage.open(CURRENT)?.seek(SeekFrom::End(0))?;
+        files.push((CURRENT.to_owned(), len));
 
-        if new_epoch >= 2 {
-            let potential_old_file = self.path.join(format!("{}.log", new_epoch - 2));
-            let _ = std::fs::remove_file(potential_old_file);
+        let mut archive = ArchiveWriter {
+            out,
+            hasher: Sha256::new(),
+        };
+        archive.write(ARCHIVE_MAGIC)?;
+        archive.write(&ARCHIVE_FORMAT.to_be_bytes())?;
+        archive.write(&(files.len() as u32).to_be_bytes())?;
+        for (name, len) in files {
+            archive.add(&name, len, writer.storage.open(&name)?)?;
         }
+        archive.finish()
+    }
+
+    /// a view of the store holding only the keys of the given namespace
+    pub fn namespace(&self, prefix: String) -> NamespacedStore {
+        NamespacedStore::new(self.clone(), &prefix)
+    }
 
-        let new_path = self.path.join(format!("{}.log", new_epoch));
-        std::fs::rename(temp_path, &new_path)?;
+    /// the epoch of the log segment being written to
+    pub fn epoch(&self) -> usize {
+        self.writer.lock().unwrap().epoch.load(Ordering::SeqCst)
+    }
+
+    /// describe the log segments in the storage, ordered by epoch.
+    ///
+    /// the writer is locked meanwhile so no compaction runs, but only the sizes of
+    /// the segments are read, not their records.
+    pub fn segments(&self) -> Result<Vec<SegmentInfo>> {
+        let writer = self.writer.lock().unwrap();
+        let mut live_keys = HashMap::new();
+        for (_, log_index) in writer.key_index.entries() {
+            *live_keys.entry(log_index.epoch).or_insert(0) += 1;
+        }
 
-        self.epoch.fetch_add(1, Ordering::SeqCst);
-        for (key, index) in new_key_index {
-            self.key_index.insert(key, index);
+        let mut segments = Vec::new();
+        for name in writer.storage.list()? {
+            if let Some(epoch) = log_epoch(&name) {
+                let size = writer.storage.open(&name)?.seek(SeekFrom::End(0))?;
+                let compressed = writer
+                    .segments
+                    .iter()
+                    .chain(writer.retired.iter())
+                    .any(|segment| segment.epoch == epoch && segment.compressed);
+                segments.push(SegmentInfo {
+                    epoch,
+                    name,
+                    size,
+                    live_keys: live_keys.get(&epoch).copied().unwrap_or(0),
+                    compressed,
+                });
+            }
         }
-        self.redundant = 0;
+        segments.sort_by_key(|segment| segment.epoch);
+        Ok(segments)
+    }
 
-        self.writer = BufWriter::new(OpenOptions::new().append(true).open(&new_path)?);
+    /// bytes of the live and dead records of each live segment, by epoch, to plan which
+    /// segments to compact.
+    ///
+    /// kept up to date on every write, so nothing is read. writes through open writer
+    /// handles are only accounted once the handles are merged.
+    pub fn segment_stats(&self) -> HashMap<usize, SegmentStat> {
+        self.writer.lock().unwrap().stats.clone()
+    }
+}
 
-        Ok(())
+/// Description of a log segment of a `KvStore`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SegmentInfo {
+    /// epoch of the segment, the highest one is being written to
+    pub epoch: usize,
+    /// file name of the segment within the storage
+    pub name: String,
+    /// size of the segment in bytes
+    pub size: u64,
+    /// number of keys whose current value is in the segment
+    pub live_keys: usize,
+    /// whether the segment is sealed and its records compressed
+    pub compressed: bool,
+}
+
+/// Bytes of the records of a log segment, to plan which segments to compact
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct SegmentStat {
+    /// bytes of the records holding the current value of a key
+    pub live_bytes: u64,
+    /// bytes of the records overwritten or removed since, and of the `Rm` records
+    pub dead_bytes: u64,
+    /// number of records in the segment
+    pub record_count: u64,
+}
+
+impl SegmentStat {
+    /// count a record, dead until `live` is called for it
+    fn add(&mut self, len: u64) {
+        self.record_count += 1;
+        self.dead_bytes += len;
+    }
+
+    fn live(&mut self, len: u64) {
+        self.dead_bytes -= len.min(self.dead_bytes);
+        self.live_bytes += len;
+    }
+
+    fn dead(&mut self, len: u64) {
+        self.live_bytes -= len.min(self.live_bytes);
+        self.dead_bytes += len;
     }
 }
+
+impl KvsEngine for KvStore {
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.validate(&key)?;
+        self.timed(Op::Get, || {
+            if self.unflushed.load(Ordering::SeqCst) {
+                self.writer.lock().unwrap().flush_buffer()?;
+            }
+            self.reader.get(key)
+        })
+    }
+
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.validate(&key)?;
+        self.timed(Op::Set, || self.writer.lock().unwrap().set(key, value))
+    }
+
+    /// append every record under one lock of the writer, flushing them once at the end
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        for (key, _) in pairs.iter() {
+            self.validate(key)?;
+        }
+        self.timed(Op::Set, || self.writer.lock().unwrap().set_many(pairs))
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.validate(&key)?;
+        self.timed(Op::Remove, || self.writer.lock().unwrap().remove(key))
+    }
+
+    /// the version is derived from the sequence number of the write, which compactions keep.
+    fn get_versioned(&self, key: String) -> Result<Option<(String, u64)>> {
+        self.validate(&key)?;
+        self.timed(Op::Get, || {
+            if self.unflushed.load(Ordering::SeqCst) {
+                self.writer.lock().unwrap().flush_buffer()?;
+            }
+            let value = self.reader.get_indexed(key)?;
+            Ok(value.map(|(value, log_index)| (value, log_index.version())))
+        })
+    }
+
+    fn set_if_version(&self, key: String, value: String, expected: u64) -> Result<bool> {
+        self.validate(&key)?;
+        self.timed(Op::Set, || {
+            self.writer.lock().unwrap().set_if_version(key, value, expected)
+        })
+    }
+
+    /// the old value is read under the writer lock, then the new one written as a plain `Set`.
+    fn set_and_get_old(&self, key: String, value: String) -> Result<Option<String>> {
+        self.validate(&key)?;
+        self.timed(Op::Set, || self.writer.lock().unwrap().set_and_get_old(key, value))
+    }
+
+    /// the operator runs eagerly under the writer lock and its result is logged as a
+    /// plain `Set`, so reads and replay never need the operator.
+    fn merge(&self, key: String, operand: String) -> Result<()> {
+        self.validate(&key)?;
+        self.timed(Op::Set, || self.writer.lock().unwrap().merge(key, operand))
+    }
+
+    /// a single `Rename` record removes `from` and sets `to`, so replay applies the whole
+    /// rename or none of it. return `KvsError::Busy` while writer handles are open.
+    fn rename(&self, from: String, to: String) -> Result<bool> {
+        self.validate(&from)?;
+        self.validate(&to)?;
+        self.timed(Op::Set, || self.writer.lock().unwrap().rename(from, to))
+    }
+
+    fn remove_range(&self, start: Bound<String>, end: Bound<String>) -> Result<usize> {
+        self.timed(Op::Remove, || self.writer.lock().unwrap().remove_range((start, end)))
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        self.reader.keys()
+    }
+
+    fn count_prefix(&self, prefix: String) -> Result<usize> {
+        Ok(self.reader.count_prefix(&prefix))
+    }
+
+    /// read the active log once from start to end
+    fn warm_up(&self) -> Result<()> {
+        self.writer.lock().unwrap().warm_up()
+    }
+
+    /// check that the writer can be locked and the active log is where the writer expects it
+    fn health_check(&self) -> Result<()> {
+        match self.writer.lock() {
+            Ok(mut writer) => writer.health_check(),
+            Err(_) => Err(KvsError::Unhealthy(
+                "a write panicked while holding the writer".to_owned(),
+            )),
+        }
+    }
+
+    /// sync the active log to the storage
+    fn flush(&self) -> Result<()> {
+        self.writer.lock().unwrap().sync()
+    }
+
+    fn compact(&self) -> Result<()> {
+        KvStore::compact(self)
+    }
+
+    /// rewrite the live records of the keys within the range into a new segment.
+    ///
+    /// the active segment is sealed behind it and a new active segment follows, so records
+    /// of other keys stay where they are. the space of a segment is only reclaimed once
+    /// no segment older than it holds live records either, or by a full compaction.
+    fn compact_range(&self, start: Bound<String>, end: Bound<String>) -> Result<()> {
+        self.lock_idle_writer().compact_range((start, end))
+    }
+}
+
+/// A writer of a `KvStore` appending to a segment of its own
+///
+/// Writes through different handles do not take turns on the writer of the store, so
+/// threads can ingest in parallel, each with its own handle. Every write through a handle,
+/// or through the store while handles are open, takes the next number of a sequence they
+/// share and is recorded with it. Writes of the same key are indexed in sequence order,
+/// whichever segment they are in, and replayed that way after a crash.
+///
+/// Each record is written to the storage before the write returns, so reads through the
+/// store see it, but only `flush` makes it durable. Closing or dropping the handle merges
+/// its segment: the records still live in it are copied into the active segment, which is
+/// synced, and the segment is removed. A segment left by a crash is merged when the store
+/// is opened again.
+///
+/// Compaction waits until no handle is open, `KvStore::compact` returns `KvsError::Busy`
+/// meanwhile. Values written through a handle are always stored inline, and a `merge`
+/// through the store does not stop handles from writing the key while it runs.
+///
+/// ```rust
+/// use kvs::{KvStore, KvStoreOptions, MemoryStorage};
+/// use std::thread;
+///
+/// let store = KvStore::open_with_storage(MemoryStorage::new(), KvStoreOptions::new()).unwrap();
+/// let threads: Vec<_> = (0..4)
+///     .map(|i| {
+///         let mut writer = store.writer_handle().unwrap();
+///         thread::spawn(move || writer.set(format!("key{}", i), "value").unwrap())
+///     })
+///     .collect();
+/// for thread in threads {
+///     thread.join().unwrap();
+/// }
+/// assert_eq!(store.get("key3").unwrap(), Some("value".to_owned()));
+/// ```
+pub struct WriterHandle {
+    store: KvStore,
+    seq: Arc<AtomicU64>,
+    /// epoch of the segment of the handle
+    epoch: usize,
+    file: Box<dyn StorageWriter>,
+    end: u64,
+    /// sequence number of the last write of each key through the handle
+    written: HashMap<String, u64>,
+    /// records made redundant by the handle, added to the store's once merged
+    redundant: u32,
+    check_sequence: bool,
+    closed: bool,
+}
+
+impl WriterHandle {
+    /// set a key-value pair, accepting any key and value convertible into `String`
+    pub fn set<K: Into<String>, V: Into<String>>(&mut self, key: K, value: V) -> Result<()> {
+        let key = key.into();
+        let value = value.into();
+        self.store.validate(&key)?;
+        let key_index = self.store.reader.key_index.clone();
+        let mut seq = 0;
+        write_locked(&key_index, &key, self.check_sequence, |current| {
+            seq = self.seq.fetch_add(1, Ordering::SeqCst) + 1;
+            let log_index = self
+                .append(&CmdRef::Seq(seq, &CmdRef::Set(&key, &value)))?
+                .with_seq(seq);
+            if current.is_some() {
+                self.redundant += 1;
+            }
+            Ok(Some(self.store.reader.entry(log_index, &value)))
+        })?;
+        self.written.insert(key, seq);
+        Ok(())
+    }
+
+    /// remove a key, return `KvsError::KeyNotFound` if it is not in the store
+    pub fn remove<K: Into<String>>(&mut self, key: K) -> Result<()> {
+        let key = key.into();
+        self.store.validate(&key)?;
+        let key_index = self.store.reader.key_index.clone();
+        let mut seq = 0;
+        write_locked(&key_index, &key, self.check_sequence, |current| match current {
+            Some(_) => {
+                seq = self.seq.fetch_add(1, Ordering::SeqCst) + 1;
+                self.append(&CmdRef::Seq(seq, &CmdRef::Rm(&key)))?;
+                self.redundant += 2;
+                Ok(None)
+            }
+            None => Err(KvsError::KeyNotFound),
+        })?;
+        self.written.insert(key, seq);
+        Ok(())
+    }
+
+    /// sync the segment of the handle to the storage
+    pub fn flush(&mut self) -> Result<()> {
+        self.file.sync()?;
+        Ok(())
+    }
+
+    /// merge the segment of the handle into the store, returning the error dropping it would log
+    pub fn close(mut self) -> Result<()> {
+        self.closed = true;
+        self.merge()
+    }
+
+    fn append(&mut self, cmd: &CmdRef) -> Result<LogIndex> {
+        let record = serde_json::to_vec(cmd)?;
+        self.file.write_all(&record)?;
+        let offset = self.end;
+        self.end += record.len() as u64;
+        Ok(LogIndex::new(self.epoch, offset, record.len() as u64))
+    }
+
+    fn merge(&mut self) -> Result<()> {
+        let written = std::mem::take(&mut self.written);
+        let mut writer = self.store.lock_idle_writer();
+        writer.merge_writers(&[self.epoch], written, self.redundant)?;
+        writer.auto_compact()
+    }
+}
+
+impl Drop for WriterHandle {
+    /// merge the segment, logging errors since `drop` cannot return them
+    ///
+    /// a segment that failed to merge keeps compaction off until the store is opened again.
+    fn drop(&mut self) {
+        if !self.closed {
+            if let Err(e) = self.merge() {
+                error!("failed to merge {}: {}", log_name(self.epoch), e);
+            }
+        }
+    }
+}
+
+/// Reader side of `KvStore`
+///
+/// Each clone keeps its own cache of open segment readers keyed by epoch, so any
+/// number of live segments can be read from. Readers of segments older than
+/// `min_epoch` have been removed by compaction and are dropped from the cache.
+/// The cache is behind a `Mutex` so a handle can be shared by reference across
+/// threads, but reads through the same handle then take turns; clone the store
+/// to read in parallel.
+///
+/// With `max_open_files` set, opening a reader beyond the limit first closes the
+/// least recently used idle reader of any handle. If every open reader is busy,
+/// the record is read through a reader closed right after.
+///
+/// The optional value cache is shared by all clones. An entry is only used while
+/// the key is still indexed at the record it was read from, so any later write or
+/// compaction of the key turns it into a miss. Values kept in the index are served
+/// before the cache is looked up.
+struct KvStoreReader {
+    storage: Arc<dyn Storage>,
+    min_epoch: Arc<AtomicUsize>,
+    key_index: Arc<KeyIndex<IndexEntry>>,
+    readers: Arc<ReaderCache>,
+    files: Arc<OpenFiles>,
+    reader_min_epoch: AtomicUsize,
+    values: Option<Arc<ValueCache>>,
+    inline: Option<Arc<InlineValues>>,
+    /// capacity of the buffer of every segment reader and writer
+    buffer_size: usize,
+}
+
+impl Clone for KvStoreReader {
+    fn clone(&self) -> Self {
+        Self {
+            storage: self.storage.clone(),
+            min_epoch: self.min_epoch.clone(),
+            key_index: self.key_index.clone(),
+            readers: self
+                .files
+                .register(&Arc::new(Mutex::new(LruCache::new(self.readers.lock().unwrap().cap())))),
+            files: self.files.clone(),
+            reader_min_epoch: AtomicUsize::new(0),
+            values: self.values.clone(),
+            inline: self.inline.clone(),
+            buffer_size: self.buffer_size,
+        }
+    }
+}
+
+impl KvStoreReader {
+    fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self.get_indexed(key)?.map(|(value, _)| value))
+    }
+
+    /// the value of a key along with the index of the record it was read from
+    fn get_indexed(&self, key: String) -> Result<Option<(String, LogIndex)>> {
+        Ok(self
+            .read_value(key)?
+            .map(|(value, log_index)| (value.into_string(), log_index)))
+    }
+
+    /// the value of a key, shared with the index or the value cache if it is in either
+    fn get_shared(&self, key: String) -> Result<Option<Arc<str>>> {
+        Ok(self.read_value(key)?.map(|(value, _)| value.into_shared()))
+    }
+
+    fn read_value(&self, key: String) -> Result<Option<(ReadValue, LogIndex)>> {
+        loop {
+            let log_index = match self.key_index.get(&key) {
+                Some(IndexEntry::Inline(log_index, value)) => {
+                    return Ok(Some((ReadValue::Shared(value.value.clone()), log_index)))
+                }
+                Some(IndexEntry::OnDisk(log_index)) => log_index,
+                None => return Ok(None),
+            };
+            if let Some(values) = &self.values {
+                if let Some((cached_index, val)) = values.lock().unwrap().get(&key) {
+                    if *cached_index == log_index {
+                        return Ok(Some((ReadValue::Shared(val.clone()), log_index)));
+                    }
+                }
+            }
+            let val = match self.read_from_log(log_index)? {
+                Cmd::Set(_, val) => val,
+                Cmd::SetRef(_, hash, len) => match self.read_blob(&hash, len) {
+                    Ok(val) => val,
+                    // collected by a compaction after the key was written again, read the new value
+                    Err(KvsError::Io(ref e))
+                        if e.kind() == io::ErrorKind::NotFound
+                            && self.key_index.get(&key).map(|entry| *entry) != Some(log_index) =>
+                    {
+                        continue
+                    }
+                    Err(e) => return Err(e),
+                },
+                Cmd::Rm(_) => return Ok(None),
+                Cmd::SetCompressed(..) | Cmd::Rename(..) => unreachable!("read_from_log keeps neither"),
+                Cmd::Seq(..) => unreachable!("read_record removes sequence numbers"),
+            };
+            self.keep_inline(&key, log_index, &val);
+            if let Some(values) = &self.values {
+                let val: Arc<str> = val.into();
+                values.lock().unwrap().put(key, (log_index, val.clone()));
+                return Ok(Some((ReadValue::Shared(val), log_index)));
+            }
+            return Ok(Some((ReadValue::Read(val), log_index)));
+        }
+    }
+
+    /// the entry of a value recorded at `log_index`, see `index_entry`
+    fn entry(&self, log_index: LogIndex, value: &str) -> IndexEntry {
+        index_entry(self.inline.as_ref(), log_index, value)
+    }
+
+    /// keep a value just read from the log in the index, if it fits now and the key is
+    /// still indexed at the same record
+    fn keep_inline(&self, key: &str, log_index: LogIndex, value: &str) {
+        if let entry @ IndexEntry::Inline(..) = self.entry(log_index, value) {
+            self.key_index.alter(key.to_owned(), |current| match current {
+                Some(IndexEntry::OnDisk(current)) if current == log_index => Some(entry),
+                current => current,
+            });
+        }
+    }
+
+    /// read a value stored in a blob file, checking it has the length its reference expects
+    fn read_blob(&self, hash: &str, len: u64) -> Result<String> {
+        let mut val = String::with_capacity(len as usize);
+        self.storage.open(&blob_name(hash))?.read_to_string(&mut val)?;
+        if val.len() as u64 != len {
+            return Err(KvsError::Corrupted(format!(
+                "{} holds {} bytes, expected {}",
+                blob_name(hash),
+                val.len(),
+                len
+            )));
+        }
+        Ok(val)
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        Ok(self.key_index.sorted_keys())
+    }
+
+    /// the index only holds live keys
+    fn count_prefix(&self, prefix: &str) -> usize {
+        self.key_index.count_prefix(prefix)
+    }
+
+    /// read the record at `log_index`, a compressed value decompressed into a `Set` and a
+    /// `Rename` reduced to the command setting the value
+    fn read_from_log(&self, log_index: LogIndex) -> Result<Cmd> {
+        match self.read_stored(log_index)?.without_rename() {
+            Cmd::SetCompressed(key, value) => Ok(Cmd::Set(key, decompress_value(&value)?)),
+            cmd => Ok(cmd),
+        }
+    }
+
+    /// read the record at `log_index` as it is stored
+    fn read_stored(&self, log_index: LogIndex) -> Result<Cmd> {
+        self.drop_stale_readers();
+        let mut readers = self.readers.lock().unwrap();
+        if !readers.contains(&log_index.epoch) {
+            if readers.len() == readers.cap() {
+                readers.pop_lru();
+            }
+            if !self.files.reserve(&mut readers) {
+                let mut reader =
+                    BufReader::with_capacity(self.buffer_size, self.storage.open(&log_name(log_index.epoch))?);
+                return read_record(&mut reader, log_index);
+            }
+            let file = match self.storage.open(&log_name(log_index.epoch)) {
+                Ok(file) => file,
+                Err(e) => {
+                    self.files.release();
+                    return Err(e);
+                }
+            };
+            let reader = BufReader::with_capacity(self.buffer_size, file);
+            readers.put(log_index.epoch, CachedReader::new(reader, &self.files));
+        }
+        let cached = readers.get_mut(&log_index.epoch).expect("reader was just cached");
+        cached.last_used = self.files.tick();
+        read_record(&mut cached.reader, log_index)
+    }
+
+    fn drop_stale_readers(&self) {
+        let min_epoch = self.min_epoch.load(Ordering::SeqCst);
+        if self.reader_min_epoch.load(Ordering::SeqCst) < min_epoch {
+            let mut readers = self.readers.lock().unwrap();
+            let stale: Vec<usize> = readers
+                .iter()
+                .map(|(epoch, _)| *epoch)
+                .filter(|epoch| *epoch < min_epoch)
+                .collect();
+            for epoch in stale {
+                readers.pop(&epoch);
+            }
+            self.reader_min_epoch.store(min_epoch, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Value read by a `KvStoreReader`
+enum ReadValue {
+    /// kept in memory, by the index or the value cache
+    Shared(Arc<str>),
+    /// read from the log
+    Read(String),
+}
+
+impl ReadValue {
+    fn into_string(self) -> String {
+        match self {
+            ReadValue::Shared(value) => value.to_string(),
+            ReadValue::Read(value) => value,
+        }
+    }
+
+    fn into_shared(self) -> Arc<str> {
+        match self {
+            ReadValue::Shared(value) => value,
+            ReadValue::Read(value) => value.into(),
+        }
+    }
+}
+
+/// read the record at `log_index`, without its sequence number
+fn read_record(reader: &mut BufReader<Box<dyn StorageReader>>, log_index: LogIndex) -> Result<Cmd> {
+    reader.seek(SeekFrom::Start(log_index.offset))?;
+    let take = reader.take(log_index.len);
+    let cmd: Cmd = if log_index.compressed {
+        serde_json::from_reader(zstd::Decoder::new(take)?)?
+    } else {
+        serde_json::from_reader(take)?
+    };
+    Ok(cmd.unseq().1)
+}
+
+/// A segment reader counted in `OpenFiles` until dropped
+struct CachedReader {
+    reader: BufReader<Box<dyn StorageReader>>,
+    last_used: u64,
+    files: Arc<OpenFiles>,
+}
+
+impl CachedReader {
+    /// wrap a reader already counted in `files`
+    fn new(reader: BufReader<Box<dyn StorageReader>>, files: &Arc<OpenFiles>) -> Self {
+        Self {
+            reader,
+            last_used: files.tick(),
+            files: files.clone(),
+        }
+    }
+}
+
+impl Drop for CachedReader {
+    fn drop(&mut self) {
+        self.files.release();
+    }
+}
+
+/// Segment readers open across all handles of a store
+struct OpenFiles {
+    /// readers allowed open at once, if limited
+    limit: Option<usize>,
+    open: AtomicUsize,
+    clock: AtomicU64,
+    caches: Mutex<Vec<Weak<ReaderCache>>>,
+}
+
+impl OpenFiles {
+    fn new(limit: Option<usize>) -> Self {
+        Self {
+            limit,
+            open: AtomicUsize::new(0),
+            clock: AtomicU64::new(0),
+            caches: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// track the readers of a new handle so they can be closed to make room
+    fn register(&self, cache: &Arc<ReaderCache>) -> Arc<ReaderCache> {
+        let mut caches = self.caches.lock().unwrap();
+        caches.retain(|cache| cache.strong_count() > 0);
+        caches.push(Arc::downgrade(cache));
+        cache.clone()
+    }
+
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::SeqCst)
+    }
+
+    /// count one more reader, closing idle ones to stay within the limit
+    ///
+    /// `own` is the locked cache of the calling handle. return false if the limit is
+    /// reached and no reader is idle.
+    fn reserve(&self, own: &mut LruCache<usize, CachedReader>) -> bool {
+        let limit = match self.limit {
+            Some(limit) => limit,
+            None => {
+                self.open.fetch_add(1, Ordering::SeqCst);
+                return true;
+            }
+        };
+        let mut open = self.open.load(Ordering::SeqCst);
+        loop {
+            if open < limit {
+                match self
+                    .open
+                    .compare_exchange(open, open + 1, Ordering::SeqCst, Ordering::SeqCst)
+                {
+                    Ok(_) => return true,
+                    Err(current) => open = current,
+                }
+            } else if self.close_idle(own) {
+                open = self.open.load(Ordering::SeqCst);
+            } else {
+                return false;
+            }
+        }
+    }
+
+    /// close the least recently used reader not in use by another handle
+    fn close_idle(&self, own: &mut LruCache<usize, CachedReader>) -> bool {
+        let mut oldest: Option<(u64, Option<Arc<ReaderCache>>, usize)> = own
+            .iter()
+            .map(|(epoch, cached)| (cached.last_used, None, *epoch))
+            .min_by_key(|(last_used, _, _)| *last_used);
+        for cache in self.caches.lock().unwrap().iter().filter_map(Weak::upgrade) {
+            // the own cache and caches of handles in the middle of a read are locked
+            if let Ok(readers) = cache.try_lock() {
+                for (epoch, cached) in readers.iter() {
+                    match &oldest {
+                        Some((last_used, _, _)) if *last_used <= cached.last_used => {}
+                        _ => oldest = Some((cached.last_used, Some(cache.clone()), *epoch)),
+                    }
+                }
+            }
+        }
+        match oldest {
+            Some((_, None, epoch)) => own.pop(&epoch).is_some(),
+            Some((_, Some(cache), epoch)) => match cache.try_lock() {
+                Ok(mut readers) => readers.pop(&epoch).is_some(),
+                // taken by its handle meanwhile, look again
+                Err(_) => true,
+            },
+            None => false,
+        }
+    }
+
+    fn release(&self) {
+        self.open.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+struct KvStoreWriter {
+    storage: Arc<dyn Storage>,
+    epoch: Arc<AtomicUsize>,
+    min_epoch: Arc<AtomicUsize>,
+    key_index: Arc<KeyIndex<IndexEntry>>,
+    writer: BufWriter<Box<dyn StorageWriter>>,
+    /// end of the active segment, including buffered records
+    end: u64,
+    redundant: u32,
+    reader: KvStoreReader,
+    auto_compaction: bool,
+    flush_on_write: bool,
+    merge_operator: Option<MergeOperator>,
+    /// set while records are buffered, as readers only see what was flushed
+    unflushed: Arc<AtomicBool>,
+    compaction_progress: Option<ProgressCallback>,
+    /// whether a compaction is between `begin_compaction` and `finish_compaction`
+    compacting: bool,
+    /// wakes the background compactor, if compaction runs in the background
+    compaction_signal: Option<Sender<()>>,
+    /// whether compaction writes a compressed sealed segment
+    compress_sealed: bool,
+    /// segments listed in `CURRENT`
+    segments: Vec<ManifestSegment>,
+    /// segments listed in `CURRENT` before the last compaction, kept for handles still reading them
+    retired: Vec<ManifestSegment>,
+    /// size above which values are stored in blob files
+    large_value_threshold: Option<usize>,
+    /// size above which values are compressed within their record
+    value_compression_threshold: Option<usize>,
+    /// hash of the blob holding the value of each key whose value is in one
+    blob_refs: HashMap<String, String>,
+    /// hashes of the blob files in the storage
+    blobs: HashSet<String>,
+    /// last sequence number taken by a write, shared with the writer handles
+    seq: Arc<AtomicU64>,
+    /// epochs of the segments of writer handles not merged yet, listed in `CURRENT`
+    writers: Vec<usize>,
+    /// epoch of the next new segment, as the epochs of merged writer segments are not reused
+    next_epoch: usize,
+    /// whether a write replacing one with a higher sequence number fails instead of panicking
+    check_sequence: bool,
+    /// size the active segment is preallocated to, if any
+    preallocate: Option<u64>,
+    /// bytes of the records of each live segment, those of open writer handles once merged
+    stats: HashMap<usize, SegmentStat>,
+    /// whether writes and compactions fail with `KvsError::ReadOnly`
+    read_only: bool,
+    /// advisory lock on the directory of the store, released when the writer is dropped
+    _lock: Option<File>,
+    #[cfg(feature = "hdrhistogram")]
+    latency: Arc<Latency>,
+}
+
+impl KvStoreWriter {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.writable()?;
+        let hash = match self.large_value_threshold {
+            Some(threshold) if value.len() > threshold => Some(self.write_blob(&value)?),
+            _ => None,
+        };
+        let compressed = self.compress_value(hash.is_none(), &value)?;
+        let cmd = match (&hash, &compressed) {
+            (Some(hash), _) => CmdRef::SetRef(&key, hash, value.len() as u64),
+            (None, Some(compressed)) => CmdRef::SetCompressed(&key, compressed),
+            (None, None) => CmdRef::Set(&key, &value),
+        };
+        let (old, log_index) = if self.writers.is_empty() {
+            let log_index = self.append_log(&cmd)?;
+            self.set_blob_ref(&key, hash);
+            let entry = self.reader.entry(log_index, &value);
+            (self.key_index.insert(key, entry).map(|old| *old), log_index)
+        } else {
+            let key_index = self.key_index.clone();
+            let mut old = None;
+            let mut written = None;
+            write_locked(&key_index, &key, self.check_sequence, |current| {
+                old = current.as_deref().copied();
+                let log_index = self.append_log(&cmd)?;
+                written = Some(log_index);
+                Ok(Some(self.reader.entry(log_index, &value)))
+            })?;
+            self.set_blob_ref(&key, hash);
+            (old, written.expect("the record was written"))
+        };
+        self.account(old, Some(log_index));
+        if old.is_some() {
+            self.redundant += 1;
+        }
+        self.auto_compact()
+    }
+
+    /// the records are flushed once, after the last one, even if flushing on every write
+    fn set_many(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        let flush_on_write = std::mem::replace(&mut self.flush_on_write, false);
+        let result = pairs.into_iter().try_for_each(|(key, value)| self.set(key, value));
+        self.flush_on_write = flush_on_write;
+        result?;
+        if flush_on_write {
+            self.flush_buffer()?;
+        }
+        Ok(())
+    }
+
+    /// the version is compared and the record appended while the key's entry in the index
+    /// is held, so neither the store nor a writer handle can write the key in between.
+    fn set_if_version(&mut self, key: String, value: String, expected: u64) -> Result<bool> {
+        self.writable()?;
+        let key_index = self.key_index.clone();
+        let mut hash = None;
+        let mut written = None;
+        let mut old = None;
+        write_locked(&key_index, &key, self.check_sequence, |current| {
+            if current.as_ref().map_or(0, |entry| entry.version()) != expected {
+                return Ok(current);
+            }
+            hash = match self.large_value_threshold {
+                Some(threshold) if value.len() > threshold => Some(self.write_blob(&value)?),
+                _ => None,
+            };
+            let compressed = self.compress_value(hash.is_none(), &value)?;
+            let cmd = match (&hash, &compressed) {
+                (Some(hash), _) => CmdRef::SetRef(&key, hash, value.len() as u64),
+                (None, Some(compressed)) => CmdRef::SetCompressed(&key, compressed),
+                (None, None) => CmdRef::Set(&key, &value),
+            };
+            old = current.as_deref().copied();
+            let log_index = self.append_log(&cmd)?;
+            written = Some(log_index);
+            Ok(Some(self.reader.entry(log_index, &value)))
+        })?;
+        if written.is_none() {
+            return Ok(false);
+        }
+        self.set_blob_ref(&key, hash);
+        self.account(old, written);
+        if old.is_some() {
+            self.redundant += 1;
+        }
+        self.auto_compact()?;
+        Ok(true)
+    }
+
+    fn set_and_get_old(&mut self, key: String, value: String) -> Result<Option<String>> {
+        self.writable()?;
+        self.flush_buffer()?;
+        let old = self.reader.get(key.clone())?;
+        self.set(key, value)?;
+        Ok(old)
+    }
+
+    fn merge(&mut self, key: String, operand: String) -> Result<()> {
+        let operator = self.merge_operator.clone().ok_or(KvsError::NoMergeOperator)?;
+        self.flush_buffer()?;
+        let value = operator(self.reader.get(key.clone())?.as_deref(), &operand);
+        self.set(key, value)
+    }
+
+    /// the `Rm` record is only kept for replay, the key leaves the index right away
+    fn remove(&mut self, key: String) -> Result<()> {
+        self.writable()?;
+        let old = if self.writers.is_empty() {
+            if !self.key_index.contains_key(&key) {
+                return Err(KvsError::KeyNotFound);
+            }
+            self.append_log(&CmdRef::Rm(&key))?;
+            self.key_index.remove(&key).map(|old| *old)
+        } else {
+            let key_index = self.key_index.clone();
+            let mut old = None;
+            write_locked(&key_index, &key, self.check_sequence, |current| match current {
+                Some(current) => {
+                    old = Some(*current);
+                    self.append_log(&CmdRef::Rm(&key)).map(|_| None)
+                }
+                None => Err(KvsError::KeyNotFound),
+            })?;
+            old
+        };
+        self.account(old, None);
+        self.blob_refs.remove(&key);
+        // both the removed record and the `Rm` record itself
+        self.redundant += 2;
+        self.auto_compact()
+    }
+
+    /// the value keeps the form of its record, a value in a blob or compressed is not read,
+    /// and the removal of `from` is part of the same record. return `KvsError::Busy` while
+    /// writer handles are open, as their writes of either key could land in between.
+    fn rename(&mut self, from: String, to: String) -> Result<bool> {
+        self.writable()?;
+        if !self.writers.is_empty() {
+            return Err(KvsError::Busy);
+        }
+        let entry = match self.key_index.get(&from) {
+            Some(entry) => entry,
+            None => return Ok(false),
+        };
+        if from == to {
+            return Ok(true);
+        }
+        self.flush_buffer()?;
+        let log_index = match self.reader.read_stored(*entry)?.without_rename() {
+            Cmd::Set(_, value) => self.append_log(&CmdRef::Rename(&from, &CmdRef::Set(&to, &value)))?,
+            Cmd::SetRef(_, hash, len) => self.append_log(&CmdRef::Rename(&from, &CmdRef::SetRef(&to, &hash, len)))?,
+            Cmd::SetCompressed(_, value) => {
+                self.append_log(&CmdRef::Rename(&from, &CmdRef::SetCompressed(&to, &value)))?
+            }
+            _ => {
+                return Err(KvsError::Corrupted(format!(
+                    "record at epoch {} offset {} is not the value of `{}`",
+                    entry.epoch, entry.offset, from
+                )))
+            }
+        };
+        let hash = self.blob_refs.remove(&from);
+        self.set_blob_ref(&to, hash);
+        self.key_index.remove(&from);
+        let old = self.key_index.insert(to, entry.moved(log_index)).map(|old| *old);
+        self.account(Some(*entry), None);
+        self.account(old, Some(log_index));
+        // the record of `from`, and the one of `to` if it is replaced
+        self.redundant += 1 + old.is_some() as u32;
+        self.auto_compact()?;
+        Ok(true)
+    }
+
+    fn remove_range(&mut self, range: (Bound<String>, Bound<String>)) -> Result<usize> {
+        let keys: Vec<String> = self.key_index.range(&range).into_iter().map(|(key, _)| key).collect();
+
+        let removed = keys.len();
+        for key in keys {
+            self.remove(key)?;
+        }
+        Ok(removed)
+    }
+
+    /// remember the hash of the blob holding the value of `key`, `None` for a value stored inline
+    fn set_blob_ref(&mut self, key: &str, hash: Option<String>) {
+        match hash {
+            Some(hash) => self.blob_refs.insert(key.to_owned(), hash),
+            None => self.blob_refs.remove(key),
+        };
+    }
+
+    /// the value compressed and encoded for a `SetCompressed` record, if it is in the log,
+    /// over the threshold and shorter that way
+    fn compress_value(&self, in_log: bool, value: &str) -> Result<Option<String>> {
+        match self.value_compression_threshold {
+            Some(threshold) if in_log && value.len() > threshold => {
+                let compressed = BASE64.encode(zstd::encode_all(value.as_bytes(), COMPRESSION_LEVEL)?);
+                Ok(Some(compressed).filter(|compressed| compressed.len() < value.len()))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// store a value in the blob file named after its hash unless it exists, return the hash
+    ///
+    /// the blob is durable before the record referring to it is appended.
+    fn write_blob(&mut self, value: &str) -> Result<String> {
+        let hash = format!("{:x}", Sha256::digest(value.as_bytes()));
+        if !self.blobs.contains(&hash) {
+            let name = blob_name(&hash);
+            let tmp = format!("{}.tmp", name);
+            let mut file = self.storage.create(&tmp)?;
+            file.write_all(value.as_bytes())?;
+            file.sync()?;
+            drop(file);
+            self.storage.rename(&tmp, &name)?;
+            self.storage.sync_dir()?;
+            self.blobs.insert(hash.clone());
+        }
+        Ok(hash)
+    }
+
+    /// remove the blob files no live key refers to, and those left unfinished by a crash
+    ///
+    /// a handle that looked a key up before it was written again may still try to read
+    /// the blob of its old value, it then reads the key again.
+    fn collect_blobs(&mut self) {
+        let names = match self.storage.list() {
+            Ok(names) => names,
+            Err(_) => return,
+        };
+        let live: HashSet<&str> = self.blob_refs.values().map(String::as_str).collect();
+        for name in names {
+            let dead = match blob_hash(&name) {
+                Some(hash) => !live.contains(hash),
+                None => name.ends_with(".blob.tmp"),
+            };
+            if dead && self.storage.remove(&name).is_ok() {
+                if let Some(hash) = blob_hash(&name) {
+                    self.blobs.remove(hash);
+                }
+            }
+        }
+    }
+
+    /// remove the files no segment, writer segment or live key uses, see `KvStore::gc_orphans`
+    ///
+    /// must not run during a compaction, whose `temp` file would be removed.
+    fn gc_orphans(&mut self) -> Result<Vec<String>> {
+        self.writable()?;
+        let epochs: HashSet<usize> = self
+            .segments
+            .iter()
+            .chain(self.retired.iter())
+            .map(|segment| segment.epoch)
+            .chain(self.writers.iter().copied())
+            .collect();
+        let live: HashSet<&str> = self.blob_refs.values().map(String::as_str).collect();
+        let mut removed = Vec::new();
+        for name in self.storage.list()? {
+            let orphan = match (log_epoch(&name), blob_hash(&name)) {
+                (Some(epoch), _) => !epochs.contains(&epoch),
+                (_, Some(hash)) => !live.contains(hash),
+                _ => name == "temp" || name.ends_with(".tmp"),
+            };
+            if orphan {
+                self.storage.remove(&name)?;
+                removed.push(name);
+            }
+        }
+        for hash in removed.iter().filter_map(|name| blob_hash(name)) {
+            self.blobs.remove(hash);
+        }
+        if !removed.is_empty() {
+            self.storage.sync_dir()?;
+        }
+        removed.sort_unstable();
+        Ok(removed)
+    }
+
+    /// append a record with the next sequence number
+    ///
+    /// the writer is locked meanwhile, and while writer handles are open, writes of a key
+    /// also hold its entry in the index through `write_locked`, so the sequence numbers of
+    /// its records are in the order they were indexed.
+    fn append_log(&mut self, cmd: &CmdRef) -> Result<LogIndex> {
+        let seq = self.seq.fetch_add(1, Ordering::SeqCst) + 1;
+        Ok(self.append_record(&CmdRef::Seq(seq, cmd))?.with_seq(seq))
+    }
+
+    fn append_record(&mut self, cmd: &CmdRef) -> Result<LogIndex> {
+        // seeking the `BufWriter` would flush it, so the end is tracked instead
+        let record = serde_json::to_vec(cmd)?;
+        self.writer.write_all(&record)?;
+        if self.flush_on_write {
+            self.writer.flush()?;
+        } else {
+            self.unflushed.store(true, Ordering::SeqCst);
+        }
+        let offset = self.end;
+        self.end += record.len() as u64;
+
+        let epoch = self.epoch.load(Ordering::SeqCst);
+        self.stats.entry(epoch).or_default().add(record.len() as u64);
+        Ok(LogIndex::new(epoch, offset, record.len() as u64))
+    }
+
+    /// move the bytes of the record `old` to the dead ones of its segment, and those of
+    /// `new` to the live ones of its segment
+    fn account(&mut self, old: Option<LogIndex>, new: Option<LogIndex>) {
+        if let Some(old) = old {
+            if let Some(stat) = self.stats.get_mut(&old.epoch) {
+                stat.dead(old.len);
+            }
+        }
+        if let Some(new) = new {
+            self.stats.entry(new.epoch).or_default().live(new.len);
+        }
+    }
+
+    /// count the live bytes of every live segment again from the index, once records moved
+    /// or segments were replaced
+    fn count_live(&mut self) {
+        let segments: HashSet<usize> = self.segments.iter().map(|segment| segment.epoch).collect();
+        self.stats.retain(|epoch, _| segments.contains(epoch));
+        for stat in self.stats.values_mut() {
+            stat.dead(stat.live_bytes);
+        }
+        for (_, log_index) in self.key_index.entries() {
+            if let Some(stat) = self.stats.get_mut(&log_index.epoch) {
+                stat.live(log_index.len);
+            }
+        }
+    }
+
+    fn writable(&self) -> Result<()> {
+        if self.read_only {
+            Err(KvsError::ReadOnly)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.flush_buffer()?;
+        self.writer.get_mut().sync()?;
+        Ok(())
+    }
+
+    /// write the buffered records to the storage, so readers can see them
+    fn flush_buffer(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.unflushed.store(false, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// cut the zeros preallocated past the records of the active segment, before it
+    /// stops being active
+    fn trim_active(&mut self) -> Result<()> {
+        if self.preallocate.is_some() {
+            self.flush_buffer()?;
+            self.writer.get_mut().set_len(self.end)?;
+        }
+        Ok(())
+    }
+
+    fn warm_up(&self) -> Result<()> {
+        let epoch = self.epoch.load(Ordering::SeqCst);
+        let mut reader = BufReader::with_capacity(self.reader.buffer_size, self.storage.open(&log_name(epoch))?);
+        std::io::copy(&mut reader, &mut std::io::sink())?;
+        Ok(())
+    }
+
+    /// check that the handle of the active log can still seek, and is at its end
+    fn health_check(&mut self) -> Result<()> {
+        let name = log_name(self.epoch.load(Ordering::SeqCst));
+        let position = self
+            .writer
+            .get_mut()
+            .stream_position()
+            .map_err(|err| KvsError::Unhealthy(format!("cannot seek {}: {}", name, err)))?;
+        let end = position + self.writer.buffer().len() as u64;
+        if !self.read_only && end != self.end {
+            return Err(KvsError::Unhealthy(format!(
+                "{} ends at offset {}, expected {}",
+                name, end, self.end
+            )));
+        }
+        Ok(())
+    }
+
+    fn auto_compact(&mut self) -> Result<()> {
+        if !self.auto_compaction {
+            return Ok(());
+        }
+        match &self.compaction_signal {
+            Some(signal) => {
+                if !self.compacting && self.writers.is_empty() && self.redundant > COMPACTION_THRESHOLD {
+                    // a full channel means the compactor is already woken up
+                    let _ = signal.try_send(());
+                }
+            }
+            None => {
+                self.try_compact()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// trigger compaction if there are too many redundant records, return whether it ran
+    ///
+    /// compaction waits until no writer handle is open.
+    fn try_compact(&mut self) -> Result<bool> {
+        if self.writers.is_empty() && self.redundant > COMPACTION_THRESHOLD {
+            self.compact()?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    fn compact(&mut self) -> Result<()> {
+        let job = self.begin_compaction()?;
+        let result = job.rewrite().and_then(|segment| self.finish_compaction(segment));
+        self.compacting = false;
+        result
+    }
+
+    /// rewrite the live records of the keys within `range`, see `KvsEngine::compact_range`
+    fn compact_range(&mut self, range: (Bound<String>, Bound<String>)) -> Result<()> {
+        let records = self.key_index.range(&range);
+        if records.is_empty() {
+            return Ok(());
+        }
+        let job = self.compaction_job(records, true)?;
+        let result = job.rewrite().and_then(|segment| self.finish_range_compaction(segment));
+        self.compacting = false;
+        result
+    }
+
+    /// take the live records to copy into the next segment
+    ///
+    /// records appended after this point are copied by `finish_compaction`.
+    fn begin_compaction(&mut self) -> Result<CompactionJob> {
+        let records = self.key_index.entries();
+        let seal = self.compress_sealed;
+        self.compaction_job(records, seal)
+    }
+
+    /// return `KvsError::Busy` while writer handles are open, as compaction would drop the
+    /// sequence numbers their segments are ordered against.
+    fn compaction_job(&mut self, records: Vec<(String, IndexEntry)>, seal: bool) -> Result<CompactionJob> {
+        self.writable()?;
+        if !self.writers.is_empty() {
+            return Err(KvsError::Busy);
+        }
+        self.flush_buffer()?;
+        self.compacting = true;
+        let epoch = self.next_epoch;
+
+        #[cfg(feature = "tracing")]
+        let span = tracing::info_span!(
+            "compact",
+            epoch,
+            redundant = self.redundant,
+            keys = records.len(),
+            duration_us = tracing::field::Empty,
+        );
+
+        Ok(CompactionJob {
+            start: Instant::now(),
+            storage: self.storage.clone(),
+            reader: self.reader.clone(),
+            epoch,
+            compress: self.compress_sealed,
+            seal,
+            snapshot_end: self.end,
+            redundant: self.redundant,
+            records: records.into_iter().map(|(key, entry)| (key, *entry)).collect(),
+            progress: self.compaction_progress.clone(),
+            #[cfg(feature = "tracing")]
+            span,
+        })
+    }
+
+    /// copy the records appended since `begin_compaction` and switch to the new segments
+    ///
+    /// uncompressed, the records appended meanwhile follow the copied ones in the new
+    /// segment, which becomes the active one. compressed, the new segment is sealed and
+    /// they start a new active segment after it. new segments are synced before they are
+    /// renamed into place, and only become live once `CURRENT` lists them, so a crash at
+    /// any point reopens either the old segments or the complete new ones.
+    fn finish_compaction(&mut self, mut segment: CompactedSegment) -> Result<()> {
+        let job = segment.job;
+        #[cfg(feature = "tracing")]
+        let _entered = job.span.enter();
+        let old_epoch = self.epoch.load(Ordering::SeqCst);
+        self.flush_buffer()?;
+        self.trim_active()?;
+
+        let mut tail = Vec::new();
+        let mut reader = self.storage.open(&log_name(old_epoch))?;
+        reader.seek(SeekFrom::Start(job.snapshot_end))?;
+        reader.read_to_end(&mut tail)?;
+
+        let (active_epoch, mut tail_writer, tail_base, segments) = if job.compress {
+            segment.writer.flush()?;
+            segment.writer.get_mut().sync()?;
+            drop(segment.writer);
+            self.storage.rename("temp", &log_name(job.epoch))?;
+
+            let mut writer = BufWriter::with_capacity(self.reader.buffer_size, self.storage.create("temp")?);
+            let base = write_header(&mut writer, false, false)?;
+            let sealed = ManifestSegment {
+                epoch: job.epoch,
+                compressed: true,
+            };
+            let active = ManifestSegment::active(job.epoch + 1);
+            (active.epoch, writer, base, vec![sealed, active])
+        } else {
+            let active = ManifestSegment::active(job.epoch);
+            (active.epoch, segment.writer, segment.end, vec![active])
+        };
+        tail_writer.write_all(&tail)?;
+        tail_writer.flush()?;
+        tail_writer.get_mut().sync()?;
+        drop(tail_writer);
+
+        let active_name = log_name(active_epoch);
+        self.storage.rename("temp", &active_name)?;
+        self.storage.sync_dir()?;
+        let end = tail_base + tail.len() as u64;
+        let writer = open_active(&*self.storage, &active_name, end, self.preallocate)?;
+        let writer = BufWriter::with_capacity(self.reader.buffer_size, writer);
+        commit_manifest(
+            &*self.storage,
+            &segments,
+            &self.writers,
+            self.seq.load(Ordering::SeqCst),
+        )?;
+
+        // nothing below can fail, the store now matches what reopening it would find
+        self.writer = writer;
+        self.end = end;
+        self.epoch.store(active_epoch, Ordering::SeqCst);
+        self.next_epoch = active_epoch + 1;
+        let retired = std::mem::replace(&mut self.segments, segments);
+        self.retire(retired);
+        self.collect_blobs();
+
+        for (key, live) in self.key_index.entries() {
+            let moved = match segment.moved.get(&key) {
+                Some((old, new)) if *old == *live => live.moved(*new),
+                _ if live.epoch == old_epoch && live.offset >= job.snapshot_end => live.moved(LogIndex::new(
+                    active_epoch,
+                    tail_base + live.offset - job.snapshot_end,
+                    live.len,
+                )),
+                _ => continue,
+            };
+            self.key_index.insert(key, moved);
+        }
+        self.redundant -= job.redundant.min(self.redundant);
+        let mut stat = moved_stat(&segment.moved);
+        if job.compress {
+            self.stats.insert(job.epoch, stat);
+            stat = SegmentStat::default();
+        }
+        replay_records(&tail, 0, ManifestSegment::active(active_epoch), |_, log_index| {
+            stat.add(log_index.len)
+        });
+        self.stats.insert(active_epoch, stat);
+        self.count_live();
+
+        let elapsed = job.start.elapsed();
+        #[cfg(feature = "hdrhistogram")]
+        self.latency.record(Op::Compact, elapsed);
+        #[cfg(feature = "tracing")]
+        job.span.record("duration_us", elapsed.as_micros() as u64);
+        #[cfg(not(feature = "tracing"))]
+        debug!("compacted into epoch {} in {:?}", job.epoch, elapsed);
+
+        Ok(())
+    }
+}
+
+impl KvStoreWriter {
+    /// seal the active segment behind the rewritten records and a new active segment
+    ///
+    /// the writer is held since the records were taken, so none has changed. the old
+    /// segments stay live, except the oldest ones left without live records.
+    fn finish_range_compaction(&mut self, segment: CompactedSegment) -> Result<()> {
+        let CompactedSegment {
+            job, mut writer, moved, ..
+        } = segment;
+        #[cfg(feature = "tracing")]
+        let _entered = job.span.enter();
+        self.trim_active()?;
+        writer.flush()?;
+        writer.get_mut().sync()?;
+        drop(writer);
+        self.storage.rename("temp", &log_name(job.epoch))?;
+
+        let active_epoch = job.epoch + 1;
+        let mut writer = BufWriter::with_capacity(self.reader.buffer_size, self.storage.create("temp")?);
+        let end = write_header(&mut writer, false, false)?;
+        writer.flush()?;
+        writer.get_mut().sync()?;
+        drop(writer);
+        let active_name = log_name(active_epoch);
+        self.storage.rename("temp", &active_name)?;
+        self.storage.sync_dir()?;
+        let writer = open_active(&*self.storage, &active_name, end, self.preallocate)?;
+        let writer = BufWriter::with_capacity(self.reader.buffer_size, writer);
+
+        let mut live = HashMap::new();
+        for (key, log_index) in self.key_index.entries() {
+            let epoch = moved.get(&key).map_or(log_index.epoch, |(_, new)| new.epoch);
+            *live.entry(epoch).or_insert(0) += 1;
+        }
+        let mut segments = self.segments.clone();
+        segments.push(ManifestSegment {
+            epoch: job.epoch,
+            compressed: job.compress,
+        });
+        segments.push(ManifestSegment::active(active_epoch));
+        // a `Rm` only cancels records of its own segment or older ones, so segments without
+        // live records can be dropped only from the oldest on
+        let dead = segments
+            .iter()
+            .take_while(|segment| !live.contains_key(&segment.epoch))
+            .count();
+        let dropped: Vec<ManifestSegment> = segments.drain(..dead).collect();
+        commit_manifest(
+            &*self.storage,
+            &segments,
+            &self.writers,
+            self.seq.load(Ordering::SeqCst),
+        )?;
+
+        // nothing below can fail, the store now matches what reopening it would find
+        self.writer = writer;
+        self.end = end;
+        self.epoch.store(active_epoch, Ordering::SeqCst);
+        self.next_epoch = active_epoch + 1;
+        self.segments = segments;
+        // the records left behind are now as redundant as overwritten ones
+        self.redundant += moved.len() as u32;
+        self.stats.insert(job.epoch, moved_stat(&moved));
+        self.stats.insert(active_epoch, SegmentStat::default());
+        for (key, (_, new)) in moved {
+            self.key_index.alter(key, |entry| entry.map(|entry| entry.moved(new)));
+        }
+        self.count_live();
+        if !dropped.is_empty() {
+            self.retire(dropped);
+        }
+
+        let elapsed = job.start.elapsed();
+        #[cfg(feature = "hdrhistogram")]
+        self.latency.record(Op::Compact, elapsed);
+        #[cfg(feature = "tracing")]
+        job.span.record("duration_us", elapsed.as_micros() as u64);
+        #[cfg(not(feature = "tracing"))]
+        debug!("compacted a range into epoch {} in {:?}", job.epoch, elapsed);
+
+        Ok(())
+    }
+
+    /// keep `retired` segments for handles still reading them, remove the ones retired before
+    fn retire(&mut self, retired: Vec<ManifestSegment>) {
+        self.retired = retired;
+        let min_epoch = self
+            .retired
+            .iter()
+            .chain(self.segments.iter())
+            .map(|segment| segment.epoch)
+            .min()
+            .expect("the manifest lists the active segment");
+        self.min_epoch.store(min_epoch, Ordering::SeqCst);
+        if let Ok(names) = self.storage.list() {
+            for epoch in names.iter().filter_map(|name| log_epoch(name)) {
+                if epoch < min_epoch {
+                    let _ = self.storage.remove(&log_name(epoch));
+                }
+            }
+        }
+    }
+}
+
+impl KvStoreWriter {
+    /// copy what the writer segments of `epochs` hold last into the active segment, then drop them
+    ///
+    /// `written` holds the sequence number of the last write of each key to the segments.
+    /// a key still indexed in one of them has its record copied, a key no longer indexed
+    /// gets a `Rm` so its records in other segments stay removed. both keep their sequence
+    /// number, for the writer segments not merged yet. the active segment is synced before
+    /// `CURRENT` stops listing the merged segments.
+    fn merge_writers(&mut self, epochs: &[usize], written: HashMap<String, u64>, redundant: u32) -> Result<()> {
+        let key_index = self.key_index.clone();
+        for (key, seq) in written {
+            let mut merged = false;
+            write_locked(&key_index, &key, self.check_sequence, |current| match current {
+                Some(entry) if epochs.contains(&entry.epoch) => {
+                    let value = match self.reader.read_from_log(*entry)? {
+                        Cmd::Set(_, value) => value,
+                        _ => {
+                            return Err(KvsError::Corrupted(format!(
+                                "record at epoch {} offset {} is not the value of `{}`",
+                                entry.epoch, entry.offset, key
+                            )))
+                        }
+                    };
+                    merged = true;
+                    let log_index = self.append_record(&CmdRef::Seq(seq, &CmdRef::Set(&key, &value)))?;
+                    Ok(Some(entry.moved(log_index.with_seq(seq))))
+                }
+                // written again through the store or another handle
+                Some(entry) => Ok(Some(entry)),
+                None => {
+                    merged = true;
+                    self.append_record(&CmdRef::Seq(seq, &CmdRef::Rm(&key)))?;
+                    Ok(None)
+                }
+            })?;
+            if merged {
+                self.blob_refs.remove(&key);
+            }
+        }
+        self.sync()?;
+
+        let writers: Vec<usize> = self
+            .writers
+            .iter()
+            .copied()
+            .filter(|epoch| !epochs.contains(epoch))
+            .collect();
+        commit_manifest(
+            &*self.storage,
+            &self.segments,
+            &writers,
+            self.seq.load(Ordering::SeqCst),
+        )?;
+        self.writers = writers;
+        for epoch in epochs {
+            let _ = self.storage.remove(&log_name(*epoch));
+        }
+        self.redundant += redundant;
+        self.count_live();
+        Ok(())
+    }
+}
+
+impl Drop for KvStoreWriter {
+    /// flush and sync the active segment, logging errors since `drop` cannot return them
+    fn drop(&mut self) {
+        if let Err(e) = self.sync() {
+            error!("failed to flush the store on drop: {}", e);
+        }
+    }
+}
+
+/// Result of replaying a log segment with `KvStore::import_log`
+struct ImportedLog {
+    /// number of redundant records
+    redundant: u32,
+    /// where the last complete record ends
+    end: u64,
+    /// whether the segment ends with a torn record, to be cut at `end`
+    torn: bool,
+    /// every record replayed counted as dead, the live ones being counted from the index afterwards
+    stat: SegmentStat,
+}
+
+/// stat of a segment written by a compaction, counting the records moved into it as dead
+/// until `count_live`
+fn moved_stat(moved: &HashMap<String, (LogIndex, LogIndex)>) -> SegmentStat {
+    let mut stat = SegmentStat::default();
+    for (_, new) in moved.values() {
+        stat.add(new.len);
+    }
+    stat
+}
+
+/// Live records taken by `begin_compaction`, to be copied into a new segment
+struct CompactionJob {
+    start: Instant,
+    storage: Arc<dyn Storage>,
+    reader: KvStoreReader,
+    /// epoch of the new segment
+    epoch: usize,
+    /// whether the records of the new segment are compressed
+    compress: bool,
+    /// whether the new segment is sealed with a footer, rather than becoming the active one
+    seal: bool,
+    /// end of the active segment when the records were taken
+    snapshot_end: u64,
+    /// redundant records when the records were taken, all dropped by the compaction
+    redundant: u32,
+    records: Vec<(String, LogIndex)>,
+    progress: Option<ProgressCallback>,
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+}
+
+/// New segment written by `CompactionJob::rewrite`, not yet switched to
+struct CompactedSegment {
+    job: CompactionJob,
+    writer: BufWriter<Box<dyn StorageWriter>>,
+    /// previous and new location of every copied record
+    moved: HashMap<String, (LogIndex, LogIndex)>,
+    /// end of the copied records
+    end: u64,
+}
+
+impl CompactionJob {
+    /// copy the records into a temporary segment, needs no lock on the writer
+    fn rewrite(mut self) -> Result<CompactedSegment> {
+        #[cfg(feature = "tracing")]
+        let span = self.span.clone();
+        #[cfg(feature = "tracing")]
+        let _entered = span.enter();
+
+        let mut writer = BufWriter::with_capacity(self.reader.buffer_size, self.storage.create("temp")?);
+        let records = std::mem::take(&mut self.records);
+        let mut moved = HashMap::with_capacity(records.len());
+
+        let bytes_total = records.iter().map(|(_, log_index)| log_index.len).sum();
+        let report = |bytes_processed| {
+            if let Some(callback) = &self.progress {
+                callback(CompactionProgress {
+                    epoch: self.epoch,
+                    bytes_processed,
+                    bytes_total,
+                });
+            }
+        };
+        report(0);
+
+        let mut header = Vec::new();
+        let mut offset = write_header(&mut header, self.compress, self.seal)?;
+        writer.write_all(&header)?;
+        let mut checksum = Sha256::new();
+        checksum.update(&header);
+        let mut processed = 0;
+        let mut next_report = COMPACTION_PROGRESS_INTERVAL;
+        for (key, log_index) in records.into_iter() {
+            // compressed values stay compressed, and every record keeps its sequence number
+            let cmd = self
+                .reader
+                .read_stored(log_index)?
+                .without_rename()
+                .with_seq(log_index.seq);
+            let record = serde_json::to_vec(&cmd)?;
+            let new_index = if self.compress {
+                let record = zstd::encode_all(&record[..], COMPRESSION_LEVEL)?;
+                let prefix = (record.len() as u32).to_be_bytes();
+                writer.write_all(&prefix)?;
+                writer.write_all(&record)?;
+                checksum.update(prefix);
+                checksum.update(&record);
+                offset += 4;
+                LogIndex::compressed(self.epoch, offset, record.len() as u64)
+            } else {
+                writer.write_all(&record)?;
+                checksum.update(&record);
+                LogIndex::new(self.epoch, offset, record.len() as u64)
+            };
+            moved.insert(key, (log_index, new_index));
+            offset += new_index.len;
+            processed += log_index.len;
+            if processed >= next_report && processed < bytes_total {
+                report(processed);
+                next_report = processed + COMPACTION_PROGRESS_INTERVAL;
+            }
+        }
+        if self.seal {
+            let footer = SegmentFooter {
+                records: moved.len() as u64,
+                checksum: checksum.finalize().into(),
+            };
+            writer.write_all(&footer.encode())?;
+        }
+        writer.flush()?;
+        report(processed);
+
+        Ok(CompactedSegment {
+            job: self,
+            writer,
+            moved,
+            end: offset,
+        })
+    }
+}
+
+/// compact on a dedicated thread whenever a write signals the threshold was crossed
+///
+/// the thread, named `kvs-compactor`, holds the writer only while compacting, and exits
+/// once every handle of the store has been dropped.
+fn spawn_compactor(writer: Weak<Mutex<KvStoreWriter>>, compacted: Arc<Condvar>, requests: Receiver<()>) -> Result<()> {
+    thread::Builder::new().name("kvs-compactor".to_owned()).spawn(move || {
+        for () in requests.iter() {
+            let writer = match writer.upgrade() {
+                Some(writer) => writer,
+                None => break,
+            };
+            if let Err(e) = compact_in_background(&writer, &compacted) {
+                error!("background compaction failed: {}", e);
+            }
+        }
+    })?;
+    Ok(())
+}
+
+/// lock the writer to begin and finish the compaction, but not while copying records
+fn compact_in_background(writer: &Mutex<KvStoreWriter>, compacted: &Condvar) -> Result<()> {
+    let job = {
+        let mut writer = writer.lock().unwrap();
+        if writer.compacting || !writer.writers.is_empty() || writer.redundant <= COMPACTION_THRESHOLD {
+            return Ok(());
+        }
+        writer.begin_compaction()?
+    };
+    let segment = job.rewrite();
+
+    let mut writer = writer.lock().unwrap();
+    let result = segment.and_then(|segment| writer.finish_compaction(segment));
+    writer.compacting = false;
+    compacted.notify_all();
+    result
+}
+
+/// run `write` on the location of `key` while holding its entry in the index, then index the
+/// key at the location it returns, or remove it for `None`
+///
+/// writes of a key through the store and writer handles are serialized this way, so a
+/// record never replaces one with a higher sequence number. if one does, the index is left
+/// as it was and `KvsError::Consistency` returned with `check_sequence`, debug builds panic
+/// without it.
+fn write_locked(
+    key_index: &KeyIndex<IndexEntry>,
+    key: &str,
+    check_sequence: bool,
+    write: impl FnOnce(Option<IndexEntry>) -> Result<Option<IndexEntry>>,
+) -> Result<()> {
+    let mut result = Ok(());
+    key_index.alter(key.to_owned(), |current| match write(current.clone()) {
+        Ok(new) => match (&current, &new) {
+            (Some(current_index), Some(new_index)) if new_index.seq < current_index.seq => {
+                let message = format!(
+                    "record of `{}` with sequence number {} replacing one with {}",
+                    key, new_index.seq, current_index.seq
+                );
+                if check_sequence {
+                    result = Err(KvsError::Consistency(message));
+                    current
+                } else if cfg!(debug_assertions) {
+                    panic!("{}", message)
+                } else {
+                    new
+                }
+            }
+            _ => new,
+        },
+        Err(e) => {
+            result = Err(e);
+            current
+        }
+    });
+    result
+}
+
+fn log_name(epoch: usize) -> String {
+    format!("{}.log", epoch)
+}
+
+/// write the header of a new segment, return its length
+fn write_header<W: Write>(writer: &mut W, compressed: bool, sealed: bool) -> Result<u64> {
+    let header = serde_json::to_vec(&SegmentHeader {
+        format: LOG_FORMAT,
+        compressed,
+        sealed,
+    })?;
+    writer.write_all(&header)?;
+    Ok(header.len() as u64)
+}
+
+/// check the header of a segment, return it and where the records start
+fn read_header(log: &[u8]) -> Result<(SegmentHeader, u64)> {
+    let mut stream = serde_json::Deserializer::from_slice(log).into_iter::<SegmentHeader>();
+    match stream.next() {
+        Some(Ok(header)) if header.format > LOG_FORMAT => Err(KvsError::UnsupportedFormat {
+            found: header.format,
+            supported: LOG_FORMAT,
+        }),
+        Some(Ok(header)) => Ok((header, stream.byte_offset() as u64)),
+        // written before segments had a header
+        _ => Ok((SegmentHeader::default(), 0)),
+    }
+}
+
+/// split a segment into the part holding its header and records, and its footer if sealed
+///
+/// a sealed segment whose footer is missing or does not match its bytes is corrupted.
+/// segments sealed before footers existed have none, and are replayed unchecked.
+fn check_footer<'a>(log: &'a [u8], header: &SegmentHeader, epoch: usize) -> Result<(&'a [u8], Option<SegmentFooter>)> {
+    if !header.sealed {
+        return Ok((log, None));
+    }
+    match SegmentFooter::decode(log) {
+        Some((footer, end)) if Sha256::digest(&log[..end])[..] == footer.checksum[..] => {
+            Ok((&log[..end], Some(footer)))
+        }
+        Some(_) => Err(KvsError::Corrupted(format!(
+            "{} does not match the checksum in its footer",
+            log_name(epoch)
+        ))),
+        None => Err(KvsError::Corrupted(format!("sealed {} has no footer", log_name(epoch)))),
+    }
+}
+
+/// Writer of a checkpoint archive, hashing every byte written for the checksum ending it
+///
+/// The header holds the magic, the format and the number of files, then every file is its
+/// name and its bytes, each preceded by its length.
+struct ArchiveWriter<W: Write> {
+    out: W,
+    hasher: Sha256,
+}
+
+impl<W: Write> ArchiveWriter<W> {
+    fn write(&mut self, bytes: &[u8]) -> Result<()> {
+        self.hasher.update(bytes);
+        self.out.write_all(bytes)?;
+        Ok(())
+    }
+
+    /// add the first `len` bytes of `file` under `name`
+    fn add(&mut self, name: &str, len: u64, file: Box<dyn StorageReader>) -> Result<()> {
+        self.write(&(name.len() as u32).to_be_bytes())?;
+        self.write(name.as_bytes())?;
+        self.write(&len.to_be_bytes())?;
+        let mut file = file.take(len);
+        let mut buf = vec![0; 64 * 1024];
+        let mut copied = 0;
+        loop {
+            let read = file.read(&mut buf)?;
+            if read == 0 {
+                break;
+            }
+            self.write(&buf[..read])?;
+            copied += read as u64;
+        }
+        if copied != len {
+            return Err(KvsError::Corrupted(format!("{} is shorter than {} bytes", name, len)));
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<()> {
+        let checksum = self.hasher.finalize();
+        self.out.write_all(&checksum)?;
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+/// Reader of a checkpoint archive, hashing every byte read to check the checksum ending it
+struct ArchiveReader<R: Read> {
+    input: R,
+    hasher: Sha256,
+}
+
+impl<R: Read> ArchiveReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<()> {
+        self.input.read_exact(buf).map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => KvsError::Corrupted("checkpoint archive is truncated".to_owned()),
+            _ => e.into(),
+        })?;
+        self.hasher.update(&buf[..]);
+        Ok(())
+    }
+
+    fn read_u32(&mut self) -> Result<u32> {
+        let mut bytes = [0; 4];
+        self.read(&mut bytes)?;
+        Ok(u32::from_be_bytes(bytes))
+    }
+
+    fn read_u64(&mut self) -> Result<u64> {
+        let mut bytes = [0; 8];
+        self.read(&mut bytes)?;
+        Ok(u64::from_be_bytes(bytes))
+    }
+
+    /// copy the next `len` bytes of the archive into `out`
+    fn copy(&mut self, mut len: u64, out: &mut dyn Write) -> Result<()> {
+        let mut buf = vec![0; 64 * 1024];
+        while len > 0 {
+            let chunk = len.min(buf.len() as u64) as usize;
+            self.read(&mut buf[..chunk])?;
+            out.write_all(&buf[..chunk])?;
+            len -= chunk as u64;
+        }
+        Ok(())
+    }
+}
+
+/// longest file name in a checkpoint archive, longer ones meaning the archive is corrupted
+const ARCHIVE_MAX_NAME: usize = 255;
+
+/// write the files of a checkpoint archive into `storage`, the manifest last
+///
+/// only the names a store uses are unpacked, so an archive cannot write elsewhere. the
+/// files written are removed again if the archive turns out to be corrupted.
+fn unpack_archive<R: Read>(storage: &dyn Storage, input: R) -> Result<()> {
+    if storage
+        .list()?
+        .iter()
+        .any(|name| name == CURRENT || log_epoch(name).is_some())
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("a store is already in {:?}", storage),
+        )
+        .into());
+    }
+    let mut written = Vec::new();
+    let manifest = match unpack_files(storage, input, &mut written) {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            for name in written {
+                let _ = storage.remove(&name);
+            }
+            return Err(e);
+        }
+    };
+    let tmp = format!("{}.tmp", CURRENT);
+    let mut file = storage.create(&tmp)?;
+    file.write_all(&manifest)?;
+    file.sync()?;
+    drop(file);
+    storage.rename(&tmp, CURRENT)?;
+    storage.sync_dir()
+}
+
+/// write every file of a checkpoint archive but the manifest into `storage`, adding their
+/// names to `written`, and return the manifest once the archive matched its checksum
+fn unpack_files<R: Read>(storage: &dyn Storage, input: R, written: &mut Vec<String>) -> Result<Vec<u8>> {
+    let corrupted = |what: String| KvsError::Corrupted(format!("checkpoint archive {}", what));
+    let mut archive = ArchiveReader {
+        input,
+        hasher: Sha256::new(),
+    };
+    let mut magic = [0; 8];
+    archive.read(&mut magic)?;
+    if &magic != ARCHIVE_MAGIC {
+        return Err(corrupted("has no archive header".to_owned()));
+    }
+    let format = archive.read_u32()?;
+    if format > ARCHIVE_FORMAT {
+        return Err(KvsError::UnsupportedFormat {
+            found: format,
+            supported: ARCHIVE_FORMAT,
+        });
+    }
+    let mut manifest = None;
+    for _ in 0..archive.read_u32()? {
+        let len = archive.read_u32()? as usize;
+        if len > ARCHIVE_MAX_NAME {
+            return Err(corrupted(format!("has a file name of {} bytes", len)));
+        }
+        let mut name = vec![0; len];
+        archive.read(&mut name)?;
+        let name = String::from_utf8(name).map_err(|_| corrupted("has a file name that is not UTF-8".to_owned()))?;
+        if name != CURRENT && log_epoch(&name).is_none() && blob_hash(&name).is_none() {
+            return Err(corrupted(format!("holds `{}`, which is no file of a store", name)));
+        }
+        let len = archive.read_u64()?;
+        if name == CURRENT {
+            // held back until the whole archive is checked
+            let mut data = Vec::new();
+            archive.copy(len, &mut data)?;
+            manifest = Some(data);
+        } else {
+            let mut file = storage.create(&name)?;
+            written.push(name);
+            archive.copy(len, &mut file)?;
+            file.sync()?;
+        }
+    }
+    let checksum = archive.hasher.finalize();
+    let mut expected = [0; 32];
+    archive
+        .input
+        .read_exact(&mut expected)
+        .map_err(|_| corrupted("is truncated".to_owned()))?;
+    if checksum[..] != expected[..] {
+        return Err(corrupted("does not match its checksum".to_owned()));
+    }
+    manifest.ok_or_else(|| corrupted(format!("holds no `{}`", CURRENT)))
+}
+
+/// Entries of the index along with the end of the log they cover
+#[cfg(feature = "test-util")]
+struct IndexSnapshot {
+    epoch: usize,
+    offset: u64,
+    entries: Vec<(String, LogIndex)>,
+}
+
+/// write a snapshot of `entries`, covering the log up to `offset` of segment `epoch`
+///
+/// the header holds the magic, the format, the epoch and the offset. every entry is the key
+/// preceded by its length, then the fields of its `LogIndex`, see `SNAPSHOT_ENTRY_LEN`, all
+/// numbers big-endian. the snapshot ends with the SHA-256 of every byte before it.
+fn write_snapshot<W: Write>(writer: &mut W, epoch: usize, offset: u64, entries: &[(String, LogIndex)]) -> Result<()> {
+    let mut hasher = Sha256::new();
+    let mut write = |bytes: &[u8]| -> Result<()> {
+        hasher.update(bytes);
+        writer.write_all(bytes)?;
+        Ok(())
+    };
+    write(SNAPSHOT_MAGIC)?;
+    write(&SNAPSHOT_FORMAT.to_be_bytes())?;
+    write(&(epoch as u64).to_be_bytes())?;
+    write(&offset.to_be_bytes())?;
+    for (key, log_index) in entries {
+        write(&(key.len() as u32).to_be_bytes())?;
+        write(key.as_bytes())?;
+        write(&(log_index.epoch as u64).to_be_bytes())?;
+        write(&log_index.offset.to_be_bytes())?;
+        write(&log_index.len.to_be_bytes())?;
+        write(&log_index.seq.to_be_bytes())?;
+        write(&[log_index.compressed as u8])?;
+    }
+    writer.write_all(&hasher.finalize())?;
+    Ok(())
+}
+
+/// split the first `len` bytes off `bytes`, `None` if it is shorter
+#[cfg(feature = "test-util")]
+fn split_off<'a>(bytes: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if bytes.len() < len {
+        return None;
+    }
+    let (head, rest) = bytes.split_at(len);
+    *bytes = rest;
+    Some(head)
+}
+
+/// parse a snapshot written by `write_snapshot`, checking it against its checksum
+#[cfg(feature = "test-util")]
+fn read_snapshot(snapshot: &[u8]) -> Result<IndexSnapshot> {
+    let corrupted = |what: &str| KvsError::Corrupted(format!("index snapshot {}", what));
+    if snapshot.len() < SNAPSHOT_HEADER_LEN + 32 {
+        return Err(corrupted("is truncated"));
+    }
+    if &snapshot[..8] != SNAPSHOT_MAGIC {
+        return Err(corrupted("has no snapshot header"));
+    }
+    let end = snapshot.len() - 32;
+    if Sha256::digest(&snapshot[..end])[..] != snapshot[end..] {
+        return Err(corrupted("does not match its checksum"));
+    }
+    let mut format = [0; 4];
+    format.copy_from_slice(&snapshot[8..12]);
+    let format = u32::from_be_bytes(format);
+    if format > SNAPSHOT_FORMAT {
+        return Err(KvsError::UnsupportedFormat {
+            found: format,
+            supported: SNAPSHOT_FORMAT,
+        });
+    }
+    let mut epoch = [0; 8];
+    epoch.copy_from_slice(&snapshot[12..20]);
+    let mut offset = [0; 8];
+    offset.copy_from_slice(&snapshot[20..28]);
+
+    let mut entries = Vec::new();
+    // the entries end where the checksum starts, no field is read past them
+    let mut body = &snapshot[SNAPSHOT_HEADER_LEN..end];
+    let be_u64 = |bytes: &[u8]| {
+        let mut buf = [0; 8];
+        buf.copy_from_slice(bytes);
+        u64::from_be_bytes(buf)
+    };
+    while !body.is_empty() {
+        let truncated = || corrupted("has a truncated entry");
+        let mut len = [0; 4];
+        len.copy_from_slice(split_off(&mut body, 4).ok_or_else(truncated)?);
+        let key = split_off(&mut body, u32::from_be_bytes(len) as usize).ok_or_else(truncated)?;
+        let key = String::from_utf8(key.to_vec()).map_err(|_| corrupted("has a key that is not UTF-8"))?;
+        let fields = split_off(&mut body, SNAPSHOT_ENTRY_LEN).ok_or_else(truncated)?;
+        let log_index = LogIndex {
+            epoch: be_u64(&fields[..8]) as usize,
+            offset: be_u64(&fields[8..16]),
+            len: be_u64(&fields[16..24]),
+            seq: be_u64(&fields[24..32]),
+            compressed: match fields[32] {
+                0 => false,
+                1 => true,
+                _ => return Err(corrupted("has an invalid compression flag")),
+            },
+        };
+        entries.push((key, log_index));
+    }
+    Ok(IndexSnapshot {
+        epoch: u64::from_be_bytes(epoch) as usize,
+        offset: u64::from_be_bytes(offset),
+        entries,
+    })
+}
+
+/// parse the records of a segment from `start`, passing each to `apply`
+///
+/// return where the last complete record ends, with the error met after it if any.
+fn replay_records(
+    log: &[u8],
+    start: u64,
+    segment: ManifestSegment,
+    mut apply: impl FnMut(Cmd, LogIndex),
+) -> (u64, Option<KvsError>) {
+    let mut pos = start;
+    if segment.compressed {
+        let truncated = || KvsError::Corrupted(format!("truncated record in {}", log_name(segment.epoch)));
+        while (pos as usize) < log.len() {
+            let record_start = pos as usize + 4;
+            let prefix = match log.get(pos as usize..record_start) {
+                Some(prefix) => prefix,
+                None => return (pos, Some(truncated())),
+            };
+            let len = u32::from_be_bytes([prefix[0], prefix[1], prefix[2], prefix[3]]) as usize;
+            let record = match log.get(record_start..record_start + len) {
+                Some(record) => record,
+                None => return (pos, Some(truncated())),
+            };
+            let cmd = zstd::decode_all(record)
+                .map_err(KvsError::from)
+                .and_then(|record| serde_json::from_slice(&record).map_err(KvsError::from));
+            match cmd {
+                Ok(cmd) => apply(
+                    cmd,
+                    LogIndex::compressed(segment.epoch, record_start as u64, len as u64),
+                ),
+                Err(e) => return (pos, Some(e)),
+            }
+            pos = (record_start + len) as u64;
+        }
+    } else {
+        let mut stream = serde_json::Deserializer::from_slice(&log[start as usize..]).into_iter::<Cmd>();
+        while let Some(cmd) = stream.next() {
+            match cmd {
+                Ok(cmd) => {
+                    let new_pos = start + stream.byte_offset() as u64;
+                    apply(cmd, LogIndex::new(segment.epoch, pos, new_pos - pos));
+                    pos = new_pos;
+                }
+                Err(e) => return (pos, Some(e.into())),
+            }
+        }
+    }
+    (pos, None)
+}
+
+/// make `segments` and `writers` the live segments, atomically replacing `CURRENT`
+fn commit_manifest(storage: &dyn Storage, segments: &[ManifestSegment], writers: &[usize], seq: u64) -> Result<()> {
+    let tmp = format!("{}.tmp", CURRENT);
+    let mut file = storage.create(&tmp)?;
+    serde_json::to_writer(
+        &mut file,
+        &Manifest {
+            segments: segments.to_vec(),
+            writers: writers.to_vec(),
+            seq,
+        },
+    )?;
+    file.sync()?;
+    drop(file);
+    storage.rename(&tmp, CURRENT)?;
+    storage.sync_dir()
+}
+
+/// find the live segments, `None` for a new store
+///
+/// segments newer than the active one in `CURRENT`, other than writer segments, were left
+/// by a compaction or a writer handle that did not commit and are removed unless
+/// `read_only`. `CURRENT` holding a bare epoch was written before segments could be
+/// compressed, and stores written before `CURRENT` existed use their newest segment.
+fn recover_manifest(storage: &dyn Storage, read_only: bool) -> Result<Option<Manifest>> {
+    let names = storage.list()?;
+    let epochs: Vec<usize> = names.iter().filter_map(|name| log_epoch(name)).collect();
+    if !names.iter().any(|name| name == CURRENT) {
+        return Ok(epochs.into_iter().max().map(|epoch| Manifest {
+            segments: vec![ManifestSegment::active(epoch)],
+            writers: Vec::new(),
+            seq: 0,
+        }));
+    }
+
+    let mut current = String::new();
+    storage.open(CURRENT)?.read_to_string(&mut current)?;
+    let manifest = match usize::from_str(current.trim()) {
+        Ok(epoch) => Manifest {
+            segments: vec![ManifestSegment::active(epoch)],
+            writers: Vec::new(),
+            seq: 0,
+        },
+        Err(_) => match serde_json::from_str::<Manifest>(&current) {
+            Ok(manifest) if !manifest.segments.is_empty() => manifest,
+            _ => {
+                return Err(KvsError::Corrupted(format!(
+                    "`{}` holds `{}`, not a list of segments",
+                    CURRENT, current
+                )))
+            }
+        },
+    };
+    let listed = || {
+        let segments = manifest.segments.iter().map(|segment| segment.epoch);
+        segments.chain(manifest.writers.iter().copied())
+    };
+    if let Some(missing) = listed().find(|epoch| !epochs.contains(epoch)) {
+        return Err(KvsError::Corrupted(format!(
+            "`{}` points to missing segment {}",
+            CURRENT,
+            log_name(missing)
+        )));
+    }
+    let active = manifest.segments.last().expect("checked not empty").epoch;
+    for epoch in epochs.into_iter().filter(|epoch| *epoch > active && !read_only) {
+        if !manifest.writers.contains(&epoch) {
+            storage.remove(&log_name(epoch))?;
+        }
+    }
+    Ok(Some(manifest))
+}
+
+/// open the active segment `name` for writing at `end`, where its records end
+///
+/// the file is extended with zeros to `preallocate` bytes, or cut to `end` without it, so
+/// zeros left by an earlier preallocation go away once it is disabled.
+fn open_active(
+    storage: &dyn Storage,
+    name: &str,
+    end: u64,
+    preallocate: Option<u64>,
+) -> Result<Box<dyn StorageWriter>> {
+    let mut writer = storage.append(name)?;
+    let len = writer.seek(SeekFrom::End(0))?;
+    let target = preallocate.map_or(end, |size| size.max(end));
+    if len != target {
+        writer.set_len(target)?;
+        writer.sync()?;
+    }
+    writer.seek(SeekFrom::Start(end))?;
+    Ok(writer)
+}
+
+/// drop the zeros ending the records of an unsealed segment, left by preallocation
+///
+/// a record never ends with a zero byte, and the header before `start` is kept.
+fn strip_zeros(records: &[u8], start: u64) -> &[u8] {
+    let end = records.iter().rposition(|byte| *byte != 0).map_or(0, |last| last + 1);
+    &records[..end.max(start as usize)]
+}
+
+/// cut the file `name` to its first `len` bytes, atomically replacing it
+fn truncate(storage: &dyn Storage, name: &str, len: u64) -> Result<()> {
+    let mut writer = storage.create("temp")?;
+    io::copy(&mut storage.open(name)?.take(len), &mut writer)?;
+    writer.sync()?;
+    drop(writer);
+    storage.rename("temp", name)?;
+    storage.sync_dir()
+}
+
+/// replace every segment with a single one holding the records live after replaying them all
+///
+/// compaction only removes segments older than the ones it replaced, so a key removed
+/// in a remaining segment had its earlier records in that segment or a later one. a
+/// compressed newest segment was sealed by a compaction that did not commit, and is
+/// skipped since the segments it was rewritten from are still there. a sealed segment
+/// not matching its footer is replayed up to its first unreadable record. records with a
+/// sequence number are skipped if the key was written with a higher one before, as the
+/// segments of writer handles are not in sequence order, and keep it in the new segment.
+fn rebuild(storage: &dyn Storage) -> Result<()> {
+    let names = storage.list()?;
+    let mut epochs: Vec<usize> = names.iter().filter_map(|name| log_epoch(name)).collect();
+    epochs.sort_unstable();
+
+    let mut live = HashMap::new();
+    let mut seqs = HashMap::new();
+    let mut last_seq = 0;
+    for (i, &epoch) in epochs.iter().enumerate() {
+        let mut log = Vec::new();
+        storage.open(&log_name(epoch))?.read_to_end(&mut log)?;
+        let (header, start) = read_header(&log)?;
+        if header.compressed && i + 1 == epochs.len() {
+            warn!("skipping {} sealed by an unfinished compaction", log_name(epoch));
+            continue;
+        }
+        let segment = ManifestSegment {
+            epoch,
+            compressed: header.compressed,
+        };
+        let records = match check_footer(&log, &header, epoch) {
+            Ok((records, _)) if header.sealed => records,
+            Ok((records, _)) => strip_zeros(records, start),
+            Err(e) => {
+                warn!("replaying {} without its footer: {}", log_name(epoch), e);
+                &log[..]
+            }
+        };
+        let (end, error) = replay_records(records, start, segment, |cmd, log_index| {
+            if !in_sequence(&mut seqs, &cmd) {
+                return;
+            }
+            let (seq, cmd) = cmd.unseq();
+            last_seq = last_seq.max(seq);
+            let log_index = log_index.with_seq(seq);
+            match cmd {
+                Cmd::Set(key, _) | Cmd::SetRef(key, ..) | Cmd::SetCompressed(key, _) => {
+                    live.insert(key, log_index);
+                }
+                Cmd::Rm(key) => {
+                    live.remove(&key);
+                }
+                Cmd::Rename(from, cmd) => {
+                    live.remove(&from);
+                    live.insert(cmd.key().to_owned(), log_index);
+                }
+                Cmd::Seq(..) => unreachable!("unseq removes every sequence number"),
+            }
+        });
+        if let Some(e) = error {
+            warn!(
+                "discarding the last {} bytes of {}: {}",
+                log.len() as u64 - end,
+                log_name(epoch),
+                e
+            );
+        }
+    }
+
+    let epoch = epochs.last().map_or(0, |epoch| epoch + 1);
+    let mut writer = BufWriter::new(storage.create("temp")?);
+    write_header(&mut writer, false, false)?;
+    let mut readers = HashMap::new();
+    for log_index in live.values() {
+        let reader = match readers.entry(log_index.epoch) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(BufReader::new(storage.open(&log_name(log_index.epoch))?)),
+        };
+        let cmd = read_record(reader, *log_index)?
+            .without_rename()
+            .with_seq(log_index.seq);
+        writer.write_all(&serde_json::to_vec(&cmd)?)?;
+    }
+    writer.flush()?;
+    writer.get_mut().sync()?;
+    drop(writer);
+    drop(readers);
+
+    storage.rename("temp", &log_name(epoch))?;
+    storage.sync_dir()?;
+    commit_manifest(storage, &[ManifestSegment::active(epoch)], &[], last_seq)?;
+    for old in epochs {
+        let _ = storage.remove(&log_name(old));
+    }
+    info!(
+        "rebuilt {:?} into {} with {} keys",
+        storage,
+        log_name(epoch),
+        live.len()
+    );
+    Ok(())
+}
+
+/// Writer of the active segment of a store opened read-only, which never writes to it
+struct ReadOnlyWriter;
+
+impl Write for ReadOnlyWriter {
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+        Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "store is opened read-only",
+        ))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for ReadOnlyWriter {
+    fn seek(&mut self, _pos: SeekFrom) -> io::Result<u64> {
+        Ok(0)
+    }
+}
+
+impl StorageWriter for ReadOnlyWriter {
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// State of the replay of the writer segments left open
+#[derive(Default)]
+struct WriterReplay {
+    /// sequence number of the last write of each key to the segments
+    written: HashMap<String, u64>,
+    /// epoch, sequence number and preceding sequence number of the first record out of order
+    /// within a segment, whose records are numbered in increasing order
+    disorder: Option<(usize, u64, u64)>,
+}
+
+/// whether `cmd` is newer than the last replayed write of its key, tracked in `seqs`
+///
+/// records without a sequence number were logged by an older version while no writer
+/// handle was open, so they are newer than every record before them. the others are in sequence order within
+/// a segment, but a segment can be followed by another with older records of the same key.
+fn in_sequence(seqs: &mut HashMap<String, u64>, cmd: &Cmd) -> bool {
+    match cmd {
+        Cmd::Seq(seq, cmd) => match seqs.get_mut(cmd.key()) {
+            Some(latest) if *latest > *seq => false,
+            Some(latest) => {
+                *latest = *seq;
+                true
+            }
+            None => {
+                seqs.insert(cmd.key().to_owned(), *seq);
+                true
+            }
+        },
+        Cmd::Rename(from, cmd) => {
+            seqs.remove(from);
+            seqs.remove(cmd.key());
+            true
+        }
+        cmd => {
+            seqs.remove(cmd.key());
+            true
+        }
+    }
+}
+
+fn is_zero(n: &u64) -> bool {
+    *n == 0
+}
+
+/// the value of a `SetCompressed` record
+fn decompress_value(compressed: &str) -> Result<String> {
+    let corrupted = |e: &dyn std::fmt::Display| KvsError::Corrupted(format!("compressed value: {}", e));
+    let compressed = BASE64.decode(compressed).map_err(|e| corrupted(&e))?;
+    let value = zstd::decode_all(&compressed[..]).map_err(|e| corrupted(&e))?;
+    String::from_utf8(value).map_err(|e| corrupted(&e))
+}
+
+fn blob_name(hash: &str) -> String {
+    format!("{}.blob", hash)
+}
+
+fn blob_hash(name: &str) -> Option<&str> {
+    name.strip_suffix(".blob")
+}
+
+fn log_epoch(name: &str) -> Option<usize> {
+    usize::from_str(name.strip_suffix(".log")?).ok()
+}
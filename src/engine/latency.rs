@@ -0,0 +1,92 @@
+#[cfg(feature = "hdrhistogram")]
+use std::sync::Mutex;
+#[cfg(feature = "hdrhistogram")]
+use std::time::Duration;
+
+#[cfg(feature = "hdrhistogram")]
+use hdrhistogram::Histogram;
+
+/// Operation of a `KvStore` whose latency is recorded
+#[derive(Copy, Clone)]
+#[cfg_attr(not(feature = "hdrhistogram"), allow(dead_code))]
+pub(crate) enum Op {
+    Get,
+    Set,
+    Remove,
+    Compact,
+}
+
+/// Latency histograms of every operation of a store, shared by all of its handles
+#[cfg(feature = "hdrhistogram")]
+pub(crate) struct Latency {
+    histograms: [Mutex<Histogram<u64>>; 4],
+}
+
+#[cfg(feature = "hdrhistogram")]
+impl Latency {
+    pub(crate) fn new() -> Self {
+        let histogram = || Mutex::new(Histogram::new(3).expect("3 significant figures are supported"));
+        Self {
+            histograms: [histogram(), histogram(), histogram(), histogram()],
+        }
+    }
+
+    pub(crate) fn record(&self, op: Op, elapsed: Duration) {
+        let micros = elapsed.as_micros().min(u64::MAX as u128) as u64;
+        self.histograms[op as usize].lock().unwrap().saturating_record(micros);
+    }
+
+    pub(crate) fn report(&self) -> LatencyReport {
+        let summary = |op: Op| OpLatency::from(&*self.histograms[op as usize].lock().unwrap());
+        LatencyReport {
+            get: summary(Op::Get),
+            set: summary(Op::Set),
+            remove: summary(Op::Remove),
+            compact: summary(Op::Compact),
+        }
+    }
+}
+
+/// Latencies recorded by a `KvStore` since it was opened
+#[cfg(feature = "hdrhistogram")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LatencyReport {
+    /// latency of `get`
+    pub get: OpLatency,
+    /// latency of `set`, including any compaction it ran
+    pub set: OpLatency,
+    /// latency of `remove`, including any compaction it ran
+    pub remove: OpLatency,
+    /// latency of compactions, however they were started
+    pub compact: OpLatency,
+}
+
+/// Percentiles of the latency of one operation, at microsecond precision
+#[cfg(feature = "hdrhistogram")]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct OpLatency {
+    /// number of operations recorded
+    pub count: u64,
+    /// median latency
+    pub p50: Duration,
+    /// 90th percentile latency
+    pub p90: Duration,
+    /// 99th percentile latency
+    pub p99: Duration,
+    /// highest latency
+    pub max: Duration,
+}
+
+#[cfg(feature = "hdrhistogram")]
+impl From<&Histogram<u64>> for OpLatency {
+    fn from(histogram: &Histogram<u64>) -> Self {
+        let percentile = |q: f64| Duration::from_micros(histogram.value_at_quantile(q));
+        Self {
+            count: histogram.len(),
+            p50: percentile(0.5),
+            p90: percentile(0.9),
+            p99: percentile(0.99),
+            max: Duration::from_micros(histogram.max()),
+        }
+    }
+}
@@ -1,13 +1,33 @@
+#[cfg(feature = "tokio")]
+mod async_store;
+mod caching;
+mod index;
 pub mod kv_store;
+mod latency;
+mod namespace;
+mod options;
+mod sharded;
 pub mod sled_engine;
+mod storage;
 
-pub use kv_store::KvStore;
+#[cfg(feature = "tokio")]
+pub use async_store::AsyncKvStore;
+pub use caching::CachingEngine;
+pub use kv_store::{KvStore, SegmentInfo, SegmentStat, WriterHandle};
+#[cfg(feature = "hdrhistogram")]
+pub use latency::{LatencyReport, OpLatency};
+pub use namespace::NamespacedStore;
+pub use options::{
+    CompactionProgress, Durability, IndexType, KeyValidator, KvStoreOptions, MergeOperator, SledOptions,
+};
+pub use sharded::{ShardHash, ShardedKvStore};
 pub use sled_engine::SledKvsEngine;
+pub use storage::{FsStorage, MemoryStorage, Storage, StorageReader, StorageWriter};
 
 use std::fmt::{Display, Formatter};
-use std::fs::File;
-use std::io::{Read, Write};
-use std::path::PathBuf;
+use std::io::{BufRead, Read, Write};
+use std::ops::{Bound, RangeBounds};
+use std::sync::Arc;
 
 use crate::{KvsError, Result};
 use std::str::FromStr;
@@ -16,7 +36,7 @@ const ENGINE_TYPE_SLED: &str = "sled";
 const ENGINE_TYPE_KVSTORE: &str = "kvs";
 
 /// Trait for key-value store
-pub trait KvsEngine: Clone + Send + 'static {
+pub trait KvsEngine: Clone + Send + Sync + 'static {
     /// get the value from the store for a given key.
     ///
     /// return `Ok(None)` if the key does not exist.
@@ -25,8 +45,282 @@ pub trait KvsEngine: Clone + Send + 'static {
     ///
     /// if the key already exists, the value will be updated.
     fn set(&self, key: String, value: String) -> Result<()>;
+    /// set every key-value pair in order, as a later pair of the same key overwrites an earlier one.
+    ///
+    /// sets them one by one by default, engines override it to write them together and
+    /// flush once, which is much faster for a bulk load.
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        for (key, value) in pairs {
+            self.set(key, value)?;
+        }
+        Ok(())
+    }
     /// remove the key from the store.
     fn remove(&self, key: String) -> Result<()>;
+    /// get the value of a key along with its version, for a later `set_if_version`.
+    ///
+    /// the version changes on every write of the key and only then, and never returns to
+    /// an earlier one. an absent key has version 0, which no value has.
+    ///
+    /// fails with `KvsError::Unsupported` by default, engines tracking versions override it
+    /// along with `set_if_version`.
+    fn get_versioned(&self, key: String) -> Result<Option<(String, u64)>> {
+        let _ = key;
+        Err(KvsError::Unsupported("get_versioned".to_owned()))
+    }
+    /// set a key-value pair only if the version of the key is still `expected`, atomically.
+    ///
+    /// return whether the value was set. `expected` is 0 to only set an absent key. fails
+    /// with `KvsError::Unsupported` by default.
+    fn set_if_version(&self, key: String, value: String, expected: u64) -> Result<bool> {
+        let _ = (key, value, expected);
+        Err(KvsError::Unsupported("set_if_version".to_owned()))
+    }
+    /// set a key-value pair and return the value it replaced, `None` if the key did not
+    /// exist, atomically.
+    ///
+    /// retries `get_versioned` and `set_if_version` until the value was set by default,
+    /// so it fails the same way for engines without versions. engines override it to swap
+    /// the value in a single step.
+    fn set_and_get_old(&self, key: String, value: String) -> Result<Option<String>> {
+        loop {
+            let old = self.get_versioned(key.clone())?;
+            let expected = old.as_ref().map_or(0, |(_, version)| *version);
+            if self.set_if_version(key.clone(), value.clone(), expected)? {
+                return Ok(old.map(|(old, _)| old));
+            }
+        }
+    }
+    /// fold `operand` into the value of the key with the merge operator set at `open`,
+    /// in a single write instead of a `get` followed by a `set`.
+    ///
+    /// the operator receives the current value, `None` if the key does not exist, and
+    /// its result is stored as the new value. fails with `KvsError::NoMergeOperator`
+    /// if the engine was opened without a merge operator, which is the default.
+    fn merge(&self, key: String, operand: String) -> Result<()> {
+        let _ = (key, operand);
+        Err(KvsError::NoMergeOperator)
+    }
+    /// move the value of `from` to `to`, replacing the value of `to` if any, and remove `from`.
+    ///
+    /// return whether `from` existed, nothing is written if it did not. sets `to` and then
+    /// removes `from` by default, which is not atomic: a failure in between leaves both keys
+    /// with the value. engines override it to rename atomically.
+    fn rename(&self, from: String, to: String) -> Result<bool> {
+        let value = match self.get(from.clone())? {
+            Some(value) => value,
+            None => return Ok(false),
+        };
+        if from != to {
+            self.set(to, value)?;
+            match self.remove(from) {
+                // removed meanwhile
+                Ok(()) | Err(KvsError::KeyNotFound) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(true)
+    }
+    /// remove every key within the given range from the store.
+    ///
+    /// return the number of keys removed. keys are removed one by one, so the
+    /// operation is not atomic: a failure part way through leaves the keys
+    /// removed so far deleted.
+    ///
+    /// lists every key and removes those within the range by default, engines override it
+    /// to visit only the range.
+    fn remove_range(&self, start: Bound<String>, end: Bound<String>) -> Result<usize> {
+        let range = (start, end);
+        let mut removed = 0;
+        for key in self.keys()?.into_iter().filter(|key| range.contains(key)) {
+            match self.remove(key) {
+                Ok(()) => removed += 1,
+                // removed meanwhile
+                Err(KvsError::KeyNotFound) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(removed)
+    }
+    /// list every key in the store, in ascending byte-wise order.
+    fn keys(&self) -> Result<Vec<String>>;
+    /// count the keys starting with `prefix`, removed keys excluded.
+    ///
+    /// lists every key by default, engines override it to count without collecting them.
+    fn count_prefix(&self, prefix: String) -> Result<usize> {
+        Ok(self.keys()?.iter().filter(|key| key.starts_with(&prefix)).count())
+    }
+    /// prepare the store to serve its first requests quickly, e.g. by reading data
+    /// into the OS page cache.
+    ///
+    /// does nothing by default.
+    fn warm_up(&self) -> Result<()> {
+        Ok(())
+    }
+    /// check cheaply that the store can serve reads and writes, without doing any, e.g. to
+    /// report readiness.
+    ///
+    /// fails with `KvsError::Unhealthy` telling what is wrong. does nothing by default.
+    fn health_check(&self) -> Result<()> {
+        Ok(())
+    }
+    /// make every write so far durable.
+    ///
+    /// does nothing by default.
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+    /// reclaim the space of overwritten and removed values.
+    ///
+    /// does nothing by default.
+    fn compact(&self) -> Result<()> {
+        Ok(())
+    }
+    /// reclaim the space of overwritten and removed values of the keys within the given range.
+    ///
+    /// compacts the whole store by default, which does nothing for engines without compaction.
+    fn compact_range(&self, start: Bound<String>, end: Bound<String>) -> Result<()> {
+        let _ = (start, end);
+        self.compact()
+    }
+}
+
+/// An engine shared behind an `Arc`, every method delegating to it
+///
+/// `&E` cannot implement the trait, which needs engines to be `'static`.
+impl<E: KvsEngine> KvsEngine for Arc<E> {
+    fn get(&self, key: String) -> Result<Option<String>> {
+        (**self).get(key)
+    }
+
+    fn set(&self, key: String, value: String) -> Result<()> {
+        (**self).set(key, value)
+    }
+
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        (**self).set_many(pairs)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        (**self).remove(key)
+    }
+
+    fn get_versioned(&self, key: String) -> Result<Option<(String, u64)>> {
+        (**self).get_versioned(key)
+    }
+
+    fn set_if_version(&self, key: String, value: String, expected: u64) -> Result<bool> {
+        (**self).set_if_version(key, value, expected)
+    }
+
+    fn set_and_get_old(&self, key: String, value: String) -> Result<Option<String>> {
+        (**self).set_and_get_old(key, value)
+    }
+
+    fn merge(&self, key: String, operand: String) -> Result<()> {
+        (**self).merge(key, operand)
+    }
+
+    fn rename(&self, from: String, to: String) -> Result<bool> {
+        (**self).rename(from, to)
+    }
+
+    fn remove_range(&self, start: Bound<String>, end: Bound<String>) -> Result<usize> {
+        (**self).remove_range(start, end)
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        (**self).keys()
+    }
+
+    fn count_prefix(&self, prefix: String) -> Result<usize> {
+        (**self).count_prefix(prefix)
+    }
+
+    fn warm_up(&self) -> Result<()> {
+        (**self).warm_up()
+    }
+
+    fn health_check(&self) -> Result<()> {
+        (**self).health_check()
+    }
+
+    fn flush(&self) -> Result<()> {
+        (**self).flush()
+    }
+
+    fn compact(&self) -> Result<()> {
+        (**self).compact()
+    }
+
+    fn compact_range(&self, start: Bound<String>, end: Bound<String>) -> Result<()> {
+        (**self).compact_range(start, end)
+    }
+}
+
+/// check whether two engines, possibly of different types, hold the same key-value pairs
+///
+/// Examples:
+/// ```rust
+/// use kvs::{engines_equal, KvStore, MemoryStorage, KvStoreOptions, SledKvsEngine};
+///
+/// let dir = tempfile::TempDir::new().unwrap();
+/// let store = KvStore::open_with_storage(MemoryStorage::new(), KvStoreOptions::new()).unwrap();
+/// let sled = SledKvsEngine::open(dir.path()).unwrap();
+/// store.set("abc", "def").unwrap();
+/// sled.set("abc", "def").unwrap();
+/// assert!(engines_equal(&store, &sled).unwrap());
+/// ```
+pub fn engines_equal(a: &impl KvsEngine, b: &impl KvsEngine) -> Result<bool> {
+    let mut keys = a.keys()?;
+    let mut other_keys = b.keys()?;
+    keys.sort_unstable();
+    other_keys.sort_unstable();
+    if keys != other_keys {
+        return Ok(false);
+    }
+    for key in keys {
+        if a.get(key.clone())? != b.get(key)? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// write every key-value pair of an engine to `writer`, one JSON `[key, value]` array per line
+///
+/// keys are written in order. return the number of pairs written.
+pub fn export<E: KvsEngine, W: Write>(engine: &E, mut writer: W) -> Result<usize> {
+    let mut keys = engine.keys()?;
+    keys.sort_unstable();
+    let mut exported = 0;
+    for key in keys {
+        // the key may have been removed since it was listed
+        if let Some(value) = engine.get(key.clone())? {
+            serde_json::to_writer(&mut writer, &(key, value))?;
+            writer.write_all(b"\n")?;
+            exported += 1;
+        }
+    }
+    writer.flush()?;
+    Ok(exported)
+}
+
+/// set every key-value pair written by [`export`](fn.export.html) into an engine
+///
+/// return the number of pairs imported.
+pub fn import<E: KvsEngine, R: BufRead>(engine: &E, reader: R) -> Result<usize> {
+    let mut imported = 0;
+    for line in reader.lines() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        let (key, value): (String, String) = serde_json::from_str(&line)?;
+        engine.set(key, value)?;
+        imported += 1;
+    }
+    Ok(imported)
 }
 
 /// Engine Type: sled or kv_store
@@ -44,7 +338,7 @@ impl FromStr for EngineType {
         match s {
             ENGINE_TYPE_KVSTORE => Ok(EngineType::KvStore),
             ENGINE_TYPE_SLED => Ok(EngineType::Sled),
-            _ => Err(KvsError::WrongEngine),
+            _ => Err(KvsError::UnknownEngine(s.to_owned())),
         }
     }
 }
@@ -58,21 +352,36 @@ impl Display for EngineType {
     }
 }
 
-fn try_add_engine_type(log_dir: &PathBuf, engine_type: EngineType) -> Result<()> {
-    let engine_file = log_dir.join(".engine");
-    if engine_file.exists() {
-        let mut file = File::open(engine_file)?;
-        let mut engine_str = String::new();
-        file.read_to_string(&mut engine_str)?;
-        let actual_type = EngineType::from_str(&engine_str)?;
-        if actual_type == engine_type {
-            Ok(())
-        } else {
-            Err(KvsError::WrongEngine)
-        }
+const ENGINE_FILE: &str = ".engine";
+
+/// fail with `KvsError::WrongEngine` if the storage belongs to another engine, return whether
+/// it records an engine at all
+fn check_engine_type(storage: &dyn Storage, engine_type: EngineType) -> Result<bool> {
+    if !storage.list()?.iter().any(|name| name == ENGINE_FILE) {
+        return Ok(false);
+    }
+    let mut file = storage.open(ENGINE_FILE)?;
+    let mut engine_str = String::new();
+    file.read_to_string(&mut engine_str)?;
+    let actual_type = EngineType::from_str(&engine_str)?;
+    if actual_type == engine_type {
+        Ok(true)
     } else {
-        let mut file = File::create(engine_file)?;
-        file.write_all(engine_type.to_string().as_bytes())?;
+        Err(KvsError::WrongEngine)
+    }
+}
+
+fn try_add_engine_type(storage: &dyn Storage, engine_type: EngineType) -> Result<()> {
+    if check_engine_type(storage, engine_type)? {
         Ok(())
+    } else {
+        // written aside and renamed, so a crash never leaves a partial name behind
+        let tmp = format!("{}.tmp", ENGINE_FILE);
+        let mut file = storage.create(&tmp)?;
+        file.write_all(engine_type.to_string().as_bytes())?;
+        file.sync()?;
+        drop(file);
+        storage.rename(&tmp, ENGINE_FILE)?;
+        storage.sync_dir()
     }
 }
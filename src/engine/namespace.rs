@@ -0,0 +1,153 @@
+use std::ops::Bound;
+
+use crate::{KvStore, KvsEngine, Result};
+
+/// separates the namespace from the key within a stored key
+const SEPARATOR: char = ':';
+/// escapes the separator and itself within a namespace
+const ESCAPE: char = '\\';
+
+/// A view of a `KvStore` holding only the keys of one namespace
+///
+/// Every key is stored as the namespace, `:` and the key. The separator is escaped
+/// within the namespace, so keys containing `:` never collide across namespaces.
+/// `keys` only lists the keys of the namespace, without the prefix.
+///
+/// Examples:
+/// ```rust
+/// use kvs::{KvStore, KvStoreOptions, KvsEngine, MemoryStorage};
+///
+/// let store = KvStore::open_with_storage(MemoryStorage::new(), KvStoreOptions::new()).unwrap();
+/// let users = store.namespace("users".to_owned());
+/// users.set("abc".to_owned(), "def".to_owned()).unwrap();
+/// assert_eq!(store.get("users:abc").unwrap(), Some("def".to_owned()));
+/// assert_eq!(store.namespace("orders".to_owned()).get("abc".to_owned()).unwrap(), None);
+/// ```
+#[derive(Clone)]
+pub struct NamespacedStore {
+    store: KvStore,
+    /// escaped namespace followed by the separator
+    prefix: String,
+}
+
+impl NamespacedStore {
+    pub(crate) fn new(store: KvStore, namespace: &str) -> Self {
+        let mut prefix = String::with_capacity(namespace.len() + 1);
+        for c in namespace.chars() {
+            if c == SEPARATOR || c == ESCAPE {
+                prefix.push(ESCAPE);
+            }
+            prefix.push(c);
+        }
+        prefix.push(SEPARATOR);
+        Self { store, prefix }
+    }
+
+    /// the store holding every namespace
+    pub fn store(&self) -> &KvStore {
+        &self.store
+    }
+
+    fn key(&self, key: String) -> String {
+        let mut prefixed = String::with_capacity(self.prefix.len() + key.len());
+        prefixed.push_str(&self.prefix);
+        prefixed.push_str(&key);
+        prefixed
+    }
+
+    /// the range of stored keys covering the given range of keys of the namespace
+    fn range(&self, start: Bound<String>, end: Bound<String>) -> (Bound<String>, Bound<String>) {
+        let start = match start {
+            Bound::Included(key) => Bound::Included(self.key(key)),
+            Bound::Excluded(key) => Bound::Excluded(self.key(key)),
+            Bound::Unbounded => Bound::Included(self.prefix.clone()),
+        };
+        let end = match end {
+            Bound::Included(key) => Bound::Included(self.key(key)),
+            Bound::Excluded(key) => Bound::Excluded(self.key(key)),
+            // the first key past the namespace, the separator being followed by `;`
+            Bound::Unbounded => {
+                let mut end = self.prefix[..self.prefix.len() - 1].to_owned();
+                end.push(';');
+                Bound::Excluded(end)
+            }
+        };
+        (start, end)
+    }
+}
+
+impl KvsEngine for NamespacedStore {
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.store.get(self.key(key))
+    }
+
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.store.set(self.key(key), value)
+    }
+
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        let pairs = pairs.into_iter().map(|(key, value)| (self.key(key), value)).collect();
+        KvsEngine::set_many(&self.store, pairs)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.store.remove(self.key(key))
+    }
+
+    fn get_versioned(&self, key: String) -> Result<Option<(String, u64)>> {
+        self.store.get_versioned(self.key(key))
+    }
+
+    fn set_if_version(&self, key: String, value: String, expected: u64) -> Result<bool> {
+        self.store.set_if_version(self.key(key), value, expected)
+    }
+
+    fn set_and_get_old(&self, key: String, value: String) -> Result<Option<String>> {
+        self.store.set_and_get_old(self.key(key), value)
+    }
+
+    fn merge(&self, key: String, operand: String) -> Result<()> {
+        KvsEngine::merge(&self.store, self.key(key), operand)
+    }
+
+    fn rename(&self, from: String, to: String) -> Result<bool> {
+        KvsEngine::rename(&self.store, self.key(from), self.key(to))
+    }
+
+    fn remove_range(&self, start: Bound<String>, end: Bound<String>) -> Result<usize> {
+        let (start, end) = self.range(start, end);
+        KvsEngine::remove_range(&self.store, start, end)
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        Ok(KvsEngine::keys(&self.store)?
+            .into_iter()
+            .filter_map(|key| key.strip_prefix(&self.prefix).map(str::to_owned))
+            .collect())
+    }
+
+    fn count_prefix(&self, prefix: String) -> Result<usize> {
+        self.store.count_prefix(self.key(prefix))
+    }
+
+    fn warm_up(&self) -> Result<()> {
+        self.store.warm_up()
+    }
+
+    fn health_check(&self) -> Result<()> {
+        self.store.health_check()
+    }
+
+    fn flush(&self) -> Result<()> {
+        KvsEngine::flush(&self.store)
+    }
+
+    fn compact(&self) -> Result<()> {
+        self.store.compact()
+    }
+
+    fn compact_range(&self, start: Bound<String>, end: Bound<String>) -> Result<()> {
+        let (start, end) = self.range(start, end);
+        KvsEngine::compact_range(&self.store, start, end)
+    }
+}
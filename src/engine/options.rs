@@ -0,0 +1,463 @@
+use std::fmt::{self, Debug, Display, Formatter};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::KvsError;
+
+const DEFAULT_READER_CACHE_CAPACITY: usize = 2;
+const DEFAULT_BUFFER_SIZE: usize = 8 * 1024;
+const DEFAULT_SLED_FLUSH_INTERVAL: Duration = Duration::from_millis(500);
+
+pub(crate) type ProgressCallback = Arc<dyn Fn(CompactionProgress) + Send + Sync>;
+
+/// Check run on every key passed to `KvStore`, returning the reason a key is rejected
+pub type KeyValidator = Arc<dyn Fn(&str) -> std::result::Result<(), String> + Send + Sync>;
+
+/// Function folding a merge operand into the current value of a key, if any
+pub type MergeOperator = Arc<dyn Fn(Option<&str>, &str) -> String + Send + Sync>;
+
+/// Progress of a running compaction
+///
+/// Reported to the callback set with `KvStoreOptions::compaction_progress`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct CompactionProgress {
+    /// epoch of the log being written by the compaction
+    pub epoch: usize,
+    /// bytes of live records copied so far
+    pub bytes_processed: u64,
+    /// bytes of live records to copy in total
+    pub bytes_total: u64,
+}
+
+/// Options to configure how a `KvStore` is opened
+///
+/// Examples:
+/// ```rust
+/// use kvs::{KvStore, KvStoreOptions};
+///
+/// let dir = tempfile::TempDir::new().unwrap();
+/// let options = KvStoreOptions::new().reader_cache_capacity(4);
+/// let store = KvStore::open_with_options(dir.path(), options).unwrap();
+/// ```
+#[derive(Clone)]
+pub struct KvStoreOptions {
+    pub(crate) reader_cache_capacity: usize,
+    pub(crate) value_cache_capacity: usize,
+    pub(crate) inline_values: Option<(usize, usize)>,
+    pub(crate) index_type: IndexType,
+    pub(crate) max_open_files: Option<usize>,
+    pub(crate) auto_compaction: bool,
+    pub(crate) flush_on_write: bool,
+    pub(crate) background_compaction: bool,
+    pub(crate) compress_sealed_segments: bool,
+    pub(crate) compaction_progress: Option<ProgressCallback>,
+    pub(crate) key_validator: Option<KeyValidator>,
+    pub(crate) merge_operator: Option<MergeOperator>,
+    pub(crate) large_value_threshold: Option<usize>,
+    pub(crate) value_compression_threshold: Option<usize>,
+    pub(crate) check_sequence: bool,
+    pub(crate) buffer_size: usize,
+    pub(crate) gc_orphans_on_open: bool,
+    pub(crate) preallocate_segments: Option<u64>,
+}
+
+impl KvStoreOptions {
+    /// create options with default values
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// set how many segment readers each store handle keeps open.
+    ///
+    /// readers are evicted in least-recently-used order, at least one is always kept.
+    /// default is 2.
+    pub fn reader_cache_capacity(mut self, capacity: usize) -> Self {
+        self.reader_cache_capacity = capacity.max(1);
+        self
+    }
+
+    /// set how many files the store may keep open across all of its handles.
+    ///
+    /// this counts the writer of the active segment and every cached segment reader,
+    /// but not the files compaction and warm-up open for their duration. opening a reader
+    /// beyond the limit closes the least recently used idle one, to be reopened on demand.
+    /// `open` fails with `KvsError::InvalidOption` if the limit is below 2.
+    /// by default there is no limit besides the reader cache of each handle.
+    pub fn max_open_files(mut self, limit: usize) -> Self {
+        self.max_open_files = Some(limit);
+        self
+    }
+
+    /// set how many recently read values are kept in memory, shared by all handles of the store.
+    ///
+    /// a cached value is served without reading the log until the key is written again.
+    /// default is 0, which disables the cache.
+    pub fn value_cache_capacity(mut self, capacity: usize) -> Self {
+        self.value_cache_capacity = capacity;
+        self
+    }
+
+    /// keep values of at most `max_len` bytes in the index, up to `capacity` bytes of them
+    /// in total.
+    ///
+    /// a value kept in the index is served from memory, without reading the log, and still
+    /// appended to the log for durability. values are kept as they are written or replayed
+    /// while the total allows it, a value found over it being kept the next time it is read
+    /// from the log with room left. the bytes of a value count until the key is written
+    /// again or removed. by default every value is read from the log.
+    pub fn inline_values(mut self, max_len: usize, capacity: usize) -> Self {
+        self.inline_values = Some((max_len, capacity));
+        self
+    }
+
+    /// set the structure holding the in-memory index of the store, see `IndexType`.
+    ///
+    /// default is `IndexType::Hash`.
+    pub fn index_type(mut self, index_type: IndexType) -> Self {
+        self.index_type = index_type;
+        self
+    }
+
+    /// set whether writes trigger compaction once there are too many redundant records.
+    ///
+    /// when disabled, compaction only runs through `KvStore::compact` or
+    /// `KvStore::compact_if_needed`, redundant records still being counted for the latter.
+    /// default is true.
+    pub fn auto_compaction(mut self, enabled: bool) -> Self {
+        self.auto_compaction = enabled;
+        self
+    }
+
+    /// set whether every write is flushed to the storage before it returns.
+    ///
+    /// when disabled, writes are buffered in memory until the buffer fills, a `get` needs
+    /// them, or the store is flushed, compacted or dropped, which saves a write call per
+    /// record. buffered writes are lost if the process crashes. default is true.
+    pub fn flush_on_write(mut self, enabled: bool) -> Self {
+        self.flush_on_write = enabled;
+        self
+    }
+
+    /// set whether automatic compaction runs on a dedicated thread instead of the writer's.
+    ///
+    /// the write crossing the threshold only signals the thread, and writes go on while the
+    /// live records are copied; the writer is locked again only to switch to the new segment.
+    /// default is false.
+    pub fn background_compaction(mut self, enabled: bool) -> Self {
+        self.background_compaction = enabled;
+        self
+    }
+
+    /// set whether compaction writes the live records into a sealed segment compressed with zstd.
+    ///
+    /// records written after a compaction go to a new active segment, which stays
+    /// uncompressed so appends are not slowed down. records of a sealed segment are
+    /// decompressed when read. default is false.
+    pub fn compress_sealed_segments(mut self, enabled: bool) -> Self {
+        self.compress_sealed_segments = enabled;
+        self
+    }
+
+    /// set a callback to report the progress of compactions.
+    ///
+    /// it is called when a compaction starts, periodically while records are copied,
+    /// and when it finishes. it runs on the thread doing the compaction, under the writer
+    /// lock unless compaction runs in the background, so it should return quickly.
+    pub fn compaction_progress<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(CompactionProgress) + Send + Sync + 'static,
+    {
+        self.compaction_progress = Some(Arc::new(callback));
+        self
+    }
+
+    /// set a check run at the start of every `get`, `set` and `remove`.
+    ///
+    /// a rejected key fails the call with `KvsError::InvalidKey` holding the returned reason.
+    /// by default no key is rejected.
+    ///
+    /// Examples:
+    /// ```rust
+    /// use kvs::{KvStore, KvStoreOptions, KvsError, MemoryStorage};
+    ///
+    /// let options = KvStoreOptions::new().key_validator(|key| {
+    ///     if key.is_empty() {
+    ///         Err("empty key".to_owned())
+    ///     } else {
+    ///         Ok(())
+    ///     }
+    /// });
+    /// let store = KvStore::open_with_storage(MemoryStorage::new(), options).unwrap();
+    /// assert!(matches!(store.set("", "value"), Err(KvsError::InvalidKey(_))));
+    /// ```
+    pub fn key_validator<F>(mut self, validator: F) -> Self
+    where
+        F: Fn(&str) -> std::result::Result<(), String> + Send + Sync + 'static,
+    {
+        self.key_validator = Some(Arc::new(validator));
+        self
+    }
+
+    /// set the function `merge` folds operands into values with.
+    ///
+    /// without one, `merge` fails with `KvsError::NoMergeOperator`.
+    pub fn merge_operator<F>(mut self, operator: F) -> Self
+    where
+        F: Fn(Option<&str>, &str) -> String + Send + Sync + 'static,
+    {
+        self.merge_operator = Some(Arc::new(operator));
+        self
+    }
+
+    /// set the size in bytes above which a value is stored in a file of its own.
+    ///
+    /// the file is named after the hash of the value, so equal values share it, and the
+    /// log only holds a reference to it, which is all compaction copies. a full compaction
+    /// removes the files no live key refers to. segments holding references cannot be read
+    /// by versions before this option existed. by default every value is stored in the log.
+    pub fn large_value_threshold(mut self, bytes: usize) -> Self {
+        self.large_value_threshold = Some(bytes);
+        self
+    }
+
+    /// set the size in bytes above which a value is compressed with zstd within its record.
+    ///
+    /// reads decompress the value, compaction copies it compressed. the compressed value
+    /// is stored base64-encoded, so a value is only kept compressed if that is shorter
+    /// than the value itself. values stored in a file of their own are not compressed.
+    /// segments holding compressed values cannot be read by versions before this option
+    /// existed. by default no value is compressed.
+    pub fn value_compression_threshold(mut self, bytes: usize) -> Self {
+        self.value_compression_threshold = Some(bytes);
+        self
+    }
+
+    /// set the capacity in bytes of the buffer of every segment reader and writer.
+    ///
+    /// larger buffers take fewer system calls to read and write long runs of records,
+    /// as replay, compaction and writes with `flush_on_write` disabled do, at the cost
+    /// of memory for each cached reader. at least 1, default is 8KiB.
+    pub fn buffer_size(mut self, bytes: usize) -> Self {
+        self.buffer_size = bytes.max(1);
+        self
+    }
+
+    /// set whether sequence numbers are checked at runtime.
+    ///
+    /// writes through writer handles, and through the store while handles are open, are
+    /// numbered in sequence, and a key must never be indexed at a record numbered lower
+    /// than the one it replaces. with the check, such a write, or a writer segment whose
+    /// records are out of order when the store is opened, fails with
+    /// `KvsError::Consistency`. without it, debug builds panic on the write and the
+    /// segment is replayed with a warning. default is false.
+    pub fn check_sequence(mut self, enabled: bool) -> Self {
+        self.check_sequence = enabled;
+        self
+    }
+
+    /// set whether opening the store removes the files a crash left behind.
+    ///
+    /// see `KvStore::gc_orphans`, which also does it on demand. ignored when opening
+    /// read-only. default is false.
+    pub fn gc_orphans_on_open(mut self, enabled: bool) -> Self {
+        self.gc_orphans_on_open = enabled;
+        self
+    }
+
+    /// set the size in bytes the active segment is preallocated to, zeros filling it past
+    /// its records.
+    ///
+    /// the file then takes its full size on disk as soon as it is created, and writes
+    /// within it neither grow it nor update its length, which spares the file system the
+    /// fragmentation and metadata writes of many small appends. a segment growing past
+    /// the size grows on demand from there. the zeros are cut once a compaction replaces
+    /// the segment, so a store with few writes keeps taking the full size, which
+    /// `segments` reports too. opening the store without this option cuts the zeros left
+    /// by an earlier preallocation. needs a storage whose writers support
+    /// `StorageWriter::set_len`. by default segments grow on demand.
+    pub fn preallocate_segments(mut self, bytes: u64) -> Self {
+        self.preallocate_segments = Some(bytes);
+        self
+    }
+}
+
+impl Debug for KvStoreOptions {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KvStoreOptions")
+            .field("reader_cache_capacity", &self.reader_cache_capacity)
+            .field("value_cache_capacity", &self.value_cache_capacity)
+            .field("inline_values", &self.inline_values)
+            .field("index_type", &self.index_type)
+            .field("max_open_files", &self.max_open_files)
+            .field("auto_compaction", &self.auto_compaction)
+            .field("flush_on_write", &self.flush_on_write)
+            .field("background_compaction", &self.background_compaction)
+            .field("compress_sealed_segments", &self.compress_sealed_segments)
+            .field("compaction_progress", &self.compaction_progress.is_some())
+            .field("key_validator", &self.key_validator.is_some())
+            .field("merge_operator", &self.merge_operator.is_some())
+            .field("large_value_threshold", &self.large_value_threshold)
+            .field("value_compression_threshold", &self.value_compression_threshold)
+            .field("check_sequence", &self.check_sequence)
+            .field("buffer_size", &self.buffer_size)
+            .field("gc_orphans_on_open", &self.gc_orphans_on_open)
+            .field("preallocate_segments", &self.preallocate_segments)
+            .finish()
+    }
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        Self {
+            reader_cache_capacity: DEFAULT_READER_CACHE_CAPACITY,
+            value_cache_capacity: 0,
+            inline_values: None,
+            index_type: IndexType::Hash,
+            max_open_files: None,
+            auto_compaction: true,
+            flush_on_write: true,
+            background_compaction: false,
+            compress_sealed_segments: false,
+            compaction_progress: None,
+            key_validator: None,
+            merge_operator: None,
+            large_value_threshold: None,
+            value_compression_threshold: None,
+            check_sequence: false,
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            gc_orphans_on_open: false,
+            preallocate_segments: None,
+        }
+    }
+}
+
+/// Structure holding the in-memory index of a `KvStore`, from each key to its record
+///
+/// Whichever it is, the index holds record locations, values are read from the log or from
+/// the cache set with `KvStoreOptions::value_cache_capacity`, unless they are small enough
+/// to be kept in the index itself, see `KvStoreOptions::inline_values`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum IndexType {
+    /// concurrent hash map locking one bucket per access, so reads and writes of
+    /// different keys rarely contend. keys are unordered: `keys`, `remove_range`,
+    /// `compact_range` and `count_prefix` visit every entry, the ordered ones also
+    /// sorting a copy of the index. this is the default.
+    Hash,
+    /// B-tree behind a read-write lock. keys are kept in order, so ordered and range
+    /// operations only visit the keys they return. reads share the lock, but every
+    /// write, through writer handles too, takes it exclusively and blocks all others.
+    BTree,
+    /// B-tree like `BTree`, whose keys are split after their first `:`, the part up to
+    /// it interned and held once for every key sharing it, as with namespaces.
+    /// keys sharing long prefixes take much less memory. keys are compared byte by byte
+    /// across their two parts rather than with one `memcmp`, which slows every lookup,
+    /// and writing a new key also looks its prefix up in a hash set.
+    Interned,
+}
+
+/// When `SledKvsEngine` makes writes durable
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Durability {
+    /// flush and wait for it after every write, so a write is durable once it returns.
+    /// this is the default.
+    FlushOnWrite,
+    /// never flush on write, leave it to the periodic flush of sled.
+    /// writes of the last flush interval may be lost on a crash.
+    Periodic,
+}
+
+impl FromStr for Durability {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<Self, KvsError> {
+        match s {
+            "flush-on-write" => Ok(Durability::FlushOnWrite),
+            "periodic" => Ok(Durability::Periodic),
+            _ => Err(KvsError::InvalidOption(format!(
+                "unknown durability `{}`, expected `flush-on-write` or `periodic`",
+                s
+            ))),
+        }
+    }
+}
+
+impl Display for Durability {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Durability::FlushOnWrite => write!(f, "flush-on-write"),
+            Durability::Periodic => write!(f, "periodic"),
+        }
+    }
+}
+
+/// Options to configure how a `SledKvsEngine` is opened
+///
+/// Examples:
+/// ```rust
+/// use kvs::{Durability, SledKvsEngine, SledOptions};
+///
+/// let dir = tempfile::TempDir::new().unwrap();
+/// let options = SledOptions::new().durability(Durability::Periodic);
+/// let engine = SledKvsEngine::open_with_options(dir.path(), options).unwrap();
+/// ```
+#[derive(Clone)]
+pub struct SledOptions {
+    pub(crate) durability: Durability,
+    pub(crate) flush_interval: Duration,
+    pub(crate) merge_operator: Option<MergeOperator>,
+}
+
+impl SledOptions {
+    /// create options with default values
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// set when writes are made durable.
+    ///
+    /// default is `Durability::FlushOnWrite`.
+    pub fn durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// set the interval of the periodic background flush of sled.
+    ///
+    /// this bounds how many writes `Durability::Periodic` may lose. default is 500ms.
+    pub fn flush_interval(mut self, interval: Duration) -> Self {
+        self.flush_interval = interval;
+        self
+    }
+
+    /// set the function `merge` folds operands into values with.
+    ///
+    /// without one, `merge` fails with `KvsError::NoMergeOperator`.
+    pub fn merge_operator<F>(mut self, operator: F) -> Self
+    where
+        F: Fn(Option<&str>, &str) -> String + Send + Sync + 'static,
+    {
+        self.merge_operator = Some(Arc::new(operator));
+        self
+    }
+}
+
+impl Debug for SledOptions {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SledOptions")
+            .field("durability", &self.durability)
+            .field("flush_interval", &self.flush_interval)
+            .field("merge_operator", &self.merge_operator.is_some())
+            .finish()
+    }
+}
+
+impl Default for SledOptions {
+    fn default() -> Self {
+        Self {
+            durability: Durability::FlushOnWrite,
+            flush_interval: DEFAULT_SLED_FLUSH_INTERVAL,
+            merge_operator: None,
+        }
+    }
+}
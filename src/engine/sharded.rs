@@ -0,0 +1,202 @@
+use std::ops::Bound;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::{KvStore, KvStoreOptions, KvsEngine, KvsError, Result};
+
+/// FNV-1a parameters, a hash that never changes across versions and platforms
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+/// Function hashing a key to pick its shard in a `ShardedKvStore`
+pub type ShardHash = Arc<dyn Fn(&str) -> u64 + Send + Sync>;
+
+/// One logical store spread over several `KvStore`s by the hash of each key
+///
+/// Each shard has a writer of its own, so writes of keys of different shards never wait
+/// for each other, and shards opened in different directories, e.g. on different disks,
+/// share the I/O. `get`, `set`, `remove` and the other operations on a single key go to
+/// the shard of the key alone, while `keys`, `count_prefix`, `remove_range`, flushes and
+/// compactions run on every shard and merge the results.
+///
+/// A key always goes to the same shard for a given hash and number of shards, so the
+/// shards must be passed in the same order every time the store is opened. Changing their
+/// number moves most keys to another shard, where they are not found.
+///
+/// Examples:
+/// ```rust
+/// use kvs::{KvStore, KvStoreOptions, KvsEngine, MemoryStorage, ShardedKvStore};
+///
+/// let shards = (0..4)
+///     .map(|_| KvStore::open_with_storage(MemoryStorage::new(), KvStoreOptions::new()))
+///     .collect::<kvs::Result<Vec<_>>>()
+///     .unwrap();
+/// let store = ShardedKvStore::new(shards).unwrap();
+/// store.set("abc".to_owned(), "def".to_owned()).unwrap();
+/// assert_eq!(store.shards()[store.shard_of("abc")].get("abc").unwrap(), Some("def".to_owned()));
+/// ```
+#[derive(Clone)]
+pub struct ShardedKvStore {
+    shards: Vec<KvStore>,
+    hash: ShardHash,
+}
+
+impl ShardedKvStore {
+    /// shard keys over `shards` by their FNV-1a hash
+    ///
+    /// return `KvsError::InvalidOption` if there is no shard.
+    pub fn new(shards: Vec<KvStore>) -> Result<Self> {
+        Self::with_hash(shards, Arc::new(fnv1a))
+    }
+
+    /// shard keys over `shards` by the given hash, which must never change for a key
+    ///
+    /// return `KvsError::InvalidOption` if there is no shard.
+    pub fn with_hash(shards: Vec<KvStore>, hash: ShardHash) -> Result<Self> {
+        if shards.is_empty() {
+            return Err(KvsError::InvalidOption(
+                "a sharded store needs at least one shard".to_owned(),
+            ));
+        }
+        Ok(Self { shards, hash })
+    }
+
+    /// open a shard in each of `dirs` with the same options, see `KvStore::open_with_options`
+    pub fn open<P: AsRef<Path>>(dirs: &[P], options: KvStoreOptions) -> Result<Self> {
+        let shards = dirs
+            .iter()
+            .map(|dir| KvStore::open_with_options(dir, options.clone()))
+            .collect::<Result<_>>()?;
+        Self::new(shards)
+    }
+
+    /// the shards, in the order they were passed
+    pub fn shards(&self) -> &[KvStore] {
+        &self.shards
+    }
+
+    /// index of the shard holding `key`
+    pub fn shard_of(&self, key: &str) -> usize {
+        ((self.hash)(key) % self.shards.len() as u64) as usize
+    }
+
+    fn shard(&self, key: &str) -> &KvStore {
+        &self.shards[self.shard_of(key)]
+    }
+}
+
+fn fnv1a(key: &str) -> u64 {
+    key.bytes().fold(FNV_OFFSET_BASIS, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(FNV_PRIME)
+    })
+}
+
+impl KvsEngine for ShardedKvStore {
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.shard(&key).get(key)
+    }
+
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.shard(&key).set(key, value)
+    }
+
+    /// the pairs of each shard are set together, keeping their order
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        let mut sharded = vec![Vec::new(); self.shards.len()];
+        for (key, value) in pairs {
+            sharded[self.shard_of(&key)].push((key, value));
+        }
+        for (shard, pairs) in self.shards.iter().zip(sharded) {
+            if !pairs.is_empty() {
+                KvsEngine::set_many(shard, pairs)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.shard(&key).remove(key)
+    }
+
+    fn get_versioned(&self, key: String) -> Result<Option<(String, u64)>> {
+        self.shard(&key).get_versioned(key)
+    }
+
+    fn set_if_version(&self, key: String, value: String, expected: u64) -> Result<bool> {
+        self.shard(&key).set_if_version(key, value, expected)
+    }
+
+    fn set_and_get_old(&self, key: String, value: String) -> Result<Option<String>> {
+        self.shard(&key).set_and_get_old(key, value)
+    }
+
+    fn merge(&self, key: String, operand: String) -> Result<()> {
+        KvsEngine::merge(self.shard(&key), key, operand)
+    }
+
+    /// atomic when both keys are in the same shard. otherwise `to` is set in its shard
+    /// before `from` is removed from its own, which a failure in between leaves both set.
+    fn rename(&self, from: String, to: String) -> Result<bool> {
+        let shard = self.shard(&from);
+        if self.shard_of(&from) == self.shard_of(&to) {
+            return KvsEngine::rename(shard, from, to);
+        }
+        let value = match shard.get(from.clone())? {
+            Some(value) => value,
+            None => return Ok(false),
+        };
+        self.shard(&to).set(to, value)?;
+        match shard.remove(from) {
+            // removed meanwhile
+            Ok(()) | Err(KvsError::KeyNotFound) => Ok(true),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn remove_range(&self, start: Bound<String>, end: Bound<String>) -> Result<usize> {
+        let mut removed = 0;
+        for shard in self.shards.iter() {
+            removed += KvsEngine::remove_range(shard, start.clone(), end.clone())?;
+        }
+        Ok(removed)
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        for shard in self.shards.iter() {
+            keys.extend(KvsEngine::keys(shard)?);
+        }
+        keys.sort_unstable();
+        Ok(keys)
+    }
+
+    fn count_prefix(&self, prefix: String) -> Result<usize> {
+        let mut count = 0;
+        for shard in self.shards.iter() {
+            count += shard.count_prefix(prefix.clone())?;
+        }
+        Ok(count)
+    }
+
+    fn warm_up(&self) -> Result<()> {
+        self.shards.iter().try_for_each(KvsEngine::warm_up)
+    }
+
+    fn health_check(&self) -> Result<()> {
+        self.shards.iter().try_for_each(KvsEngine::health_check)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.shards.iter().try_for_each(KvsEngine::flush)
+    }
+
+    fn compact(&self) -> Result<()> {
+        self.shards.iter().try_for_each(KvStore::compact)
+    }
+
+    fn compact_range(&self, start: Bound<String>, end: Bound<String>) -> Result<()> {
+        self.shards
+            .iter()
+            .try_for_each(|shard| KvsEngine::compact_range(shard, start.clone(), end.clone()))
+    }
+}
@@ -1,27 +1,106 @@
-use std::path::{Path, PathBuf};
+use std::ops::Bound;
+use std::path::Path;
 
-use sled::Db;
+use sled::{ConflictableTransactionResult, Db, TransactionError, Transactional, TransactionalTree, Tree};
 
-use crate::engine::{try_add_engine_type, EngineType};
+use crate::engine::{try_add_engine_type, Durability, EngineType, FsStorage, MergeOperator, SledOptions};
 use crate::{KvsEngine, KvsError, Result};
 
+/// name of the tree holding the version of every key
+const VERSIONS_TREE: &str = "versions";
+
 /// Sled implementation of `KvsEngine`
+///
+/// The version of each key is kept in a tree of its own, written along with the value in
+/// a single transaction by every write.
 #[derive(Clone)]
 pub struct SledKvsEngine {
     db: Db,
+    versions: Tree,
+    durability: Durability,
+    merge_operator: Option<MergeOperator>,
 }
 
 impl SledKvsEngine {
     /// load the sled db from disk
     pub fn open<T: AsRef<Path>>(dir: T) -> Result<Self> {
-        let mut log_dir = PathBuf::new();
-        log_dir.push(dir);
-        std::fs::create_dir_all(&log_dir)?;
+        Self::open_with_options(dir, SledOptions::default())
+    }
+
+    /// load the sled db from disk with the given options
+    pub fn open_with_options<T: AsRef<Path>>(dir: T, options: SledOptions) -> Result<Self> {
+        let storage = FsStorage::new(dir)?;
+        try_add_engine_type(&storage, EngineType::Sled)?;
+
+        let db = sled::Config::new()
+            .path(storage.dir())
+            .flush_every_ms(Some(options.flush_interval.as_millis() as u64))
+            .open()?;
+        let versions = db.open_tree(VERSIONS_TREE)?;
+        Ok(Self {
+            db,
+            versions,
+            durability: options.durability,
+            merge_operator: options.merge_operator,
+        })
+    }
+
+    /// a version no write of any key had before, encoded as stored in the versions tree
+    ///
+    /// sled hands out increasing IDs and never the same one twice, even across restarts,
+    /// so writing a key back to an earlier value still changes its version.
+    fn next_version(&self) -> Result<[u8; 8]> {
+        Ok((self.db.generate_id()? + 2).to_be_bytes())
+    }
+
+    /// run `f` on the values and their versions in a single transaction
+    fn transaction<A>(
+        &self,
+        f: impl Fn(&TransactionalTree, &TransactionalTree) -> ConflictableTransactionResult<A>,
+    ) -> Result<A> {
+        let values: &Tree = &self.db;
+        (values, &self.versions)
+            .transaction(|(values, versions)| f(values, versions))
+            .map_err(|e| match e {
+                TransactionError::Abort(()) => unreachable!("the transactions never abort"),
+                TransactionError::Storage(e) => KvsError::from(e),
+            })
+    }
 
-        try_add_engine_type(&log_dir, EngineType::Sled)?;
+    /// remove a key along with its version, return whether it existed
+    fn remove_key(&self, key: &[u8]) -> Result<bool> {
+        self.transaction(|values, versions| {
+            versions.remove(key)?;
+            Ok(values.remove(key)?.is_some())
+        })
+    }
+
+    fn flush(&self) -> Result<()> {
+        if self.durability == Durability::FlushOnWrite {
+            self.db.flush()?;
+        }
+        Ok(())
+    }
+
+    /// get the value for a given key, accepting any key convertible into `String`.
+    ///
+    /// see [`KvsEngine::get`](trait.KvsEngine.html#tymethod.get).
+    pub fn get<K: Into<String>>(&self, key: K) -> Result<Option<String>> {
+        KvsEngine::get(self, key.into())
+    }
+
+    /// set a key-value pair, accepting any key and value convertible into `String`.
+    ///
+    /// see [`KvsEngine::set`](trait.KvsEngine.html#tymethod.set).
+    pub fn set<K: Into<String>, V: Into<String>>(&self, key: K, value: V) -> Result<()> {
+        KvsEngine::set(self, key.into(), value.into())
+    }
 
-        let db = sled::open(log_dir)?;
-        Ok(Self { db })
+    /// remove a key, accepting any key convertible into `String`.
+    ///
+    /// see [`KvsEngine::remove`](trait.KvsEngine.html#tymethod.remove).
+    pub fn remove<K: Into<String>>(&self, key: K) -> Result<()> {
+        KvsEngine::remove(self, key.into())
     }
 }
 
@@ -33,17 +112,148 @@ impl KvsEngine for SledKvsEngine {
             .map_err(|e| e.into())
     }
     fn set(&self, key: String, value: String) -> Result<()> {
-        self.db.insert(key, value.as_bytes()).map(|_| ())?;
-        self.db.flush()?;
-        Ok(())
+        let version = self.next_version()?;
+        self.transaction(|values, versions| {
+            values.insert(key.as_bytes(), value.as_bytes())?;
+            versions.insert(key.as_bytes(), &version)?;
+            Ok(())
+        })?;
+        self.flush()
+    }
+    /// set every pair in a single transaction, flushed once
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        let version = self.next_version()?;
+        self.transaction(|values, versions| {
+            for (key, value) in pairs.iter() {
+                values.insert(key.as_bytes(), value.as_bytes())?;
+                versions.insert(key.as_bytes(), &version)?;
+            }
+            Ok(())
+        })?;
+        self.flush()
     }
     fn remove(&self, key: String) -> Result<()> {
-        let res = match self.db.remove(key) {
-            Ok(Some(_)) => Ok(()),
-            Ok(None) => Err(KvsError::KeyNotFound),
-            Err(e) => Err(e.into()),
-        };
+        let removed = self.remove_key(key.as_bytes())?;
+        self.flush()?;
+        if removed {
+            Ok(())
+        } else {
+            Err(KvsError::KeyNotFound)
+        }
+    }
+    /// read the value and its version in a single transaction
+    fn get_versioned(&self, key: String) -> Result<Option<(String, u64)>> {
+        self.transaction(|values, versions| {
+            Ok(match values.get(key.as_bytes())? {
+                Some(value) => Some((
+                    unsafe { String::from_utf8_unchecked(value.to_vec()) },
+                    version_of(versions.get(key.as_bytes())?.as_deref()),
+                )),
+                None => None,
+            })
+        })
+    }
+    /// compare the version and write in a single transaction
+    fn set_if_version(&self, key: String, value: String, expected: u64) -> Result<bool> {
+        let version = self.next_version()?;
+        let set = self.transaction(|values, versions| {
+            let current = match values.get(key.as_bytes())? {
+                Some(_) => version_of(versions.get(key.as_bytes())?.as_deref()),
+                None => 0,
+            };
+            if current != expected {
+                return Ok(false);
+            }
+            values.insert(key.as_bytes(), value.as_bytes())?;
+            versions.insert(key.as_bytes(), &version)?;
+            Ok(true)
+        })?;
+        if set {
+            self.flush()?;
+        }
+        Ok(set)
+    }
+    fn set_and_get_old(&self, key: String, value: String) -> Result<Option<String>> {
+        let version = self.next_version()?;
+        let old = self.transaction(|values, versions| {
+            versions.insert(key.as_bytes(), &version)?;
+            Ok(values.insert(key.as_bytes(), value.as_bytes())?)
+        })?;
+        self.flush()?;
+        Ok(old.map(|vec| unsafe { String::from_utf8_unchecked(vec.to_vec()) }))
+    }
+    /// the operator runs within a transaction, so it may be called more than once
+    fn merge(&self, key: String, operand: String) -> Result<()> {
+        let operator = self.merge_operator.as_ref().ok_or(KvsError::NoMergeOperator)?;
+        let version = self.next_version()?;
+        self.transaction(|values, versions| {
+            let old = values.get(key.as_bytes())?;
+            let old = old.as_deref().map(|vec| unsafe { std::str::from_utf8_unchecked(vec) });
+            values.insert(key.as_bytes(), operator(old, &operand).into_bytes())?;
+            versions.insert(key.as_bytes(), &version)?;
+            Ok(())
+        })?;
+        self.flush()
+    }
+    /// remove `from` and insert `to` in a single transaction
+    fn rename(&self, from: String, to: String) -> Result<bool> {
+        let version = self.next_version()?;
+        let renamed = self.transaction(|values, versions| match values.remove(from.as_bytes())? {
+            Some(value) => {
+                versions.remove(from.as_bytes())?;
+                values.insert(to.as_bytes(), value)?;
+                versions.insert(to.as_bytes(), &version)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        })?;
+        self.flush()?;
+        Ok(renamed)
+    }
+    fn remove_range(&self, start: Bound<String>, end: Bound<String>) -> Result<usize> {
+        let keys = self
+            .db
+            .range::<String, _>((start, end))
+            .keys()
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        for key in keys.iter() {
+            self.remove_key(key)?;
+        }
+        self.flush()?;
+        Ok(keys.len())
+    }
+    fn flush(&self) -> Result<()> {
         self.db.flush()?;
-        res
+        Ok(())
+    }
+    fn keys(&self) -> Result<Vec<String>> {
+        self.db
+            .iter()
+            .keys()
+            .map(|key| Ok(unsafe { String::from_utf8_unchecked(key?.to_vec()) }))
+            .collect()
+    }
+    fn count_prefix(&self, prefix: String) -> Result<usize> {
+        let mut count = 0;
+        for key in self.db.scan_prefix(prefix).keys() {
+            key?;
+            count += 1;
+        }
+        Ok(count)
+    }
+}
+
+/// version of a value along with the version recorded for it, if any
+///
+/// values written before versions were recorded all have version 1, which
+/// `SledKvsEngine::next_version` never returns.
+fn version_of(recorded: Option<&[u8]>) -> u64 {
+    match recorded {
+        Some(recorded) => {
+            let mut version = [0; 8];
+            version.copy_from_slice(recorded);
+            u64::from_be_bytes(version)
+        }
+        None => 1,
     }
 }
@@ -0,0 +1,370 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::fs::{create_dir_all, File, OpenOptions};
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use fs2::FileExt;
+
+use crate::{KvsError, Result};
+
+/// file other processes opening the directory lock
+const LOCK: &str = "LOCK";
+
+/// attempts of a rename failing with a transient error before giving up
+const RENAME_ATTEMPTS: u32 = 5;
+/// wait before the first retry of a rename, doubled for each one after it
+const RENAME_BACKOFF: Duration = Duration::from_millis(10);
+
+/// A readable handle to a file of a `Storage`
+pub trait StorageReader: Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> StorageReader for T {}
+
+/// A writable handle to a file of a `Storage`
+pub trait StorageWriter: Write + Seek + Send {
+    /// make everything written so far durable
+    fn sync(&mut self) -> io::Result<()>;
+
+    /// cut or extend the file to `len` bytes, extending it with zeros
+    ///
+    /// fails with `io::ErrorKind::Unsupported` by default.
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        let _ = len;
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "cannot set the length of the file",
+        ))
+    }
+}
+
+/// File access used by `KvStore`
+///
+/// Files are addressed by plain names within the storage, such as `0.log`.
+pub trait Storage: Debug + Send + Sync + 'static {
+    /// open an existing file for reading
+    fn open(&self, name: &str) -> Result<Box<dyn StorageReader>>;
+    /// create a file for writing, truncating it if it already exists
+    fn create(&self, name: &str) -> Result<Box<dyn StorageWriter>>;
+    /// open an existing file for writing, positioned at its end
+    fn append(&self, name: &str) -> Result<Box<dyn StorageWriter>>;
+    /// rename a file, replacing the target if it exists
+    fn rename(&self, from: &str, to: &str) -> Result<()>;
+    /// remove a file
+    fn remove(&self, name: &str) -> Result<()>;
+    /// list the names of all files
+    fn list(&self) -> Result<Vec<String>>;
+    /// make the files created, renamed and removed so far durable
+    fn sync_dir(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// `Storage` backed by a directory of the file system
+#[derive(Clone, Debug)]
+pub struct FsStorage {
+    dir: PathBuf,
+}
+
+impl FsStorage {
+    /// use the given directory, creating it if necessary
+    pub fn new<T: AsRef<Path>>(dir: T) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        create_dir_all(&dir).map_err(|e| write_error(&dir, e))?;
+        Ok(Self { dir })
+    }
+
+    /// the directory holding the files
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// take an advisory lock on the directory, held until the returned file is closed
+    ///
+    /// an exclusive lock excludes every other lock, a shared one only exclusive locks.
+    /// fail with `KvsError::AlreadyLocked` right away instead of waiting for a conflicting
+    /// lock to be released. locks are per open file, so they also conflict within a process.
+    pub(crate) fn lock(&self, exclusive: bool) -> Result<File> {
+        let path = self.dir.join(LOCK);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .map_err(|e| write_error(&path, e))?;
+        // qualified, as newer versions of `File` have inherent methods of the same names
+        let locked = if exclusive {
+            FileExt::try_lock_exclusive(&file)
+        } else {
+            FileExt::try_lock_shared(&file)
+        };
+        match locked {
+            Ok(()) => Ok(file),
+            Err(e) if e.kind() == fs2::lock_contended_error().kind() => {
+                Err(KvsError::AlreadyLocked(self.dir.display().to_string()))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl StorageWriter for File {
+    fn sync(&mut self) -> io::Result<()> {
+        self.sync_all()
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        File::set_len(self, len)
+    }
+}
+
+impl Storage for FsStorage {
+    fn open(&self, name: &str) -> Result<Box<dyn StorageReader>> {
+        Ok(Box::new(File::open(self.dir.join(name))?))
+    }
+
+    fn create(&self, name: &str) -> Result<Box<dyn StorageWriter>> {
+        let path = self.dir.join(name);
+        let file = File::create(&path).map_err(|e| write_error(&path, e))?;
+        Ok(Box::new(file))
+    }
+
+    /// the file is not opened in append mode, so that writes may seek back within it
+    fn append(&self, name: &str) -> Result<Box<dyn StorageWriter>> {
+        let path = self.dir.join(name);
+        let mut file = OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .map_err(|e| write_error(&path, e))?;
+        file.seek(SeekFrom::End(0))?;
+        Ok(Box::new(file))
+    }
+
+    /// a rename failing with a transient error, e.g. on Windows while another process such
+    /// as a virus scanner has the file open, is retried with a growing, jittered backoff
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        let (from, to) = (self.dir.join(from), self.dir.join(to));
+        let mut backoff = RENAME_BACKOFF;
+        for attempt in 1.. {
+            match std::fs::rename(&from, &to) {
+                Ok(()) => break,
+                Err(e) if is_transient(&e) && attempt < RENAME_ATTEMPTS => {
+                    thread::sleep(backoff + jitter(backoff));
+                    backoff *= 2;
+                }
+                Err(e) if is_transient(&e) => {
+                    let e = io::Error::new(e.kind(), format!("still failing after {} attempts: {}", attempt, e));
+                    return Err(write_error(&to, e));
+                }
+                Err(e) => return Err(write_error(&to, e)),
+            }
+        }
+        Ok(())
+    }
+
+    fn remove(&self, name: &str) -> Result<()> {
+        let path = self.dir.join(name);
+        std::fs::remove_file(&path).map_err(|e| write_error(&path, e))?;
+        Ok(())
+    }
+
+    fn list(&self) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in self.dir.read_dir()? {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                if let Some(name) = entry.file_name().to_str() {
+                    names.push(name.to_owned());
+                }
+            }
+        }
+        Ok(names)
+    }
+
+    fn sync_dir(&self) -> Result<()> {
+        // directories cannot be opened as files everywhere, but unix needs this for durable renames
+        if cfg!(unix) {
+            File::open(&self.dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+/// attach the path to errors caused by a read-only or otherwise unwritable location
+/// whether a rename may succeed if retried
+///
+/// on Windows, renaming a file another handle has open fails with access denied, which
+/// elsewhere means the directory cannot be written at all.
+fn is_transient(e: &io::Error) -> bool {
+    match e.kind() {
+        io::ErrorKind::ResourceBusy => true,
+        io::ErrorKind::PermissionDenied => cfg!(windows),
+        _ => false,
+    }
+}
+
+/// random wait of up to half `backoff`, so that retries of concurrent renames spread out
+fn jitter(backoff: Duration) -> Duration {
+    let random = RandomState::new().build_hasher().finish();
+    backoff / 2 * (random % 1024) as u32 / 1024
+}
+
+fn write_error(path: &Path, err: io::Error) -> KvsError {
+    match err.kind() {
+        io::ErrorKind::PermissionDenied | io::ErrorKind::ReadOnlyFilesystem => {
+            KvsError::NotWritable(path.display().to_string(), err)
+        }
+        _ => err.into(),
+    }
+}
+
+type MemoryFile = Arc<RwLock<Vec<u8>>>;
+
+/// `Storage` keeping all files in memory
+///
+/// Clones share the same files, so a store can be dropped and reopened from a clone.
+/// Handles opened before a file is replaced or removed keep seeing the old content,
+/// like unlinked files on disk.
+///
+/// Examples:
+/// ```rust
+/// use kvs::{KvStore, KvStoreOptions, MemoryStorage};
+///
+/// let storage = MemoryStorage::new();
+/// let store = KvStore::open_with_storage(storage.clone(), KvStoreOptions::new()).unwrap();
+/// store.set("abc", "def").unwrap();
+/// drop(store);
+///
+/// let store = KvStore::open_with_storage(storage, KvStoreOptions::new()).unwrap();
+/// assert_eq!(store.get("abc").unwrap(), Some("def".to_string()));
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct MemoryStorage {
+    files: Arc<Mutex<HashMap<String, MemoryFile>>>,
+}
+
+impl MemoryStorage {
+    /// create an empty storage
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn file(&self, name: &str) -> Result<MemoryFile> {
+        self.files
+            .lock()
+            .unwrap()
+            .get(name)
+            .cloned()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} not found", name)).into())
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn open(&self, name: &str) -> Result<Box<dyn StorageReader>> {
+        Ok(Box::new(MemoryHandle {
+            file: self.file(name)?,
+            pos: 0,
+        }))
+    }
+
+    fn create(&self, name: &str) -> Result<Box<dyn StorageWriter>> {
+        let file = MemoryFile::default();
+        self.files.lock().unwrap().insert(name.to_owned(), file.clone());
+        Ok(Box::new(MemoryHandle { file, pos: 0 }))
+    }
+
+    fn append(&self, name: &str) -> Result<Box<dyn StorageWriter>> {
+        let file = self.file(name)?;
+        let pos = file.read().unwrap().len() as u64;
+        Ok(Box::new(MemoryHandle { file, pos }))
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        let mut files = self.files.lock().unwrap();
+        let file = files
+            .remove(from)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} not found", from)))?;
+        files.insert(to.to_owned(), file);
+        Ok(())
+    }
+
+    fn remove(&self, name: &str) -> Result<()> {
+        self.files
+            .lock()
+            .unwrap()
+            .remove(name)
+            .map(|_| ())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} not found", name)).into())
+    }
+
+    fn list(&self) -> Result<Vec<String>> {
+        Ok(self.files.lock().unwrap().keys().cloned().collect())
+    }
+}
+
+struct MemoryHandle {
+    file: MemoryFile,
+    pos: u64,
+}
+
+impl Read for MemoryHandle {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let data = self.file.read().unwrap();
+        let start = (self.pos as usize).min(data.len());
+        let len = buf.len().min(data.len() - start);
+        buf[..len].copy_from_slice(&data[start..start + len]);
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl Write for MemoryHandle {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut data = self.file.write().unwrap();
+        let start = self.pos as usize;
+        let end = start + buf.len();
+        if data.len() < end {
+            data.resize(end, 0);
+        }
+        data[start..end].copy_from_slice(buf);
+        self.pos = end as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MemoryHandle {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let len = self.file.read().unwrap().len() as i64;
+        let new_pos = match pos {
+            SeekFrom::Start(offset) => offset as i64,
+            SeekFrom::End(offset) => len + offset,
+            SeekFrom::Current(offset) => self.pos as i64 + offset,
+        };
+        if new_pos < 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "seek before start of file"));
+        }
+        self.pos = new_pos as u64;
+        Ok(self.pos)
+    }
+}
+
+impl StorageWriter for MemoryHandle {
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.file.write().unwrap().resize(len as usize, 0);
+        Ok(())
+    }
+}
@@ -1,3 +1,6 @@
+// `failure_derive` expands its impls inside an anonymous const
+#![allow(non_local_definitions)]
+
 use std::io;
 
 /// result type for kvs crate
@@ -24,9 +27,87 @@ pub enum KvsError {
     /// Wrong engine
     #[fail(display = "wrong engine")]
     WrongEngine,
+    /// Unknown engine name
+    #[fail(display = "unknown engine `{}`, expected `kvs` or `sled`", _0)]
+    UnknownEngine(String),
+    /// Data directory cannot be written, e.g. because it is on a read-only filesystem
+    #[fail(display = "cannot write to `{}`: {}, is the filesystem read-only?", _0, _1)]
+    NotWritable(String, io::Error),
+    /// Log written in a newer format than this version can read
+    #[fail(display = "unsupported log format {}, supported up to {}", found, supported)]
+    UnsupportedFormat {
+        /// format version of the log
+        found: u32,
+        /// latest format version this version can read
+        supported: u32,
+    },
+    /// Key rejected by the key validator
+    #[fail(display = "invalid key: {}", _0)]
+    InvalidKey(String),
+    /// `merge` called on an engine opened without a merge operator
+    #[fail(display = "no merge operator set")]
+    NoMergeOperator,
+    /// Operation the engine does not implement, e.g. `KvsEngine::get_versioned`
+    #[fail(display = "`{}` is not supported by this engine", _0)]
+    Unsupported(String),
+    /// Value that does not parse into the requested type
+    #[fail(display = "parse error: {}", _0)]
+    ParseError(String),
+    /// Option out of its valid range
+    #[fail(display = "invalid option: {}", _0)]
+    InvalidOption(String),
+    /// Response the client did not expect for its query
+    #[fail(display = "protocol error: {}", _0)]
+    ProtocolError(String),
+    /// Client and server speak no common protocol version
+    #[fail(
+        display = "protocol version mismatch: client speaks {}, server speaks {}",
+        client, server
+    )]
+    VersionMismatch {
+        /// protocol version of the client
+        client: u8,
+        /// protocol version of the server
+        server: u8,
+    },
+    /// Failure of a query traced with a request ID, see `KvsClient::trace_requests`
+    #[fail(display = "request {:016x} failed: {}", id, error)]
+    Request {
+        /// ID the query was sent with, as logged by the server
+        id: u64,
+        /// why the query failed
+        error: Box<KvsError>,
+    },
+    /// Command refused by the server
+    #[fail(display = "command not allowed by the server")]
+    Forbidden,
+    /// A log record does not match the index
+    #[fail(display = "corrupted log: {}", _0)]
+    Corrupted(String),
+    /// Records numbered out of sequence, see `KvStoreOptions::check_sequence`
+    #[fail(display = "consistency error: {}", _0)]
+    Consistency(String),
+    /// Directory locked by another open store, possibly of another process
+    #[fail(display = "`{}` is locked by another open store", _0)]
+    AlreadyLocked(String),
+    /// Store failing its health check, see `KvsEngine::health_check`
+    #[fail(display = "store is unhealthy: {}", _0)]
+    Unhealthy(String),
+    /// Write to a store opened read-only
+    #[fail(display = "store is opened read-only")]
+    ReadOnly,
     /// Thread Pool creation error
     #[fail(display = "failed to create thread pool")]
     ThreadPoolError,
+    /// Thread Pool created with zero threads
+    #[fail(display = "thread pool needs at least one thread")]
+    InvalidThreadCount,
+    /// The writer is held by another write or a compaction, or writer handles keep compaction and renames off
+    #[fail(display = "store is busy")]
+    Busy,
+    /// Thread Pool queue is full
+    #[fail(display = "thread pool queue is full")]
+    ThreadPoolFull,
 }
 
 impl From<io::Error> for KvsError {
@@ -41,6 +122,18 @@ impl From<serde_json::error::Error> for KvsError {
     }
 }
 
+impl From<std::num::ParseIntError> for KvsError {
+    fn from(err: std::num::ParseIntError) -> Self {
+        KvsError::ParseError(err.to_string())
+    }
+}
+
+impl From<std::num::ParseFloatError> for KvsError {
+    fn from(err: std::num::ParseFloatError) -> Self {
+        KvsError::ParseError(err.to_string())
+    }
+}
+
 impl From<sled::Error> for KvsError {
     fn from(err: sled::Error) -> Self {
         KvsError::SledError(err)
@@ -9,6 +9,20 @@ mod error;
 mod net;
 pub mod thread_pool;
 
-pub use engine::{EngineType, KvStore, KvsEngine, SledKvsEngine};
+#[cfg(feature = "tokio")]
+pub use engine::AsyncKvStore;
+pub use engine::{
+    engines_equal, export, import, CachingEngine, CompactionProgress, Durability, EngineType, FsStorage, IndexType,
+    KeyValidator, KvStore, KvStoreOptions, KvsEngine, MemoryStorage, MergeOperator, NamespacedStore, SegmentInfo,
+    SegmentStat, ShardHash, ShardedKvStore, SledKvsEngine, SledOptions, Storage, StorageReader, StorageWriter,
+    WriterHandle,
+};
+#[cfg(feature = "hdrhistogram")]
+pub use engine::{LatencyReport, OpLatency};
 pub use error::{KvsError, Result};
-pub use net::{KvsClient, KvsServer};
+#[cfg(feature = "test-util")]
+pub use net::TestServer;
+pub use net::{
+    AdminCmd, AdminResult, BatchingKvsClient, ChangeEvent, ChangeOp, ChangeStream, Command, CommandPolicy, KvsClient,
+    KvsClientPool, KvsServer, ServerConfig, PROTOCOL_VERSION,
+};
@@ -0,0 +1,107 @@
+use std::mem;
+use std::time::{Duration, Instant};
+
+use log::error;
+
+use crate::net::{KvsClient, Query};
+use crate::Result;
+
+/// A client buffering writes and sending them to the server in batches
+///
+/// `set` and `remove` only queue the write until `capacity` writes are queued, or
+/// the oldest one has waited `max_delay`, at which point the whole queue is sent as
+/// one batch. `flush` sends the queue right away, and dropping the client flushes it
+/// too, logging any error.
+///
+/// A queued write is applied by the server only once it is flushed, so reads through
+/// the inner client do not see it before. Errors of a write, such as removing a
+/// missing key, are returned by the call that flushed it.
+///
+/// ```
+/// use kvs::{BatchingKvsClient, KvStore, KvStoreOptions, KvsClient, MemoryStorage, TestServer};
+///
+/// let engine = KvStore::open_with_storage(MemoryStorage::new(), KvStoreOptions::new()).unwrap();
+/// let server = TestServer::new(engine).unwrap();
+/// let mut client = BatchingKvsClient::new(KvsClient::init(&server.addr()).unwrap(), 100);
+/// client.set("key".to_owned(), "value".to_owned()).unwrap();
+/// client.flush().unwrap();
+/// ```
+pub struct BatchingKvsClient {
+    client: KvsClient,
+    capacity: usize,
+    max_delay: Option<Duration>,
+    queued: Vec<Query>,
+    /// when the oldest queued write was queued
+    oldest: Option<Instant>,
+}
+
+impl BatchingKvsClient {
+    /// buffer up to `capacity` writes before sending them, at least 1
+    pub fn new(client: KvsClient, capacity: usize) -> Self {
+        Self {
+            client,
+            capacity: capacity.max(1),
+            max_delay: None,
+            queued: Vec::new(),
+            oldest: None,
+        }
+    }
+
+    /// also send the queue on the next write once its oldest write has waited `delay`
+    ///
+    /// there is no timer, so an idle client keeps its queue until it is flushed or dropped.
+    pub fn max_delay(mut self, delay: Duration) -> Self {
+        self.max_delay = Some(delay);
+        self
+    }
+
+    /// queue a key-value pair to set
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.queue(Query::Set(key, value))
+    }
+
+    /// queue a key to remove
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.queue(Query::Rm(key))
+    }
+
+    /// number of writes queued
+    pub fn queued(&self) -> usize {
+        self.queued.len()
+    }
+
+    /// send every queued write, return the error of the first one that failed
+    pub fn flush(&mut self) -> Result<()> {
+        self.oldest = None;
+        if self.queued.is_empty() {
+            return Ok(());
+        }
+        let queued = mem::take(&mut self.queued);
+        self.client.batch(queued)
+    }
+
+    /// the inner client, e.g. to read back flushed writes
+    pub fn client(&mut self) -> &mut KvsClient {
+        &mut self.client
+    }
+
+    fn queue(&mut self, query: Query) -> Result<()> {
+        self.queued.push(query);
+        let oldest = *self.oldest.get_or_insert_with(Instant::now);
+        let expired = self.max_delay.is_some_and(|delay| oldest.elapsed() >= delay);
+        if self.queued.len() >= self.capacity || expired {
+            self.flush()
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Drop for BatchingKvsClient {
+    fn drop(&mut self) {
+        let queued = self.queued.len();
+        if let Err(e) = self.flush() {
+            error!("failed to flush {} queued writes on drop: {}", queued, e);
+        }
+    }
+}
@@ -1,70 +1,582 @@
-use std::io::{Read, Write};
-use std::net::{SocketAddr, TcpStream};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
 
-use crate::net::{Query, Response};
+use crate::net::{
+    AdminCmd, AdminResult, ChangeEvent, Query, Response, BATCH_PROTOCOL_VERSION, BYTES_PROTOCOL_VERSION,
+    COUNT_PREFIX_PROTOCOL_VERSION, GET_SET_PROTOCOL_VERSION, IDEMPOTENT_PROTOCOL_VERSION, PROTOCOL_VERSION,
+    SUBSCRIBE_PROTOCOL_VERSION, SYNC_PROTOCOL_VERSION, TRACED_PROTOCOL_VERSION, VERSIONED_PROTOCOL_VERSION,
+};
 use crate::{KvsError, Result};
 
 /// A TCP client to interact with key-value server
+///
+/// All queries of a client are sent over the same connection.
+///
+/// Queries may be tagged with request IDs, see `trace_requests`.
 pub struct KvsClient {
+    addr: SocketAddr,
     stream: TcpStream,
+    buffer: Vec<u8>,
+    reconnect: bool,
+    /// protocol version agreed on with the server
+    version: u8,
+    /// ID of the next generated request ID, if requests are traced
+    trace: Option<u64>,
+    /// ID set for the next query with `set_request_id`
+    next_request_id: Option<u64>,
+    /// ID the last query was sent with, if traced
+    last_request_id: Option<u64>,
 }
 
 impl KvsClient {
     /// initiate a connection to remote socket
+    ///
+    /// return `KvsError::VersionMismatch` if the server does not speak this client's protocol.
     pub fn init(addr: &SocketAddr) -> Result<Self> {
-        let stream = TcpStream::connect(addr)?;
-        Ok(Self { stream })
+        Self::connect(addr, false)
     }
 
-    /// query value from server for the given key
-    pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        let query = Query::Get(key);
-        self.send(query)?;
+    /// initiate a connection to remote socket, reconnecting whenever the connection breaks
+    ///
+    /// a query failing because the connection was closed or reset, e.g. by a server
+    /// restart, reconnects once. `get`, `get_to` and `admin` are then retried, as running
+    /// them twice does no harm, and so are `set_idempotent` and `remove_idempotent`, which
+    /// the server applies once per token unless it restarted in between. `set` and `rm`
+    /// are not: the server may have applied them before the connection broke, and a
+    /// concurrent write in between would be overwritten by a retried `set`, or turn a
+    /// retried `rm` into `KeyNotFound`. they return the error, and the next query goes
+    /// over the new connection. the error is returned if reconnecting fails too.
+    pub fn init_with_reconnect(addr: &SocketAddr) -> Result<Self> {
+        Self::connect(addr, true)
+    }
 
+    fn connect(addr: &SocketAddr, reconnect: bool) -> Result<Self> {
+        let mut client = Self {
+            addr: *addr,
+            stream: TcpStream::connect(addr)?,
+            buffer: Vec::new(),
+            reconnect,
+            version: PROTOCOL_VERSION,
+            trace: None,
+            next_request_id: None,
+            last_request_id: None,
+        };
+        client.handshake()?;
+        Ok(client)
+    }
+
+    fn handshake(&mut self) -> Result<()> {
+        self.stream.write_all(&[PROTOCOL_VERSION])?;
         match self.receive()? {
+            Response::Version(version) => {
+                self.version = version;
+                Ok(())
+            }
+            Response::VersionMismatch(server) => Err(KvsError::VersionMismatch {
+                client: PROTOCOL_VERSION,
+                server,
+            }),
+            response => Err(unexpected("handshake", &response)),
+        }
+    }
+
+    /// tag every query with a request ID, or stop doing so
+    ///
+    /// the server logs the ID along with the query and echoes it in its response, and a
+    /// query that fails returns `KvsError::Request` holding it, so the failure can be
+    /// found in the logs of the server. IDs are generated by the client, counting up from
+    /// a random one. an absent key or a forbidden command is an answer rather than a
+    /// failure, and still returns `KeyNotFound` or `Forbidden`. servers too old to trace
+    /// queries are sent them untraced.
+    pub fn trace_requests(&mut self, enabled: bool) {
+        self.trace = if enabled {
+            Some(self.trace.unwrap_or_else(random_request_id))
+        } else {
+            None
+        };
+    }
+
+    /// tag the next query with `id`, e.g. one propagated from a caller, whether or not
+    /// requests are traced
+    pub fn set_request_id(&mut self, id: u64) {
+        self.next_request_id = Some(id);
+    }
+
+    /// ID the last query was sent with, `None` if it was not traced
+    pub fn last_request_id(&self) -> Option<u64> {
+        self.last_request_id
+    }
+
+    /// query value from server for the given key
+    ///
+    /// return `Ok(None)` if the key does not exist.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        let response = self.query(&Query::Get(key), true);
+        let result = response.and_then(|response| match response {
             Response::Ok(val) => Ok(val),
-            Response::KeyNotFound => Err(KvsError::KeyNotFound),
+            Response::KeyNotFound => Ok(None),
             Response::Err => Err(KvsError::ServerError),
-            _ => unreachable!(),
-        }
+            response => Err(unexpected("get", &response)),
+        });
+        self.traced(result)
+    }
+
+    /// query value from server for the given key and write it into `sink`
+    ///
+    /// unlike `get`, the value is copied to `sink` in chunks as it arrives instead of
+    /// being buffered whole. return `Ok(false)` if the key does not exist. if writing to
+    /// `sink` fails, the rest of the value is still read off the connection, so the client
+    /// stays usable; if reading it fails, the connection is shut down.
+    pub fn get_to<W: Write>(&mut self, key: String, mut sink: W) -> Result<bool> {
+        let response = self.query(&Query::GetRaw(key), true);
+        let result = response.and_then(|response| match response {
+            Response::Raw(Some(len)) => {
+                let mut raw = (&self.stream).take(len);
+                let copied = io::copy(&mut raw, &mut sink);
+                // skip whatever the sink did not take, the next response follows it
+                let drained = io::copy(&mut raw, &mut io::sink());
+                if drained.is_err() || raw.limit() > 0 {
+                    let _ = self.stream.shutdown(Shutdown::Both);
+                }
+                copied?;
+                drained?;
+                if raw.limit() > 0 {
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+                }
+                Ok(true)
+            }
+            Response::Raw(None) => Ok(false),
+            Response::Err => Err(KvsError::ServerError),
+            response => Err(unexpected("get_raw", &response)),
+        });
+        self.traced(result)
     }
 
     /// set key value pair to server
     pub fn set(&mut self, key: String, val: String) -> Result<()> {
-        let query = Query::Set(key, val);
-        self.send(query)?;
-        match self.receive()? {
+        let response = self.query(&Query::Set(key, val), false);
+        let result = response.and_then(|response| match response {
             Response::Success => Ok(()),
             Response::Err => Err(KvsError::ServerError),
-            _ => unreachable!(),
-        }
+            response => Err(unexpected("set", &response)),
+        });
+        self.traced(result)
     }
 
     /// remove key-value pair from server for the given key
     pub fn remove(&mut self, key: String) -> Result<()> {
-        let query = Query::Rm(key);
-        self.send(query)?;
-        match self.receive()? {
+        let response = self.query(&Query::Rm(key), false);
+        let result = response.and_then(|response| match response {
             Response::Success => Ok(()),
             Response::KeyNotFound => Err(KvsError::KeyNotFound),
             Response::Err => Err(KvsError::ServerError),
-            _ => unreachable!(),
+            response => Err(unexpected("rm", &response)),
+        });
+        self.traced(result)
+    }
+
+    /// set key value pair to server, at most once for a given `token`
+    ///
+    /// the server answers a repeated token with the response to its first write instead of
+    /// writing again, as long as it remembers the token, see
+    /// [`ServerConfig::idempotency_window`](struct.ServerConfig.html#method.idempotency_window).
+    /// so unlike `set`, it is retried over a new connection if the connection broke. each
+    /// write should get a token of its own, e.g. a random one, reused only to retry it.
+    /// return `KvsError::ProtocolError` if the server is too old for idempotent writes.
+    pub fn set_idempotent(&mut self, key: String, val: String, token: u64) -> Result<()> {
+        self.require(IDEMPOTENT_PROTOCOL_VERSION, "idempotent writes")?;
+        let query = Query::Idempotent(token, Box::new(Query::Set(key, val)));
+        let response = self.query(&query, true);
+        let result = response.and_then(|response| match response {
+            Response::Success => Ok(()),
+            Response::Err => Err(KvsError::ServerError),
+            response => Err(unexpected("set", &response)),
+        });
+        self.traced(result)
+    }
+
+    /// remove key-value pair from server for the given key, at most once for a given `token`
+    ///
+    /// see `set_idempotent`. a repeated token is answered like the first removal, even if
+    /// the key was set again in between.
+    pub fn remove_idempotent(&mut self, key: String, token: u64) -> Result<()> {
+        self.require(IDEMPOTENT_PROTOCOL_VERSION, "idempotent writes")?;
+        let query = Query::Idempotent(token, Box::new(Query::Rm(key)));
+        let response = self.query(&query, true);
+        let result = response.and_then(|response| match response {
+            Response::Success => Ok(()),
+            Response::KeyNotFound => Err(KvsError::KeyNotFound),
+            Response::Err => Err(KvsError::ServerError),
+            response => Err(unexpected("rm", &response)),
+        });
+        self.traced(result)
+    }
+
+    /// query the value of the key along with its version, for a later `set_if_version`
+    ///
+    /// return `Ok(None)` if the key does not exist, see
+    /// [`KvsEngine::get_versioned`](trait.KvsEngine.html#tymethod.get_versioned). return
+    /// `KvsError::ProtocolError` if the server is too old to version values.
+    pub fn get_versioned(&mut self, key: String) -> Result<Option<(String, u64)>> {
+        self.require(VERSIONED_PROTOCOL_VERSION, "versioned reads")?;
+        let response = self.query(&Query::GetVersioned(key), true);
+        let result = response.and_then(|response| match response {
+            Response::Versioned(versioned) => Ok(versioned),
+            Response::Err => Err(KvsError::ServerError),
+            response => Err(unexpected("get_versioned", &response)),
+        });
+        self.traced(result)
+    }
+
+    /// set key value pair only if the version of the key on the server is still `expected`
+    ///
+    /// the server compares and writes atomically, so a read-modify-write retried until
+    /// this returns `Ok(true)` needs no lock. `expected` is 0 to only set an absent key.
+    /// return `KvsError::ProtocolError` if the server is too old to version values.
+    pub fn set_if_version(&mut self, key: String, val: String, expected: u64) -> Result<bool> {
+        self.require(VERSIONED_PROTOCOL_VERSION, "conditional writes")?;
+        let response = self.query(&Query::SetIfVersion(key, val, expected), false);
+        let result = response.and_then(|response| match response {
+            Response::Bool(set) => Ok(set),
+            Response::Err => Err(KvsError::ServerError),
+            response => Err(unexpected("set_if_version", &response)),
+        });
+        self.traced(result)
+    }
+
+    /// set key value pair to server and return the value it replaced, `None` if the key did
+    /// not exist
+    ///
+    /// the server swaps the value atomically, in a single round trip. return
+    /// `KvsError::ProtocolError` if the server is too old for it.
+    pub fn get_set(&mut self, key: String, val: String) -> Result<Option<String>> {
+        self.require(GET_SET_PROTOCOL_VERSION, "swapping values")?;
+        let response = self.query(&Query::GetSet(key, val), false);
+        let result = response.and_then(|response| match response {
+            Response::Ok(old) => Ok(old),
+            Response::Err => Err(KvsError::ServerError),
+            response => Err(unexpected("get_set", &response)),
+        });
+        self.traced(result)
+    }
+
+    /// query the value of the key as bytes, e.g. one set by `set_bytes`
+    ///
+    /// a value set as a string is returned as its UTF-8 bytes. return `Ok(None)` if the
+    /// key does not exist, and `KvsError::ProtocolError` if the server is too old for
+    /// binary values.
+    pub fn get_bytes(&mut self, key: String) -> Result<Option<Vec<u8>>> {
+        self.require(BYTES_PROTOCOL_VERSION, "binary values")?;
+        let response = self.query(&Query::GetBytes(key), true);
+        let result = response.and_then(|response| match response {
+            Response::Bytes(val) => Ok(val),
+            Response::Err => Err(KvsError::ServerError),
+            response => Err(unexpected("get_bytes", &response)),
+        });
+        self.traced(result)
+    }
+
+    /// set the key to a value of arbitrary bytes
+    ///
+    /// the engines of the server only store strings, so bytes that are not UTF-8 are
+    /// stored encoded, and `get` returns them encoded. read them with `get_bytes`. return
+    /// `KvsError::ProtocolError` if the server is too old for binary values.
+    pub fn set_bytes(&mut self, key: String, val: Vec<u8>) -> Result<()> {
+        self.require(BYTES_PROTOCOL_VERSION, "binary values")?;
+        let response = self.query(&Query::SetBytes(key, val), false);
+        let result = response.and_then(|response| match response {
+            Response::Success => Ok(()),
+            Response::Err => Err(KvsError::ServerError),
+            response => Err(unexpected("set_bytes", &response)),
+        });
+        self.traced(result)
+    }
+
+    /// count the keys of the server starting with `prefix`, without transferring them
+    ///
+    /// return `KvsError::ProtocolError` if the server is too old to count keys.
+    pub fn count_prefix(&mut self, prefix: String) -> Result<usize> {
+        self.require(COUNT_PREFIX_PROTOCOL_VERSION, "counting keys")?;
+        let response = self.query(&Query::CountPrefix(prefix), true);
+        let result = response.and_then(|response| match response {
+            Response::Count(count) => Ok(count),
+            Response::Err => Err(KvsError::ServerError),
+            response => Err(unexpected("count_prefix", &response)),
+        });
+        self.traced(result)
+    }
+
+    /// make every write the server applied so far durable, e.g. before snapshotting its disk
+    ///
+    /// returns once the engine has synced its files, see
+    /// [`KvsEngine::flush`](trait.KvsEngine.html#method.flush). unlike `AdminCmd::Flush`,
+    /// it is allowed on every server. writes applied after the server answered, by other
+    /// clients, may not be durable yet. return `KvsError::ProtocolError` if the server is
+    /// too old to sync.
+    pub fn sync(&mut self) -> Result<()> {
+        self.require(SYNC_PROTOCOL_VERSION, "syncing")?;
+        let response = self.query(&Query::Sync, true);
+        let result = response.and_then(|response| match response {
+            Response::Success => Ok(()),
+            Response::Err => Err(KvsError::ServerError),
+            response => Err(unexpected("sync", &response)),
+        });
+        self.traced(result)
+    }
+
+    /// run a maintenance command on the engine of the server
+    ///
+    /// return `KvsError::Forbidden` unless the server allows admin commands.
+    pub fn admin(&mut self, cmd: AdminCmd) -> Result<AdminResult> {
+        let response = self.query(&Query::Admin(cmd), true);
+        let result = response.and_then(|response| match response {
+            Response::AdminResult(result) => Ok(result),
+            Response::Err => Err(KvsError::ServerError),
+            response => Err(unexpected("admin", &response)),
+        });
+        self.traced(result)
+    }
+
+    /// close the connection, once the server is done with it
+    ///
+    /// the client stops writing, which the server reads as the end of its queries, and
+    /// waits for the server to close the connection in turn, so the worker serving it is
+    /// free again when this returns. dropping the client also closes the connection, but
+    /// without waiting for the server. return `KvsError::ProtocolError` if the server
+    /// sends anything more.
+    pub fn close(mut self) -> Result<()> {
+        self.stream.shutdown(Shutdown::Write)?;
+        let mut rest = Vec::new();
+        self.stream.read_to_end(&mut rest)?;
+        if rest.is_empty() {
+            Ok(())
+        } else {
+            Err(KvsError::ProtocolError(format!(
+                "{} bytes received after closing",
+                rest.len()
+            )))
         }
     }
 
-    fn send(&mut self, query: Query) -> Result<()> {
-        let serialized_query = serde_json::to_vec(&query)?;
-        self.stream.write_all(&(serialized_query.len() as u32).to_be_bytes())?;
-        self.stream.write_all(&serialized_query)?;
+    /// turn the connection into a stream of the writes the server applies from now on
+    ///
+    /// see [`ChangeStream`]. return `KvsError::ProtocolError` if the server is too old
+    /// to stream changes.
+    pub fn subscribe(mut self) -> Result<ChangeStream> {
+        self.require(SUBSCRIBE_PROTOCOL_VERSION, "subscribing")?;
+        let result = match self.query(&Query::Subscribe, true) {
+            Ok(Response::Success) => {
+                return Ok(ChangeStream {
+                    client: self,
+                    done: false,
+                })
+            }
+            Ok(Response::Err) => Err(KvsError::ServerError),
+            Ok(response) => Err(unexpected("subscribe", &response)),
+            Err(e) => Err(e),
+        };
+        self.traced(result)
+    }
+
+    /// fail with `KvsError::ProtocolError` unless the server speaks at least `version`
+    fn require(&self, version: u8, what: &str) -> Result<()> {
+        if self.version < version {
+            return Err(KvsError::ProtocolError(format!(
+                "server speaks protocol version {}, {} needs {}",
+                self.version, what, version
+            )));
+        }
+        Ok(())
+    }
+
+    /// apply `Set` and `Rm` queries in order, in a single round trip if the server speaks `Batch`
+    ///
+    /// fails with the error of the first query that failed, the others are applied regardless.
+    pub(super) fn batch(&mut self, queries: Vec<Query>) -> Result<()> {
+        if self.version < BATCH_PROTOCOL_VERSION {
+            return queries.into_iter().try_for_each(|query| match query {
+                Query::Set(key, val) => self.set(key, val),
+                Query::Rm(key) => self.remove(key),
+                query => Err(unexpected_query(&query)),
+            });
+        }
+        let len = queries.len();
+        let response = self.query(&Query::Batch(queries), false);
+        let result = response.and_then(|response| match response {
+            Response::Batch(responses) if responses.len() == len => {
+                responses.into_iter().try_for_each(|response| match response {
+                    Response::Success => Ok(()),
+                    Response::KeyNotFound => Err(KvsError::KeyNotFound),
+                    Response::Err => Err(KvsError::ServerError),
+                    response => Err(unexpected("batch", &response)),
+                })
+            }
+            Response::Err => Err(KvsError::ServerError),
+            response => Err(unexpected("batch", &response)),
+        });
+        self.traced(result)
+    }
+
+    /// send a query and receive its response, tagged with a request ID if traced
+    ///
+    /// a query the server refused fails with `KvsError::Forbidden`.
+    fn query(&mut self, query: &Query, retry: bool) -> Result<Response> {
+        self.last_request_id = self.request_id();
+        let response = match self.last_request_id {
+            Some(id) => match self.send_query(&Query::Traced(id, Box::new(query.clone())), retry)? {
+                Response::Traced(echoed, response) if echoed == id => *response,
+                response => return Err(unexpected("traced query", &response)),
+            },
+            None => self.send_query(query, retry)?,
+        };
+        match response {
+            Response::Forbidden => Err(KvsError::Forbidden),
+            response => Ok(response),
+        }
+    }
+
+    /// ID to tag the next query with, if it is traced
+    fn request_id(&mut self) -> Option<u64> {
+        let next = self.next_request_id.take();
+        if self.version < TRACED_PROTOCOL_VERSION {
+            return None;
+        }
+        if next.is_some() {
+            return next;
+        }
+        let id = self.trace?;
+        self.trace = Some(id.wrapping_add(1));
+        Some(id)
+    }
+
+    /// attach the request ID of the last query, if traced, to its failure
+    fn traced<T>(&self, result: Result<T>) -> Result<T> {
+        match (result, self.last_request_id) {
+            (Err(e @ KvsError::KeyNotFound), _) | (Err(e @ KvsError::Forbidden), _) => Err(e),
+            (Err(error), Some(id)) => Err(KvsError::Request {
+                id,
+                error: Box::new(error),
+            }),
+            (result, _) => result,
+        }
+    }
+
+    /// send a query and receive its response, reconnecting if the connection broke
+    fn send_query(&mut self, query: &Query, retry: bool) -> Result<Response> {
+        match self.send(query).and_then(|()| self.receive()) {
+            Err(KvsError::Io(e)) if self.reconnect && is_disconnect(&e) => {
+                self.stream = TcpStream::connect(self.addr)?;
+                self.handshake()?;
+                if retry {
+                    self.send(query)?;
+                    self.receive()
+                } else {
+                    Err(KvsError::Io(e))
+                }
+            }
+            result => result,
+        }
+    }
+
+    fn send(&mut self, query: &Query) -> Result<()> {
+        self.buffer.clear();
+        self.buffer.extend_from_slice(&[0; 4]);
+        serde_json::to_writer(&mut self.buffer, query)?;
+        let len = (self.buffer.len() - 4) as u32;
+        self.buffer[..4].copy_from_slice(&len.to_be_bytes());
+        self.stream.write_all(&self.buffer)?;
         Ok(())
     }
 
     fn receive(&mut self) -> Result<Response> {
+        self.receive_next()?
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof).into())
+    }
+
+    /// receive the next response, `Ok(None)` if the server closed the connection before it
+    fn receive_next(&mut self) -> Result<Option<Response>> {
         let mut msg_len = [0; 4];
-        self.stream.read_exact(&mut msg_len)?;
+        match self.stream.read_exact(&mut msg_len) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
         let len = u32::from_be_bytes(msg_len) as usize;
-        let mut msg = vec![0; len];
-        self.stream.read_exact(&mut msg)?;
-        serde_json::from_slice::<Response>(&msg).map_err(|e| e.into())
+        self.buffer.clear();
+        self.buffer.resize(len, 0);
+        self.stream.read_exact(&mut self.buffer)?;
+        Ok(Some(serde_json::from_slice::<Response>(&self.buffer)?))
     }
 }
+
+/// The writes applied by a server, as they are applied
+///
+/// Returned by `KvsClient::subscribe`, it yields an event for each `set` and `rm` the
+/// server applies, in the order of their sequence numbers, and ends when the server
+/// closes the connection or after the first error.
+///
+/// The server never waits for a subscriber: events are queued for each one, and a
+/// subscriber not reading fast enough, e.g. one printing to a slow terminal, falls behind
+/// until the server drops it. The stream then ends once the events queued so far are
+/// read, and the client may subscribe again.
+///
+/// ```
+/// use kvs::{ChangeOp, KvStore, KvStoreOptions, KvsClient, MemoryStorage, TestServer};
+///
+/// let engine = KvStore::open_with_storage(MemoryStorage::new(), KvStoreOptions::new()).unwrap();
+/// let server = TestServer::new(engine).unwrap();
+/// let mut changes = KvsClient::init(&server.addr()).unwrap().subscribe().unwrap();
+/// let mut client = KvsClient::init(&server.addr()).unwrap();
+/// client.set("key".to_owned(), "value".to_owned()).unwrap();
+/// let event = changes.next().unwrap().unwrap();
+/// assert_eq!((event.op, event.key.as_str()), (ChangeOp::Set, "key"));
+/// ```
+pub struct ChangeStream {
+    client: KvsClient,
+    done: bool,
+}
+
+impl Iterator for ChangeStream {
+    type Item = Result<ChangeEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let event = match self.client.receive_next() {
+            Ok(Some(Response::Change(event))) => Some(Ok(event)),
+            Ok(Some(response)) => Some(Err(unexpected("subscribe", &response))),
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        };
+        self.done = !matches!(event, Some(Ok(_)));
+        event
+    }
+}
+
+/// whether the error means the server closed or reset the connection
+fn is_disconnect(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::BrokenPipe
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::NotConnected
+            | io::ErrorKind::UnexpectedEof
+    )
+}
+
+/// random first request ID, so that clients tracing requests hardly ever share IDs
+fn random_request_id() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+fn unexpected_query(query: &Query) -> KvsError {
+    KvsError::ProtocolError(format!("{} cannot be batched", query.describe().0))
+}
+
+fn unexpected(query: &str, response: &Response) -> KvsError {
+    KvsError::ProtocolError(format!("unexpected response to {}: {:?}", query, response))
+}
@@ -0,0 +1,239 @@
+use std::collections::HashSet;
+use std::time::Duration;
+
+use serde::de::{self, Deserializer, Unexpected};
+use serde::Deserialize;
+
+use crate::net::{Command, Query};
+
+/// how long a query may take before it is logged as slow by default
+const SLOW_QUERY_THRESHOLD: Duration = Duration::from_millis(100);
+/// idempotency tokens remembered by default
+const IDEMPOTENCY_WINDOW: usize = 1024;
+/// connections waiting to be accepted by default, as `TcpListener::bind` allows
+const LISTEN_BACKLOG: u32 = 128;
+
+/// Options to configure a `KvsServer`
+///
+/// Examples:
+/// ```rust
+/// use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+/// use kvs::{KvStore, KvsServer, MemoryStorage, KvStoreOptions, ServerConfig};
+///
+/// let engine = KvStore::open_with_storage(MemoryStorage::new(), KvStoreOptions::new()).unwrap();
+/// let pool = SharedQueueThreadPool::new(4).unwrap();
+/// let config = ServerConfig::new().warm_up(true);
+/// let server = KvsServer::init_with_config(engine, "127.0.0.1:4000".parse().unwrap(), pool, config).unwrap();
+/// ```
+///
+/// It can also be read from a config file, every field being optional. Durations are in
+/// milliseconds, 0 disabling them, and `commands` lists the allowed commands:
+/// ```rust
+/// use kvs::ServerConfig;
+///
+/// let config: ServerConfig = serde_json::from_str(
+///     r#"{"warm_up": true, "slow_query_ms": 50, "commands": ["Get", {"Admin": "Stats"}]}"#,
+/// )
+/// .unwrap();
+/// ```
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub(crate) warm_up: bool,
+    pub(crate) allow_admin: bool,
+    #[serde(rename = "slow_query_ms", deserialize_with = "millis")]
+    pub(crate) slow_query: Option<Duration>,
+    #[serde(deserialize_with = "at_least_one")]
+    pub(crate) idempotency_window: usize,
+    pub(crate) commands: CommandPolicy,
+    #[serde(rename = "idle_timeout_ms", deserialize_with = "millis")]
+    pub(crate) idle_timeout: Option<Duration>,
+    pub(crate) reuse_address: bool,
+    pub(crate) listen_backlog: u32,
+}
+
+/// read a duration given in milliseconds, 0 for none
+fn millis<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+    let millis = u64::deserialize(deserializer)?;
+    Ok(Some(Duration::from_millis(millis)).filter(|duration| !duration.is_zero()))
+}
+
+/// read a count which must be at least 1
+fn at_least_one<'de, D: Deserializer<'de>>(deserializer: D) -> Result<usize, D::Error> {
+    match usize::deserialize(deserializer)? {
+        0 => Err(de::Error::invalid_value(Unexpected::Unsigned(0), &"at least 1")),
+        value => Ok(value),
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            warm_up: false,
+            allow_admin: false,
+            slow_query: Some(SLOW_QUERY_THRESHOLD),
+            idempotency_window: IDEMPOTENCY_WINDOW,
+            commands: CommandPolicy::default(),
+            idle_timeout: None,
+            reuse_address: true,
+            listen_backlog: LISTEN_BACKLOG,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// create a config with default values
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// set whether the engine is warmed up before the server starts.
+    ///
+    /// see [`KvsEngine::warm_up`](trait.KvsEngine.html#method.warm_up). default is false.
+    pub fn warm_up(mut self, warm_up: bool) -> Self {
+        self.warm_up = warm_up;
+        self
+    }
+
+    /// set whether clients may run `AdminCmd`s such as compaction.
+    ///
+    /// refused commands fail with `KvsError::Forbidden`. default is false.
+    pub fn allow_admin(mut self, allow: bool) -> Self {
+        self.allow_admin = allow;
+        self
+    }
+
+    /// set how long handling a query may take before a warning is logged with its key.
+    ///
+    /// `None` disables the slow query log. default is 100ms.
+    pub fn slow_query_threshold(mut self, threshold: Option<Duration>) -> Self {
+        self.slow_query = threshold;
+        self
+    }
+
+    /// set how many idempotency tokens the server remembers the response of, at least 1.
+    ///
+    /// a write sent with a token, e.g. by
+    /// [`KvsClient::set_idempotent`](struct.KvsClient.html#method.set_idempotent), is answered
+    /// with the response to its first application as long as its token is among the most
+    /// recently used ones, instead of being applied again. tokens are kept in memory, so a
+    /// restarted server forgets them. default is 1024.
+    pub fn idempotency_window(mut self, tokens: usize) -> Self {
+        self.idempotency_window = tokens.max(1);
+        self
+    }
+
+    /// set how long a connection may wait for its next query before the server closes it.
+    ///
+    /// a connection keeps a worker of the server busy until it is closed, so an idle
+    /// timeout stops idle clients from holding workers other connections wait for. a
+    /// subscribed connection only receives, and is never closed for being idle. `None`
+    /// or zero disables the timeout. default is disabled.
+    pub fn idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.idle_timeout = timeout.filter(|timeout| !timeout.is_zero());
+        self
+    }
+
+    /// set whether the listeners bind with `SO_REUSEADDR`.
+    ///
+    /// a restarted server can then bind its address while connections of the previous
+    /// one linger in `TIME_WAIT`, instead of failing with "address already in use". on
+    /// Windows, the option also lets other sockets bind the same address. default is true.
+    pub fn reuse_address(mut self, enabled: bool) -> Self {
+        self.reuse_address = enabled;
+        self
+    }
+
+    /// set how many connections may wait to be accepted by each listener.
+    ///
+    /// the OS may cap it, e.g. at `net.core.somaxconn` on Linux, and refuses or drops
+    /// the connections beyond it. default is 128.
+    pub fn listen_backlog(mut self, backlog: u32) -> Self {
+        self.listen_backlog = backlog;
+        self
+    }
+
+    /// set which commands clients may send, whatever the engine allows.
+    ///
+    /// refused commands fail with `KvsError::Forbidden`. default allows every command.
+    pub fn command_policy(mut self, policy: CommandPolicy) -> Self {
+        self.commands = policy;
+        self
+    }
+}
+
+/// Commands a `KvsServer` accepts, the others being answered as forbidden
+///
+/// A batch is refused if any of its queries is. Admin commands also need
+/// `ServerConfig::allow_admin`.
+///
+/// Examples:
+/// ```rust
+/// use kvs::{AdminCmd, Command, CommandPolicy, ServerConfig};
+///
+/// // a public read replica, whose statistics may be collected
+/// let policy = CommandPolicy::read_only().allow(Command::Admin(AdminCmd::Stats));
+/// let config = ServerConfig::new().allow_admin(true).command_policy(policy);
+/// ```
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(transparent)]
+pub struct CommandPolicy {
+    /// `None` to allow every command
+    allowed: Option<HashSet<Command>>,
+}
+
+impl CommandPolicy {
+    /// allow every command
+    pub fn allow_all() -> Self {
+        Self::default()
+    }
+
+    /// allow only the given commands
+    pub fn only<I: IntoIterator<Item = Command>>(commands: I) -> Self {
+        Self {
+            allowed: Some(commands.into_iter().collect()),
+        }
+    }
+
+    /// allow only the commands leaving the data as it is: reads, counts, subscriptions and syncs
+    pub fn read_only() -> Self {
+        Self::only(vec![
+            Command::Get,
+            Command::GetVersioned,
+            Command::CountPrefix,
+            Command::Subscribe,
+            Command::Sync,
+        ])
+    }
+
+    /// allow one more command
+    pub fn allow(mut self, command: Command) -> Self {
+        if let Some(allowed) = self.allowed.as_mut() {
+            allowed.insert(command);
+        }
+        self
+    }
+
+    /// whether the query may be processed
+    pub(super) fn allows(&self, query: &Query) -> bool {
+        let allowed = match self.allowed.as_ref() {
+            Some(allowed) => allowed,
+            None => return true,
+        };
+        let command = match query {
+            Query::Get(_) | Query::GetRaw(_) | Query::GetBytes(_) => Command::Get,
+            Query::GetVersioned(_) => Command::GetVersioned,
+            Query::CountPrefix(_) => Command::CountPrefix,
+            Query::Subscribe => Command::Subscribe,
+            Query::Sync => Command::Sync,
+            Query::Set(..) | Query::SetBytes(..) => Command::Set,
+            Query::Rm(_) => Command::Rm,
+            Query::SetIfVersion(..) => Command::SetIfVersion,
+            Query::GetSet(..) => Command::GetSet,
+            Query::Admin(cmd) => Command::Admin(*cmd),
+            Query::Batch(queries) => return queries.iter().all(|query| self.allows(query)),
+            Query::Traced(_, query) | Query::Idempotent(_, query) => return self.allows(query),
+        };
+        allowed.contains(&command)
+    }
+}
@@ -0,0 +1,57 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use crossbeam::channel::{self, Receiver, Sender, TrySendError};
+use log::warn;
+
+use crate::net::{ChangeEvent, ChangeOp};
+
+/// events a subscriber may lag behind before it is dropped
+pub(crate) const SUBSCRIBER_QUEUE: usize = 1024;
+
+/// Writes applied by a server, pushed to every subscribed connection
+///
+/// Each subscriber has a queue of its own, so a write never waits for a slow
+/// subscriber. One that falls `SUBSCRIBER_QUEUE` events behind is dropped instead,
+/// which ends its stream.
+#[derive(Default)]
+pub(crate) struct ChangeFeed {
+    /// last sequence number taken by an event
+    seq: AtomicU64,
+    subscribers: Mutex<Vec<Sender<ChangeEvent>>>,
+}
+
+impl ChangeFeed {
+    /// receive every event published from now on
+    pub(crate) fn subscribe(&self) -> Receiver<ChangeEvent> {
+        let (tx, rx) = channel::bounded(SUBSCRIBER_QUEUE);
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    /// push a write to every subscriber, dropping those that are gone or too far behind
+    pub(crate) fn publish(&self, op: ChangeOp, key: &str) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        if subscribers.is_empty() {
+            return;
+        }
+        let event = ChangeEvent {
+            seq: self.seq.fetch_add(1, Ordering::SeqCst) + 1,
+            op,
+            key: key.to_owned(),
+        };
+        subscribers.retain(|subscriber| match subscriber.try_send(event.clone()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                warn!("dropping a subscriber {} events behind", SUBSCRIBER_QUEUE);
+                false
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        });
+    }
+
+    /// end the stream of every subscriber, e.g. when the server stops
+    pub(crate) fn close(&self) {
+        self.subscribers.lock().unwrap().clear();
+    }
+}
@@ -1,22 +1,299 @@
+mod batch;
 mod client;
+mod config;
+mod feed;
+mod pool;
 mod server;
+#[cfg(feature = "test-util")]
+mod test_server;
 
-pub use client::KvsClient;
+pub use batch::BatchingKvsClient;
+pub use client::{ChangeStream, KvsClient};
+pub use config::{CommandPolicy, ServerConfig};
+pub use pool::KvsClientPool;
 pub use server::KvsServer;
+#[cfg(feature = "test-util")]
+pub use test_server::TestServer;
+
+use std::convert::TryFrom;
+use std::fmt;
 
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize)]
+/// Version of the protocol spoken by this crate
+///
+/// A client sends it as a single byte right after connecting. The server answers
+/// with a `Version` response holding the version both sides will speak, or with
+/// `VersionMismatch` holding its own version and closes the connection.
+///
+/// Version 2 added `Batch` queries, version 3 `Subscribe` queries, version 4
+/// `CountPrefix` queries, version 5 `Sync` queries, version 6 `GetVersioned` and
+/// `SetIfVersion` queries, version 7 `Traced` queries, version 8 `Idempotent` queries,
+/// version 9 `GetSet` queries and version 10 `GetBytes` and `SetBytes` queries.
+pub const PROTOCOL_VERSION: u8 = 10;
+
+/// First version of the protocol with `Batch` queries
+const BATCH_PROTOCOL_VERSION: u8 = 2;
+
+/// First version of the protocol with `Subscribe` queries
+const SUBSCRIBE_PROTOCOL_VERSION: u8 = 3;
+
+/// First version of the protocol with `CountPrefix` queries
+const COUNT_PREFIX_PROTOCOL_VERSION: u8 = 4;
+
+/// First version of the protocol with `Sync` queries
+const SYNC_PROTOCOL_VERSION: u8 = 5;
+
+/// First version of the protocol with `GetVersioned` and `SetIfVersion` queries
+const VERSIONED_PROTOCOL_VERSION: u8 = 6;
+
+/// First version of the protocol with `Traced` queries
+const TRACED_PROTOCOL_VERSION: u8 = 7;
+
+/// First version of the protocol with `Idempotent` queries
+const IDEMPOTENT_PROTOCOL_VERSION: u8 = 8;
+
+/// First version of the protocol with `GetSet` queries
+const GET_SET_PROTOCOL_VERSION: u8 = 9;
+
+/// First version of the protocol with `GetBytes` and `SetBytes` queries
+const BYTES_PROTOCOL_VERSION: u8 = 10;
+
+/// Oldest version of the protocol a server still speaks
+const MIN_PROTOCOL_VERSION: u8 = 1;
+
+/// version to speak with a client of the given version, if the server understands it
+fn negotiate(client: u8) -> Option<u8> {
+    if client < MIN_PROTOCOL_VERSION {
+        None
+    } else {
+        Some(client.min(PROTOCOL_VERSION))
+    }
+}
+
+/// Maintenance command run on the engine of a server
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum AdminCmd {
+    /// make every write so far durable
+    Flush,
+    /// compact the engine
+    Compact,
+    /// report statistics of the engine
+    Stats,
+}
+
+/// Kind of query a `CommandPolicy` allows, named after the client method sending it
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Deserialize)]
+pub enum Command {
+    /// read a value, with `get`, `get_to` or `get_bytes`
+    Get,
+    /// read a value along with its version
+    GetVersioned,
+    /// count the keys starting with a prefix
+    CountPrefix,
+    /// stream the writes of the server
+    Subscribe,
+    /// make the writes so far durable
+    Sync,
+    /// set a value, with `set` or `set_bytes`, alone or in a batch
+    Set,
+    /// remove a key, alone or in a batch
+    Rm,
+    /// set a value if its version matches
+    SetIfVersion,
+    /// swap a value
+    GetSet,
+    /// run a maintenance command, which the server must allow admin commands for too
+    Admin(AdminCmd),
+}
+
+/// Result of an `AdminCmd`
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AdminResult {
+    /// the command completed
+    Done,
+    /// statistics of the engine
+    Stats {
+        /// number of keys in the engine
+        keys: usize,
+        /// connections waiting for a worker of the server, 0 from servers before it was reported
+        #[serde(default)]
+        queue_depth: usize,
+    },
+}
+
+/// Kind of write reported by a `ChangeEvent`
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChangeOp {
+    /// the key was set
+    Set,
+    /// the key was removed
+    Rm,
+}
+
+/// Write applied by a server, streamed to subscribed clients
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChangeEvent {
+    /// position of the event in the feed of the server, starting at 1
+    pub seq: u64,
+    /// kind of write
+    pub op: ChangeOp,
+    /// key written
+    pub key: String,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 enum Query {
     Get(String),
+    GetRaw(String),
     Set(String, String),
     Rm(String),
+    Admin(AdminCmd),
+    /// `Set` and `Rm` queries applied in order, answered with one response each
+    Batch(Vec<Query>),
+    /// turn the connection into a stream of `Change` responses, one per write of the server
+    Subscribe,
+    /// number of keys starting with the prefix, answered with `Count`
+    CountPrefix(String),
+    /// make every write so far durable, answered with `Success` once it is
+    Sync,
+    /// value of the key along with its version, answered with `Versioned`
+    GetVersioned(String),
+    /// set the key only if its version is the expected one, answered with `Bool`
+    SetIfVersion(String, String, u64),
+    /// query tagged with a request ID, logged by the server and echoed in a `Traced` response
+    Traced(u64, Box<Query>),
+    /// `Set` or `Rm` tagged with a token, answered as the first query with the same token
+    /// if the server still remembers it instead of being applied again
+    Idempotent(u64, Box<Query>),
+    /// set the key and answer with `Ok` holding the value it replaced, atomically
+    GetSet(String, String),
+    /// value of the key as bytes, answered with `Bytes`
+    GetBytes(String),
+    /// set the key to a value of arbitrary bytes
+    SetBytes(String, Vec<u8>),
 }
 
-#[derive(Serialize, Deserialize)]
+impl Query {
+    /// name of the operation and length of the key, or number of queries of a batch, for instrumentation
+    fn describe(&self) -> (&'static str, usize) {
+        match self {
+            Query::Get(key) => ("get", key.len()),
+            Query::GetRaw(key) => ("get_raw", key.len()),
+            Query::Set(key, _) => ("set", key.len()),
+            Query::Rm(key) => ("rm", key.len()),
+            Query::Admin(_) => ("admin", 0),
+            Query::Batch(queries) => ("batch", queries.len()),
+            Query::Subscribe => ("subscribe", 0),
+            Query::CountPrefix(prefix) => ("count_prefix", prefix.len()),
+            Query::Sync => ("sync", 0),
+            Query::GetVersioned(key) => ("get_versioned", key.len()),
+            Query::SetIfVersion(key, ..) => ("set_if_version", key.len()),
+            Query::GetSet(key, _) => ("get_set", key.len()),
+            Query::GetBytes(key) => ("get_bytes", key.len()),
+            Query::SetBytes(key, _) => ("set_bytes", key.len()),
+            Query::Traced(_, query) | Query::Idempotent(_, query) => query.describe(),
+        }
+    }
+
+    /// key the query is about, if it is about a single one
+    fn key(&self) -> Option<&str> {
+        match self {
+            Query::Get(key)
+            | Query::GetRaw(key)
+            | Query::Set(key, _)
+            | Query::Rm(key)
+            | Query::CountPrefix(key)
+            | Query::GetVersioned(key)
+            | Query::SetIfVersion(key, ..)
+            | Query::GetSet(key, _)
+            | Query::GetBytes(key)
+            | Query::SetBytes(key, _) => Some(key),
+            Query::Traced(_, query) | Query::Idempotent(_, query) => query.key(),
+            Query::Admin(_) | Query::Batch(_) | Query::Subscribe | Query::Sync => None,
+        }
+    }
+}
+
+/// Reply to a `Query`
+///
+/// A `Get` is answered with `Ok(Some(value))` if the key exists and `KeyNotFound`
+/// otherwise. Servers before this distinction answered `Ok(None)` for an absent key,
+/// which clients still read as absent. An `Rm` of an absent key is answered with
+/// `KeyNotFound` as well.
+#[derive(Clone, Debug, Serialize, Deserialize)]
 enum Response {
     Success,
     KeyNotFound,
     Ok(Option<String>),
+    /// length of the value, whose raw bytes follow the response frame
+    Raw(Option<u64>),
+    AdminResult(AdminResult),
+    Forbidden,
     Err,
+    /// protocol version agreed on in the handshake
+    Version(u8),
+    /// responses to the queries of a `Batch`, in order
+    Batch(Vec<Response>),
+    /// the server does not speak the client's protocol version, holds the server's
+    VersionMismatch(u8),
+    /// write pushed to a subscribed connection
+    Change(ChangeEvent),
+    /// number of keys found by a `CountPrefix`
+    Count(usize),
+    /// value and version found by a `GetVersioned`, `None` for an absent key
+    Versioned(Option<(String, u64)>),
+    /// whether a `SetIfVersion` set the value
+    Bool(bool),
+    /// response to a `Traced` query, with its request ID
+    Traced(u64, Box<Response>),
+    /// value found by a `GetBytes`, `None` for an absent key
+    Bytes(Option<Vec<u8>>),
+}
+
+/// Marks the value of a `SetBytes` stored encoded, engines only storing strings
+const BINARY_MARKER: char = '\0';
+
+/// the string an engine stores for a value of arbitrary bytes
+///
+/// UTF-8 is stored as is, so `Get` reads it back. other bytes are stored as the marker
+/// followed by one char per byte, which `Get` returns as is.
+fn encode_bytes(bytes: Vec<u8>) -> String {
+    match String::from_utf8(bytes) {
+        Ok(value) if !value.starts_with(BINARY_MARKER) => value,
+        Ok(value) => encode_chars(value.as_bytes()),
+        Err(e) => encode_chars(e.as_bytes()),
+    }
+}
+
+fn encode_chars(bytes: &[u8]) -> String {
+    let mut value = String::with_capacity(bytes.len() * 2 + 1);
+    value.push(BINARY_MARKER);
+    value.extend(bytes.iter().map(|&byte| char::from(byte)));
+    value
+}
+
+/// the bytes of a value stored by `encode_bytes`, or of any other value as UTF-8
+///
+/// a string set as such that starts with the marker and holds no char past U+00FF reads
+/// as encoded bytes.
+fn decode_bytes(value: String) -> Vec<u8> {
+    if let Some(chars) = value.strip_prefix(BINARY_MARKER) {
+        if let Ok(bytes) = chars.chars().map(u8::try_from).collect() {
+            return bytes;
+        }
+    }
+    value.into_bytes()
+}
+
+/// Request ID of a traced query as it appears in logs and errors, `None` for an untraced one
+struct RequestId(Option<u64>);
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(id) => write!(f, " of request {:016x}", id),
+            None => Ok(()),
+        }
+    }
 }
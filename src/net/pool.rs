@@ -0,0 +1,100 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use crate::net::KvsClient;
+use crate::{KvsError, Result};
+
+/// A pool of connections to one server, to run many independent queries at once
+///
+/// Connections are opened on demand and kept for later calls. `get_all` fans its keys
+/// out over up to `concurrency` connections, each on its own thread, so a batch takes
+/// about as many round trips as its largest share instead of one per key. as the server
+/// serves each connection on one of its threads until it is closed, `concurrency` should
+/// leave threads of the server for other clients.
+///
+/// ```
+/// use kvs::{KvStore, KvStoreOptions, KvsClientPool, MemoryStorage, TestServer};
+///
+/// let engine = KvStore::open_with_storage(MemoryStorage::new(), KvStoreOptions::new()).unwrap();
+/// engine.set("key", "value").unwrap();
+/// let server = TestServer::new(engine).unwrap();
+/// let pool = KvsClientPool::new(server.addr(), 4).unwrap();
+/// let values = pool.get_all(vec!["key".to_owned(), "missing".to_owned()]).unwrap();
+/// assert_eq!(values, vec![Some("value".to_owned()), None]);
+/// ```
+pub struct KvsClientPool {
+    addr: SocketAddr,
+    concurrency: usize,
+    idle: Mutex<Vec<KvsClient>>,
+}
+
+impl KvsClientPool {
+    /// create a pool running up to `concurrency` queries at once against `addr`
+    ///
+    /// return `KvsError::InvalidOption` if `concurrency` is 0. no connection is opened yet.
+    pub fn new(addr: SocketAddr, concurrency: usize) -> Result<Self> {
+        if concurrency == 0 {
+            return Err(KvsError::InvalidOption(
+                "concurrency of a client pool must be at least 1".to_owned(),
+            ));
+        }
+        Ok(Self {
+            addr,
+            concurrency,
+            idle: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// run `f` with an idle connection, opening one if there is none
+    ///
+    /// the connection is returned to the pool only if `f` succeeds, as a failed query
+    /// may leave a response unread on it.
+    pub fn with_client<T>(&self, f: impl FnOnce(&mut KvsClient) -> Result<T>) -> Result<T> {
+        let idle = self.idle.lock().unwrap().pop();
+        let mut client = match idle {
+            Some(client) => client,
+            None => KvsClient::init(&self.addr)?,
+        };
+        let result = f(&mut client)?;
+        self.idle.lock().unwrap().push(client);
+        Ok(result)
+    }
+
+    /// query the values of every key, in the order of `keys`
+    ///
+    /// fails with the first error met, the other queries may have run or not.
+    pub fn get_all(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        let next = AtomicUsize::new(0);
+        let workers = self.concurrency.min(keys.len());
+        let query = || -> Result<Vec<(usize, Option<String>)>> {
+            self.with_client(|client| {
+                let mut values = Vec::new();
+                loop {
+                    let i = next.fetch_add(1, Ordering::SeqCst);
+                    match keys.get(i) {
+                        Some(key) => values.push((i, client.get(key.clone())?)),
+                        None => return Ok(values),
+                    }
+                }
+            })
+        };
+
+        let shares = crossbeam::scope(|scope| {
+            let handles: Vec<_> = (0..workers).map(|_| scope.spawn(|_| query())).collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().expect("query thread panicked"))
+                .collect::<Vec<_>>()
+        })
+        .expect("query thread panicked");
+
+        let mut values = vec![None; keys.len()];
+        for share in shares {
+            for (i, value) in share? {
+                values[i] = value;
+            }
+        }
+        Ok(values)
+    }
+}
@@ -1,67 +1,288 @@
-use std::io::{Read, Write};
-use std::net::{SocketAddr, TcpListener, TcpStream};
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Mutex};
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
 use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
-use log::info;
+use arc_swap::ArcSwap;
+use lru::LruCache;
 
-use crate::net::{Query, Response};
+#[cfg(not(feature = "tracing"))]
+use log::debug;
+use log::{error, info, warn};
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::net::feed::{ChangeFeed, SUBSCRIBER_QUEUE};
+use crate::net::{
+    decode_bytes, encode_bytes, negotiate, AdminCmd, AdminResult, ChangeOp, Query, RequestId, Response, ServerConfig,
+    PROTOCOL_VERSION,
+};
 use crate::thread_pool::ThreadPool;
-use crate::{KvsEngine, Result};
+use crate::{KvsEngine, KvsError, Result};
 
 /// A TCP Server to handle queries from client
+///
+/// Each connection may carry any number of queries and occupies a worker of the
+/// thread pool until the client closes it.
+///
+/// The server may listen on several addresses, each with its own accept loop feeding
+/// the same engine and thread pool. Stopping the server stops all of them.
+///
+/// Queries taking longer than the slow query threshold of the config are logged
+/// with a warning, and `queue_depth` tells how many connections wait for a worker.
+///
+/// The engine can be replaced while the server runs with `reload`. Every query
+/// picks up the engine current when it starts, so queries already running finish
+/// on the old engine and the next query of any connection, open or new, runs on
+/// the new one. Writes made to the old engine after the swap are not visible in the new one.
+///
+/// A connection sending `Subscribe` is answered with every `set` and `rm` the server
+/// applies from then on, until either side closes it, and occupies its worker meanwhile.
+/// Writes never wait for subscribers: each one has a queue of 1024 events, and one falling
+/// further behind, e.g. because the client reads too slowly, has its stream ended once the
+/// events already queued are sent. Writes made to the engine other than through the server
+/// are not streamed.
+///
+/// Writes sent with an idempotency token are applied once per token, as long as the server
+/// remembers it, see `ServerConfig::idempotency_window`.
+///
+/// `stop_server` only stops accepting connections, `shutdown` also drains the open ones
+/// and flushes the engine.
 #[derive(Clone)]
 pub struct KvsServer<E: KvsEngine, P: ThreadPool> {
-    addr: SocketAddr,
-    engine: E,
+    listeners: Vec<(SocketAddr, Arc<TcpListener>)>,
+    engine: Arc<ArcSwap<E>>,
+    config: Arc<ServerConfig>,
     thread_pool: Arc<Mutex<P>>,
+    /// connections handed to the thread pool whose job has not started yet
+    queued: Arc<AtomicUsize>,
+    feed: Arc<ChangeFeed>,
+    tokens: Arc<Tokens>,
+    connections: Arc<Connections>,
     stop: Arc<AtomicBool>,
 }
 
+/// Responses to the idempotent writes applied by a server, by token
+///
+/// Only writes that ran are remembered, a token answered with `Response::Err` may be retried.
+/// A token is claimed while its write is applied, so a retry arriving meanwhile waits for
+/// the write to finish instead of applying it again, while writes of other tokens go on.
+struct Tokens {
+    state: Mutex<TokenState>,
+    released: Condvar,
+}
+
+struct TokenState {
+    responses: LruCache<u64, Response>,
+    /// tokens whose write is being applied
+    claimed: HashSet<u64>,
+}
+
+impl Tokens {
+    fn new(window: usize) -> Self {
+        let state = TokenState {
+            responses: LruCache::new(window),
+            claimed: HashSet::new(),
+        };
+        Self {
+            state: Mutex::new(state),
+            released: Condvar::new(),
+        }
+    }
+
+    /// return the response remembered for `token`, or claim it to apply its write
+    fn claim(&self, token: u64) -> std::result::Result<Response, TokenClaim<'_>> {
+        let mut state = self.state.lock().unwrap();
+        while state.claimed.contains(&token) {
+            state = self.released.wait(state).unwrap();
+        }
+        if let Some(response) = state.responses.get(&token) {
+            return Ok(response.clone());
+        }
+        state.claimed.insert(token);
+        Err(TokenClaim { tokens: self, token })
+    }
+}
+
+/// A token whose write is being applied, released when dropped
+struct TokenClaim<'a> {
+    tokens: &'a Tokens,
+    token: u64,
+}
+
+impl TokenClaim<'_> {
+    /// remember `response` for the token if the write ran, and release it
+    fn finish(self, response: &Response) {
+        if let Response::Success | Response::KeyNotFound = response {
+            let mut state = self.tokens.state.lock().unwrap();
+            state.responses.put(self.token, response.clone());
+        }
+    }
+}
+
+impl Drop for TokenClaim<'_> {
+    fn drop(&mut self) {
+        self.tokens.state.lock().unwrap().claimed.remove(&self.token);
+        self.tokens.released.notify_all();
+    }
+}
+
 impl<E: KvsEngine, P: ThreadPool> KvsServer<E, P> {
     /// Initialize the key-value server
+    ///
+    /// The address is bound here, so clients may connect as soon as this returns;
+    /// their connections are accepted once the server is started.
     pub fn init(engine: E, addr: SocketAddr, thread_pool: P) -> Result<Self> {
+        Self::init_with_config(engine, addr, thread_pool, ServerConfig::default())
+    }
+
+    /// Initialize the key-value server with the given config
+    ///
+    /// If `warm_up` is set, the engine is warmed up here, before any connection is accepted.
+    pub fn init_with_config(engine: E, addr: SocketAddr, thread_pool: P, config: ServerConfig) -> Result<Self> {
+        if config.warm_up {
+            warm_up(&engine)?;
+        }
+        let listener = bind(addr, &config)?;
         Ok(Self {
-            addr,
-            engine,
+            listeners: vec![(listener.local_addr()?, Arc::new(listener))],
+            engine: Arc::new(ArcSwap::from_pointee(engine)),
+            tokens: Arc::new(Tokens::new(config.idempotency_window)),
+            config: Arc::new(config),
             thread_pool: Arc::new(Mutex::new(thread_pool)),
+            queued: Arc::new(AtomicUsize::new(0)),
+            feed: Arc::new(ChangeFeed::default()),
+            connections: Arc::new(Connections::default()),
             stop: Arc::new(AtomicBool::new(false)),
         })
     }
 
+    /// Also listen on `addr`, return the address bound
+    ///
+    /// Listeners added after `start` are not served by the running accept loops.
+    pub fn add_listener(&mut self, addr: SocketAddr) -> Result<SocketAddr> {
+        let listener = bind(addr, &self.config)?;
+        let addr = listener.local_addr()?;
+        self.listeners.push((addr, Arc::new(listener)));
+        Ok(addr)
+    }
+
+    /// Address the server was initialized with
+    ///
+    /// Useful to find the port picked by the OS when initialized with port 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.listeners[0].0
+    }
+
+    /// Every address the server is bound to, starting with the one it was initialized with
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.listeners.iter().map(|(addr, _)| *addr).collect()
+    }
+
+    /// Number of accepted connections waiting for a worker of the thread pool
+    ///
+    /// Each connection occupies a worker until it is closed, so this grows once every
+    /// worker serves a connection. Also reported by `AdminCmd::Stats`.
+    pub fn queue_depth(&self) -> usize {
+        self.queued.load(Ordering::SeqCst)
+    }
+
+    /// Replace the engine serving queries
+    ///
+    /// The new engine is warmed up first if the config asks for it. The old engine is
+    /// dropped once the queries still running on it finish.
+    pub fn reload(&self, engine: E) -> Result<()> {
+        if self.config.warm_up {
+            warm_up(&engine)?;
+        }
+        self.engine.store(Arc::new(engine));
+        info!("reloaded engine");
+        Ok(())
+    }
+
     /// Start the server to serve client queries
+    ///
+    /// The returned thread runs one accept loop per address and exits once all of
+    /// them have stopped. It and the threads of the accept loops are named `kvs-accept`.
     pub fn start(&self) -> JoinHandle<Result<()>> {
-        let addr = self.addr;
+        let listeners: Vec<Arc<TcpListener>> = self.listeners.iter().map(|(_, listener)| listener.clone()).collect();
         let thread_pool = self.thread_pool.clone();
         let engine = self.engine.clone();
+        let config = self.config.clone();
+        let queued = self.queued.clone();
+        let feed = self.feed.clone();
+        let tokens = self.tokens.clone();
+        let connections = self.connections.clone();
         let stop_sign = self.stop.clone();
 
-        thread::spawn(move || {
-            let pool_lock = thread_pool.lock().unwrap();
-            let listener = TcpListener::bind(addr)?;
-            for stream in listener.incoming() {
-                if stop_sign.load(Ordering::Acquire) {
-                    break;
+        let accept_thread = thread::Builder::new().name("kvs-accept".to_owned());
+        let spawned = accept_thread.spawn(move || {
+            crossbeam::scope(|scope| {
+                for listener in listeners.iter() {
+                    let (thread_pool, engine, config, queued, feed, tokens, connections, stop_sign) = (
+                        &thread_pool,
+                        &engine,
+                        &config,
+                        &queued,
+                        &feed,
+                        &tokens,
+                        &connections,
+                        &stop_sign,
+                    );
+                    scope
+                        .builder()
+                        .name("kvs-accept".to_owned())
+                        .spawn(move |_| {
+                            accept(
+                                listener,
+                                thread_pool,
+                                engine,
+                                config,
+                                queued,
+                                feed,
+                                tokens,
+                                connections,
+                                stop_sign,
+                            )
+                        })
+                        .expect("failed to spawn an accept loop");
                 }
-                if let Ok(stream) = stream {
-                    info!("serving: {:?}", stream.peer_addr()?);
-                    let engine = engine.clone();
-
-                    pool_lock.spawn(move || {
-                        handle(stream, engine).unwrap();
-                    });
-                }
-            }
+            })
+            .expect("accept loop panicked");
             Ok(())
-        })
+        });
+        spawned.expect("failed to spawn the accept thread")
     }
 
-    /// Stop the server
+    /// Stop the server, ending the streams of subscribed connections
     pub fn stop_server(&self) {
         self.stop.store(true, Ordering::Release);
-        let _stream = TcpStream::connect(&self.addr);
+        self.feed.close();
+        for (addr, _) in self.listeners.iter() {
+            let _stream = TcpStream::connect(addr);
+        }
+    }
+
+    /// Stop the server, wait for every open connection to finish and flush the engine
+    ///
+    /// Each connection answers the query it is running, if any, then is closed instead of
+    /// reading another one. Connections still waiting for a worker are closed unanswered.
+    /// Call it once the thread returned by `start` has exited, so no connection is accepted
+    /// meanwhile.
+    pub fn shutdown(&self) -> Result<()> {
+        self.stop_server();
+        let open = self.connections.drain();
+        if open > 0 {
+            info!("waiting for {} open connections to finish", open);
+        }
+        self.connections.wait_closed();
+        info!("every connection is closed, flushing the engine");
+        self.engine.load().flush()?;
+        info!("server shut down");
+        Ok(())
     }
 }
 
@@ -71,38 +292,445 @@ impl<E: KvsEngine, P: ThreadPool> Drop for KvsServer<E, P> {
     }
 }
 
-fn handle<E: KvsEngine>(mut stream: TcpStream, engine: E) -> Result<()> {
-    let query = receive(&mut stream)?;
-    let response = match query {
-        Query::Set(key, val) => match engine.set(key, val) {
-            Ok(_) => Response::Success,
-            Err(_) => Response::Err,
+/// listen on `addr` with the socket options of `config`
+fn bind(addr: SocketAddr, config: &ServerConfig) -> Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_reuse_address(config.reuse_address)?;
+    socket.bind(&addr.into())?;
+    socket.listen(config.listen_backlog.min(i32::MAX as u32) as i32)?;
+    Ok(socket.into())
+}
+
+/// Hand every connection of `listener` to the thread pool until the server is stopped
+#[allow(clippy::too_many_arguments)]
+fn accept<E: KvsEngine, P: ThreadPool>(
+    listener: &TcpListener,
+    thread_pool: &Mutex<P>,
+    engine: &Arc<ArcSwap<E>>,
+    config: &Arc<ServerConfig>,
+    queued: &Arc<AtomicUsize>,
+    feed: &Arc<ChangeFeed>,
+    tokens: &Arc<Tokens>,
+    connections: &Arc<Connections>,
+    stop_sign: &AtomicBool,
+) {
+    for stream in listener.incoming() {
+        if stop_sign.load(Ordering::Acquire) {
+            break;
+        }
+        match stream {
+            Ok(stream) => {
+                let peer = stream.peer_addr();
+                info!("serving: {:?}", peer);
+                let engine = engine.clone();
+                let config = config.clone();
+                let queued = queued.clone();
+                let feed = feed.clone();
+                let tokens = tokens.clone();
+                let mut connection = Connection::open(connections.clone());
+
+                queued.fetch_add(1, Ordering::SeqCst);
+                thread_pool.lock().unwrap().spawn(move || {
+                    queued.fetch_sub(1, Ordering::SeqCst);
+                    let served = connection.serve(&stream).and_then(|serving| match serving {
+                        true => handle(stream, engine, &config, &queued, &feed, &tokens),
+                        false => Ok(()),
+                    });
+                    if let Err(e) = served {
+                        error!("error serving {:?}: {}", peer, e);
+                    }
+                });
+            }
+            Err(e) => error!("failed to accept connection: {}", e),
+        }
+    }
+}
+
+/// Serve queries from one connection until the client closes it, or sends nothing for
+/// the idle timeout of the server
+///
+/// The connection starts with the client's protocol version, and is closed right
+/// after the handshake if the server does not speak it. The same buffer is reused to
+/// receive each query and to send each response. Values requested raw are written
+/// straight after their response frame.
+///
+/// The connection works on its own clone of the engine, taken again whenever the
+/// server has been reloaded with another engine. A `Subscribe` query ends the queries
+/// of the connection, which only streams changes from then on. A `Traced` query is
+/// logged with its request ID and answered with a `Traced` response echoing it.
+fn handle<E: KvsEngine>(
+    mut stream: TcpStream,
+    slot: Arc<ArcSwap<E>>,
+    config: &ServerConfig,
+    queued: &AtomicUsize,
+    feed: &ChangeFeed,
+    tokens: &Tokens,
+) -> Result<()> {
+    let mut buffer = Vec::new();
+    let mut version = [0];
+    stream.set_read_timeout(config.idle_timeout)?;
+    match stream.read_exact(&mut version) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+        Err(e) if is_timeout(&e) => {
+            info!(
+                "closed a connection idle for {:?}",
+                config.idle_timeout.unwrap_or_default()
+            );
+            return Ok(());
+        }
+        Err(e) => return Err(e.into()),
+    }
+    match negotiate(version[0]) {
+        Some(version) => send(&mut stream, &Response::Version(version), &mut buffer)?,
+        None => {
+            info!("refused client of protocol version {}", version[0]);
+            return send(&mut stream, &Response::VersionMismatch(PROTOCOL_VERSION), &mut buffer);
+        }
+    }
+
+    let mut current = slot.load_full();
+    let mut engine = (*current).clone();
+    while let Some(query) = receive(&mut stream, &mut buffer, config.idle_timeout)? {
+        if !Arc::ptr_eq(&slot.load(), &current) {
+            current = slot.load_full();
+            engine = (*current).clone();
+        }
+        let (request_id, query) = match query {
+            Query::Traced(id, query) => (Some(id), *query),
+            query => (None, query),
+        };
+        if !config.commands.allows(&query) {
+            info!("refused {}{}", query.describe().0, RequestId(request_id));
+            send(&mut stream, &traced(request_id, Response::Forbidden), &mut buffer)?;
+            continue;
+        }
+        if let Query::Subscribe = query {
+            return stream_changes(&mut stream, feed, &mut buffer, request_id);
+        }
+        let (response, raw) = process(&engine, config, queued, feed, tokens, query, request_id);
+        send(&mut stream, &traced(request_id, response), &mut buffer)?;
+        if let Some(value) = raw {
+            stream.write_all(value.as_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+/// Send every change published to `feed` until the client disconnects or the feed drops it
+fn stream_changes(
+    stream: &mut TcpStream,
+    feed: &ChangeFeed,
+    buffer: &mut Vec<u8>,
+    request_id: Option<u64>,
+) -> Result<()> {
+    let changes = feed.subscribe();
+    send(stream, &traced(request_id, Response::Success), buffer)?;
+    for event in changes.iter() {
+        match send(stream, &Response::Change(event), buffer) {
+            Ok(()) => {}
+            Err(KvsError::Io(ref e)) if is_disconnect(e) => return Ok(()),
+            Err(e) => return Err(e),
+        }
+    }
+    info!(
+        "ended a subscription{}, the server stopped or the client fell {} events behind",
+        RequestId(request_id),
+        SUBSCRIBER_QUEUE
+    );
+    Ok(())
+}
+
+/// Connections accepted by a server and not closed yet, drained by `KvsServer::shutdown`
+#[derive(Default)]
+struct Connections {
+    state: Mutex<ConnectionState>,
+    closed: Condvar,
+}
+
+#[derive(Default)]
+struct ConnectionState {
+    /// connections accepted and not closed yet, including those waiting for a worker
+    open: usize,
+    next_id: u64,
+    /// a handle of each connection being served, to stop its reads once draining
+    serving: HashMap<u64, TcpStream>,
+    /// set by `drain`, connections starting afterwards are closed right away
+    draining: bool,
+}
+
+impl Connections {
+    /// stop reading from every connection being served, return how many connections are open
+    fn drain(&self) -> usize {
+        let mut state = self.state.lock().unwrap();
+        state.draining = true;
+        for stream in state.serving.values() {
+            // the query being run, if any, is read already and its response still goes out
+            let _ = stream.shutdown(Shutdown::Read);
+        }
+        state.open
+    }
+
+    fn wait_closed(&self) {
+        let mut state = self.state.lock().unwrap();
+        while state.open > 0 {
+            state = self.closed.wait(state).unwrap();
+        }
+    }
+}
+
+/// An accepted connection, counted as open until dropped
+struct Connection {
+    connections: Arc<Connections>,
+    id: Option<u64>,
+}
+
+impl Connection {
+    fn open(connections: Arc<Connections>) -> Self {
+        connections.state.lock().unwrap().open += 1;
+        Self { connections, id: None }
+    }
+
+    /// start serving `stream`, return false if the server is draining and it should be closed
+    fn serve(&mut self, stream: &TcpStream) -> Result<bool> {
+        let mut state = self.connections.state.lock().unwrap();
+        if state.draining {
+            return Ok(false);
+        }
+        let id = state.next_id;
+        state.next_id += 1;
+        state.serving.insert(id, stream.try_clone()?);
+        self.id = Some(id);
+        Ok(true)
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let mut state = self.connections.state.lock().unwrap();
+        if let Some(id) = self.id {
+            state.serving.remove(&id);
+        }
+        state.open -= 1;
+        if state.open == 0 {
+            self.connections.closed.notify_all();
+        }
+    }
+}
+
+/// whether the error means the client closed or reset the connection
+fn is_disconnect(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::BrokenPipe | io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted
+    )
+}
+
+/// whether the error means a read timed out, which is reported as either kind depending on the platform
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+/// the response to a query, tagged with its request ID if it was traced
+fn traced(request_id: Option<u64>, response: Response) -> Response {
+    match request_id {
+        Some(id) => Response::Traced(id, Box::new(response)),
+        None => response,
+    }
+}
+
+fn process<E: KvsEngine>(
+    engine: &E,
+    config: &ServerConfig,
+    queued: &AtomicUsize,
+    feed: &ChangeFeed,
+    tokens: &Tokens,
+    query: Query,
+    request_id: Option<u64>,
+) -> (Response, Option<String>) {
+    let start = Instant::now();
+    let (op, key_len) = query.describe();
+    // kept only to name the key of a slow query
+    let key = config.slow_query.and_then(|_| query.key().map(str::to_owned));
+    #[cfg(feature = "tracing")]
+    let span = tracing::info_span!("handle", op, key_len, request_id, duration_us = tracing::field::Empty).entered();
+
+    let (response, raw) = match query {
+        Query::Set(key, val) => match engine.set(key.clone(), val) {
+            Ok(_) => {
+                feed.publish(ChangeOp::Set, &key);
+                (Response::Success, None)
+            }
+            Err(_) => (Response::Err, None),
         },
         Query::Get(key) => match engine.get(key) {
-            Ok(val) => Response::Ok(val),
-            Err(_) => Response::Err,
+            Ok(Some(val)) => (Response::Ok(Some(val)), None),
+            Ok(None) => (Response::KeyNotFound, None),
+            Err(_) => (Response::Err, None),
+        },
+        Query::GetRaw(key) => match engine.get(key) {
+            Ok(Some(val)) => (Response::Raw(Some(val.len() as u64)), Some(val)),
+            Ok(None) => (Response::Raw(None), None),
+            Err(_) => (Response::Err, None),
+        },
+        Query::Rm(key) => match engine.remove(key.clone()) {
+            Ok(_) => {
+                feed.publish(ChangeOp::Rm, &key);
+                (Response::Success, None)
+            }
+            Err(KvsError::KeyNotFound) => (Response::KeyNotFound, None),
+            Err(_) => (Response::Err, None),
+        },
+        Query::Batch(queries) => {
+            let responses = queries
+                .into_iter()
+                .map(|query| match query {
+                    Query::Set(..) | Query::Rm(_) => process(engine, config, queued, feed, tokens, query, request_id).0,
+                    _ => Response::Err,
+                })
+                .collect();
+            (Response::Batch(responses), None)
+        }
+        Query::Sync => match engine.flush() {
+            Ok(()) => (Response::Success, None),
+            Err(e) => {
+                error!("sync{} failed: {}", RequestId(request_id), e);
+                (Response::Err, None)
+            }
+        },
+        Query::GetVersioned(key) => match engine.get_versioned(key) {
+            Ok(versioned) => (Response::Versioned(versioned), None),
+            Err(_) => (Response::Err, None),
+        },
+        Query::SetIfVersion(key, val, expected) => match engine.set_if_version(key.clone(), val, expected) {
+            Ok(set) => {
+                if set {
+                    feed.publish(ChangeOp::Set, &key);
+                }
+                (Response::Bool(set), None)
+            }
+            Err(_) => (Response::Err, None),
+        },
+        Query::GetSet(key, val) => match engine.set_and_get_old(key.clone(), val) {
+            Ok(old) => {
+                feed.publish(ChangeOp::Set, &key);
+                (Response::Ok(old), None)
+            }
+            Err(_) => (Response::Err, None),
+        },
+        Query::GetBytes(key) => match engine.get(key) {
+            Ok(val) => (Response::Bytes(val.map(decode_bytes)), None),
+            Err(_) => (Response::Err, None),
+        },
+        Query::SetBytes(key, val) => match engine.set(key.clone(), encode_bytes(val)) {
+            Ok(_) => {
+                feed.publish(ChangeOp::Set, &key);
+                (Response::Success, None)
+            }
+            Err(_) => (Response::Err, None),
+        },
+        Query::CountPrefix(prefix) => match engine.count_prefix(prefix) {
+            Ok(count) => (Response::Count(count), None),
+            Err(_) => (Response::Err, None),
         },
-        Query::Rm(key) => match engine.remove(key) {
-            Ok(_) => Response::Success,
-            Err(_) => Response::Err,
+        // answered by `handle`, which streams changes from then on
+        Query::Subscribe => (Response::Err, None),
+        Query::Idempotent(token, query) => match *query {
+            query @ Query::Set(..) | query @ Query::Rm(_) => match tokens.claim(token) {
+                Ok(response) => {
+                    info!("ignored a replayed {}{}", op, RequestId(request_id));
+                    (response, None)
+                }
+                Err(claim) => {
+                    let (response, _) = process(engine, config, queued, feed, tokens, query, request_id);
+                    claim.finish(&response);
+                    (response, None)
+                }
+            },
+            _ => (Response::Err, None),
+        },
+        // unwrapped by `handle`, a traced query cannot hold another
+        Query::Traced(..) => (Response::Err, None),
+        Query::Admin(_) if !config.allow_admin => (Response::Forbidden, None),
+        Query::Admin(cmd) => match admin(engine, cmd, queued) {
+            Ok(result) => (Response::AdminResult(result), None),
+            Err(e) => {
+                error!("admin command {:?}{} failed: {}", cmd, RequestId(request_id), e);
+                (Response::Err, None)
+            }
         },
     };
-    send(&mut stream, response)?;
+
+    let elapsed = start.elapsed();
+    #[cfg(feature = "tracing")]
+    span.record("duration_us", elapsed.as_micros() as u64);
+    #[cfg(not(feature = "tracing"))]
+    debug!(
+        "handled {}{} with key length {} in {:?}",
+        op,
+        RequestId(request_id),
+        key_len,
+        elapsed
+    );
+    if config.slow_query.is_some_and(|threshold| elapsed >= threshold) {
+        match key {
+            Some(key) => warn!(
+                "slow {}{} of key `{}` took {:?}",
+                op,
+                RequestId(request_id),
+                key,
+                elapsed
+            ),
+            None => warn!("slow {}{} took {:?}", op, RequestId(request_id), elapsed),
+        }
+    }
+    (response, raw)
+}
+
+fn admin<E: KvsEngine>(engine: &E, cmd: AdminCmd, queued: &AtomicUsize) -> Result<AdminResult> {
+    match cmd {
+        AdminCmd::Flush => engine.flush().map(|_| AdminResult::Done),
+        AdminCmd::Compact => engine.compact().map(|_| AdminResult::Done),
+        AdminCmd::Stats => Ok(AdminResult::Stats {
+            keys: engine.keys()?.len(),
+            queue_depth: queued.load(Ordering::SeqCst),
+        }),
+    }
+}
+
+fn warm_up<E: KvsEngine>(engine: &E) -> Result<()> {
+    let start = Instant::now();
+    engine.warm_up()?;
+    info!("warmed up engine in {:?}", start.elapsed());
     Ok(())
 }
 
-fn receive(stream: &mut TcpStream) -> Result<Query> {
+/// Read the next query, return `Ok(None)` once the client has closed the connection or
+/// sent nothing for `idle_timeout`
+fn receive(stream: &mut TcpStream, buffer: &mut Vec<u8>, idle_timeout: Option<Duration>) -> Result<Option<Query>> {
     let mut msg_len = [0; 4];
-    stream.read_exact(&mut msg_len)?;
+    match stream.read_exact(&mut msg_len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) if is_timeout(&e) => {
+            info!("closed a connection idle for {:?}", idle_timeout.unwrap_or_default());
+            return Ok(None);
+        }
+        Err(e) => return Err(e.into()),
+    }
     let len = u32::from_be_bytes(msg_len) as usize;
-    let mut msg = vec![0; len];
-    stream.read_exact(&mut msg)?;
-    serde_json::from_slice::<Query>(&msg).map_err(|e| e.into())
+    buffer.clear();
+    buffer.resize(len, 0);
+    stream.read_exact(buffer)?;
+    Ok(Some(serde_json::from_slice::<Query>(buffer)?))
 }
 
-fn send(stream: &mut TcpStream, response: Response) -> Result<()> {
-    let serialized_query = serde_json::to_vec(&response)?;
-    stream.write_all(&(serialized_query.len() as u32).to_be_bytes())?;
-    stream.write_all(&serialized_query)?;
+fn send(stream: &mut TcpStream, response: &Response, buffer: &mut Vec<u8>) -> Result<()> {
+    buffer.clear();
+    buffer.extend_from_slice(&[0; 4]);
+    serde_json::to_writer(&mut *buffer, response)?;
+    let len = (buffer.len() - 4) as u32;
+    buffer[..4].copy_from_slice(&len.to_be_bytes());
+    stream.write_all(buffer)?;
     Ok(())
 }
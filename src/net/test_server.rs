@@ -0,0 +1,65 @@
+use std::net::SocketAddr;
+use std::thread::JoinHandle;
+
+use crate::net::{KvsServer, ServerConfig};
+use crate::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use crate::{KvsEngine, Result};
+
+const TEST_SERVER_THREADS: u32 = 4;
+
+/// A running server for tests, shut down when dropped
+///
+/// The server listens on an ephemeral port of localhost and accepts connections
+/// as soon as `new` returns, so no sleep is needed before connecting to `addr`.
+/// Dropping it stops the server and waits for the accept loop to exit.
+///
+/// ```
+/// use kvs::{KvStore, KvStoreOptions, KvsClient, MemoryStorage, TestServer};
+///
+/// let engine = KvStore::open_with_storage(MemoryStorage::new(), KvStoreOptions::new()).unwrap();
+/// let server = TestServer::new(engine).unwrap();
+/// let mut client = KvsClient::init(&server.addr()).unwrap();
+/// client.set("key".to_owned(), "value".to_owned()).unwrap();
+/// ```
+pub struct TestServer<E: KvsEngine> {
+    server: KvsServer<E, SharedQueueThreadPool>,
+    handle: Option<JoinHandle<Result<()>>>,
+}
+
+impl<E: KvsEngine> TestServer<E> {
+    /// Start a server on `engine` with the default config
+    pub fn new(engine: E) -> Result<Self> {
+        Self::with_config(engine, ServerConfig::default())
+    }
+
+    /// Start a server on `engine` with the given config
+    pub fn with_config(engine: E, config: ServerConfig) -> Result<Self> {
+        let addr: SocketAddr = ([127, 0, 0, 1], 0).into();
+        let thread_pool = SharedQueueThreadPool::new(TEST_SERVER_THREADS)?;
+        let server = KvsServer::init_with_config(engine, addr, thread_pool, config)?;
+        let handle = server.start();
+        Ok(Self {
+            server,
+            handle: Some(handle),
+        })
+    }
+
+    /// Address clients should connect to
+    pub fn addr(&self) -> SocketAddr {
+        self.server.local_addr()
+    }
+
+    /// The underlying server, e.g. to `reload` its engine
+    pub fn server(&self) -> &KvsServer<E, SharedQueueThreadPool> {
+        &self.server
+    }
+}
+
+impl<E: KvsEngine> Drop for TestServer<E> {
+    fn drop(&mut self) {
+        self.server.stop_server();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
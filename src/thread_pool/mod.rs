@@ -10,19 +10,45 @@ pub use self::rayon::RayonThreadPool;
 pub use naive::NaiveThreadPool;
 pub use shared_queue::SharedQueueThreadPool;
 
-use crate::Result;
+use std::time::Instant;
+
+use crate::{KvsError, Result};
 
 /// Interface for thread pool implementation
 pub trait ThreadPool: Send + 'static {
     /// Creates a thread pool
     ///
-    /// return error if failed to create any thread
+    /// return `KvsError::InvalidThreadCount` if `threads` is 0,
+    /// or another error if failed to create any thread
     fn new(threads: u32) -> Result<Self>
     where
         Self: Sized;
 
     /// Send a closure to thread pool
+    ///
+    /// The job is fire-and-forget: nothing tells the caller when it ran or what it
+    /// returned. `RayonThreadPool::spawn_result` hands back the result of a job.
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static;
+
+    /// Send a closure to thread pool, to be skipped if it is still queued after `deadline`
+    ///
+    /// The default implementation ignores the deadline and runs every job, which is
+    /// what `NaiveThreadPool` and `RayonThreadPool` do.
+    fn spawn_with_deadline<F>(&self, job: F, deadline: Instant)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let _ = deadline;
+        self.spawn(job)
+    }
+}
+
+fn check_threads(threads: u32) -> Result<()> {
+    if threads == 0 {
+        Err(KvsError::InvalidThreadCount)
+    } else {
+        Ok(())
+    }
 }
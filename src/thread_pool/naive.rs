@@ -1,6 +1,6 @@
 use std::thread;
 
-use super::ThreadPool;
+use super::{check_threads, ThreadPool};
 use crate::Result;
 
 /// Naive thread pool
@@ -9,7 +9,8 @@ use crate::Result;
 pub struct NaiveThreadPool;
 
 impl ThreadPool for NaiveThreadPool {
-    fn new(_threads: u32) -> Result<Self> {
+    fn new(threads: u32) -> Result<Self> {
+        check_threads(threads)?;
         Ok(NaiveThreadPool)
     }
 
@@ -1,15 +1,56 @@
-use super::ThreadPool;
+use std::panic::{self, AssertUnwindSafe};
+
+use crossbeam::Receiver;
+use log::error;
+
+use super::{check_threads, ThreadPool};
 use crate::{KvsError, Result};
 
 /// Rayon thread pool wrapper
+///
+/// Workers are named `kvs-worker-{i}` like those of `SharedQueueThreadPool`.
 pub struct RayonThreadPool {
     pool: rayon::ThreadPool,
 }
 
+impl RayonThreadPool {
+    /// Send a closure to thread pool, return a channel receiving its return value
+    ///
+    /// A panic of the job is caught, instead of aborting the process as a panic of a
+    /// job sent with `spawn` does, and disconnects the channel without a value, so
+    /// `recv` returns an error instead of blocking forever.
+    ///
+    /// ```
+    /// use kvs::thread_pool::{RayonThreadPool, ThreadPool};
+    ///
+    /// let pool = RayonThreadPool::new(2).unwrap();
+    /// let result = pool.spawn_result(|| 6 * 7);
+    /// assert_eq!(result.recv().unwrap(), 42);
+    /// ```
+    pub fn spawn_result<F, T>(&self, job: F) -> Receiver<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (tx, rx) = crossbeam::bounded(1);
+        self.pool
+            .spawn(move || match panic::catch_unwind(AssertUnwindSafe(job)) {
+                // the caller may have dropped the receiver, not caring about the result
+                Ok(result) => {
+                    let _ = tx.send(result);
+                }
+                Err(_) => error!("job sent with spawn_result panicked"),
+            });
+        rx
+    }
+}
+
 impl ThreadPool for RayonThreadPool {
     fn new(threads: u32) -> Result<Self> {
+        check_threads(threads)?;
         let pool = rayon::ThreadPoolBuilder::new()
             .num_threads(threads as usize)
+            .thread_name(|index| format!("kvs-worker-{}", index))
             .build()
             .map_err(|_e| KvsError::ThreadPoolError)?;
         Ok(Self { pool })
@@ -1,30 +1,85 @@
+use std::io;
 use std::thread;
+use std::time::Instant;
 
+use crossbeam::channel::TrySendError;
 use crossbeam::{Receiver, Sender};
+use log::{debug, error};
 
-use super::ThreadPool;
-use crate::Result;
+use super::{check_threads, ThreadPool};
+use crate::{KvsError, Result};
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
 
 /// Shared queue thread pool
 ///
-/// The thread pool create dispatch tasks by crossbeam channel
+/// The thread pool create dispatch tasks by crossbeam channel.
+/// The queue is unbounded unless the pool is created with `bounded`.
+/// Workers are named `kvs-worker-{i}`, `i` counting from 0, so profilers tell them apart.
 pub struct SharedQueueThreadPool {
-    sender: Sender<Box<dyn FnOnce() + Send + 'static>>,
+    sender: Sender<Job>,
 }
 
-impl ThreadPool for SharedQueueThreadPool {
-    fn new(threads: u32) -> Result<Self> {
-        let (tx, rx) = crossbeam::unbounded();
-        for _ in 0..threads {
-            let receiver = ReceiverWrapper(rx.clone());
-            thread::spawn(move || {
-                while let Ok(f) = receiver.0.recv() {
-                    f();
-                }
-            });
+impl SharedQueueThreadPool {
+    /// Creates a thread pool whose queue holds at most `queue_capacity` pending jobs
+    ///
+    /// `spawn` blocks while the queue is full, `try_spawn` returns an error instead.
+    pub fn bounded(threads: u32, queue_capacity: usize) -> Result<Self> {
+        check_threads(threads)?;
+        Self::with_channel(threads, crossbeam::bounded(queue_capacity), false)
+    }
+
+    /// Creates a thread pool whose worker `i` is pinned to the `i`-th CPU core, modulo
+    /// the number of cores
+    ///
+    /// return `KvsError::ThreadPoolError` if the cores cannot be listed. a worker that
+    /// cannot be pinned logs an error and runs unpinned.
+    #[cfg(feature = "core_affinity")]
+    pub fn pinned(threads: u32) -> Result<Self> {
+        check_threads(threads)?;
+        Self::with_channel(threads, crossbeam::unbounded(), true)
+    }
+
+    /// Send a closure to thread pool without blocking
+    ///
+    /// return `KvsError::ThreadPoolFull` if the queue of a bounded pool is full
+    pub fn try_spawn<F>(&self, job: F) -> Result<()>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.sender.try_send(Box::new(job)).map_err(|e| match e {
+            TrySendError::Full(_) => KvsError::ThreadPoolFull,
+            TrySendError::Disconnected(_) => KvsError::ThreadPoolError,
+        })
+    }
+
+    fn with_channel(threads: u32, (tx, rx): (Sender<Job>, Receiver<Job>), pin: bool) -> Result<Self> {
+        #[cfg(feature = "core_affinity")]
+        let cores = if pin {
+            Some(core_affinity::get_core_ids().ok_or(KvsError::ThreadPoolError)?)
+        } else {
+            None
+        };
+        #[cfg(not(feature = "core_affinity"))]
+        let _ = pin;
+        for index in 0..threads as usize {
+            let receiver = ReceiverWrapper {
+                receiver: rx.clone(),
+                index,
+                #[cfg(feature = "core_affinity")]
+                core: cores.as_ref().map(|cores| cores[index % cores.len()]),
+            };
+            receiver.spawn_worker().map_err(|_| KvsError::ThreadPoolError)?;
         }
         Ok(Self { sender: tx })
     }
+}
+
+impl ThreadPool for SharedQueueThreadPool {
+    fn new(threads: u32) -> Result<Self> {
+        check_threads(threads)?;
+        Self::with_channel(threads, crossbeam::unbounded(), false)
+    }
 
     /// Send a closure to thread pool
     fn spawn<F>(&self, job: F)
@@ -33,19 +88,64 @@ impl ThreadPool for SharedQueueThreadPool {
     {
         self.sender.send(Box::new(job)).unwrap();
     }
+
+    /// Send a closure to thread pool, the worker skips it if the deadline has passed
+    fn spawn_with_deadline<F>(&self, job: F, deadline: Instant)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.spawn(move || {
+            if Instant::now() <= deadline {
+                job();
+            } else {
+                debug!("skipped job queued past its deadline");
+            }
+        })
+    }
+}
+
+/// Receiving end of a worker, which respawns the worker if a job panics
+struct ReceiverWrapper {
+    receiver: Receiver<Job>,
+    /// position of the worker in the pool, naming its thread
+    index: usize,
+    /// core the worker is pinned to, if any
+    #[cfg(feature = "core_affinity")]
+    core: Option<core_affinity::CoreId>,
 }
 
-struct ReceiverWrapper(Receiver<Box<dyn FnOnce() + Send + 'static>>);
+impl ReceiverWrapper {
+    /// run the jobs received on a new thread named after the worker
+    fn spawn_worker(self) -> io::Result<()> {
+        thread::Builder::new()
+            .name(format!("kvs-worker-{}", self.index))
+            .spawn(move || {
+                #[cfg(feature = "core_affinity")]
+                if let Some(core) = self.core {
+                    if !core_affinity::set_for_current(core) {
+                        error!("failed to pin kvs-worker-{} to core {}", self.index, core.id);
+                    }
+                }
+                while let Ok(f) = self.receiver.recv() {
+                    f();
+                }
+            })
+            .map(|_| ())
+    }
+}
 
 impl Drop for ReceiverWrapper {
     fn drop(&mut self) {
         if thread::panicking() {
-            let receiver = ReceiverWrapper(self.0.clone());
-            thread::spawn(move || {
-                while let Ok(f) = receiver.0.recv() {
-                    f();
-                }
-            });
+            let receiver = ReceiverWrapper {
+                receiver: self.receiver.clone(),
+                index: self.index,
+                #[cfg(feature = "core_affinity")]
+                core: self.core,
+            };
+            if let Err(e) = receiver.spawn_worker() {
+                error!("failed to respawn kvs-worker-{}: {}", self.index, e);
+            }
         }
     }
 }
@@ -0,0 +1,391 @@
+use kvs::{KvStore, KvStoreOptions, KvsEngine, MemoryStorage, Result, Storage, StorageReader, StorageWriter};
+use std::collections::HashMap;
+use std::io::{self, Cursor, Seek, SeekFrom, Write};
+use std::ops::Bound;
+use std::sync::{Arc, Mutex};
+
+/// What a crash leaves on the disk
+#[derive(Clone, Copy, Debug)]
+enum Crash {
+    /// every write reached the disk, like a process killed while the OS keeps running
+    Process,
+    /// only synced data and directory changes reached the disk, like a power loss
+    Power,
+}
+
+/// The contents of a file, and what of them was synced
+#[derive(Default)]
+struct File {
+    data: Vec<u8>,
+    synced: Vec<u8>,
+}
+
+#[derive(Default)]
+struct Disk {
+    files: Vec<File>,
+    /// names of the files as seen by the running process
+    names: HashMap<String, usize>,
+    /// names of the files as of the last `sync_dir`
+    synced_names: HashMap<String, usize>,
+    /// operations changing the disk so far
+    ops: usize,
+    /// operation at which the process crashes
+    crash_at: Option<usize>,
+}
+
+impl Disk {
+    /// count an operation changing the disk, fail if it is the one crashing or a later one
+    fn check(&mut self) -> io::Result<()> {
+        self.ops += 1;
+        match self.crash_at {
+            Some(crash_at) if self.ops > crash_at => Err(io::Error::other("crashed")),
+            _ => Ok(()),
+        }
+    }
+
+    fn crashing(&self) -> bool {
+        self.crash_at == Some(self.ops)
+    }
+}
+
+/// A `Storage` keeping track of what would survive a crash, crashing at a given operation
+///
+/// Every write, sync, create, append, rename, remove and directory sync counts as an
+/// operation. The crashing one fails, except a write which first writes half of its
+/// buffer, and every operation after it fails too, so the disk stays as the crash left it.
+/// Files are synced with `StorageWriter::sync`, and their names with `Storage::sync_dir`.
+#[derive(Clone, Debug, Default)]
+struct FaultyStorage {
+    disk: Arc<Mutex<Disk>>,
+}
+
+impl std::fmt::Debug for Disk {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Disk({} ops)", self.ops)
+    }
+}
+
+impl FaultyStorage {
+    fn crash_at(crash_at: usize) -> Self {
+        let storage = Self::default();
+        storage.disk.lock().unwrap().crash_at = Some(crash_at);
+        storage
+    }
+
+    /// crash at the given operation from now on
+    fn crash_after(&self, ops: usize) {
+        let mut disk = self.disk.lock().unwrap();
+        disk.crash_at = Some(disk.ops + ops);
+    }
+
+    fn crashed(&self) -> bool {
+        let disk = self.disk.lock().unwrap();
+        disk.crash_at.is_some_and(|crash_at| disk.ops > crash_at)
+    }
+
+    /// what reopening the store would find after `crash`
+    fn after_crash(&self, crash: Crash) -> Result<MemoryStorage> {
+        let disk = self.disk.lock().unwrap();
+        let storage = MemoryStorage::new();
+        let names = match crash {
+            Crash::Process => &disk.names,
+            Crash::Power => &disk.synced_names,
+        };
+        for (name, &id) in names.iter() {
+            let file = &disk.files[id];
+            let data = match crash {
+                Crash::Process => &file.data,
+                Crash::Power => &file.synced,
+            };
+            storage.create(name)?.write_all(data)?;
+        }
+        Ok(storage)
+    }
+
+    fn file(&self, name: &str) -> io::Result<usize> {
+        self.disk
+            .lock()
+            .unwrap()
+            .names
+            .get(name)
+            .copied()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} not found", name)))
+    }
+}
+
+impl Storage for FaultyStorage {
+    fn open(&self, name: &str) -> Result<Box<dyn StorageReader>> {
+        let id = self.file(name)?;
+        let data = self.disk.lock().unwrap().files[id].data.clone();
+        Ok(Box::new(Cursor::new(data)))
+    }
+
+    fn create(&self, name: &str) -> Result<Box<dyn StorageWriter>> {
+        let mut disk = self.disk.lock().unwrap();
+        disk.check()?;
+        disk.files.push(File::default());
+        let id = disk.files.len() - 1;
+        disk.names.insert(name.to_owned(), id);
+        Ok(Box::new(FaultyWriter {
+            disk: self.disk.clone(),
+            id,
+            pos: 0,
+        }))
+    }
+
+    fn append(&self, name: &str) -> Result<Box<dyn StorageWriter>> {
+        let id = self.file(name)?;
+        let mut disk = self.disk.lock().unwrap();
+        disk.check()?;
+        let pos = disk.files[id].data.len() as u64;
+        Ok(Box::new(FaultyWriter {
+            disk: self.disk.clone(),
+            id,
+            pos,
+        }))
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        let id = self.file(from)?;
+        let mut disk = self.disk.lock().unwrap();
+        disk.check()?;
+        disk.names.remove(from);
+        disk.names.insert(to.to_owned(), id);
+        Ok(())
+    }
+
+    fn remove(&self, name: &str) -> Result<()> {
+        self.file(name)?;
+        let mut disk = self.disk.lock().unwrap();
+        disk.check()?;
+        disk.names.remove(name);
+        Ok(())
+    }
+
+    fn list(&self) -> Result<Vec<String>> {
+        Ok(self.disk.lock().unwrap().names.keys().cloned().collect())
+    }
+
+    fn sync_dir(&self) -> Result<()> {
+        let mut disk = self.disk.lock().unwrap();
+        disk.check()?;
+        disk.synced_names = disk.names.clone();
+        Ok(())
+    }
+}
+
+struct FaultyWriter {
+    disk: Arc<Mutex<Disk>>,
+    id: usize,
+    pos: u64,
+}
+
+impl Write for FaultyWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut disk = self.disk.lock().unwrap();
+        let crashing = disk.crashing();
+        let result = disk.check();
+        // the crashing write is torn, the others land whole or not at all
+        let len = match result {
+            Ok(()) => buf.len(),
+            Err(_) if crashing => buf.len() / 2,
+            Err(_) => 0,
+        };
+        let data = &mut disk.files[self.id].data;
+        let start = self.pos as usize;
+        if data.len() < start + len {
+            data.resize(start + len, 0);
+        }
+        data[start..start + len].copy_from_slice(&buf[..len]);
+        self.pos += len as u64;
+        result.map(|_| buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for FaultyWriter {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let len = self.disk.lock().unwrap().files[self.id].data.len() as i64;
+        let new_pos = match pos {
+            SeekFrom::Start(offset) => offset as i64,
+            SeekFrom::End(offset) => len + offset,
+            SeekFrom::Current(offset) => self.pos as i64 + offset,
+        };
+        self.pos = new_pos.max(0) as u64;
+        Ok(self.pos)
+    }
+}
+
+impl StorageWriter for FaultyWriter {
+    fn sync(&mut self) -> io::Result<()> {
+        let mut disk = self.disk.lock().unwrap();
+        disk.check()?;
+        let file = &mut disk.files[self.id];
+        file.synced = file.data.clone();
+        Ok(())
+    }
+}
+
+/// The values each key may be found with after a crash
+#[derive(Default)]
+struct History {
+    values: HashMap<String, Vec<Option<String>>>,
+}
+
+impl History {
+    /// a write of `key` was attempted, its value may be found from now on
+    fn write(&mut self, key: &str, value: Option<&str>) {
+        self.values
+            .entry(key.to_owned())
+            .or_insert_with(|| vec![None])
+            .push(value.map(str::to_owned));
+    }
+
+    /// every write so far must survive a crash, only the last value of each key may be found
+    fn durable(&mut self) {
+        for values in self.values.values_mut() {
+            values.drain(..values.len() - 1);
+        }
+    }
+
+    fn check(&self, store: &KvStore) -> Result<()> {
+        for (key, values) in self.values.iter() {
+            let found = store.get(key.as_str())?;
+            assert!(
+                values.contains(&found),
+                "{} is {:?}, expected one of {:?}",
+                key,
+                found,
+                values
+            );
+        }
+        for key in store.keys()? {
+            assert!(self.values.contains_key(&key), "{} was never written", key);
+        }
+        Ok(())
+    }
+}
+
+/// Write, remove and compact until the storage crashes, return what may be found after it
+fn workload(storage: FaultyStorage, options: KvStoreOptions, crash: Crash) -> History {
+    let mut history = History::default();
+    let store = match KvStore::open_with_storage(storage, options) {
+        Ok(store) => store,
+        Err(_) => return history,
+    };
+    let mut run = || -> Result<()> {
+        for round in 0..3 {
+            for key_id in 0..20 {
+                let key = format!("key{}", key_id);
+                if round > 0 && key_id % 7 == round {
+                    history.write(&key, None);
+                    store.remove(key.clone())?;
+                } else {
+                    let value = format!("value{}-{}", key_id, round);
+                    history.write(&key, Some(&value));
+                    store.set(key.clone(), value)?;
+                }
+                if let Crash::Process = crash {
+                    history.durable();
+                }
+            }
+            store.flush()?;
+            history.durable();
+            store.compact_range(Bound::Included("key1".to_owned()), Bound::Excluded("key2".to_owned()))?;
+            store.compact()?;
+        }
+        Ok(())
+    };
+    let _ = run();
+    history
+}
+
+fn crash_at_every_op(options: impl Fn() -> KvStoreOptions, crash: Crash) -> Result<()> {
+    for crash_at in 0.. {
+        let storage = FaultyStorage::crash_at(crash_at);
+        let mut history = workload(storage.clone(), options(), crash);
+
+        let recovered = storage.after_crash(crash)?;
+        let store = KvStore::open_with_storage(recovered.clone(), options())
+            .unwrap_or_else(|e| panic!("failed to reopen after crashing at op {}: {}", crash_at, e));
+        history.check(&store)?;
+        store.set("after", "crash")?;
+        history.write("after", Some("crash"));
+        drop(store);
+        let store = KvStore::open_with_storage(recovered, options())?;
+        assert_eq!(store.get("after")?, Some("crash".to_owned()));
+        history.check(&store)?;
+
+        if !storage.crashed() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+// A process crash at any point should lose no acknowledged write and leave the store openable
+#[test]
+fn process_crash() -> Result<()> {
+    crash_at_every_op(KvStoreOptions::new, Crash::Process)
+}
+
+// A power loss at any point should lose no write acknowledged by a flush
+#[test]
+fn power_loss() -> Result<()> {
+    crash_at_every_op(KvStoreOptions::new, Crash::Power)
+}
+
+#[test]
+fn process_crash_with_compressed_segments() -> Result<()> {
+    crash_at_every_op(|| KvStoreOptions::new().compress_sealed_segments(true), Crash::Process)
+}
+
+#[test]
+fn power_loss_with_compressed_segments() -> Result<()> {
+    crash_at_every_op(|| KvStoreOptions::new().compress_sealed_segments(true), Crash::Power)
+}
+
+// Blobs of large values should be durable before the records referring to them
+#[test]
+fn power_loss_with_large_values() -> Result<()> {
+    crash_at_every_op(|| KvStoreOptions::new().large_value_threshold(8), Crash::Power)
+}
+
+// A crash in the middle of a rename should leave the value under exactly one of the keys
+#[test]
+fn crash_during_rename() -> Result<()> {
+    let before = (Some("value".to_owned()), Some("old".to_owned()));
+    let after = (None, Some("value".to_owned()));
+    for &crash in [Crash::Process, Crash::Power].iter() {
+        for crash_at in 0.. {
+            let storage = FaultyStorage::default();
+            let store = KvStore::open_with_storage(storage.clone(), KvStoreOptions::new())?;
+            store.set("from", "value")?;
+            store.set("to", "old")?;
+            store.flush()?;
+            storage.crash_after(crash_at);
+            let renamed = store.rename("from", "to").and_then(|_| KvsEngine::flush(&store));
+            drop(store);
+
+            let store = KvStore::open_with_storage(storage.after_crash(crash)?, KvStoreOptions::new())?;
+            let found = (store.get("from")?, store.get("to")?);
+            if renamed.is_ok() {
+                assert_eq!(found, after, "renamed before crashing at op {}", crash_at);
+            } else {
+                assert!(
+                    found == before || found == after,
+                    "crashing at op {} left {:?}",
+                    crash_at,
+                    found
+                );
+            }
+            if !storage.crashed() {
+                break;
+            }
+        }
+    }
+    Ok(())
+}
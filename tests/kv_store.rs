@@ -1,6 +1,17 @@
-use kvs::{KvStore, KvsEngine, Result};
-use std::sync::{Arc, Barrier};
+#[cfg(feature = "tokio")]
+use kvs::AsyncKvStore;
+use kvs::{
+    engines_equal, CachingEngine, CompactionProgress, Durability, EngineType, IndexType, KvStore, KvStoreOptions,
+    KvsEngine, KvsError, MemoryStorage, Result, SegmentStat, ShardedKvStore, SledKvsEngine, SledOptions, Storage,
+    StorageReader, StorageWriter, WriterHandle,
+};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Bound;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Barrier, Mutex};
 use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -81,6 +92,109 @@ fn remove_key() -> Result<()> {
     Ok(())
 }
 
+// A removed key should be gone from the index, not left behind as a tombstone
+#[test]
+fn remove_key_twice() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+    store.set("key2", "value2")?;
+    store.remove("key1")?;
+    assert!(matches!(store.remove("key1"), Err(KvsError::KeyNotFound)));
+    assert_eq!(store.keys()?, vec!["key2".to_owned()]);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(matches!(store.remove("key1"), Err(KvsError::KeyNotFound)));
+    assert_eq!(store.keys()?, vec!["key2".to_owned()]);
+    Ok(())
+}
+
+fn remove_range_from<E: KvsEngine>(engine: E) -> Result<()> {
+    for i in 0..10 {
+        engine.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    engine.remove("key3".to_owned())?;
+
+    let removed = engine.remove_range(Bound::Included("key2".to_owned()), Bound::Excluded("key6".to_owned()))?;
+    assert_eq!(removed, 3);
+    for i in 0..10 {
+        let expected = if (2..6).contains(&i) {
+            None
+        } else {
+            Some(format!("value{}", i))
+        };
+        assert_eq!(engine.get(format!("key{}", i))?, expected);
+    }
+
+    assert_eq!(
+        engine.remove_range(Bound::Excluded("key8".to_owned()), Bound::Unbounded)?,
+        1
+    );
+    assert_eq!(engine.get("key9".to_owned())?, None);
+    assert_eq!(engine.get("key8".to_owned())?, Some("value8".to_owned()));
+    Ok(())
+}
+
+/// A `KvStore` behind only the required methods of `KvsEngine`, to exercise the defaults
+#[derive(Clone)]
+struct MinimalEngine(KvStore);
+
+impl KvsEngine for MinimalEngine {
+    fn get(&self, key: String) -> Result<Option<String>> {
+        KvsEngine::get(&self.0, key)
+    }
+
+    fn set(&self, key: String, value: String) -> Result<()> {
+        KvsEngine::set(&self.0, key, value)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        KvsEngine::remove(&self.0, key)
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        KvsEngine::keys(&self.0)
+    }
+}
+
+#[test]
+fn remove_range() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    remove_range_from(KvStore::open(temp_dir.path())?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    remove_range_from(SledKvsEngine::open(temp_dir.path())?)?;
+    let store = KvStore::open_with_storage(MemoryStorage::new(), KvStoreOptions::new())?;
+    remove_range_from(MinimalEngine(store))
+}
+
+fn cached_engine_invalidation<E: KvsEngine>(inner: E) -> Result<()> {
+    let engine = CachingEngine::new(inner, 100);
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    engine.clone().set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    engine.remove("key1".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+    engine.remove_range(Bound::Unbounded, Bound::Unbounded)?;
+    assert_eq!(engine.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// Cached results must follow overwrites and removals made through the cache
+#[test]
+fn caching_engine() -> Result<()> {
+    cached_engine_invalidation(KvStore::open_with_storage(MemoryStorage::new(), KvStoreOptions::new())?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    cached_engine_invalidation(SledKvsEngine::open(temp_dir.path())?)
+}
+
 // Insert data until total size of the directory decreases.
 // Test data correctness after compaction.
 #[test]
@@ -124,81 +238,2413 @@ fn compaction() -> Result<()> {
     panic!("No compaction detected");
 }
 
+// Writes after a compaction should go to the active segment it created, on disk
 #[test]
-fn concurrent_set() -> Result<()> {
+fn write_after_compaction() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::open(temp_dir.path())?;
-    let barrier = Arc::new(Barrier::new(1001));
-    for i in 0..1000 {
-        let store = store.clone();
-        let barrier = barrier.clone();
-        thread::spawn(move || {
-            store.set(format!("key{}", i), format!("value{}", i)).unwrap();
-            barrier.wait();
-        });
-    }
-    barrier.wait();
-
-    for i in 0..1000 {
-        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "old")?;
+        store.set(format!("key{}", key_id), "new")?;
     }
+    store.compact()?;
+    let active = store.segments()?.pop().unwrap().name;
+    let size = std::fs::metadata(temp_dir.path().join(&active))?.len();
 
-    // Open from disk again and check persistent data
+    store.set("key0", "after")?;
+    store.flush()?;
+    assert!(std::fs::metadata(temp_dir.path().join(&active))?.len() > size);
     drop(store);
+
     let store = KvStore::open(temp_dir.path())?;
-    for i in 0..1000 {
-        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    assert_eq!(store.get("key0")?, Some("after".to_owned()));
+    assert_eq!(store.get("key99")?, Some("new".to_owned()));
+    Ok(())
+}
+
+// Should compact and reopen without touching the file system
+#[test]
+fn memory_storage_compaction() -> Result<()> {
+    let storage = MemoryStorage::new();
+    let store = KvStore::open_with_storage(storage.clone(), KvStoreOptions::new())?;
+
+    for iter in 0..20 {
+        for key_id in 0..1000 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
     }
+    store.remove("key0")?;
+    drop(store);
+
+    let files = storage.list()?;
+    assert!(
+        files.contains(&"1.log".to_owned()),
+        "no compaction detected: {:?}",
+        files
+    );
 
+    let store = KvStore::open_with_storage(storage, KvStoreOptions::new())?;
+    assert_eq!(store.get("key0")?, None);
+    for key_id in 1..1000 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("19".to_owned()));
+    }
     Ok(())
 }
 
+// Should leave compaction to the caller when automatic compaction is disabled
 #[test]
-fn concurrent_get() -> Result<()> {
+fn compact_if_needed() -> Result<()> {
+    let storage = MemoryStorage::new();
+    let options = KvStoreOptions::new().auto_compaction(false);
+    let store = KvStore::open_with_storage(storage.clone(), options)?;
+    assert!(!store.compact_if_needed()?);
+
+    for iter in 0..12 {
+        for key_id in 0..1000 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    assert!(!storage.list()?.contains(&"1.log".to_owned()));
+
+    assert!(store.compact_if_needed()?);
+    assert!(storage.list()?.contains(&"1.log".to_owned()));
+    assert!(!store.compact_if_needed()?);
+    assert_eq!(store.get("key0")?, Some("11".to_owned()));
+    Ok(())
+}
+
+// Should describe the live segments before and after compaction
+#[test]
+fn list_segments() -> Result<()> {
+    let store = KvStore::open_with_storage(MemoryStorage::new(), KvStoreOptions::new().auto_compaction(false))?;
+    store.set("key1", "value1")?;
+    store.set("key2", "value2")?;
+    store.set("key1", "value3")?;
+
+    let segments = store.segments()?;
+    assert_eq!(store.epoch(), 0);
+    assert_eq!(segments.len(), 1);
+    assert_eq!(segments[0].epoch, 0);
+    assert_eq!(segments[0].name, "0.log");
+    assert_eq!(segments[0].live_keys, 2);
+    let size = segments[0].size;
+    assert!(size > 0);
+
+    for key_id in 0..10_001 {
+        store.set("key3", format!("{}", key_id))?;
+    }
+    assert!(store.compact_if_needed()?);
+    store.set("key4", "value4")?;
+
+    let segments = store.segments()?;
+    assert_eq!(store.epoch(), 1);
+    let summary: Vec<(usize, usize)> = segments.iter().map(|s| (s.epoch, s.live_keys)).collect();
+    assert_eq!(summary, vec![(0, 0), (1, 4)]);
+    assert!(segments[0].size > size);
+    Ok(())
+}
+
+// Should read values back from a compressed sealed segment, which takes less space on disk
+#[test]
+fn compressed_segments() -> Result<()> {
+    let live_size = |store: &KvStore| -> Result<u64> {
+        Ok(store
+            .segments()?
+            .iter()
+            .filter(|s| s.live_keys > 0)
+            .map(|s| s.size)
+            .sum())
+    };
+    let fill = |store: &KvStore| -> Result<()> {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("value{}", key_id).repeat(100))?;
+        }
+        store.compact()?;
+        store.set("key100", "value100")?;
+        Ok(())
+    };
+
+    let plain_dir = TempDir::new().expect("unable to create temporary working directory");
+    let plain = KvStore::open_with_options(plain_dir.path(), KvStoreOptions::new())?;
+    fill(&plain)?;
+
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = KvStore::open(temp_dir.path())?;
-    for i in 0..100 {
-        store.set(format!("key{}", i), format!("value{}", i)).unwrap();
+    let options = || KvStoreOptions::new().compress_sealed_segments(true);
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    fill(&store)?;
+
+    let segments = store.segments()?;
+    let summary: Vec<(usize, usize, bool)> = segments.iter().map(|s| (s.epoch, s.live_keys, s.compressed)).collect();
+    assert_eq!(summary, vec![(0, 0, false), (1, 100, true), (2, 1, false)]);
+    assert_eq!(store.epoch(), 2);
+    assert!(live_size(&store)? * 4 < live_size(&plain)?);
+
+    assert_eq!(store.get("key42")?, Some("value42".repeat(100)));
+    assert_eq!(store.get("key100")?, Some("value100".to_owned()));
+    assert_eq!(store.verify()?, 101);
+
+    // Reopen and compact again, rewriting the compressed segment
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    assert_eq!(store.get("key42")?, Some("value42".repeat(100)));
+    store.set("key42", "changed")?;
+    store.compact()?;
+    assert_eq!(store.epoch(), 4);
+    assert_eq!(store.get("key42")?, Some("changed".to_owned()));
+    assert_eq!(store.get("key7")?, Some("value7".repeat(100)));
+
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), KvStoreOptions::new())?;
+    assert_eq!(store.keys()?.len(), 101);
+    assert_eq!(store.get("key7")?, Some("value7".repeat(100)));
+    Ok(())
+}
+
+// Range compaction should rewrite only the keys within the range
+#[test]
+fn compact_range() -> Result<()> {
+    let storage = MemoryStorage::new();
+    let store = KvStore::open_with_storage(storage.clone(), KvStoreOptions::new())?;
+    for key_id in 0..10 {
+        store.set(format!("cold{}", key_id), format!("value{}", key_id))?;
+    }
+    for iter in 0..10 {
+        for key_id in 0..10 {
+            store.set(format!("hot{}", key_id), format!("{}", iter))?;
+        }
     }
+    store.remove("hot0")?;
+    store.compact_range(Bound::Included("hot".to_owned()), Bound::Excluded("hou".to_owned()))?;
 
-    let mut handles = Vec::new();
-    for thread_id in 0..100 {
-        let store = store.clone();
-        let handle = thread::spawn(move || {
-            for i in 0..100 {
-                let key_id = (i + thread_id) % 100;
-                assert_eq!(
-                    store.get(format!("key{}", key_id)).unwrap(),
-                    Some(format!("value{}", key_id))
-                );
+    let live: Vec<(usize, usize)> = store.segments()?.iter().map(|s| (s.epoch, s.live_keys)).collect();
+    assert_eq!(live, vec![(0, 10), (1, 9), (2, 0)]);
+    assert_eq!(store.epoch(), 2);
+    assert_eq!(store.verify()?, 19);
+
+    // the cold keys are left in place, until a range covering them leaves the oldest segment dead
+    store.set("hot1", "new")?;
+    store.compact_range(Bound::Included("cold".to_owned()), Bound::Excluded("coldz".to_owned()))?;
+    let live: Vec<(usize, usize)> = store.segments()?.iter().map(|s| (s.epoch, s.live_keys)).collect();
+    assert_eq!(live, vec![(0, 0), (1, 8), (2, 1), (3, 10), (4, 0)]);
+
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.get("cold3")?, Some("value3".to_owned()));
+        assert_eq!(store.get("hot0")?, None);
+        assert_eq!(store.get("hot1")?, Some("new".to_owned()));
+        assert_eq!(store.get("hot2")?, Some("9".to_owned()));
+        assert_eq!(store.keys()?.len(), 19);
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    let store = KvStore::open_with_storage(storage, KvStoreOptions::new())?;
+    check(&store)?;
+
+    // the dead segment was only retired, the next range compaction removes it
+    store.compact_range(Bound::Included("hot".to_owned()), Bound::Unbounded)?;
+    let epochs: Vec<usize> = store.segments()?.iter().map(|s| s.epoch).collect();
+    assert_eq!(epochs, vec![1, 2, 3, 4, 5, 6]);
+    check(&store)
+}
+
+fn modify_sealed_segment(compress: bool) -> Result<()> {
+    let storage = MemoryStorage::new();
+    let options = || KvStoreOptions::new().compress_sealed_segments(compress);
+    let store = KvStore::open_with_storage(storage.clone(), options())?;
+    for key_id in 0..20 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.compact_range(Bound::Unbounded, Bound::Unbounded)?;
+    let sealed = format!("{}.log", store.epoch() - 1);
+    assert_eq!(store.verify()?, 20);
+    drop(store);
+    let store = KvStore::open_with_storage(storage.clone(), options())?;
+    assert_eq!(store.verify()?, 20);
+
+    let mut log = Vec::new();
+    storage.open(&sealed)?.read_to_end(&mut log)?;
+    // plain records still parse with `value7` turned into `value6`
+    let pos = match compress {
+        true => log.len() / 2,
+        false => log.windows(6).position(|w| w == b"value7").unwrap() + 5,
+    };
+    log[pos] ^= 1;
+    storage.create(&sealed)?.write_all(&log)?;
+
+    match store.verify() {
+        Err(KvsError::Corrupted(_)) => {}
+        Err(e) => panic!("expected a corrupted segment, got {}", e),
+        Ok(_) => panic!("expected a corrupted segment"),
+    }
+    drop(store);
+    match KvStore::open_with_storage(storage.clone(), options()) {
+        Err(KvsError::Corrupted(_)) => {}
+        Err(e) => panic!("expected a corrupted segment, got {}", e),
+        Ok(_) => panic!("expected a corrupted segment"),
+    }
+    // the segment the records were compacted from is still there to rebuild them
+    let store = KvStore::repair_with_storage(storage, options())?;
+    assert_eq!(store.keys()?.len(), 20);
+    Ok(())
+}
+
+// A modified byte in a sealed segment should be caught by the checksum in its footer,
+// even where the records still parse
+#[test]
+fn sealed_segment_checksum() -> Result<()> {
+    modify_sealed_segment(false)?;
+    modify_sealed_segment(true)
+}
+
+// Large values should live in blob files, collected once no live key refers to them
+#[test]
+fn large_values() -> Result<()> {
+    let storage = MemoryStorage::new();
+    let options = || KvStoreOptions::new().large_value_threshold(100);
+    let blobs = || -> Result<usize> { Ok(storage.list()?.iter().filter(|name| name.ends_with(".blob")).count()) };
+    let log_size = |store: &KvStore| -> Result<u64> { Ok(store.segments()?.iter().map(|s| s.size).sum()) };
+
+    let store = KvStore::open_with_storage(storage.clone(), options())?;
+    let (a, b) = ("a".repeat(1000), "b".repeat(1000));
+    store.set("small", "value")?;
+    store.set("key1", a.as_str())?;
+    store.set("key2", a.as_str())?;
+    assert_eq!(blobs()?, 1);
+    assert!(log_size(&store)? < 1000);
+    store.set("key1", b.as_str())?;
+    assert_eq!(blobs()?, 2);
+    assert_eq!(store.get("key1")?, Some(b.clone()));
+    assert_eq!(store.get("key2")?, Some(a.clone()));
+    assert_eq!(store.verify()?, 3);
+
+    // both blobs are still referred to, also after reopening
+    drop(store);
+    let store = KvStore::open_with_storage(storage.clone(), options())?;
+    store.compact()?;
+    assert_eq!(blobs()?, 2);
+    assert_eq!(store.get("key2")?, Some(a.clone()));
+
+    store.remove("key2")?;
+    store.compact()?;
+    assert_eq!(blobs()?, 1);
+    assert_eq!(store.get("key1")?, Some(b.clone()));
+
+    // stored in the log again without the option, which still reads the blobs
+    drop(store);
+    let store = KvStore::open_with_storage(storage.clone(), KvStoreOptions::new())?;
+    assert_eq!(store.get("key1")?, Some(b.clone()));
+    store.set("key3", a.as_str())?;
+    store.set("key1", "small")?;
+    store.compact()?;
+    assert_eq!(blobs()?, 0);
+    assert_eq!(store.get("key3")?, Some(a));
+    assert_eq!(store.verify()?, 3);
+    Ok(())
+}
+
+// Should report compaction progress from start to finish
+#[test]
+fn compaction_progress() -> Result<()> {
+    let events = Arc::new(Mutex::new(Vec::<CompactionProgress>::new()));
+    let recorded = events.clone();
+    let options = KvStoreOptions::new().compaction_progress(move |progress| recorded.lock().unwrap().push(progress));
+    let store = KvStore::open_with_storage(MemoryStorage::new(), options)?;
+
+    for iter in 0..12 {
+        for key_id in 0..1000 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+
+    let events = events.lock().unwrap();
+    let first = events.first().expect("no compaction progress reported");
+    let last = events.last().unwrap();
+    assert_eq!(first.epoch, 1);
+    assert_eq!(first.bytes_processed, 0);
+    assert!(last.bytes_total > 0);
+    assert_eq!(last.bytes_processed, last.bytes_total);
+    Ok(())
+}
+
+// Writes made while a background compaction copies records must survive it and a reopen
+#[test]
+fn background_compaction() -> Result<()> {
+    // hold the first compaction right after it took its records, until the writes below are done
+    let barrier = Arc::new(Barrier::new(2));
+    let held = Arc::new(AtomicBool::new(false));
+    let compactor_barrier = barrier.clone();
+    let options = KvStoreOptions::new()
+        .background_compaction(true)
+        .compaction_progress(move |progress| {
+            if progress.bytes_processed == 0 && !held.swap(true, Ordering::SeqCst) {
+                compactor_barrier.wait();
+                compactor_barrier.wait();
             }
         });
-        handles.push(handle);
+    let storage = MemoryStorage::new();
+    let store = KvStore::open_with_storage(storage.clone(), options.clone())?;
+
+    for iter in 0..12 {
+        for key_id in 0..1000 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
     }
-    for handle in handles {
-        handle.join().unwrap();
+    barrier.wait();
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "after")?;
+        store.remove(format!("key{}", key_id + 100))?;
+        store.set(format!("new{}", key_id), "after")?;
     }
+    assert_eq!(store.epoch(), 0);
+    barrier.wait();
 
-    // Open from disk again and check persistent data
+    for _ in 0..500 {
+        if store.epoch() == 1 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(store.epoch(), 1);
+
+    let check = |store: &KvStore| -> Result<()> {
+        for key_id in 0..100 {
+            assert_eq!(store.get(format!("key{}", key_id))?, Some("after".to_owned()));
+            assert_eq!(store.get(format!("key{}", key_id + 100))?, None);
+            assert_eq!(store.get(format!("new{}", key_id))?, Some("after".to_owned()));
+        }
+        for key_id in 200..1000 {
+            assert_eq!(store.get(format!("key{}", key_id))?, Some("11".to_owned()));
+        }
+        Ok(())
+    };
+    check(&store)?;
     drop(store);
-    let store = KvStore::open(temp_dir.path())?;
-    let mut handles = Vec::new();
-    for thread_id in 0..100 {
-        let store = store.clone();
-        let handle = thread::spawn(move || {
-            for i in 0..100 {
-                let key_id = (i + thread_id) % 100;
-                assert_eq!(
-                    store.get(format!("key{}", key_id)).unwrap(),
-                    Some(format!("value{}", key_id))
-                );
+
+    let store = KvStore::open_with_storage(storage, options)?;
+    check(&store)?;
+    Ok(())
+}
+
+// Keys written once must stay readable while later writes span several log segments
+#[test]
+fn get_across_segments() -> Result<()> {
+    let storage = MemoryStorage::new();
+    let store = KvStore::open_with_storage(storage.clone(), KvStoreOptions::new().reader_cache_capacity(1))?;
+    for key_id in 0..100 {
+        store.set(format!("stable{}", key_id), format!("value{}", key_id))?;
+    }
+    store.set("removed", "value")?;
+    store.remove("removed")?;
+
+    for iter in 0..25 {
+        for key_id in 0..1000 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+        assert_eq!(store.get("stable0")?, Some("value0".to_owned()));
+        assert_eq!(store.get(format!("key{}", iter))?, Some(format!("{}", iter)));
+    }
+
+    let check = |store: &KvStore| -> Result<()> {
+        for key_id in 0..100 {
+            assert_eq!(
+                store.get(format!("stable{}", key_id))?,
+                Some(format!("value{}", key_id))
+            );
+        }
+        for key_id in 0..1000 {
+            assert_eq!(store.get(format!("key{}", key_id))?, Some("24".to_owned()));
+        }
+        assert_eq!(store.get("removed")?, None);
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    check(&KvStore::open_with_storage(storage, KvStoreOptions::new())?)
+}
+
+// Cached values must not outlive writes or compactions of their key
+#[test]
+fn value_cache() -> Result<()> {
+    let store = KvStore::open_with_storage(MemoryStorage::new(), KvStoreOptions::new().value_cache_capacity(10))?;
+    let reader = store.clone();
+    for iter in 0..15 {
+        for key_id in 0..1000 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+            if key_id % 100 == 0 {
+                assert_eq!(reader.get(format!("key{}", key_id))?, Some(format!("{}", iter)));
+                assert_eq!(reader.get("key0")?, Some(format!("{}", iter)));
             }
-        });
-        handles.push(handle);
+        }
     }
-    for handle in handles {
-        handle.join().unwrap();
+    store.remove("key0")?;
+    assert_eq!(reader.get("key0")?, None);
+    store.set("key0", "again")?;
+    assert_eq!(reader.get("key0")?, Some("again".to_owned()));
+    Ok(())
+}
+
+// Keys rejected by the validator should fail every operation
+#[test]
+fn key_validator() -> Result<()> {
+    let options = KvStoreOptions::new().key_validator(|key| {
+        if key.len() > 8 {
+            Err(format!("`{}` is longer than 8 bytes", key))
+        } else {
+            Ok(())
+        }
+    });
+    let store = KvStore::open_with_storage(MemoryStorage::new(), options)?;
+    store.set("key1", "value1")?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+
+    let long_key = "a-long-key";
+    match store.set(long_key, "value") {
+        Err(KvsError::InvalidKey(reason)) => assert!(reason.contains(long_key)),
+        other => panic!("expected an invalid key, got {:?}", other),
+    }
+    assert!(matches!(store.get(long_key), Err(KvsError::InvalidKey(_))));
+    assert!(matches!(store.remove(long_key), Err(KvsError::InvalidKey(_))));
+    Ok(())
+}
+
+/// `MemoryStorage` keeping track of how many readers are open at most, and of the reads
+#[derive(Clone, Debug, Default)]
+struct CountingStorage {
+    inner: MemoryStorage,
+    readers: Arc<AtomicUsize>,
+    peak: Arc<AtomicUsize>,
+    reads: Arc<AtomicUsize>,
+}
+
+struct CountedReader {
+    inner: Box<dyn StorageReader>,
+    readers: Arc<AtomicUsize>,
+    reads: Arc<AtomicUsize>,
+}
+
+impl Read for CountedReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reads.fetch_add(1, Ordering::SeqCst);
+        self.inner.read(buf)
+    }
+}
+
+impl Seek for CountedReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+impl Drop for CountedReader {
+    fn drop(&mut self) {
+        self.readers.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Storage for CountingStorage {
+    fn open(&self, name: &str) -> Result<Box<dyn StorageReader>> {
+        let open = self.readers.fetch_add(1, Ordering::SeqCst) + 1;
+        self.peak.fetch_max(open, Ordering::SeqCst);
+        Ok(Box::new(CountedReader {
+            inner: self.inner.open(name)?,
+            readers: self.readers.clone(),
+            reads: self.reads.clone(),
+        }))
+    }
+
+    fn create(&self, name: &str) -> Result<Box<dyn StorageWriter>> {
+        self.inner.create(name)
+    }
+
+    fn append(&self, name: &str) -> Result<Box<dyn StorageWriter>> {
+        self.inner.append(name)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        self.inner.rename(from, to)
+    }
+
+    fn remove(&self, name: &str) -> Result<()> {
+        self.inner.remove(name)
     }
 
+    fn list(&self) -> Result<Vec<String>> {
+        self.inner.list()
+    }
+}
+
+// Handles should close each other's idle readers to stay within `max_open_files`
+#[test]
+fn max_open_files() -> Result<()> {
+    let options = KvStoreOptions::new().max_open_files(1);
+    assert!(matches!(
+        KvStore::open_with_storage(MemoryStorage::new(), options),
+        Err(KvsError::InvalidOption(_))
+    ));
+
+    let storage = CountingStorage::default();
+    let store = KvStore::open_with_storage(storage.clone(), KvStoreOptions::new().max_open_files(3))?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "value")?;
+    }
+    store.compact()?;
+    storage
+        .peak
+        .store(storage.readers.load(Ordering::SeqCst), Ordering::SeqCst);
+
+    let handles: Vec<KvStore> = (0..5).map(|_| store.clone()).collect();
+    for _ in 0..3 {
+        for (handle_id, handle) in handles.iter().enumerate() {
+            assert_eq!(handle.get(format!("key{}", handle_id))?, Some("value".to_owned()));
+        }
+    }
+    assert!(storage.peak.load(Ordering::SeqCst) <= 2);
+    Ok(())
+}
+
+// Short values should be served from the index without reading the log, within its capacity
+#[test]
+fn inline_values() -> Result<()> {
+    let storage = CountingStorage::default();
+    let options = || KvStoreOptions::new().inline_values(8, 16);
+    let store = KvStore::open_with_storage(storage.clone(), options())?;
+    store.set("small1", "12345678")?;
+    store.set("large", "123456789")?;
+    store.set("small2", "abcdefgh")?;
+    // past the capacity until `small1` is removed
+    store.set("small3", "x")?;
+    store.remove("small1")?;
+    drop(store);
+
+    let store = KvStore::open_with_storage(storage.clone(), options())?;
+    store.set("small4", "y")?;
+    let reads = |key: &str| -> Result<(Option<String>, usize)> {
+        let before = storage.reads.load(Ordering::SeqCst);
+        let value = store.get(key)?;
+        Ok((value, storage.reads.load(Ordering::SeqCst) - before))
+    };
+    assert_eq!(reads("small2")?, (Some("abcdefgh".to_owned()), 0));
+    assert_eq!(reads("small4")?, (Some("y".to_owned()), 0));
+    assert_eq!(reads("small1")?, (None, 0));
+    let (value, count) = reads("large")?;
+    assert_eq!(value, Some("123456789".to_owned()));
+    assert!(count > 0);
+    // kept once read, as there is room left now
+    let (value, count) = reads("small3")?;
+    assert_eq!(value, Some("x".to_owned()));
+    assert!(count > 0);
+    assert_eq!(reads("small3")?, (Some("x".to_owned()), 0));
+
+    store.set("small2", "changed")?;
+    assert_eq!(store.get("small2")?, Some("changed".to_owned()));
+    store.compact()?;
+    assert_eq!(reads("small4")?, (Some("y".to_owned()), 0));
+    assert_eq!(store.get("small2")?, Some("changed".to_owned()));
+    assert_eq!(store.get("large")?, Some("123456789".to_owned()));
+    Ok(())
+}
+
+// Values kept in memory should be shared between gets, others read into new strings
+#[test]
+fn get_shared() -> Result<()> {
+    let inline = KvStore::open_with_storage(MemoryStorage::new(), KvStoreOptions::new().inline_values(16, 1024))?;
+    let cached = KvStore::open_with_storage(MemoryStorage::new(), KvStoreOptions::new().value_cache_capacity(10))?;
+    let plain = KvStore::open_with_storage(MemoryStorage::new(), KvStoreOptions::new())?;
+    for store in [&inline, &cached, &plain].iter() {
+        store.set("key1", "value1")?;
+        assert_eq!(store.get_shared("key2")?, None);
+    }
+
+    let shared = |store: &KvStore| -> Result<bool> {
+        let first = store.get_shared("key1")?.unwrap();
+        let second = store.get_shared("key1")?.unwrap();
+        assert_eq!(&*first, "value1");
+        assert_eq!(first, second);
+        Ok(Arc::ptr_eq(&first, &second))
+    };
+    assert!(shared(&inline)?);
+    assert!(shared(&cached)?);
+    assert!(!shared(&plain)?);
+
+    let before = inline.get_shared("key1")?.unwrap();
+    inline.set("key1", "value2")?;
+    assert_eq!(inline.get_shared("key1")?.as_deref(), Some("value2"));
+    assert_eq!(&*before, "value1");
+    Ok(())
+}
+
+// Every operation should be counted in the latency report
+#[cfg(feature = "hdrhistogram")]
+#[test]
+fn latency_report() -> Result<()> {
+    let store = KvStore::open_with_storage(MemoryStorage::new(), KvStoreOptions::new())?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), "value")?;
+        store.get(format!("key{}", key_id))?;
+    }
+    store.remove("key0")?;
+    store.remove_range(Bound::Included("key8".to_owned()), Bound::Unbounded)?;
+    store.compact()?;
+
+    let report = store.clone().latency_report();
+    assert_eq!(report.get.count, 10);
+    assert_eq!(report.set.count, 10);
+    assert_eq!(report.remove.count, 2);
+    assert_eq!(report.compact.count, 1);
+    assert!(report.set.p50 <= report.set.p99 && report.set.p99 <= report.set.max);
+    Ok(())
+}
+
+/// `MemoryStorage` whose file operations start failing after a given number, like a crashed process
+#[derive(Clone, Debug, Default)]
+struct CrashingStorage {
+    inner: MemoryStorage,
+    ops_left: Arc<Mutex<Option<usize>>>,
+}
+
+impl CrashingStorage {
+    fn check(&self) -> Result<()> {
+        match &mut *self.ops_left.lock().unwrap() {
+            Some(0) => Err(io::Error::other("crashed").into()),
+            Some(left) => {
+                *left -= 1;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+impl Storage for CrashingStorage {
+    fn open(&self, name: &str) -> Result<Box<dyn StorageReader>> {
+        self.inner.open(name)
+    }
+
+    fn create(&self, name: &str) -> Result<Box<dyn StorageWriter>> {
+        self.check()?;
+        self.inner.create(name)
+    }
+
+    fn append(&self, name: &str) -> Result<Box<dyn StorageWriter>> {
+        self.check()?;
+        self.inner.append(name)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        self.check()?;
+        self.inner.rename(from, to)
+    }
+
+    fn remove(&self, name: &str) -> Result<()> {
+        self.check()?;
+        self.inner.remove(name)
+    }
+
+    fn list(&self) -> Result<Vec<String>> {
+        self.inner.list()
+    }
+
+    fn sync_dir(&self) -> Result<()> {
+        self.check()
+    }
+}
+
+// A compaction interrupted at any file operation should reopen with every write intact
+#[test]
+fn crash_during_compaction() -> Result<()> {
+    for crash_at in 0.. {
+        let storage = CrashingStorage::default();
+        let store = KvStore::open_with_storage(storage.clone(), KvStoreOptions::new())?;
+        for iter in 0..3 {
+            for key_id in 0..100 {
+                store.set(format!("key{}", key_id), format!("{}", iter))?;
+            }
+            // the second compaction also removes the segment of the first one
+            if iter == 1 {
+                store.compact()?;
+            }
+        }
+
+        *storage.ops_left.lock().unwrap() = Some(crash_at);
+        let crashed = store.compact().is_err();
+        drop(store);
+
+        let store = KvStore::open_with_storage(storage.inner.clone(), KvStoreOptions::new())?;
+        for key_id in 0..100 {
+            assert_eq!(store.get(format!("key{}", key_id))?, Some("2".to_owned()));
+        }
+        store.set("key0", "after")?;
+        drop(store);
+        let store = KvStore::open_with_storage(storage.inner, KvStoreOptions::new())?;
+        assert_eq!(store.get("key0")?, Some("after".to_owned()));
+
+        if !crashed {
+            break;
+        }
+    }
+    Ok(())
+}
+
+// Files left by a crash should be removed, without touching any the store uses
+#[test]
+fn gc_orphans() -> Result<()> {
+    let storage = MemoryStorage::new();
+    let options = || KvStoreOptions::new().large_value_threshold(16);
+    let store = KvStore::open_with_storage(storage.clone(), options())?;
+    for iter in 0..2 {
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+        store.set("large", format!("a value longer than the threshold {}", iter))?;
+        store.compact()?;
+    }
+    store.set("key0", "after")?;
+    assert!(!storage.list()?.contains(&"0.log".to_owned()));
+    let segments = store.segments()?;
+    let mut files = storage.list()?;
+
+    let orphans = ["0.log", "100.log", "CURRENT.tmp", "dead.blob", "dead.blob.tmp", "temp"];
+    for name in orphans.iter().chain(["notes.txt"].iter()) {
+        storage.create(name)?.write_all(b"junk")?;
+    }
+    assert_eq!(store.gc_orphans()?, orphans);
+    assert!(store.gc_orphans()?.is_empty());
+    files.push("notes.txt".to_owned());
+    files.sort();
+    let mut left = storage.list()?;
+    left.sort();
+    assert_eq!(left, files);
+    assert_eq!(store.segments()?, segments);
+    assert_eq!(store.get("key0")?, Some("after".to_owned()));
+    assert_eq!(
+        store.get("large")?,
+        Some("a value longer than the threshold 1".to_owned())
+    );
+    drop(store);
+
+    storage.create("temp")?.write_all(b"junk")?;
+    let store = KvStore::open_with_storage(storage.clone(), options().gc_orphans_on_open(true))?;
+    assert!(!storage.list()?.contains(&"temp".to_owned()));
+    assert_eq!(store.get("key1")?, Some("1".to_owned()));
+    Ok(())
+}
+
+fn storage_with_log(log: &str) -> Result<MemoryStorage> {
+    let storage = MemoryStorage::new();
+    storage.create(".engine")?.write_all(b"kvs")?;
+    storage.create("0.log")?.write_all(log.as_bytes())?;
+    Ok(storage)
+}
+
+// Segments written before the format header should still open
+#[test]
+fn open_log_without_header() -> Result<()> {
+    let storage = storage_with_log(r#"{"Set":["key1","value1"]}{"Set":["key2","value2"]}{"Rm":"key1"}"#)?;
+    let store = KvStore::open_with_storage(storage, KvStoreOptions::new())?;
+    assert_eq!(store.get("key1")?, None);
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+    store.set("key3", "value3")?;
+    assert_eq!(store.get("key3")?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Segments of a newer format should be refused instead of misread
+#[test]
+fn open_log_of_newer_format() -> Result<()> {
+    let storage = storage_with_log(r#"{"format":99}{"Set":["key1","value1"]}"#)?;
+    match KvStore::open_with_storage(storage, KvStoreOptions::new()) {
+        Err(KvsError::UnsupportedFormat { found, supported }) => {
+            assert_eq!(found, 99);
+            assert!(supported < found);
+        }
+        Err(e) => panic!("expected an unsupported format, got {}", e),
+        Ok(_) => panic!("expected an unsupported format"),
+    }
+    Ok(())
+}
+
+// Repair should rebuild the store from its segments when `CURRENT` is garbage
+#[test]
+fn repair_corrupted_manifest() -> Result<()> {
+    let storage = MemoryStorage::new();
+    let options = || KvStoreOptions::new().compress_sealed_segments(true);
+    let store = KvStore::open_with_storage(storage.clone(), options())?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), "old")?;
+    }
+    store.remove("key0")?;
+    store.compact()?;
+    store.set("key1", "new")?;
+    store.remove("key2")?;
+    store.compact()?;
+    store.set("key3", "newer")?;
+    store.remove("key4")?;
+    drop(store);
+
+    storage.create("CURRENT")?.write_all(b"\0garbage")?;
+    match KvStore::open_with_storage(storage.clone(), options()) {
+        Err(KvsError::Corrupted(_)) => {}
+        Err(e) => panic!("expected a corrupted log, got {}", e),
+        Ok(_) => panic!("expected a corrupted log"),
+    }
+
+    let check = |store: &KvStore| -> Result<()> {
+        let mut expected: Vec<String> = (5..10).map(|key_id| format!("key{}", key_id)).collect();
+        expected.splice(0..0, vec!["key1".to_owned(), "key3".to_owned()]);
+        assert_eq!(store.keys()?, expected);
+        assert_eq!(store.get("key1")?, Some("new".to_owned()));
+        assert_eq!(store.get("key3")?, Some("newer".to_owned()));
+        assert_eq!(store.get("key5")?, Some("old".to_owned()));
+        Ok(())
+    };
+    let store = KvStore::repair_with_storage(storage.clone(), options())?;
+    check(&store)?;
+    assert_eq!(store.segments()?.len(), 1);
+    assert_eq!(store.verify()?, 7);
+    drop(store);
+
+    check(&KvStore::open_with_storage(storage, options())?)
+}
+
+// Repair should keep the records before a corrupted one and drop the rest of its segment
+#[test]
+fn repair_corrupted_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+    store.set("key2", "value2")?;
+    store.remove("key1")?;
+    drop(store);
+
+    let mut log = std::fs::OpenOptions::new()
+        .append(true)
+        .open(temp_dir.path().join("0.log"))?;
+    log.write_all(br#"{"Set":["key3",#]}{"Set":["key4","value4"]}"#)?;
+    drop(log);
+    assert!(KvStore::open(temp_dir.path()).is_err());
+
+    let store = KvStore::repair(temp_dir.path())?;
+    assert_eq!(store.keys()?, vec!["key2".to_owned()]);
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+    store.set("key3", "value3")?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3")?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Reads must stay correct across several compactions even with a single cached reader.
+#[test]
+fn small_reader_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_options(temp_dir.path(), KvStoreOptions::new().reader_cache_capacity(1))?;
+    let reader = store.clone();
+
+    for iter in 0..30 {
+        for key_id in 0..1000 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+        for key_id in (0..1000).step_by(100) {
+            assert_eq!(reader.get(format!("key{}", key_id))?, Some(format!("{}", iter)));
+        }
+    }
+
+    drop(store);
+    drop(reader);
+    let store = KvStore::open_with_options(temp_dir.path(), KvStoreOptions::new().reader_cache_capacity(1))?;
+    for key_id in 0..1000 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("29".to_owned()));
+    }
+    Ok(())
+}
+
+#[test]
+fn concurrent_set() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let barrier = Arc::new(Barrier::new(1001));
+    for i in 0..1000 {
+        let store = store.clone();
+        let barrier = barrier.clone();
+        thread::spawn(move || {
+            store.set(format!("key{}", i), format!("value{}", i)).unwrap();
+            barrier.wait();
+        });
+    }
+    barrier.wait();
+
+    for i in 0..1000 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..1000 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn concurrent_get() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i)).unwrap();
+    }
+
+    let mut handles = Vec::new();
+    for thread_id in 0..100 {
+        let store = store.clone();
+        let handle = thread::spawn(move || {
+            for i in 0..100 {
+                let key_id = (i + thread_id) % 100;
+                assert_eq!(
+                    store.get(format!("key{}", key_id)).unwrap(),
+                    Some(format!("value{}", key_id))
+                );
+            }
+        });
+        handles.push(handle);
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    let mut handles = Vec::new();
+    for thread_id in 0..100 {
+        let store = store.clone();
+        let handle = thread::spawn(move || {
+            for i in 0..100 {
+                let key_id = (i + thread_id) % 100;
+                assert_eq!(
+                    store.get(format!("key{}", key_id)).unwrap(),
+                    Some(format!("value{}", key_id))
+                );
+            }
+        });
+        handles.push(handle);
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    Ok(())
+}
+
+#[test]
+fn engine_type_round_trip() -> Result<()> {
+    for engine in [EngineType::KvStore, EngineType::Sled].iter() {
+        assert_eq!(engine.to_string().parse::<EngineType>()?, *engine);
+    }
+    match "foo".parse::<EngineType>() {
+        Err(KvsError::UnknownEngine(name)) => assert_eq!(name, "foo"),
+        other => panic!("unexpected result: {:?}", other),
+    }
+    Ok(())
+}
+
+// sled releases its file lock from background threads shortly after the last handle is dropped
+fn reopen_sled(path: &Path) -> Result<SledKvsEngine> {
+    let mut attempts = 0;
+    loop {
+        match SledKvsEngine::open(path) {
+            Err(KvsError::SledError(_)) if attempts < 50 => {
+                attempts += 1;
+                thread::sleep(Duration::from_millis(20));
+            }
+            result => return result,
+        }
+    }
+}
+
+// Writes without a flush per operation should still be persisted once the engine is dropped
+#[test]
+fn sled_durability_modes() -> Result<()> {
+    for durability in [Durability::FlushOnWrite, Durability::Periodic] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let engine = SledKvsEngine::open_with_options(temp_dir.path(), SledOptions::new().durability(durability))?;
+        engine.set("key1", "value1")?;
+        engine.set("key2", "value2")?;
+        engine.remove("key2")?;
+        drop(engine);
+
+        let engine = reopen_sled(temp_dir.path())?;
+        assert_eq!(engine.get("key1")?, Some("value1".to_owned()));
+        assert_eq!(engine.get("key2")?, None);
+    }
+    Ok(())
+}
+
+// Should compare the live keyspace of engines of different types
+#[test]
+fn compare_engines() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_storage(MemoryStorage::new(), KvStoreOptions::new())?;
+    let sled = SledKvsEngine::open(temp_dir.path())?;
+    assert!(engines_equal(&store, &sled)?);
+
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.remove("key0")?;
+    for key in store.keys()? {
+        sled.set(key.clone(), store.get(key)?.unwrap())?;
+    }
+    assert!(engines_equal(&store, &sled)?);
+
+    sled.set("key1", "changed")?;
+    assert!(!engines_equal(&store, &sled)?);
+    sled.remove("key1")?;
+    assert!(!engines_equal(&store, &sled)?);
+    Ok(())
+}
+
+// Writes buffered without flushing should be readable, and persisted when the store is dropped
+#[test]
+fn deferred_flush_persisted_on_drop() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || KvStoreOptions::new().flush_on_write(false);
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    let empty = std::fs::metadata(temp_dir.path().join("0.log"))?.len();
+
+    store.set("key1", "value1")?;
+    store.set("key2", "value2")?;
+    store.remove("key2")?;
+    assert_eq!(std::fs::metadata(temp_dir.path().join("0.log"))?.len(), empty);
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+
+    store.set("key3", "value3")?;
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2")?, None);
+    assert_eq!(store.get("key3")?, Some("value3".to_owned()));
+
+    // compaction flushes the buffer before copying the live records
+    for key_id in 0..2000 {
+        store.set("key4", format!("{}", key_id))?;
+    }
+    store.compact()?;
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    assert_eq!(store.get("key4")?, Some("1999".to_owned()));
+    assert_eq!(store.keys()?.len(), 3);
+    Ok(())
+}
+
+fn add(current: Option<&str>, operand: &str) -> String {
+    let current: i64 = current.map_or(0, |value| value.parse().unwrap());
+    (current + operand.parse::<i64>().unwrap()).to_string()
+}
+
+// Merges should fold operands into the value with the operator set at open
+#[test]
+fn merge_operator() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_options(temp_dir.path(), KvStoreOptions::new().merge_operator(add))?;
+    store.merge("counter", "2")?;
+    store.merge("counter", "3")?;
+    store.set("other", "10")?;
+    store.merge("other", "-1")?;
+    assert_eq!(store.get("counter")?, Some("5".to_owned()));
+    assert_eq!(store.get("other")?, Some("9".to_owned()));
+
+    // merged values are plain values, readable without the operator
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("counter")?, Some("5".to_owned()));
+    assert!(matches!(store.merge("counter", "1"), Err(KvsError::NoMergeOperator)));
+
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    let sled = SledKvsEngine::open_with_options(sled_dir.path(), SledOptions::new().merge_operator(add))?;
+    sled.merge("counter".to_owned(), "2".to_owned())?;
+    sled.merge("counter".to_owned(), "3".to_owned())?;
+    assert_eq!(sled.get("counter")?, Some("5".to_owned()));
+
+    let cached = CachingEngine::new(sled, 10);
+    assert_eq!(cached.get("counter".to_owned())?, Some("5".to_owned()));
+    cached.merge("counter".to_owned(), "1".to_owned())?;
+    assert_eq!(cached.get("counter".to_owned())?, Some("6".to_owned()));
+    Ok(())
+}
+
+// Health checks should pass on working stores, and fail once a write panicked holding the writer
+#[test]
+fn health_check() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_options(temp_dir.path(), KvStoreOptions::new().merge_operator(add))?;
+    store.health_check()?;
+    store.set("key1", "value1")?;
+    store.health_check()?;
+    store.compact()?;
+    store.set("key2", "value2")?;
+    store.health_check()?;
+    store.namespace("users".to_owned()).health_check()?;
+
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    CachingEngine::new(SledKvsEngine::open(sled_dir.path())?, 10).health_check()?;
+    drop(store);
+    KvStore::open_read_only(temp_dir.path(), KvStoreOptions::new())?.health_check()?;
+
+    let store = KvStore::open_with_storage(MemoryStorage::new(), KvStoreOptions::new().merge_operator(add))?;
+    let panicking = store.clone();
+    assert!(thread::spawn(move || panicking.merge("counter", "not a number"))
+        .join()
+        .is_err());
+    match store.health_check() {
+        Err(KvsError::Unhealthy(_)) => {}
+        other => panic!("expected an unhealthy store, got {:?}", other),
+    }
+    Ok(())
+}
+
+// `try_set` and `try_remove` should fail right away while a compaction holds the writer
+#[test]
+fn try_write_while_busy() -> Result<()> {
+    let barrier = Arc::new(Barrier::new(2));
+    let held = Arc::new(AtomicBool::new(false));
+    let compactor_barrier = barrier.clone();
+    let options = KvStoreOptions::new()
+        .auto_compaction(false)
+        .compaction_progress(move |progress| {
+            if progress.bytes_processed == 0 && !held.swap(true, Ordering::SeqCst) {
+                compactor_barrier.wait();
+                compactor_barrier.wait();
+            }
+        });
+    let store = KvStore::open_with_storage(MemoryStorage::new(), options)?;
+    store.set("key1", "value1")?;
+
+    let compactor = store.clone();
+    let handle = thread::spawn(move || compactor.compact());
+    barrier.wait();
+    assert!(matches!(store.try_set("key1", "value2"), Err(KvsError::Busy)));
+    assert!(matches!(store.try_remove("key1"), Err(KvsError::Busy)));
+    barrier.wait();
+    handle.join().unwrap()?;
+
+    store.try_set("key1", "value2")?;
+    assert_eq!(store.get("key1")?, Some("value2".to_owned()));
+    store.try_remove("key1")?;
+    assert_eq!(store.get("key1")?, None);
+    assert!(matches!(store.try_remove("key1"), Err(KvsError::KeyNotFound)));
+    Ok(())
+}
+
+// Integer values should parse, anything else should fail with `ParseError`
+#[test]
+fn get_i64() -> Result<()> {
+    let store = KvStore::open_with_storage(MemoryStorage::new(), KvStoreOptions::new())?;
+    store.set("answer", "42")?;
+    store.set("negative", "-7")?;
+    store.set("word", "forty-two")?;
+
+    assert_eq!(store.get_i64("answer")?, Some(42));
+    assert_eq!(store.get_i64("negative")?, Some(-7));
+    assert_eq!(store.get_i64("missing")?, None);
+    assert!(matches!(store.get_i64("word"), Err(KvsError::ParseError(_))));
+
+    let parsed: Result<f64> = "1.5x".parse::<f64>().map_err(KvsError::from);
+    assert!(matches!(parsed, Err(KvsError::ParseError(_))));
+    Ok(())
+}
+
+// Namespaces of a store should not see each other's keys
+#[test]
+fn namespaces() -> Result<()> {
+    let store = KvStore::open_with_storage(MemoryStorage::new(), KvStoreOptions::new())?;
+    let users = store.namespace("users".to_owned());
+    let orders = store.namespace("orders".to_owned());
+    users.set("key1".to_owned(), "user1".to_owned())?;
+    orders.set("key1".to_owned(), "order1".to_owned())?;
+    store.set("key1", "plain")?;
+
+    assert_eq!(users.get("key1".to_owned())?, Some("user1".to_owned()));
+    assert_eq!(orders.get("key1".to_owned())?, Some("order1".to_owned()));
+    assert_eq!(store.get("users:key1")?, Some("user1".to_owned()));
+    assert_eq!(users.keys()?, vec!["key1".to_owned()]);
+
+    users.remove("key1".to_owned())?;
+    assert_eq!(users.get("key1".to_owned())?, None);
+    assert_eq!(orders.get("key1".to_owned())?, Some("order1".to_owned()));
+    assert!(matches!(users.remove("key1".to_owned()), Err(KvsError::KeyNotFound)));
+
+    // the separator is escaped within the namespace, so these keys do not collide
+    let a = store.namespace("a".to_owned());
+    let ab = store.namespace("a:b".to_owned());
+    a.set("b:c".to_owned(), "1".to_owned())?;
+    ab.set("c".to_owned(), "2".to_owned())?;
+    assert_eq!(a.get("b:c".to_owned())?, Some("1".to_owned()));
+    assert_eq!(ab.get("c".to_owned())?, Some("2".to_owned()));
+    assert_eq!(a.keys()?, vec!["b:c".to_owned()]);
+    assert_eq!(ab.keys()?, vec!["c".to_owned()]);
+
+    orders.set("key2".to_owned(), "order2".to_owned())?;
+    store.set("orders;", "after the namespace")?;
+    assert_eq!(orders.remove_range(Bound::Unbounded, Bound::Unbounded)?, 2);
+    assert!(orders.keys()?.is_empty());
+    assert_eq!(store.get("orders;")?, Some("after the namespace".to_owned()));
+    assert_eq!(store.get("key1")?, Some("plain".to_owned()));
+    Ok(())
+}
+
+// Both engines should list keys in ascending byte-wise order
+#[test]
+fn keys_in_order() -> Result<()> {
+    let store = KvStore::open_with_storage(MemoryStorage::new(), KvStoreOptions::new())?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let sled = SledKvsEngine::open(temp_dir.path())?;
+
+    let keys = ["b", "a", "ab", "B", "é", "z", "10", "9", "", "a\u{0}"];
+    for key in keys.iter() {
+        store.set(key.to_string(), "value")?;
+        sled.set(key.to_string(), "value")?;
+    }
+    let mut expected: Vec<String> = keys.iter().map(|key| key.to_string()).collect();
+    expected.sort_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+
+    assert_eq!(store.keys()?, expected);
+    assert_eq!(sled.keys()?, expected);
+    assert_eq!(CachingEngine::new(store.clone(), 10).keys()?, expected);
+
+    let namespace = store.namespace("ns".to_owned());
+    for key in keys.iter() {
+        namespace.set(key.to_string(), "value".to_owned())?;
+    }
+    assert_eq!(namespace.keys()?, expected);
+    Ok(())
+}
+
+fn count_prefix_of<E: KvsEngine>(engine: E) -> Result<()> {
+    for i in 0..10 {
+        engine.set(format!("user:{}", i), "value".to_owned())?;
+        engine.set(format!("order:{}", i), "value".to_owned())?;
+    }
+    engine.set("user".to_owned(), "value".to_owned())?;
+    for i in 0..3 {
+        engine.remove(format!("user:{}", i))?;
+    }
+    assert_eq!(engine.count_prefix("user:".to_owned())?, 7);
+    assert_eq!(engine.count_prefix("user".to_owned())?, 8);
+    assert_eq!(engine.count_prefix("".to_owned())?, 18);
+    assert_eq!(engine.count_prefix("missing".to_owned())?, 0);
+    Ok(())
+}
+
+fn empty_value_in<E: KvsEngine>(engine: E) -> Result<()> {
+    engine.set("empty".to_owned(), String::new())?;
+    assert_eq!(engine.get("empty".to_owned())?, Some(String::new()));
+    let (value, version) = engine.get_versioned("empty".to_owned())?.unwrap();
+    assert_eq!(value, "");
+    assert!(engine.set_if_version("empty".to_owned(), String::new(), version)?);
+    assert_eq!(engine.count_prefix("empty".to_owned())?, 1);
+    assert!(engine.keys()?.contains(&"empty".to_owned()));
+    engine.set_many(vec![("also_empty".to_owned(), String::new())])?;
+    assert_eq!(engine.get("also_empty".to_owned())?, Some(String::new()));
+    engine.remove("empty".to_owned())?;
+    assert_eq!(engine.get("empty".to_owned())?, None);
+    engine.set("empty".to_owned(), String::new())?;
+    Ok(())
+}
+
+// An empty value should be stored and read back as such, never mistaken for an absent key
+#[test]
+fn empty_value() -> Result<()> {
+    let storage = MemoryStorage::new();
+    let options = || {
+        KvStoreOptions::new()
+            .compress_sealed_segments(true)
+            .large_value_threshold(0)
+            .value_cache_capacity(10)
+    };
+    let store = KvStore::open_with_storage(storage.clone(), options())?;
+    empty_value_in(store.clone())?;
+    empty_value_in(store.namespace("ns".to_owned()))?;
+    empty_value_in(CachingEngine::new(store.namespace("cached".to_owned()), 10))?;
+    store.compact()?;
+    assert_eq!(store.get("empty")?, Some(String::new()));
+    drop(store);
+    let store = KvStore::open_with_storage(storage, options())?;
+    assert_eq!(store.get("empty")?, Some(String::new()));
+    assert_eq!(store.get("ns:also_empty")?, Some(String::new()));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    empty_value_in(SledKvsEngine::open(temp_dir.path())?)?;
+    let sled = reopen_sled(temp_dir.path())?;
+    assert_eq!(sled.get("empty")?, Some(String::new()));
+    Ok(())
+}
+
+// Removed keys should not be counted
+#[test]
+fn count_prefix() -> Result<()> {
+    let store = KvStore::open_with_storage(MemoryStorage::new(), KvStoreOptions::new())?;
+    count_prefix_of(store.clone())?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    count_prefix_of(SledKvsEngine::open(temp_dir.path())?)?;
+    count_prefix_of(store.namespace("ns".to_owned()))?;
+    assert_eq!(store.count_prefix("ns:".to_owned())?, 18);
+    Ok(())
+}
+
+fn set_if_version_on<E: KvsEngine>(engine: E) -> Result<()> {
+    assert_eq!(engine.get_versioned("key1".to_owned())?, None);
+    assert!(engine.set_if_version("key1".to_owned(), "value1".to_owned(), 0)?);
+    assert!(!engine.set_if_version("key1".to_owned(), "value2".to_owned(), 0)?);
+    let (value, version) = engine.get_versioned("key1".to_owned())?.unwrap();
+    assert_eq!(value, "value1");
+    assert_ne!(version, 0);
+
+    // a write in between makes the version read stale
+    engine.set("key1".to_owned(), "value3".to_owned())?;
+    assert!(!engine.set_if_version("key1".to_owned(), "value4".to_owned(), version)?);
+    let (_, version) = engine.get_versioned("key1".to_owned())?.unwrap();
+    assert!(engine.set_if_version("key1".to_owned(), "value4".to_owned(), version)?);
+    assert_eq!(engine.get("key1".to_owned())?, Some("value4".to_owned()));
+
+    // writing the value back, or removing the key and setting it again, still changes the version
+    let (_, version) = engine.get_versioned("key1".to_owned())?.unwrap();
+    engine.set("key1".to_owned(), "value5".to_owned())?;
+    engine.set("key1".to_owned(), "value4".to_owned())?;
+    assert!(!engine.set_if_version("key1".to_owned(), "value6".to_owned(), version)?);
+    let (_, version) = engine.get_versioned("key1".to_owned())?.unwrap();
+    engine.remove("key1".to_owned())?;
+    engine.set("key1".to_owned(), "value4".to_owned())?;
+    assert!(!engine.set_if_version("key1".to_owned(), "value6".to_owned(), version)?);
+    assert_eq!(engine.get("key1".to_owned())?, Some("value4".to_owned()));
+
+    engine.set("counter".to_owned(), "0".to_owned())?;
+    let threads: Vec<_> = (0..4)
+        .map(|_| {
+            let engine = engine.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..25 {
+                    loop {
+                        let (value, version) = engine.get_versioned("counter".to_owned())?.unwrap();
+                        let next = value.parse::<u32>()? + 1;
+                        if engine.set_if_version("counter".to_owned(), next.to_string(), version)? {
+                            break;
+                        }
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap()?;
+    }
+    assert_eq!(engine.get("counter".to_owned())?, Some("100".to_owned()));
+    Ok(())
+}
+
+// A conditional write should only apply if the key was not written since it was read
+#[test]
+fn set_if_version() -> Result<()> {
+    let store = KvStore::open_with_storage(MemoryStorage::new(), KvStoreOptions::new())?;
+    set_if_version_on(store.clone())?;
+    set_if_version_on(store.namespace("ns".to_owned()))?;
+    set_if_version_on(CachingEngine::new(store.namespace("cached".to_owned()), 10))?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    set_if_version_on(SledKvsEngine::open(temp_dir.path())?)?;
+
+    // compaction moves the record but keeps its version
+    let (_, version) = store.get_versioned("key1".to_owned())?.unwrap();
+    store.compact()?;
+    assert!(store.set_if_version("key1".to_owned(), "value5".to_owned(), version)?);
+    Ok(())
+}
+
+// Versions should survive compactions and reopening, and never be given out twice
+#[test]
+fn versions_after_compaction_and_reopen() -> Result<()> {
+    let storage = MemoryStorage::new();
+    let store = KvStore::open_with_storage(storage.clone(), KvStoreOptions::new())?;
+    store.set("key1", "value1")?;
+    store.set("key2", "value2")?;
+    let (_, version1) = store.get_versioned("key1".to_owned())?.unwrap();
+    let (_, stale) = store.get_versioned("key2".to_owned())?.unwrap();
+    for i in 0..10 {
+        store.set("key2", format!("value{}", i))?;
+    }
+    // the records numbered past `stale` are all dropped by the compaction
+    store.remove("key2")?;
+    store.compact_range(Bound::Unbounded, Bound::Unbounded)?;
+    store.compact()?;
+    assert_eq!(store.get_versioned("key1".to_owned())?.unwrap().1, version1);
+    drop(store);
+
+    let store = KvStore::open_with_storage(storage.clone(), KvStoreOptions::new())?;
+    assert_eq!(store.get_versioned("key1".to_owned())?.unwrap().1, version1);
+    for i in 0..20 {
+        store.set("key2", format!("value{}", i))?;
+        assert!(!store.set_if_version("key2".to_owned(), "stale".to_owned(), stale)?);
+    }
+    assert!(store.set_if_version("key1".to_owned(), "value3".to_owned(), version1)?);
+    Ok(())
+}
+
+// An engine without versions should refuse versioned calls rather than guess
+#[test]
+fn set_if_version_unsupported() -> Result<()> {
+    let engine = MinimalEngine(KvStore::open_with_storage(MemoryStorage::new(), KvStoreOptions::new())?);
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(matches!(
+        engine.get_versioned("key1".to_owned()),
+        Err(KvsError::Unsupported(_))
+    ));
+    assert!(matches!(
+        engine.set_if_version("key1".to_owned(), "value2".to_owned(), 0),
+        Err(KvsError::Unsupported(_))
+    ));
+    assert!(matches!(
+        engine.set_and_get_old("key1".to_owned(), "value2".to_owned()),
+        Err(KvsError::Unsupported(_))
+    ));
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+fn set_and_get_old_on<E: KvsEngine>(engine: E) -> Result<()> {
+    assert_eq!(engine.set_and_get_old("key1".to_owned(), "value1".to_owned())?, None);
+    assert_eq!(
+        engine.set_and_get_old("key1".to_owned(), "value2".to_owned())?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(engine.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    // every value swapped in is swapped out exactly once, but for the last one
+    let threads: Vec<_> = (0..4)
+        .map(|t| {
+            let engine = engine.clone();
+            thread::spawn(move || -> Result<Vec<Option<String>>> {
+                (0..25)
+                    .map(|i| engine.set_and_get_old("swapped".to_owned(), format!("{}-{}", t, i)))
+                    .collect()
+            })
+        })
+        .collect();
+    let mut olds = Vec::new();
+    for thread in threads {
+        olds.extend(thread.join().unwrap()?);
+    }
+    olds.push(engine.get("swapped".to_owned())?);
+    olds.sort();
+    let mut expected: Vec<Option<String>> = (0..4)
+        .flat_map(|t| (0..25).map(move |i| Some(format!("{}-{}", t, i))))
+        .collect();
+    expected.push(None);
+    expected.sort();
+    assert_eq!(olds, expected);
+    Ok(())
+}
+
+// A swap should return the value it replaced, with no other write in between
+#[test]
+fn set_and_get_old() -> Result<()> {
+    let store = KvStore::open_with_storage(MemoryStorage::new(), KvStoreOptions::new())?;
+    set_and_get_old_on(store.clone())?;
+    set_and_get_old_on(store.namespace("ns".to_owned()))?;
+    set_and_get_old_on(CachingEngine::new(store.namespace("cached".to_owned()), 10))?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    set_and_get_old_on(SledKvsEngine::open(temp_dir.path())?)?;
+    Ok(())
+}
+
+fn set_many_on<E: KvsEngine>(engine: E) -> Result<()> {
+    engine.set("key0".to_owned(), "old".to_owned())?;
+    let pairs = (0..100)
+        .map(|key_id| (format!("key{}", key_id), format!("value{}", key_id)))
+        .chain(Some(("key1".to_owned(), "last".to_owned())))
+        .collect();
+    engine.set_many(pairs)?;
+    assert_eq!(engine.get("key0".to_owned())?, Some("value0".to_owned()));
+    assert_eq!(engine.get("key1".to_owned())?, Some("last".to_owned()));
+    assert_eq!(engine.get("key99".to_owned())?, Some("value99".to_owned()));
+    assert_eq!(engine.count_prefix("key".to_owned())?, 100);
+    Ok(())
+}
+
+// A bulk write should leave the same values as setting each pair in order
+#[test]
+fn set_many() -> Result<()> {
+    let storage = MemoryStorage::new();
+    let store = KvStore::open_with_storage(storage.clone(), KvStoreOptions::new())?;
+    set_many_on(store.clone())?;
+    set_many_on(store.namespace("ns".to_owned()))?;
+    set_many_on(CachingEngine::new(store.namespace("cached".to_owned()), 10))?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    set_many_on(SledKvsEngine::open(temp_dir.path())?)?;
+    drop(store);
+
+    let store = KvStore::open_with_storage(storage, KvStoreOptions::new())?;
+    assert_eq!(store.get("key1")?, Some("last".to_owned()));
+    assert_eq!(store.get("ns:key99")?, Some("value99".to_owned()));
+    Ok(())
+}
+
+// Keys should spread evenly over the shards, and be listed across all of them
+#[test]
+fn sharded_store() -> Result<()> {
+    let dirs: Vec<TempDir> = (0..4)
+        .map(|_| TempDir::new().expect("unable to create temporary working directory"))
+        .collect();
+    let store = ShardedKvStore::open(
+        &dirs.iter().map(TempDir::path).collect::<Vec<_>>(),
+        KvStoreOptions::new(),
+    )?;
+    let mut expected: Vec<String> = (0..1000).map(|key_id| format!("key{}", key_id)).collect();
+    for key in expected.iter() {
+        store.set(key.clone(), "value".to_owned())?;
+    }
+    for (i, shard) in store.shards().iter().enumerate() {
+        let keys = shard.keys()?;
+        assert!(
+            (200..300).contains(&keys.len()),
+            "shard {} holds {} keys",
+            i,
+            keys.len()
+        );
+        assert!(keys.iter().all(|key| store.shard_of(key) == i));
+    }
+    expected.sort_unstable();
+    assert_eq!(store.keys()?, expected);
+    assert_eq!(store.count_prefix("key1".to_owned())?, 111);
+    drop(store);
+
+    let store = ShardedKvStore::open(
+        &dirs.iter().map(TempDir::path).collect::<Vec<_>>(),
+        KvStoreOptions::new(),
+    )?;
+    assert_eq!(store.keys()?, expected);
+    assert_eq!(store.remove_range(Bound::Unbounded, Bound::Unbounded)?, 1000);
+    count_prefix_of(store.clone())?;
+    store.remove_range(Bound::Unbounded, Bound::Unbounded)?;
+    set_if_version_on(store.clone())?;
+    store.remove_range(Bound::Unbounded, Bound::Unbounded)?;
+    remove_range_from(store.clone())?;
+    set_many_on(store.clone())?;
+    store.compact()?;
+    assert_eq!(store.get("key1".to_owned())?, Some("last".to_owned()));
+
+    assert!(matches!(
+        ShardedKvStore::new(Vec::new()),
+        Err(KvsError::InvalidOption(_))
+    ));
+    Ok(())
+}
+
+// An index snapshot should hold every entry of the index and reject corrupted or truncated bytes
+#[test]
+fn index_snapshot() -> Result<()> {
+    let storage = MemoryStorage::new();
+    let store = KvStore::open_with_storage(storage.clone(), KvStoreOptions::new())?;
+    let pairs = (0..50_000)
+        .map(|id| (format!("key{}", id), format!("value{}", id)))
+        .collect();
+    store.set_many(pairs)?;
+    for id in 0..1000 {
+        store.remove(format!("key{}", id))?;
+    }
+    store.compact()?;
+    store.set("key1000", "overwritten")?;
+
+    assert_eq!(store.snapshot_index("index.snap")?, 49_000);
+    assert!(storage.list()?.contains(&"index.snap".to_owned()));
+    assert!(!storage.list()?.contains(&"index.snap.tmp".to_owned()));
+    assert!(store.check_index_snapshot("index.snap")?);
+    store.set("key1001", "overwritten")?;
+    assert!(!store.check_index_snapshot("index.snap")?);
+    assert_eq!(store.snapshot_index("index.snap")?, 49_000);
+    drop(store);
+
+    // the index replayed on open is the one the snapshot holds
+    let store = KvStore::open_with_storage(storage.clone(), KvStoreOptions::new())?;
+    assert!(store.check_index_snapshot("index.snap")?);
+
+    let mut snapshot = Vec::new();
+    storage.open("index.snap")?.read_to_end(&mut snapshot)?;
+    let mid = snapshot.len() / 2;
+    snapshot[mid] ^= 1;
+    storage.create("index.snap")?.write_all(&snapshot)?;
+    match store.check_index_snapshot("index.snap") {
+        Err(KvsError::Corrupted(_)) => {}
+        other => panic!("expected a checksum mismatch, got {:?}", other),
+    }
+    storage.create("index.snap")?.write_all(&snapshot[..mid])?;
+    match store.check_index_snapshot("index.snap") {
+        Err(KvsError::Corrupted(_)) => {}
+        other => panic!("expected a truncated snapshot, got {:?}", other),
+    }
+    Ok(())
+}
+
+fn ordered_index(index_type: IndexType) -> Result<()> {
+    let storage = MemoryStorage::new();
+    let options = || KvStoreOptions::new().index_type(index_type);
+    let store = KvStore::open_with_storage(storage.clone(), options())?;
+    remove_range_from(store.namespace("range".to_owned()))?;
+    count_prefix_of(store.namespace("prefix".to_owned()))?;
+    set_if_version_on(store.namespace("version".to_owned()))?;
+    set_many_on(store.namespace("many".to_owned()))?;
+
+    let keys = ["b", "a", "ab", "B", "é", "z", "10", "9", "", "a\u{0}"];
+    for key in keys.iter() {
+        store.set(format!("order:{}", key), "value")?;
+    }
+    let mut expected: Vec<String> = keys.iter().map(|key| format!("order:{}", key)).collect();
+    expected.sort_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+    let ordered: Vec<String> = store
+        .keys()?
+        .into_iter()
+        .filter(|key| key.starts_with("order:"))
+        .collect();
+    assert_eq!(ordered, expected);
+    for key in ["a", "a:", "a:b:c", "ab", ":", "::", "a;", "a:\u{0}"].iter() {
+        store.set(*key, "value")?;
+    }
+    let keys = store.keys()?;
+    let mut sorted = keys.clone();
+    sorted.sort_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+    assert_eq!(keys, sorted);
+    assert_eq!(store.count_prefix("a".to_owned())?, 6);
+    assert_eq!(store.count_prefix("a:".to_owned())?, 3);
+    assert_eq!(
+        store.remove_range(Bound::Included("a".to_owned()), Bound::Excluded("a:b".to_owned()))?,
+        3
+    );
+    assert_eq!(store.get("a:b:c")?, Some("value".to_owned()));
+
+    // reversed bounds hold no key
+    assert_eq!(
+        store.remove_range(Bound::Included("z".to_owned()), Bound::Excluded("a".to_owned()))?,
+        0
+    );
+    store.compact_range(Bound::Included("many:".to_owned()), Bound::Excluded("many;".to_owned()))?;
+    assert_eq!(store.get("many:key1")?, Some("last".to_owned()));
+
+    let (first, second) = write_through_handles(&store)?;
+    assert_written_through_handles(&store)?;
+    first.close()?;
+    second.close()?;
+    store.compact()?;
+    let keys = store.keys()?;
+    drop(store);
+
+    let store = KvStore::open_with_storage(storage, options())?;
+    assert_eq!(store.keys()?, keys);
+    assert_written_through_handles(&store)?;
+    assert_eq!(store.count_prefix("prefix:user:".to_owned())?, 7);
+    Ok(())
+}
+
+#[test]
+fn btree_index() -> Result<()> {
+    ordered_index(IndexType::BTree)
+}
+
+#[test]
+fn interned_index() -> Result<()> {
+    ordered_index(IndexType::Interned)
+}
+
+fn assert_send_sync<T: Send + Sync>() {}
+
+// Engines should be shareable by reference across threads, e.g. in an `Arc` or async task
+#[test]
+fn engines_are_send_sync() -> Result<()> {
+    assert_send_sync::<KvStore>();
+    assert_send_sync::<SledKvsEngine>();
+
+    let store = Arc::new(KvStore::open_with_storage(MemoryStorage::new(), KvStoreOptions::new())?);
+    let handles: Vec<_> = (0..4)
+        .map(|i| {
+            let store = Arc::clone(&store);
+            thread::spawn(move || -> Result<()> {
+                for key_id in 0..100 {
+                    let key = format!("key{}_{}", i, key_id);
+                    store.set(key.clone(), format!("{}", key_id))?;
+                    assert_eq!(store.get(key)?, Some(format!("{}", key_id)));
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(store.keys()?.len(), 400);
+    Ok(())
+}
+
+const WRITER_THREADS: usize = 8;
+
+// The writes of thread `t` to its own keys, `None` removing the key
+fn ingest(t: usize, write: &mut dyn FnMut(String, Option<String>) -> Result<()>) -> Result<()> {
+    for round in 0..3 {
+        for key_id in 0..300 {
+            let key = format!("key{}_{}", t, key_id);
+            let removed = match round {
+                1 => key_id % 7 == 0,
+                2 => key_id % 5 == t % 5 && key_id % 7 != 0,
+                _ => false,
+            };
+            write(key, Some(format!("value{}_{}", key_id, round)).filter(|_| !removed))?;
+        }
+    }
+    Ok(())
+}
+
+// Writers appending to segments of their own should end up with what a single writer would
+#[test]
+fn writer_handles_match_single_writer() -> Result<()> {
+    let reference = KvStore::open_with_storage(MemoryStorage::new(), KvStoreOptions::new())?;
+    for t in 0..WRITER_THREADS {
+        ingest(t, &mut |key, value| match value {
+            Some(value) => reference.set(key, value),
+            None => reference.remove(key),
+        })?;
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let barrier = Arc::new(Barrier::new(WRITER_THREADS));
+    let threads: Vec<_> = (0..WRITER_THREADS)
+        .map(|t| {
+            let store = store.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || -> Result<()> {
+                // the first thread writes through the store, along with the handles
+                let mut writer = if t == 0 { None } else { Some(store.writer_handle()?) };
+                barrier.wait();
+                let mut write = |key: String, value: Option<String>| match (&mut writer, value) {
+                    (Some(writer), Some(value)) => writer.set(key, value),
+                    (Some(writer), None) => writer.remove(key),
+                    (None, Some(value)) => store.set(key, value),
+                    (None, None) => store.remove(key),
+                };
+                ingest(t, &mut write)?;
+                for i in 0..50 {
+                    write(format!("shared{}", i), Some(t.to_string()))?;
+                }
+                match writer {
+                    Some(writer) => writer.close(),
+                    None => Ok(()),
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap()?;
+    }
+
+    let shared: Vec<Option<String>> = (0..50)
+        .map(|i| store.get(format!("shared{}", i)))
+        .collect::<Result<_>>()?;
+    let check = |store: &KvStore| -> Result<()> {
+        for t in 0..WRITER_THREADS {
+            for key_id in 0..300 {
+                let key = format!("key{}_{}", t, key_id);
+                assert_eq!(store.get(key.clone())?, reference.get(key)?);
+            }
+        }
+        for (i, value) in shared.iter().enumerate() {
+            assert_eq!(store.get(format!("shared{}", i))?, *value);
+            let writer: usize = value.as_ref().expect("shared keys are never removed").parse()?;
+            assert!(writer < WRITER_THREADS);
+        }
+        assert_eq!(store.keys()?.len(), reference.keys()?.len() + shared.len());
+        Ok(())
+    };
+    check(&store)?;
+    store.verify()?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    check(&store)?;
+    store.compact()?;
+    check(&store)?;
+    Ok(())
+}
+
+// Writes of the same key through the store and two handles, in the order
+// `key1`: first, second; `key2`: second, first; `key3`: removed; `key4`: store, second
+fn write_through_handles(store: &KvStore) -> Result<(WriterHandle, WriterHandle)> {
+    for key in ["key1", "key2", "key3"].iter() {
+        store.set(*key, "old")?;
+    }
+    let mut first = store.writer_handle()?;
+    let mut second = store.writer_handle()?;
+    first.set("key1", "first")?;
+    second.set("key1", "second")?;
+    second.set("key2", "second")?;
+    first.set("key2", "first")?;
+    first.remove("key3")?;
+    store.set("key4", "store")?;
+    second.set("key4", "second")?;
+    assert!(matches!(first.remove("key5"), Err(KvsError::KeyNotFound)));
+    Ok((first, second))
+}
+
+fn assert_written_through_handles(store: &KvStore) -> Result<()> {
+    assert_eq!(store.get("key1")?, Some("second".to_owned()));
+    assert_eq!(store.get("key2")?, Some("first".to_owned()));
+    assert_eq!(store.get("key3")?, None);
+    assert_eq!(store.get("key4")?, Some("second".to_owned()));
+    Ok(())
+}
+
+// The latest write of a key should win whichever handle is merged first
+#[test]
+fn writer_handles_merge_in_sequence() -> Result<()> {
+    let storage = MemoryStorage::new();
+    let store = KvStore::open_with_storage(storage.clone(), KvStoreOptions::new())?;
+    let (first, second) = write_through_handles(&store)?;
+    assert_written_through_handles(&store)?;
+    assert!(matches!(store.compact(), Err(KvsError::Busy)));
+
+    second.close()?;
+    assert_written_through_handles(&store)?;
+    first.close()?;
+    assert_written_through_handles(&store)?;
+    store.compact()?;
+    assert_written_through_handles(&store)?;
+    drop(store);
+
+    let store = KvStore::open_with_storage(storage, KvStoreOptions::new())?;
+    assert_written_through_handles(&store)?;
+    Ok(())
+}
+
+// A writer segment left by a crash should be merged when the store is opened again
+#[test]
+fn writer_handles_recover() -> Result<()> {
+    let storage = MemoryStorage::new();
+    let store = KvStore::open_with_storage(storage.clone(), KvStoreOptions::new())?;
+    let (mut first, mut second) = write_through_handles(&store)?;
+    first.flush()?;
+    second.flush()?;
+    store.flush()?;
+    // crash with both handles open, the store is never dropped either
+    std::mem::forget(first);
+    std::mem::forget(second);
+    drop(store);
+
+    let store = KvStore::open_with_storage(storage.clone(), KvStoreOptions::new())?;
+    assert_written_through_handles(&store)?;
+    assert_eq!(store.segments()?.len(), 1);
+    store.compact()?;
+    drop(store);
+
+    let store = KvStore::open_with_storage(storage, KvStoreOptions::new())?;
+    assert_written_through_handles(&store)?;
+    Ok(())
+}
+
+// A writer segment with records out of sequence should be detected when checking sequences
+#[test]
+fn writer_segment_out_of_sequence() -> Result<()> {
+    let storage = storage_with_log(r#"{"format":3}"#)?;
+    storage
+        .create("1.log")?
+        .write_all(br#"{"format":3}{"Seq":[2,{"Set":["key1","second"]}]}{"Seq":[1,{"Set":["key1","first"]}]}"#)?;
+    storage
+        .create("CURRENT")?
+        .write_all(br#"{"segments":[{"epoch":0,"compressed":false}],"writers":[1]}"#)?;
+    let checked = || KvStoreOptions::new().check_sequence(true);
+    assert!(matches!(
+        KvStore::open_with_storage(storage.clone(), checked()),
+        Err(KvsError::Consistency(_))
+    ));
+
+    // without the check, the record numbered last wins
+    let store = KvStore::open_with_storage(storage.clone(), KvStoreOptions::new())?;
+    assert_eq!(store.get("key1")?, Some("second".to_owned()));
+    drop(store);
+
+    let store = KvStore::open_with_storage(storage, checked())?;
+    assert_eq!(store.get("key1")?, Some("second".to_owned()));
+    let mut writer = store.writer_handle()?;
+    writer.set("key1", "third")?;
+    store.set("key1", "fourth")?;
+    writer.remove("key1")?;
+    writer.close()?;
+    assert_eq!(store.get("key1")?, None);
+    Ok(())
+}
+
+// Buffers of any size, down to a single byte, should read back what they wrote
+#[test]
+fn buffer_size() -> Result<()> {
+    let storage = MemoryStorage::new();
+    let options = || KvStoreOptions::new().buffer_size(1).flush_on_write(false);
+    let store = KvStore::open_with_storage(storage.clone(), options())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i % 10), format!("value{}", i))?;
+    }
+    assert_eq!(store.get("key3")?, Some("value93".to_owned()));
+    store.compact()?;
+    store.set("key0", "after compaction")?;
+    drop(store);
+
+    let store = KvStore::open_with_storage(storage, options())?;
+    assert_eq!(store.get("key0")?, Some("after compaction".to_owned()));
+    assert_eq!(store.get("key9")?, Some("value99".to_owned()));
+    Ok(())
+}
+
+// Closing a handle should flush its buffered writes, and leave the other handles usable
+#[test]
+fn close_store() -> Result<()> {
+    let storage = MemoryStorage::new();
+    let store = KvStore::open_with_storage(storage.clone(), KvStoreOptions::new().flush_on_write(false))?;
+    let other = store.clone();
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), "value")?;
+    }
+    store.close()?;
+
+    let reopened = KvStore::open_with_storage(storage, KvStoreOptions::new())?;
+    assert_eq!(reopened.get("key9")?, Some("value".to_owned()));
+    other.set("key10", "value")?;
+    assert_eq!(other.get("key10")?, Some("value".to_owned()));
+    other.close()
+}
+
+// A second store opening the same directory should fail right away instead of corrupting it
+#[test]
+fn lock_directory() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+
+    let path = temp_dir.path().to_owned();
+    let second = thread::spawn(move || KvStore::open(&path).map(drop));
+    assert!(matches!(second.join().unwrap(), Err(KvsError::AlreadyLocked(_))));
+    assert!(matches!(
+        KvStore::open_read_only(temp_dir.path(), KvStoreOptions::new()),
+        Err(KvsError::AlreadyLocked(_))
+    ));
+
+    // clones share the lock, which is released with the last of them
+    let clone = store.clone();
+    drop(store);
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::AlreadyLocked(_))
+    ));
+    drop(clone);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Read-only stores should share the directory, and refuse every write
+#[test]
+fn open_read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+    store.set("key2", "value2")?;
+    drop(store);
+
+    let reader = KvStore::open_read_only(temp_dir.path(), KvStoreOptions::new())?;
+    let other = KvStore::open_read_only(temp_dir.path(), KvStoreOptions::new())?;
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::AlreadyLocked(_))
+    ));
+    assert_eq!(reader.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(other.keys()?, vec!["key1".to_owned(), "key2".to_owned()]);
+
+    assert!(matches!(reader.set("key3", "value3"), Err(KvsError::ReadOnly)));
+    assert!(matches!(reader.remove("key1"), Err(KvsError::ReadOnly)));
+    assert!(matches!(reader.compact(), Err(KvsError::ReadOnly)));
+    assert!(matches!(reader.writer_handle(), Err(KvsError::ReadOnly)));
+    reader.close()?;
+    drop(other);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.keys()?, vec!["key1".to_owned(), "key2".to_owned()]);
+
+    let missing = temp_dir.path().join("missing");
+    assert!(KvStore::open_read_only(&missing, KvStoreOptions::new()).is_err());
+    assert!(!missing.exists());
+    Ok(())
+}
+
+// Preallocated segments should be filled with zeros past their records, which are cut once
+// a segment stops being active or the store is reopened without preallocation
+#[test]
+fn preallocate_segments() -> Result<()> {
+    const SIZE: u64 = 64 * 1024;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || KvStoreOptions::new().auto_compaction(false).preallocate_segments(SIZE);
+    let active_len = |store: &KvStore| {
+        let name = store.segments().unwrap().pop().unwrap().name;
+        temp_dir.path().join(name).metadata().unwrap().len()
+    };
+
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    assert_eq!(active_len(&store), SIZE);
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "value1")?;
+    }
+    assert_eq!(active_len(&store), SIZE);
+    drop(store);
+
+    let reader = KvStore::open_read_only(temp_dir.path(), KvStoreOptions::new())?;
+    assert_eq!(reader.get("key99")?, Some("value1".to_owned()));
+    drop(reader);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    assert_eq!(store.get("key0")?, Some("value1".to_owned()));
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "value2")?;
+    }
+    store.compact_range(Bound::Included("key1".to_owned()), Bound::Excluded("key2".to_owned()))?;
+    let segments = store.segments()?;
+    let (active, sealed) = segments.split_last().unwrap();
+    assert!(sealed.iter().all(|segment| segment.size < SIZE));
+    assert_eq!(active.size, SIZE);
+    store.compact()?;
+    assert_eq!(active_len(&store), SIZE);
+    store.set("key100", "value2")?;
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), KvStoreOptions::new())?;
+    assert!(active_len(&store) < SIZE);
+    assert_eq!(store.keys()?.len(), 101);
+    assert_eq!(store.get("key50")?, Some("value2".to_owned()));
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    store.set("key101", "value3")?;
+    drop(store);
+    let store = KvStore::repair(temp_dir.path())?;
+    assert_eq!(store.keys()?.len(), 102);
+    assert_eq!(store.get("key101")?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Segment statistics should move the bytes of overwritten and removed records to the dead
+// ones, and match those found by replaying the segments
+#[test]
+fn segment_stats() -> Result<()> {
+    let storage = MemoryStorage::new();
+    let options = || KvStoreOptions::new().auto_compaction(false);
+    let store = KvStore::open_with_storage(storage.clone(), options())?;
+    let stat = |store: &KvStore| store.segment_stats()[&store.epoch()];
+    store.set("key1", "value1")?;
+    let set = stat(&store);
+    assert_eq!((set.record_count, set.dead_bytes), (1, 0));
+    store.set("key1", "value2")?;
+    assert_eq!(
+        stat(&store),
+        SegmentStat {
+            live_bytes: set.live_bytes,
+            dead_bytes: set.live_bytes,
+            record_count: 2
+        }
+    );
+    store.remove("key1")?;
+    let removed = stat(&store);
+    assert_eq!((removed.record_count, removed.live_bytes), (3, 0));
+    assert!(removed.dead_bytes > 2 * set.live_bytes);
+
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "value1")?;
+        store.set(format!("key{}", key_id % 10), "value2")?;
+    }
+    let before = store.segment_stats();
+    drop(store);
+    let store = KvStore::open_with_storage(storage.clone(), options())?;
+    assert_eq!(store.segment_stats(), before);
+
+    store.compact_range(Bound::Included("key5".to_owned()), Bound::Excluded("key6".to_owned()))?;
+    let stats = store.segment_stats();
+    assert_eq!(stats.len(), 3);
+    assert_eq!(stats[&store.epoch()], SegmentStat::default());
+    let live: u64 = stats.values().map(|stat| stat.live_bytes).sum();
+    assert_eq!(live, before.values().map(|stat| stat.live_bytes).sum::<u64>());
+    drop(store);
+    let store = KvStore::open_with_storage(storage.clone(), options())?;
+    assert_eq!(store.segment_stats(), stats);
+
+    let mut handle = store.writer_handle()?;
+    handle.set("key1", "handle")?;
+    handle.remove("key2")?;
+    store.set("key3", "store")?;
+    handle.close()?;
+    let merged = store.segment_stats();
+    drop(store);
+    let store = KvStore::open_with_storage(storage, options())?;
+    assert_eq!(store.segment_stats(), merged);
+
+    store.compact()?;
+    let stats = store.segment_stats();
+    assert_eq!(stats.len(), 1);
+    assert_eq!(stats[&store.epoch()].dead_bytes, 0);
+    assert_eq!(stats[&store.epoch()].record_count, 99);
+    Ok(())
+}
+
+// A checkpoint restores into an equal store, on disk or in memory
+#[test]
+fn checkpoint_restore() -> Result<()> {
+    let options = || KvStoreOptions::new().large_value_threshold(16);
+    let store = KvStore::open_with_storage(MemoryStorage::new(), options())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.set("large", "a value past the large value threshold")?;
+    store.remove_range(Bound::Included("key5".to_owned()), Bound::Excluded("key6".to_owned()))?;
+    store.set("key1", "overwritten")?;
+
+    let mut archive = Vec::new();
+    store.checkpoint(&mut archive)?;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let restored = KvStore::restore(temp_dir.path(), &archive[..])?;
+    assert!(engines_equal(&store, &restored)?);
+    restored.set("key1", "after restore")?;
+    drop(restored);
+    let reopened = KvStore::open(temp_dir.path())?;
+    assert_eq!(reopened.get("key1")?, Some("after restore".to_owned()));
+    drop(reopened);
+    assert!(matches!(
+        KvStore::restore(temp_dir.path(), &archive[..]),
+        Err(KvsError::Io(ref e)) if e.kind() == io::ErrorKind::AlreadyExists
+    ));
+
+    let restored = KvStore::restore_with_storage(MemoryStorage::new(), &archive[..], options())?;
+    assert!(engines_equal(&store, &restored)?);
+
+    // a store compacted first restores the same pairs from a smaller archive
+    store.compact()?;
+    let mut compacted = Vec::new();
+    store.checkpoint(&mut compacted)?;
+    assert!(compacted.len() < archive.len());
+    let restored = KvStore::restore_with_storage(MemoryStorage::new(), &compacted[..], options())?;
+    assert!(engines_equal(&store, &restored)?);
+
+    let storage = MemoryStorage::new();
+    let middle = archive.len() / 2;
+    archive[middle] ^= 1;
+    assert!(matches!(
+        KvStore::restore_with_storage(storage.clone(), &archive[..], options()),
+        Err(KvsError::Corrupted(_))
+    ));
+    assert!(storage
+        .list()?
+        .iter()
+        .all(|name| !name.ends_with(".log") && name != "CURRENT"));
+
+    let handle = store.writer_handle()?;
+    assert!(matches!(store.checkpoint(&mut Vec::new()), Err(KvsError::Busy)));
+    handle.close()?;
+    store.checkpoint(&mut Vec::new())?;
+    Ok(())
+}
+
+// Async calls should reach the store and survive reopening it
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn async_kv_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = AsyncKvStore::open(temp_dir.path()).await?;
+    store.set("key1".to_owned(), "value1".to_owned()).await?;
+    store
+        .set_many(vec![
+            ("key2".to_owned(), "value2".to_owned()),
+            ("key3".to_owned(), "value3".to_owned()),
+        ])
+        .await?;
+    store.remove("key3".to_owned()).await?;
+    assert!(matches!(
+        store.remove("key3".to_owned()).await,
+        Err(KvsError::KeyNotFound)
+    ));
+    let (_, version) = store.get_versioned("key1".to_owned()).await?.unwrap();
+    assert!(
+        store
+            .set_if_version("key1".to_owned(), "value4".to_owned(), version)
+            .await?
+    );
+    assert_eq!(store.keys().await?, vec!["key1".to_owned(), "key2".to_owned()]);
+    store.flush().await?;
+    drop(store);
+
+    let store = AsyncKvStore::open(temp_dir.path()).await?;
+    assert_eq!(store.get("key1".to_owned()).await?, Some("value4".to_owned()));
+    assert_eq!(store.get("key2".to_owned()).await?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned()).await?, None);
+    Ok(())
+}
+
+/// A `KvStore` whose `get` blocks until the test meets it at the barrier
+#[cfg(feature = "tokio")]
+#[derive(Clone)]
+struct BlockingGetEngine {
+    inner: KvStore,
+    barrier: Arc<Barrier>,
+}
+
+#[cfg(feature = "tokio")]
+impl KvsEngine for BlockingGetEngine {
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.barrier.wait();
+        self.inner.get(key)
+    }
+
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.inner.set(key, value)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.inner.remove(key)
+    }
+
+    fn get_versioned(&self, key: String) -> Result<Option<(String, u64)>> {
+        self.inner.get_versioned(key)
+    }
+
+    fn set_if_version(&self, key: String, value: String, expected: u64) -> Result<bool> {
+        self.inner.set_if_version(key, value, expected)
+    }
+
+    fn remove_range(&self, start: Bound<String>, end: Bound<String>) -> Result<usize> {
+        KvsEngine::remove_range(&self.inner, start, end)
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        KvsEngine::keys(&self.inner)
+    }
+}
+
+// A blocked engine call should leave the single thread of the runtime to other tasks
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn async_kv_store_does_not_block_executor() -> Result<()> {
+    let barrier = Arc::new(Barrier::new(2));
+    let store = AsyncKvStore::new(BlockingGetEngine {
+        inner: KvStore::open_with_storage(MemoryStorage::new(), KvStoreOptions::new())?,
+        barrier: barrier.clone(),
+    });
+    let blocked = tokio::spawn({
+        let store = store.clone();
+        async move { store.get("key1".to_owned()).await }
+    });
+    tokio::task::yield_now().await;
+    // the spawned get waits at the barrier meanwhile
+    store.set("key1".to_owned(), "value1".to_owned()).await?;
+    assert!(!blocked.is_finished());
+    barrier.wait();
+    assert_eq!(blocked.await.unwrap()?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Values over the compression threshold should read back the same, compressed or not
+#[test]
+fn value_compression() -> Result<()> {
+    let storage = MemoryStorage::new();
+    let options = || KvStoreOptions::new().value_compression_threshold(64);
+    let live_bytes = |store: &KvStore| -> u64 { store.segment_stats().values().map(|stat| stat.live_bytes).sum() };
+    let large = "large value ".repeat(1000);
+    let store = KvStore::open_with_storage(storage.clone(), options())?;
+    store.set("small", "value1")?;
+    store.set("large", large.clone())?;
+    assert!(store.set_if_version("other".to_owned(), large.to_uppercase(), 0)?);
+    store.set("overwritten", large.clone())?;
+    store.set("overwritten", "value2")?;
+    assert!(live_bytes(&store) < 1000);
+
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.get("small")?, Some("value1".to_owned()));
+        assert_eq!(store.get("large")?, Some(large.clone()));
+        assert_eq!(store.get("other")?, Some(large.to_uppercase()));
+        assert_eq!(store.get("overwritten")?, Some("value2".to_owned()));
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+
+    // reading needs no option, and compaction keeps the values compressed
+    let store = KvStore::open_with_storage(storage.clone(), KvStoreOptions::new())?;
+    check(&store)?;
+    assert_eq!(store.verify()?, 4);
+    store.compact()?;
+    check(&store)?;
+    assert!(live_bytes(&store) < 1000);
+    store.set("uncompressed", large.clone())?;
+    assert_eq!(store.get("uncompressed")?, Some(large.clone()));
+    assert!(live_bytes(&store) > large.len() as u64);
+    drop(store);
+    check(&KvStore::open_with_storage(storage, options())?)?;
+    Ok(())
+}
+
+// Rename should move the value, whatever its record holds, and survive reopening and compaction
+#[test]
+fn rename() -> Result<()> {
+    let storage = MemoryStorage::new();
+    let options = || {
+        KvStoreOptions::new()
+            .large_value_threshold(1000)
+            .value_compression_threshold(100)
+    };
+    let blob = "blob value ".repeat(200);
+    let compressed = "compressed value ".repeat(20);
+    let store = KvStore::open_with_storage(storage.clone(), options())?;
+    store.set("key1", "value1")?;
+    store.set("key2", "value2")?;
+    store.set("blob", blob.clone())?;
+    store.set("compressed", compressed.clone())?;
+
+    assert!(store.rename("key1", "key3")?);
+    assert!(store.rename("key3", "key2")?);
+    assert!(store.rename("blob", "blob2")?);
+    assert!(store.rename("compressed", "compressed2")?);
+    assert!(!store.rename("key1", "key4")?);
+    assert!(store.rename("key2", "key2")?);
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.keys()?, vec!["blob2", "compressed2", "key2"]);
+        assert_eq!(store.get("key2")?, Some("value1".to_owned()));
+        assert_eq!(store.get("blob2")?, Some(blob.clone()));
+        assert_eq!(store.get("compressed2")?, Some(compressed.clone()));
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    let store = KvStore::open_with_storage(storage.clone(), options())?;
+    check(&store)?;
+
+    // a key renamed from and written again keeps its new value through compaction
+    store.set("key3", "value3")?;
+    store.compact()?;
+    drop(store);
+    let store = KvStore::open_with_storage(storage, options())?;
+    assert_eq!(store.get("key3")?, Some("value3".to_owned()));
+    store.remove("key3")?;
+    check(&store)?;
+
+    let handle = store.writer_handle()?;
+    assert!(matches!(store.rename("key2", "key1"), Err(KvsError::Busy)));
+    handle.close()?;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let sled = SledKvsEngine::open(temp_dir.path())?;
+    sled.set("key1", "value1")?;
+    assert!(KvsEngine::rename(&sled, "key1".to_owned(), "key2".to_owned())?);
+    assert!(!KvsEngine::rename(&sled, "key1".to_owned(), "key3".to_owned())?);
+    assert_eq!(sled.get("key1")?, None);
+    assert_eq!(sled.get("key2")?, Some("value1".to_owned()));
     Ok(())
 }
@@ -0,0 +1,911 @@
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use tempfile::TempDir;
+
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{
+    AdminCmd, AdminResult, BatchingKvsClient, ChangeOp, Command, CommandPolicy, KvStore, KvStoreOptions, KvsClient,
+    KvsClientPool, KvsError, KvsServer, MemoryStorage, Result, ServerConfig, TestServer, PROTOCOL_VERSION,
+};
+
+// A client that disconnects mid-request must not take down the worker serving it.
+#[test]
+fn survive_broken_connection() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = TestServer::new(KvStore::open(temp_dir.path())?)?;
+    let addr = server.addr();
+
+    for _ in 0..3 {
+        let mut stream = TcpStream::connect(addr)?;
+        stream.write_all(&[0, 0])?;
+    }
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(&[PROTOCOL_VERSION])?;
+    stream.write_all(&100u32.to_be_bytes())?;
+    stream.write_all(b"{\"Get\":")?;
+    drop(stream);
+
+    let mut client = KvsClient::init(&addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn many_queries_per_connection() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = TestServer::new(KvStore::open(temp_dir.path())?)?;
+    let addr = server.addr();
+
+    let mut client = KvsClient::init(&addr)?;
+    for i in 0..100 {
+        client.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    for i in 0..100 {
+        assert_eq!(client.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    client.remove("key0".to_owned())?;
+    assert_eq!(client.get("key0".to_owned())?, None);
+
+    Ok(())
+}
+
+// `get_all` should spread the keys over the pool and keep the results in order.
+#[test]
+fn client_pool_get_all() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = TestServer::new(KvStore::open(temp_dir.path())?)?;
+    let mut client = KvsClient::init(&server.addr())?;
+    for i in 0..100 {
+        client.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    // every open connection takes a thread of the server until closed
+    drop(client);
+
+    let pool = KvsClientPool::new(server.addr(), 4)?;
+    let keys: Vec<String> = (0..200).map(|i| format!("key{}", i)).collect();
+    let values = pool.get_all(keys)?;
+    assert_eq!(values.len(), 200);
+    for (i, value) in values.into_iter().enumerate() {
+        assert_eq!(value, if i < 100 { Some(format!("value{}", i)) } else { None });
+    }
+
+    // connections are kept for the next call
+    assert_eq!(pool.get_all(vec!["key1".to_owned()])?, vec![Some("value1".to_owned())]);
+    assert!(pool.get_all(Vec::new())?.is_empty());
+    assert!(matches!(
+        KvsClientPool::new(server.addr(), 0),
+        Err(KvsError::InvalidOption(_))
+    ));
+    Ok(())
+}
+
+// Every address of the server should serve the same engine, and stop together.
+#[test]
+fn multiple_listeners() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(temp_dir.path())?;
+    let thread_pool = SharedQueueThreadPool::new(4)?;
+    let mut server = KvsServer::init(engine, ([127, 0, 0, 1], 0).into(), thread_pool)?;
+    let second = server.add_listener(([127, 0, 0, 1], 0).into())?;
+    assert_eq!(server.local_addrs(), vec![server.local_addr(), second]);
+    let handle = server.start();
+
+    KvsClient::init(&server.local_addr())?.set("key1".to_owned(), "value1".to_owned())?;
+    let mut client = KvsClient::init(&second)?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(client);
+
+    server.stop_server();
+    handle.join().unwrap()?;
+    Ok(())
+}
+
+// Connections waiting for a worker should be counted until one serves them.
+#[test]
+fn queue_depth() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(temp_dir.path())?;
+    let thread_pool = SharedQueueThreadPool::new(1)?;
+    let server = KvsServer::init(engine, ([127, 0, 0, 1], 0).into(), thread_pool)?;
+    let handle = server.start();
+    let addr = server.local_addr();
+
+    let mut first = KvsClient::init(&addr)?;
+    first.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(server.queue_depth(), 0);
+
+    // the only worker serves the first connection, so the second one waits
+    let waiting = thread::spawn(move || KvsClient::init(&addr).and_then(|mut client| client.get("key1".to_owned())));
+    while server.queue_depth() == 0 {
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(server.queue_depth(), 1);
+    drop(first);
+    assert_eq!(waiting.join().unwrap()?, Some("value1".to_owned()));
+    assert_eq!(server.queue_depth(), 0);
+
+    server.stop_server();
+    handle.join().unwrap()?;
+    Ok(())
+}
+
+// Closing a client should free its worker by the time it returns.
+#[test]
+fn close_client() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(temp_dir.path())?;
+    let thread_pool = SharedQueueThreadPool::new(1)?;
+    let server = KvsServer::init(engine, ([127, 0, 0, 1], 0).into(), thread_pool)?;
+    let handle = server.start();
+    let addr = server.local_addr();
+
+    let mut client = KvsClient::init(&addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.close()?;
+
+    let mut client = KvsClient::init(&addr)?;
+    assert_eq!(server.queue_depth(), 0);
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    client.close()?;
+
+    server.stop_server();
+    handle.join().unwrap()?;
+    Ok(())
+}
+
+// Queued writes should reach the server only once the batch is full, flushed or dropped.
+#[test]
+fn batching_client() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = TestServer::new(KvStore::open(temp_dir.path())?)?;
+    let mut reader = KvsClient::init(&server.addr())?;
+
+    let mut client = BatchingKvsClient::new(KvsClient::init(&server.addr())?, 3);
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(client.queued(), 2);
+    assert_eq!(reader.get("key1".to_owned())?, None);
+    client.remove("key2".to_owned())?;
+    assert_eq!(client.queued(), 0);
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(reader.get("key2".to_owned())?, None);
+
+    // the failed remove does not stop the writes around it
+    client.set("key3".to_owned(), "value3".to_owned())?;
+    client.remove("missing".to_owned())?;
+    assert!(client.flush().is_err());
+    assert_eq!(reader.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    client.set("key4".to_owned(), "value4".to_owned())?;
+    drop(client);
+    assert_eq!(reader.get("key4".to_owned())?, Some("value4".to_owned()));
+
+    let mut client = BatchingKvsClient::new(KvsClient::init(&server.addr())?, 100).max_delay(Duration::from_millis(0));
+    client.set("key5".to_owned(), "value5".to_owned())?;
+    assert_eq!(client.queued(), 0);
+    assert_eq!(client.client().get("key5".to_owned())?, Some("value5".to_owned()));
+    Ok(())
+}
+
+// Servers of protocol version 1 cannot batch, so each write is sent on its own
+#[test]
+fn batching_client_before_batches() -> Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = thread::spawn(move || -> Result<()> {
+        let (mut stream, _) = listener.accept()?;
+        stream.read_exact(&mut [0])?;
+        write_frame(&mut stream, b"{\"Version\":1}")?;
+        assert_eq!(read_frame(&mut stream)?, b"{\"Set\":[\"key1\",\"value1\"]}");
+        write_frame(&mut stream, b"\"Success\"")?;
+        assert_eq!(read_frame(&mut stream)?, b"{\"Rm\":\"key2\"}");
+        write_frame(&mut stream, b"\"Success\"")
+    });
+
+    let mut client = BatchingKvsClient::new(KvsClient::init(&addr)?, 10);
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.remove("key2".to_owned())?;
+    client.flush()?;
+    server.join().unwrap()
+}
+
+// A large value should stream into the sink and leave the connection usable.
+#[test]
+fn get_to_streams_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = TestServer::new(KvStore::open(temp_dir.path())?)?;
+    let addr = server.addr();
+
+    let value = "v".repeat(4 << 20);
+    let mut client = KvsClient::init(&addr)?;
+    client.set("big".to_owned(), value.clone())?;
+
+    let mut sink = Vec::new();
+    assert!(client.get_to("big".to_owned(), &mut sink)?);
+    assert_eq!(sink, value.as_bytes());
+
+    let mut sink = Vec::new();
+    assert!(!client.get_to("missing".to_owned(), &mut sink)?);
+    assert!(sink.is_empty());
+
+    assert_eq!(client.get("big".to_owned())?.map(|v| v.len()), Some(value.len()));
+    Ok(())
+}
+
+/// A sink taking `capacity` bytes and failing afterwards
+struct FailingSink {
+    capacity: usize,
+}
+
+impl Write for FailingSink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.capacity == 0 {
+            return Err(std::io::Error::other("sink is full"));
+        }
+        let len = buf.len().min(self.capacity);
+        self.capacity -= len;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// A sink failing partway should not leave the rest of the value to the next query.
+#[test]
+fn get_to_failing_sink() -> Result<()> {
+    let engine = KvStore::open_with_storage(MemoryStorage::new(), KvStoreOptions::new())?;
+    let server = TestServer::new(engine)?;
+    let mut client = KvsClient::init(&server.addr())?;
+    let value = "v".repeat(1 << 20);
+    client.set("big".to_owned(), value.clone())?;
+    client.set("small".to_owned(), "value".to_owned())?;
+
+    let sink = FailingSink { capacity: 1000 };
+    assert!(matches!(client.get_to("big".to_owned(), sink), Err(KvsError::Io(_))));
+    assert_eq!(client.get("small".to_owned())?, Some("value".to_owned()));
+    assert_eq!(client.get("big".to_owned())?, Some(value));
+    Ok(())
+}
+
+// Warming up should read the existing data without disturbing it.
+#[test]
+fn warm_up_on_init() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    let config = ServerConfig::new().warm_up(true);
+    let server = TestServer::with_config(store, config)?;
+    let addr = server.addr();
+
+    let mut client = KvsClient::init(&addr)?;
+    assert_eq!(client.get("key42".to_owned())?, Some("value42".to_owned()));
+    Ok(())
+}
+
+// Admin commands should only run on servers that allow them.
+#[test]
+fn admin_commands() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = TestServer::new(KvStore::open(temp_dir.path())?)?;
+    let addr = server.addr();
+    let mut client = KvsClient::init(&addr)?;
+    assert!(matches!(client.admin(AdminCmd::Compact), Err(KvsError::Forbidden)));
+    drop(client);
+    drop(server);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = ServerConfig::new().allow_admin(true);
+    let server = TestServer::with_config(KvStore::open(temp_dir.path())?, config)?;
+    let addr = server.addr();
+
+    let mut client = KvsClient::init(&addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("key1".to_owned(), "value2".to_owned())?;
+    client.set("key2".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.admin(AdminCmd::Flush)?, AdminResult::Done);
+    assert_eq!(client.admin(AdminCmd::Compact)?, AdminResult::Done);
+    assert_eq!(
+        client.admin(AdminCmd::Stats)?,
+        AdminResult::Stats {
+            keys: 2,
+            queue_depth: 0
+        }
+    );
+    assert_eq!(client.get("key1".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// A command policy should refuse the commands it does not allow, whatever the engine allows
+#[test]
+fn command_policy() -> Result<()> {
+    let engine = KvStore::open_with_storage(MemoryStorage::new(), KvStoreOptions::new())?;
+    engine.set("key1", "value1")?;
+    let policy = CommandPolicy::read_only().allow(Command::Admin(AdminCmd::Stats));
+    let config = ServerConfig::new().allow_admin(true).command_policy(policy);
+    let server = TestServer::with_config(engine, config)?;
+    let mut client = KvsClient::init(&server.addr())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(client.get_bytes("key1".to_owned())?, Some(b"value1".to_vec()));
+    assert_eq!(client.count_prefix("key".to_owned())?, 1);
+    assert!(matches!(
+        client.set("key1".to_owned(), "value2".to_owned()),
+        Err(KvsError::Forbidden)
+    ));
+    assert!(matches!(client.remove("key1".to_owned()), Err(KvsError::Forbidden)));
+    assert!(matches!(
+        client.set_idempotent("key1".to_owned(), "value2".to_owned(), 1),
+        Err(KvsError::Forbidden)
+    ));
+    assert!(matches!(client.admin(AdminCmd::Compact), Err(KvsError::Forbidden)));
+    assert_eq!(
+        client.admin(AdminCmd::Stats)?,
+        AdminResult::Stats {
+            keys: 1,
+            queue_depth: 0
+        }
+    );
+
+    client.trace_requests(true);
+    assert!(matches!(
+        client.get_set("key1".to_owned(), "value2".to_owned()),
+        Err(KvsError::Forbidden)
+    ));
+    let mut batch = BatchingKvsClient::new(client, 10);
+    batch.set("key2".to_owned(), "value2".to_owned())?;
+    assert!(matches!(batch.flush(), Err(KvsError::Forbidden)));
+    assert_eq!(batch.client().get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(batch.client().get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// A sync should leave buffered writes in the storage, without admin commands being allowed
+#[test]
+fn sync_over_the_wire() -> Result<()> {
+    let storage = MemoryStorage::new();
+    let engine = KvStore::open_with_storage(storage.clone(), KvStoreOptions::new().flush_on_write(false))?;
+    let server = TestServer::new(engine)?;
+    let mut client = KvsClient::init(&server.addr())?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.sync()?;
+
+    let snapshot = KvStore::open_with_storage(storage, KvStoreOptions::new())?;
+    assert_eq!(snapshot.get("key1")?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Clients incrementing a counter with versioned reads and conditional writes should lose
+// no increment, retrying whenever another client wrote first
+#[test]
+fn optimistic_writes_over_the_wire() -> Result<()> {
+    let engine = KvStore::open_with_storage(MemoryStorage::new(), KvStoreOptions::new())?;
+    let server = TestServer::new(engine)?;
+    let addr = server.addr();
+    let mut client = KvsClient::init(&addr)?;
+    assert_eq!(client.get_versioned("counter".to_owned())?, None);
+    assert!(client.set_if_version("counter".to_owned(), "0".to_owned(), 0)?);
+    assert!(!client.set_if_version("counter".to_owned(), "0".to_owned(), 0)?);
+
+    let threads: Vec<_> = (0..2)
+        .map(|_| {
+            thread::spawn(move || -> Result<()> {
+                let mut client = KvsClient::init(&addr)?;
+                for _ in 0..50 {
+                    loop {
+                        let (value, version) = client.get_versioned("counter".to_owned())?.unwrap();
+                        let next = value.parse::<u32>()? + 1;
+                        if client.set_if_version("counter".to_owned(), next.to_string(), version)? {
+                            break;
+                        }
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap()?;
+    }
+    assert_eq!(client.get("counter".to_owned())?, Some("100".to_owned()));
+    Ok(())
+}
+
+// Swaps over the wire should return the value each replaced, no two clients getting the same one
+#[test]
+fn get_set_over_the_wire() -> Result<()> {
+    let engine = KvStore::open_with_storage(MemoryStorage::new(), KvStoreOptions::new())?;
+    let server = TestServer::new(engine)?;
+    let addr = server.addr();
+    let mut client = KvsClient::init(&addr)?;
+    assert_eq!(client.get_set("key1".to_owned(), "value1".to_owned())?, None);
+    assert_eq!(
+        client.get_set("key1".to_owned(), "value2".to_owned())?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(client.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    let threads: Vec<_> = (0..2)
+        .map(|t| {
+            thread::spawn(move || -> Result<Vec<Option<String>>> {
+                let mut client = KvsClient::init(&addr)?;
+                (0..50)
+                    .map(|i| client.get_set("token".to_owned(), format!("{}-{}", t, i)))
+                    .collect()
+            })
+        })
+        .collect();
+    let mut olds = Vec::new();
+    for thread in threads {
+        olds.extend(thread.join().unwrap()?);
+    }
+    olds.push(client.get("token".to_owned())?);
+    olds.sort();
+    let mut expected: Vec<Option<String>> = (0..2)
+        .flat_map(|t| (0..50).map(move |i| Some(format!("{}-{}", t, i))))
+        .collect();
+    expected.push(None);
+    expected.sort();
+    assert_eq!(olds, expected);
+    Ok(())
+}
+
+// Binary values should round trip over the wire, and textual ones read either way
+#[test]
+fn bytes_over_the_wire() -> Result<()> {
+    let engine = KvStore::open_with_storage(MemoryStorage::new(), KvStoreOptions::new())?;
+    let server = TestServer::new(engine)?;
+    let mut client = KvsClient::init(&server.addr())?;
+    let binary: Vec<u8> = (0..=255).rev().collect();
+    client.set_bytes("binary".to_owned(), binary.clone())?;
+    assert_eq!(client.get_bytes("binary".to_owned())?, Some(binary));
+
+    let nul = b"\0starts with the marker".to_vec();
+    client.set_bytes("nul".to_owned(), nul.clone())?;
+    assert_eq!(client.get_bytes("nul".to_owned())?, Some(nul));
+
+    client.set_bytes("text".to_owned(), "välue".as_bytes().to_vec())?;
+    assert_eq!(client.get("text".to_owned())?, Some("välue".to_owned()));
+    client.set("text".to_owned(), "välue2".to_owned())?;
+    assert_eq!(client.get_bytes("text".to_owned())?, Some("välue2".as_bytes().to_vec()));
+    assert_eq!(client.get_bytes("missing".to_owned())?, None);
+    Ok(())
+}
+
+// A shutdown should close idle connections and leave buffered writes in the storage
+#[test]
+fn graceful_shutdown() -> Result<()> {
+    let storage = MemoryStorage::new();
+    let engine = KvStore::open_with_storage(storage.clone(), KvStoreOptions::new().flush_on_write(false))?;
+    let thread_pool = SharedQueueThreadPool::new(2)?;
+    let server = KvsServer::init(engine, ([127, 0, 0, 1], 0).into(), thread_pool)?;
+    let handle = server.start();
+    let mut client = KvsClient::init(&server.local_addr())?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+
+    server.stop_server();
+    handle.join().unwrap()?;
+    server.shutdown()?;
+    assert!(client.get("key1".to_owned()).is_err());
+
+    let snapshot = KvStore::open_with_storage(storage, KvStoreOptions::new())?;
+    assert_eq!(snapshot.get("key1")?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// An idle connection should be closed after the idle timeout, freeing its worker
+#[test]
+fn idle_timeout() -> Result<()> {
+    let engine = KvStore::open_with_storage(MemoryStorage::new(), KvStoreOptions::new())?;
+    let thread_pool = SharedQueueThreadPool::new(1)?;
+    let config = ServerConfig::new().idle_timeout(Some(Duration::from_millis(100)));
+    let server = KvsServer::init_with_config(engine, ([127, 0, 0, 1], 0).into(), thread_pool, config)?;
+    let handle = server.start();
+    let addr = server.local_addr();
+
+    let mut idle = KvsClient::init(&addr)?;
+    idle.set("key1".to_owned(), "value1".to_owned())?;
+    // served by the only worker once the idle connection is closed
+    let mut client = KvsClient::init(&addr)?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(idle.get("key1".to_owned()).is_err());
+
+    let mut reconnecting = KvsClient::init_with_reconnect(&addr)?;
+    drop(client);
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(reconnecting.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    server.stop_server();
+    handle.join().unwrap()?;
+    server.shutdown()
+}
+
+// A restarted server should bind its address while connections it closed are in TIME_WAIT
+#[test]
+fn rebind_after_restart() -> Result<()> {
+    let storage = MemoryStorage::new();
+    let config = || {
+        ServerConfig::new()
+            .idle_timeout(Some(Duration::from_millis(100)))
+            .listen_backlog(16)
+    };
+    let engine = KvStore::open_with_storage(storage.clone(), KvStoreOptions::new())?;
+    let server = KvsServer::init_with_config(
+        engine,
+        ([127, 0, 0, 1], 0).into(),
+        SharedQueueThreadPool::new(2)?,
+        config(),
+    )?;
+    let handle = server.start();
+    let addr = server.local_addr();
+    let mut client = KvsClient::init(&addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    // closed by the server, which leaves its end in TIME_WAIT
+    thread::sleep(Duration::from_millis(300));
+    server.stop_server();
+    handle.join().unwrap()?;
+    server.shutdown()?;
+    drop(server);
+
+    let engine = KvStore::open_with_storage(storage, KvStoreOptions::new())?;
+    let server = KvsServer::init_with_config(engine, addr, SharedQueueThreadPool::new(2)?, config())?;
+    let handle = server.start();
+    let mut client = KvsClient::init(&addr)?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    client.close()?;
+    server.stop_server();
+    handle.join().unwrap()?;
+    server.shutdown()
+}
+
+// An engine shared behind an `Arc` should serve queries and stay usable by its other owners
+#[test]
+fn serve_shared_engine() -> Result<()> {
+    let engine = Arc::new(KvStore::open_with_storage(MemoryStorage::new(), KvStoreOptions::new())?);
+    let thread_pool = SharedQueueThreadPool::new(2)?;
+    let server = KvsServer::init(engine.clone(), ([127, 0, 0, 1], 0).into(), thread_pool)?;
+    let handle = server.start();
+
+    let mut client = KvsClient::init(&server.local_addr())?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(engine.get("key1")?, Some("value1".to_owned()));
+    engine.set("key2", "value2")?;
+    assert_eq!(client.get("key2".to_owned())?, Some("value2".to_owned()));
+    client.close()?;
+
+    server.stop_server();
+    handle.join().unwrap()
+}
+
+// Queries after a reload should run on the new engine, on open and new connections alike.
+#[test]
+fn reload_engine() -> Result<()> {
+    let old_dir = TempDir::new().expect("unable to create temporary working directory");
+    let new_dir = TempDir::new().expect("unable to create temporary working directory");
+    let old_store = KvStore::open(old_dir.path())?;
+    old_store.set("key1", "old")?;
+    let new_store = KvStore::open(new_dir.path())?;
+    new_store.set("key1", "new")?;
+
+    let server = TestServer::new(old_store)?;
+    let addr = server.addr();
+
+    let mut client = KvsClient::init(&addr)?;
+    assert_eq!(client.get("key1".to_owned())?, Some("old".to_owned()));
+    server.server().reload(new_store)?;
+    assert_eq!(client.get("key1".to_owned())?, Some("new".to_owned()));
+    drop(client);
+
+    let mut client = KvsClient::init(&addr)?;
+    assert_eq!(client.get("key1".to_owned())?, Some("new".to_owned()));
+    Ok(())
+}
+
+// Absent keys are answered with `KeyNotFound`, which tools speaking the protocol rely on.
+#[test]
+fn get_missing_key_on_the_wire() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = TestServer::new(KvStore::open(temp_dir.path())?)?;
+
+    let mut stream = TcpStream::connect(server.addr())?;
+    stream.write_all(&[PROTOCOL_VERSION])?;
+    assert_eq!(
+        read_frame(&mut stream)?,
+        format!("{{\"Version\":{}}}", PROTOCOL_VERSION).as_bytes()
+    );
+
+    let query = b"{\"Get\":\"missing\"}";
+    stream.write_all(&(query.len() as u32).to_be_bytes())?;
+    stream.write_all(query)?;
+
+    assert_eq!(read_frame(&mut stream)?, b"\"KeyNotFound\"");
+    Ok(())
+}
+
+// An empty value should go over the wire as such, unlike an absent key
+#[test]
+fn empty_value_on_the_wire() -> Result<()> {
+    let server = TestServer::new(KvStore::open_with_storage(MemoryStorage::new(), KvStoreOptions::new())?)?;
+    let mut client = KvsClient::init(&server.addr())?;
+    client.set("key1".to_owned(), String::new())?;
+    assert_eq!(client.get("key1".to_owned())?, Some(String::new()));
+    let mut value = Vec::new();
+    assert!(client.get_to("key1".to_owned(), &mut value)?);
+    assert!(value.is_empty());
+    assert_eq!(client.get_versioned("key1".to_owned())?.unwrap().0, "");
+    assert!(!client.get_to("key2".to_owned(), &mut value)?);
+
+    let mut batching = BatchingKvsClient::new(client, 10);
+    batching.set("key2".to_owned(), String::new())?;
+    batching.flush()?;
+    assert_eq!(batching.client().get("key2".to_owned())?, Some(String::new()));
+    assert_eq!(batching.client().get("key3".to_owned())?, None);
+    Ok(())
+}
+
+fn read_frame(stream: &mut TcpStream) -> Result<Vec<u8>> {
+    let mut len = [0; 4];
+    stream.read_exact(&mut len)?;
+    let mut frame = vec![0; u32::from_be_bytes(len) as usize];
+    stream.read_exact(&mut frame)?;
+    Ok(frame)
+}
+
+// Clients of a protocol version the server does not speak should be told so and disconnected
+#[test]
+fn protocol_version_mismatch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = TestServer::new(KvStore::open(temp_dir.path())?)?;
+
+    let mut stream = TcpStream::connect(server.addr())?;
+    stream.write_all(&[0])?;
+    assert_eq!(
+        read_frame(&mut stream)?,
+        format!("{{\"VersionMismatch\":{}}}", PROTOCOL_VERSION).as_bytes()
+    );
+    assert_eq!(stream.read(&mut [0; 1])?, 0);
+
+    // newer clients are answered with the version this server speaks
+    let mut stream = TcpStream::connect(server.addr())?;
+    stream.write_all(&[PROTOCOL_VERSION + 1])?;
+    assert_eq!(
+        read_frame(&mut stream)?,
+        format!("{{\"Version\":{}}}", PROTOCOL_VERSION).as_bytes()
+    );
+    Ok(())
+}
+
+// A response that does not fit the query should fail the call instead of panicking the client
+#[test]
+fn unexpected_response() -> Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = thread::spawn(move || -> Result<()> {
+        let (mut stream, _) = listener.accept()?;
+        stream.read_exact(&mut [0])?;
+        write_frame(&mut stream, format!("{{\"Version\":{}}}", PROTOCOL_VERSION).as_bytes())?;
+        read_frame(&mut stream)?;
+        write_frame(&mut stream, b"{\"Count\":1}")
+    });
+
+    let mut client = KvsClient::init(&addr)?;
+    assert!(matches!(client.get("key1".to_owned()), Err(KvsError::ProtocolError(_))));
+    server.join().unwrap()
+}
+
+fn write_frame(stream: &mut TcpStream, frame: &[u8]) -> Result<()> {
+    stream.write_all(&(frame.len() as u32).to_be_bytes())?;
+    stream.write_all(frame)?;
+    Ok(())
+}
+
+// Traced queries should be answered as usual, each with its own request ID
+#[test]
+fn traced_requests() -> Result<()> {
+    let engine = KvStore::open_with_storage(MemoryStorage::new(), KvStoreOptions::new())?;
+    let server = TestServer::new(engine)?;
+    let mut client = KvsClient::init(&server.addr())?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.last_request_id(), None);
+
+    client.trace_requests(true);
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    let first = client.last_request_id().unwrap();
+    assert_eq!(client.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(client.last_request_id(), Some(first.wrapping_add(1)));
+    let id = first.wrapping_add(2);
+    assert!(matches!(
+        client.remove("missing".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert_eq!(client.last_request_id(), Some(id));
+
+    client.set_request_id(42);
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(client.last_request_id(), Some(42));
+    let mut batching = BatchingKvsClient::new(client, 10);
+    batching.set("key3".to_owned(), "value3".to_owned())?;
+    batching.flush()?;
+    assert_eq!(batching.client().get("key3".to_owned())?, Some("value3".to_owned()));
+
+    batching.client().trace_requests(false);
+    batching.client().get("key3".to_owned())?;
+    assert_eq!(batching.client().last_request_id(), None);
+    Ok(())
+}
+
+// A traced query that fails should name its request ID in the error
+#[test]
+fn traced_request_failure() -> Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = thread::spawn(move || -> Result<()> {
+        let (mut stream, _) = listener.accept()?;
+        stream.read_exact(&mut [0])?;
+        write_frame(&mut stream, format!("{{\"Version\":{}}}", PROTOCOL_VERSION).as_bytes())?;
+        assert_eq!(read_frame(&mut stream)?, b"{\"Traced\":[42,{\"Get\":\"key1\"}]}");
+        write_frame(&mut stream, b"{\"Traced\":[42,\"Err\"]}")
+    });
+
+    let mut client = KvsClient::init(&addr)?;
+    client.set_request_id(42);
+    let error = client.get("key1".to_owned()).unwrap_err();
+    assert_eq!(error.to_string(), "request 000000000000002a failed: server error");
+    assert!(matches!(error, KvsError::Request { id: 42, ref error } if matches!(**error, KvsError::ServerError)));
+    server.join().unwrap()
+}
+
+// A reconnecting client should retry reads over a new connection, but not writes
+#[test]
+fn reconnect_on_broken_connection() -> Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = thread::spawn(move || -> Result<()> {
+        let accept = || -> Result<TcpStream> {
+            let (mut stream, _) = listener.accept()?;
+            stream.read_exact(&mut [0])?;
+            write_frame(&mut stream, format!("{{\"Version\":{}}}", PROTOCOL_VERSION).as_bytes())?;
+            Ok(stream)
+        };
+        // closed right after the handshake, like a restarting server
+        drop(accept()?);
+        let mut stream = accept()?;
+        assert_eq!(read_frame(&mut stream)?, b"{\"Get\":\"key1\"}");
+        write_frame(&mut stream, b"{\"Ok\":\"value1\"}")?;
+        drop(stream);
+        let mut stream = accept()?;
+        assert_eq!(read_frame(&mut stream)?, b"{\"Get\":\"key2\"}");
+        write_frame(&mut stream, b"\"KeyNotFound\"")?;
+
+        // a client without reconnection
+        drop(accept()?);
+        Ok(())
+    });
+
+    let mut client = KvsClient::init_with_reconnect(&addr)?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(matches!(
+        client.set("key1".to_owned(), "value2".to_owned()),
+        Err(KvsError::Io(_))
+    ));
+    assert_eq!(client.get("key2".to_owned())?, None);
+
+    let mut client = KvsClient::init(&addr)?;
+    assert!(matches!(client.get("key1".to_owned()), Err(KvsError::Io(_))));
+    server.join().unwrap()
+}
+
+// A write repeated with the same token should be answered without being applied again
+#[test]
+fn idempotent_writes() -> Result<()> {
+    let engine = KvStore::open_with_storage(MemoryStorage::new(), KvStoreOptions::new())?;
+    let server = TestServer::with_config(engine, ServerConfig::new().idempotency_window(2))?;
+    let mut client = KvsClient::init(&server.addr())?;
+    client.set_idempotent("key1".to_owned(), "value1".to_owned(), 1)?;
+    client.set("key1".to_owned(), "value2".to_owned())?;
+    client.set_idempotent("key1".to_owned(), "value1".to_owned(), 1)?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    client.remove_idempotent("key1".to_owned(), 2)?;
+    client.remove_idempotent("key1".to_owned(), 2)?;
+    assert!(matches!(
+        client.remove_idempotent("key1".to_owned(), 3),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert_eq!(client.get("key1".to_owned())?, None);
+    // a removal that found no key is remembered too
+    client.set("key1".to_owned(), "value3".to_owned())?;
+    assert!(matches!(
+        client.remove_idempotent("key1".to_owned(), 3),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert_eq!(client.get("key1".to_owned())?, Some("value3".to_owned()));
+
+    // only the 2 most recent tokens are remembered
+    client.set_idempotent("key1".to_owned(), "value1".to_owned(), 1)?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// An idempotent write should be retried over a new connection if the connection broke
+#[test]
+fn retry_idempotent_write() -> Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = thread::spawn(move || -> Result<()> {
+        let accept = || -> Result<TcpStream> {
+            let (mut stream, _) = listener.accept()?;
+            stream.read_exact(&mut [0])?;
+            write_frame(&mut stream, format!("{{\"Version\":{}}}", PROTOCOL_VERSION).as_bytes())?;
+            Ok(stream)
+        };
+        let query = b"{\"Idempotent\":[7,{\"Set\":[\"key1\",\"value1\"]}]}";
+        // closed before answering, like a crashing server
+        assert_eq!(read_frame(&mut accept()?)?, query);
+        let mut stream = accept()?;
+        assert_eq!(read_frame(&mut stream)?, query);
+        write_frame(&mut stream, b"\"Success\"")
+    });
+
+    let mut client = KvsClient::init_with_reconnect(&addr)?;
+    client.set_idempotent("key1".to_owned(), "value1".to_owned(), 7)?;
+    server.join().unwrap()
+}
+
+#[test]
+fn count_prefix_on_the_wire() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = TestServer::new(KvStore::open(temp_dir.path())?)?;
+    let mut client = KvsClient::init(&server.addr())?;
+    for i in 0..5 {
+        client.set(format!("key{}", i), "value".to_owned())?;
+    }
+    client.set("other".to_owned(), "value".to_owned())?;
+    client.remove("key0".to_owned())?;
+    assert_eq!(client.count_prefix("key".to_owned())?, 4);
+    assert_eq!(client.count_prefix("missing".to_owned())?, 0);
+    Ok(())
+}
+
+// A subscribed client should receive every write in order, and its stream end with the server
+#[test]
+fn subscribe_to_changes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = TestServer::new(KvStore::open(temp_dir.path())?)?;
+    let mut changes = KvsClient::init(&server.addr())?.subscribe()?;
+
+    let mut client = KvsClient::init(&server.addr())?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.remove("key1".to_owned())?;
+    assert!(client.remove("key1".to_owned()).is_err());
+    let mut batching = BatchingKvsClient::new(client, 10);
+    batching.set("key2".to_owned(), "value2".to_owned())?;
+    batching.flush()?;
+
+    let events: Vec<(u64, ChangeOp, String)> = changes
+        .by_ref()
+        .take(3)
+        .map(|event| event.map(|event| (event.seq, event.op, event.key)))
+        .collect::<Result<_>>()?;
+    assert_eq!(
+        events,
+        vec![
+            (1, ChangeOp::Set, "key1".to_owned()),
+            (2, ChangeOp::Rm, "key1".to_owned()),
+            (3, ChangeOp::Set, "key2".to_owned()),
+        ]
+    );
+
+    drop(server);
+    assert!(changes.next().is_none());
+    Ok(())
+}
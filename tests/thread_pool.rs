@@ -1,8 +1,9 @@
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use kvs::thread_pool::*;
-use kvs::Result;
+use kvs::{KvsError, Result};
 
 use crossbeam_utils::sync::WaitGroup;
 
@@ -64,7 +65,140 @@ fn rayon_thread_pool_spawn_counter() -> Result<()> {
     spawn_counter(pool)
 }
 
+#[test]
+fn rayon_thread_pool_spawn_result() -> Result<()> {
+    let pool = RayonThreadPool::new(4)?;
+    let results: Vec<_> = (0..100u64).map(|i| pool.spawn_result(move || i * i)).collect();
+    for (i, result) in results.into_iter().enumerate() {
+        assert_eq!(result.recv().unwrap(), (i * i) as u64);
+    }
+
+    let panicked = pool.spawn_result(|| -> u64 {
+        panic_control::disable_hook_in_current_thread();
+        panic!();
+    });
+    assert!(panicked.recv().is_err());
+    assert_eq!(pool.spawn_result(|| 42).recv().unwrap(), 42);
+    Ok(())
+}
+
 #[test]
 fn shared_queue_thread_pool_panic_task() -> Result<()> {
     spawn_panic_task::<SharedQueueThreadPool>()
 }
+
+#[test]
+fn bounded_shared_queue_thread_pool_spawn_counter() -> Result<()> {
+    let pool = SharedQueueThreadPool::bounded(4, 2)?;
+    spawn_counter(pool)
+}
+
+#[test]
+fn bounded_shared_queue_thread_pool_try_spawn_full() -> Result<()> {
+    let pool = SharedQueueThreadPool::bounded(1, 1)?;
+    let (started_tx, started_rx) = crossbeam::bounded(0);
+    let (release_tx, release_rx) = crossbeam::bounded::<()>(0);
+
+    pool.spawn(move || {
+        started_tx.send(()).unwrap();
+        release_rx.recv().unwrap();
+    });
+    // the only worker is busy, so the queue takes one job and rejects the next
+    started_rx.recv().unwrap();
+    pool.try_spawn(|| {})?;
+    match pool.try_spawn(|| {}) {
+        Err(KvsError::ThreadPoolFull) => {}
+        other => panic!("expected a full queue, got {:?}", other),
+    }
+
+    release_tx.send(()).unwrap();
+    Ok(())
+}
+
+#[test]
+fn shared_queue_thread_pool_skips_expired_jobs() -> Result<()> {
+    let pool = SharedQueueThreadPool::new(1)?;
+    let counter = Arc::new(AtomicUsize::new(0));
+    let (release_tx, release_rx) = crossbeam::bounded::<()>(0);
+    pool.spawn(move || release_rx.recv().unwrap());
+
+    // both jobs wait behind the blocked worker, only the second is still wanted afterwards
+    let expired = Arc::clone(&counter);
+    pool.spawn_with_deadline(
+        move || {
+            expired.fetch_add(1, Ordering::SeqCst);
+        },
+        Instant::now() + Duration::from_millis(10),
+    );
+    let pending = Arc::clone(&counter);
+    pool.spawn_with_deadline(
+        move || {
+            pending.fetch_add(10, Ordering::SeqCst);
+        },
+        Instant::now() + Duration::from_secs(60),
+    );
+    std::thread::sleep(Duration::from_millis(50));
+    release_tx.send(()).unwrap();
+
+    let wg = WaitGroup::new();
+    let done = wg.clone();
+    pool.spawn(move || drop(done));
+    wg.wait();
+    assert_eq!(counter.load(Ordering::SeqCst), 10);
+    Ok(())
+}
+
+#[test]
+fn zero_threads_rejected() {
+    assert!(matches!(NaiveThreadPool::new(0), Err(KvsError::InvalidThreadCount)));
+    assert!(matches!(
+        SharedQueueThreadPool::new(0),
+        Err(KvsError::InvalidThreadCount)
+    ));
+    assert!(matches!(
+        SharedQueueThreadPool::bounded(0, 1),
+        Err(KvsError::InvalidThreadCount)
+    ));
+    assert!(matches!(RayonThreadPool::new(0), Err(KvsError::InvalidThreadCount)));
+}
+
+/// names of the threads running `jobs` jobs of the pool, which must have `threads` workers
+fn worker_names<P: ThreadPool>(pool: &P, threads: usize, jobs: usize) -> Vec<String> {
+    // every worker holds a job until all of them hold one, so each job names a distinct worker
+    let barrier = Arc::new(std::sync::Barrier::new(threads));
+    let (tx, rx) = crossbeam::unbounded();
+    for _ in 0..jobs {
+        let barrier = Arc::clone(&barrier);
+        let tx = tx.clone();
+        pool.spawn(move || {
+            barrier.wait();
+            tx.send(std::thread::current().name().map(str::to_owned)).unwrap();
+        });
+    }
+    let mut names: Vec<String> = rx.iter().take(jobs).map(Option::unwrap).collect();
+    names.sort();
+    names
+}
+
+#[test]
+fn workers_are_named() -> Result<()> {
+    let expected = ["kvs-worker-0", "kvs-worker-1", "kvs-worker-2"];
+    assert_eq!(worker_names(&SharedQueueThreadPool::new(3)?, 3, 3), expected);
+    assert_eq!(worker_names(&RayonThreadPool::new(3)?, 3, 3), expected);
+
+    // a worker respawned after a panic keeps its name
+    let pool = SharedQueueThreadPool::new(1)?;
+    pool.spawn(|| {
+        panic_control::disable_hook_in_current_thread();
+        panic!();
+    });
+    assert_eq!(worker_names(&pool, 1, 1), ["kvs-worker-0"]);
+    Ok(())
+}
+
+#[cfg(feature = "core_affinity")]
+#[test]
+fn pinned_shared_queue_thread_pool_spawn_counter() -> Result<()> {
+    let pool = SharedQueueThreadPool::pinned(4)?;
+    spawn_counter(pool)
+}